@@ -0,0 +1,204 @@
+// This module lets operators take an offline backup of the canister's state and restore
+// it into another canister, independently of stable memory and upgrades.
+//
+// A backup is the Candid encoding of `FullState`: the order book with its client order
+// ids, the people records and the configuration. In-memory caches are not included; they
+// are rebuilt on demand.
+
+use crate::{
+    config::{self, Config},
+    htlc_orders::{self, HtlcDetail},
+    state::{self, Person},
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::update;
+use std::collections::HashSet;
+
+/// Everything `export_full_state` writes to a backup.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct FullState {
+    /// All orders keyed by order number, sorted by order number.
+    pub orders: Vec<(u64, HtlcDetail)>,
+    /// Order number the next created order gets.
+    pub next_order_no: u64,
+    /// Order numbers created through `create_order_with_key`, keyed by the calling
+    /// principal and the client's id, sorted by principal and id.
+    pub client_order_ids: Vec<(Principal, String, u64)>,
+    /// All people records keyed by name, sorted by name.
+    pub people: Vec<(String, Person)>,
+    pub config: Config,
+}
+
+impl FullState {
+    /// Captures the current state of the canister.
+    fn current() -> Self {
+        let (orders, next_order_no) = htlc_orders::export_orders();
+        Self {
+            orders,
+            next_order_no,
+            client_order_ids: htlc_orders::export_client_order_ids(),
+            people: state::export_people(),
+            config: config::current(),
+        }
+    }
+
+    /// Returns whether the state holds no orders and no people. The configuration always
+    /// exists and is not considered.
+    fn is_empty(&self) -> bool {
+        self.orders.is_empty() && self.next_order_no == 0 && self.people.is_empty()
+    }
+
+    /// Checks that the state is consistent: order numbers are unique and below
+    /// `next_order_no`, client order ids are unique and point at stored orders, and
+    /// people are unique and stored under their own name.
+    fn validate(&self) -> Result<(), String> {
+        let mut order_nos = HashSet::new();
+        for (order_no, _) in &self.orders {
+            if !order_nos.insert(*order_no) {
+                return Err(format!("Order {} appears more than once", order_no));
+            }
+            if *order_no >= self.next_order_no {
+                return Err(format!(
+                    "Order {} is not below the next order number {}",
+                    order_no, self.next_order_no
+                ));
+            }
+        }
+
+        let mut client_ids = HashSet::new();
+        for (caller, id, order_no) in &self.client_order_ids {
+            if !client_ids.insert((caller, id)) {
+                return Err(format!(
+                    "Client order id {} of {} appears more than once",
+                    id, caller
+                ));
+            }
+            if !order_nos.contains(order_no) {
+                return Err(format!(
+                    "Client order id {} of {} points at missing order {}",
+                    id, caller, order_no
+                ));
+            }
+        }
+
+        let mut names = HashSet::new();
+        for (name, person) in &self.people {
+            if !names.insert(name) {
+                return Err(format!("Person {} appears more than once", name));
+            }
+            if *name != person.name {
+                return Err(format!(
+                    "Person {} is stored under the name {}",
+                    person.name, name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the Candid-encoded `FullState` of the canister for an offline backup.
+///
+/// Only controllers can call this method.
+#[update(guard = "crate::config::caller_is_controller")]
+pub fn export_full_state() -> Vec<u8> {
+    candid::encode_one(FullState::current()).expect("encoding the state should succeed")
+}
+
+/// Decodes a backup created by `export_full_state` and checks that it is consistent.
+fn decode_full_state(data: &[u8]) -> Result<FullState, String> {
+    let state: FullState =
+        candid::decode_one(data).map_err(|e| format!("Invalid state backup: {}", e))?;
+    state.validate()?;
+    Ok(state)
+}
+
+/// Restores a backup created by `export_full_state`, replacing the order book with its
+/// client order ids, the people records and the configuration.
+///
+/// The backup is validated before anything is changed. Importing over a canister that
+/// already holds orders or people is refused unless `force` is set, in which case the
+/// existing state is discarded. Only controllers can call this method.
+#[update(guard = "crate::config::caller_is_controller")]
+pub fn import_full_state(data: Vec<u8>, force: bool) -> Result<(), String> {
+    let imported = decode_full_state(&data)?;
+    if !force && !FullState::current().is_empty() {
+        return Err("The canister already holds state; set force to overwrite it".to_string());
+    }
+
+    htlc_orders::restore_orders(
+        imported.orders,
+        imported.next_order_no,
+        imported.client_order_ids,
+    );
+    state::restore_people(imported.people);
+    config::restore(imported.config);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_state() -> FullState {
+        FullState {
+            orders: vec![
+                (0, HtlcDetail::default()),
+                (
+                    2,
+                    HtlcDetail {
+                        time_lock: 144,
+                        ..Default::default()
+                    },
+                ),
+            ],
+            next_order_no: 3,
+            client_order_ids: vec![(Principal::anonymous(), "client-1".to_string(), 2)],
+            people: vec![("Alice".to_string(), Person::new("Alice".to_string(), 30))],
+            config: Config::default(),
+        }
+    }
+
+    #[test]
+    fn test_decode_full_state_validates() {
+        let state = full_state();
+        let encoded = candid::encode_one(&state).unwrap();
+        assert_eq!(decode_full_state(&encoded).unwrap(), state);
+        assert!(decode_full_state(&[0, 1, 2]).is_err());
+
+        let mut duplicate = full_state();
+        duplicate.orders.push((0, HtlcDetail::default()));
+        assert!(duplicate.validate().unwrap_err().contains("more than once"));
+
+        let mut behind = full_state();
+        behind.next_order_no = 2;
+        assert!(behind.validate().unwrap_err().contains("next order number"));
+
+        let mut repeated = full_state();
+        repeated
+            .client_order_ids
+            .push(repeated.client_order_ids[0].clone());
+        assert!(repeated.validate().unwrap_err().contains("more than once"));
+
+        let mut dangling = full_state();
+        dangling.client_order_ids[0].2 = 1;
+        assert!(dangling.validate().unwrap_err().contains("missing order"));
+
+        let mut renamed = full_state();
+        renamed.people[0].0 = "Bob".to_string();
+        assert!(renamed.validate().is_err());
+    }
+
+    #[test]
+    fn test_import_refuses_non_empty_state_without_force() {
+        let data = candid::encode_one(full_state()).unwrap();
+        import_full_state(data.clone(), false).unwrap();
+        assert_eq!(FullState::current(), full_state());
+
+        assert!(import_full_state(data.clone(), false)
+            .unwrap_err()
+            .contains("force"));
+        import_full_state(data, true).unwrap();
+        assert_eq!(FullState::current(), full_state());
+    }
+}
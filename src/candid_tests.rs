@@ -0,0 +1,334 @@
+// Round-trip tests for every Candid type exposed by the canister.
+//
+// Clients decode these types by their Candid shape, so any change here that breaks a
+// round trip is also likely to break deployed clients. The values below double as a
+// reference for the canonical shape of each type.
+
+use crate::{
+    backup::FullState,
+    common::CoinSelection,
+    config::Config,
+    error::HtlcError,
+    htlc_orders::{
+        BroadcastRecord, CoinInfo, FeeDetail, HtlcDetail, HtlcPreview, OrderEvent, OrderEventKind,
+        OrderPatch, OrderStatus, PreparedWithdraw, RedeemTemplate, Reservation, ScriptHashDebug,
+        SelfTestReport, SpendRecord, SwapSimulation, WithdrawResult,
+    },
+    htlc_script::{HtlcAddressFormat, HtlcHashType, HtlcScriptParams, HtlcTimelockType, SpendPath},
+    service::{BitcoinIdentity, VersionInfo},
+    state::Person,
+    SendRequest,
+};
+use candid::{decode_one, encode_one, CandidType, Principal};
+use ic_cdk::bitcoin_canister::Network;
+use serde::de::DeserializeOwned;
+use std::fmt::Debug;
+
+fn assert_round_trip<T: CandidType + DeserializeOwned + PartialEq + Debug>(value: T) {
+    let bytes = encode_one(&value).expect("encoding should succeed");
+    let decoded: T = decode_one(&bytes).expect("decoding should succeed");
+    assert_eq!(decoded, value);
+}
+
+fn fee_detail() -> FeeDetail {
+    FeeDetail {
+        txid: "ab".repeat(32),
+        fee: 1_410,
+        fee_rate: 10_000,
+        vsize: 141,
+    }
+}
+
+#[test]
+fn test_htlc_detail_round_trip() {
+    // All optional fields absent, as for a freshly created order.
+    assert_round_trip(HtlcDetail {
+        initiator_pubkey: "02".to_string() + &"11".repeat(32),
+        time_lock: 144,
+        secret_hash: "22".repeat(32),
+        ..Default::default()
+    });
+
+    // All optional fields present, as for a funded order with a sub-account.
+    assert_round_trip(HtlcDetail {
+        initiator_pubkey: "02".to_string() + &"11".repeat(32),
+        time_lock: 144,
+        secret_hash: "22".repeat(32),
+        htlc_address: Some("bcrt1qexample".to_string()),
+        responder_pubkey: Some("03".to_string() + &"33".repeat(32)),
+        fee_details: vec![fee_detail()],
+        sub_accounts: vec![("deposit".to_string(), "bcrt1qdeposit".to_string())],
+        funding_height: Some(800_000),
+        status: OrderStatus::Funded,
+        prepared_withdraw: Some(PreparedWithdraw {
+            transaction: vec![2, 0, 0, 0],
+            responder_pubkey: "03".to_string() + &"33".repeat(32),
+            fee_detail: fee_detail(),
+        }),
+        inflight_value: 50_000,
+        created_at_ns: 1_700_000_000_000_000_000,
+        funding_outpoint: Some(format!("{}:0", "44".repeat(32))),
+        requested_amount: Some(50_000),
+        reservation: Some(Reservation {
+            responder_pubkey: "03".to_string() + &"33".repeat(32),
+            expires_at_ns: 1_700_000_060_000_000_000,
+            holder: Some(Principal::from_slice(&[2])),
+            renewals: Some(1),
+        }),
+        spend: Some(SpendRecord {
+            txid: "55".repeat(32),
+            destination: "bcrt1qdestination".to_string(),
+        }),
+        timelock_type: Some(HtlcTimelockType::Cltv),
+        owner: Some(Principal::from_slice(&[1])),
+        funding_addresses: Some(vec!["bcrt1qfresh".to_string()]),
+        hash_type: Some(HtlcHashType::Hash160),
+        htlc_address_network: Some(Network::Regtest),
+        funding_transaction: Some(vec![0x02, 0x00, 0x00, 0x00]),
+        address_format: Some(HtlcAddressFormat::NestedP2shP2wsh),
+    });
+}
+
+#[test]
+fn test_order_status_round_trip() {
+    for status in [
+        OrderStatus::Created,
+        OrderStatus::AddressGenerated,
+        OrderStatus::Funded,
+        OrderStatus::Claimed,
+        OrderStatus::Refunded,
+    ] {
+        assert_round_trip(status);
+    }
+}
+
+#[test]
+fn test_fee_detail_round_trip() {
+    assert_round_trip(fee_detail());
+}
+
+#[test]
+fn test_withdraw_result_round_trip() {
+    assert_round_trip(WithdrawResult {
+        txid: "11".repeat(32),
+        htlc_address: "bcrt1qhtlc".to_string(),
+        amount: 40_000,
+        fee_paid: 306,
+        inputs: 2,
+    });
+}
+
+#[test]
+fn test_coin_selection_round_trip() {
+    for coin_selection in [
+        CoinSelection::LargestFirst,
+        CoinSelection::SmallestFirst,
+        CoinSelection::BranchAndBound,
+    ] {
+        assert_round_trip(coin_selection);
+    }
+}
+
+#[test]
+fn test_broadcast_record_round_trip() {
+    assert_round_trip(BroadcastRecord {
+        txid: "11".repeat(32),
+        order_no: 3,
+        kind: "refund".to_string(),
+        timestamp: 1_700_000_000_000_000_000,
+        fee: 306,
+    });
+}
+
+#[test]
+fn test_order_patch_round_trip() {
+    assert_round_trip(OrderPatch {
+        requested_amount: Some(50_000),
+        time_lock: None,
+        responder_pubkey: Some("02".repeat(33)),
+    });
+}
+
+#[test]
+fn test_order_event_round_trip() {
+    for kind in [
+        OrderEventKind::Created,
+        OrderEventKind::AddressGenerated,
+        OrderEventKind::FundingBroadcast,
+        OrderEventKind::Funded,
+        OrderEventKind::Claimed,
+        OrderEventKind::Refunded,
+    ] {
+        assert_round_trip(OrderEvent {
+            order_no: 3,
+            kind,
+            txid: Some("11".repeat(32)),
+            timestamp_ns: 1_700_000_000_000_000_000,
+        });
+    }
+}
+
+#[test]
+fn test_coin_info_round_trip() {
+    assert_round_trip(CoinInfo {
+        outpoint: format!("{}:1", "ab".repeat(32)),
+        value: 50_000,
+        confirmations: 3,
+        spendable: true,
+    });
+}
+
+#[test]
+fn test_htlc_preview_round_trip() {
+    assert_round_trip(HtlcPreview {
+        address: "bcrt1qhtlc".to_string(),
+        witness_script_hex: "63a820".to_string(),
+    });
+}
+
+#[test]
+fn test_self_test_report_round_trip() {
+    assert_round_trip(SelfTestReport {
+        order_no: 3,
+        derivation_path: "m/84/0/3/0/0".to_string(),
+        order_pubkey: "02".to_string() + &"11".repeat(32),
+        funding_address: "bcrt1qfunding".to_string(),
+        htlc_address: "bcrt1qhtlc".to_string(),
+        witness_script_hex: "63a820".to_string(),
+        secret_hash: "22".repeat(32),
+        preimage_hex: "33".repeat(32),
+        time_lock: 6,
+    });
+}
+
+#[test]
+fn test_swap_simulation_round_trip() {
+    assert_round_trip(SwapSimulation {
+        funding_address: "bcrt1qfunding".to_string(),
+        htlc_address: "bcrt1qhtlc".to_string(),
+        redeem_script_hex: "63a820".to_string(),
+        estimated_funding_fee: 1_530,
+        estimated_claim_fee: 1_380,
+    });
+}
+
+#[test]
+fn test_redeem_template_round_trip() {
+    assert_round_trip(RedeemTemplate {
+        unsigned_tx_hex: "02000000".to_string(),
+        sighash: "44".repeat(32),
+        witness_script_hex: "63a820".to_string(),
+        input_amount: 10_000,
+    });
+}
+
+#[test]
+fn test_script_hash_debug_round_trip() {
+    assert_round_trip(ScriptHashDebug {
+        script_hex: "63a820".to_string(),
+        script_sha256_hex: "55".repeat(32),
+        redeem_script_hex: Some(format!("0020{}", "55".repeat(32))),
+        scriptpubkey_hex: format!("0020{}", "55".repeat(32)),
+        address: "bcrt1qhtlc".to_string(),
+    });
+}
+
+#[test]
+fn test_htlc_script_params_round_trip() {
+    assert_round_trip(HtlcScriptParams {
+        initiator_pubkey: "02".to_string() + &"11".repeat(32),
+        responder_pubkey: "03".to_string() + &"33".repeat(32),
+        secret_hash: "22".repeat(20),
+        time_lock: 800_000,
+        hash_type: HtlcHashType::Hash160,
+        timelock_type: HtlcTimelockType::Cltv,
+        cooperative: true,
+    });
+}
+
+#[test]
+fn test_spend_path_round_trip() {
+    assert_round_trip(SpendPath {
+        path: "hashlock".to_string(),
+        preimage: Some("42".repeat(32)),
+    });
+    assert_round_trip(SpendPath {
+        path: "timelock".to_string(),
+        preimage: None,
+    });
+}
+
+#[test]
+fn test_bitcoin_identity_round_trip() {
+    assert_round_trip(BitcoinIdentity {
+        key_name: "test_key_1".to_string(),
+        network: "testnet".to_string(),
+        example_address: "tb1qexample".to_string(),
+    });
+}
+
+#[test]
+fn test_version_info_round_trip() {
+    assert_round_trip(VersionInfo {
+        version: "0.1.0".to_string(),
+        features: vec!["cltv".to_string(), "sub-accounts".to_string()],
+    });
+}
+
+#[test]
+fn test_person_and_send_request_round_trip() {
+    assert_round_trip(Person::new("Alice".to_string(), 30));
+    assert_round_trip(SendRequest {
+        destination_address: "tb1qdestination".to_string(),
+        amount_in_satoshi: 50_000,
+    });
+}
+
+#[test]
+fn test_config_round_trip() {
+    assert_round_trip(Config::default());
+    assert_round_trip(Config {
+        allow_spend_unconfirmed_change: true,
+        enforce_claimable_amount: false,
+        max_total_inflight_value: Some(100_000_000),
+        min_time_lock: Some(144),
+        max_time_lock: None,
+        large_withdrawal_threshold: Some(10_000_000),
+        min_deposit_confirmations: 1,
+        max_signing_attempts: 5,
+        fee_percentile: 90,
+    });
+}
+
+#[test]
+fn test_htlc_error_round_trip() {
+    for error in [
+        HtlcError::InputsMissingOrSpent("bad-txns-inputs-missingorspent".to_string()),
+        HtlcError::FeeTooLow("min relay fee not met".to_string()),
+        HtlcError::NonStandard("dust".to_string()),
+        HtlcError::AlreadyInMempool("txn-already-in-mempool".to_string()),
+        HtlcError::BroadcastRejected("MalformedTransaction".to_string()),
+        HtlcError::NoUtxos,
+        HtlcError::Validation("Secret hash must be 32 bytes, got 20".to_string()),
+        HtlcError::FundingUnconfirmed {
+            unconfirmed_value: 25_000,
+        },
+        HtlcError::ConfirmationRequired {
+            token: "cd".repeat(32),
+        },
+        HtlcError::Other("Order 7 not found".to_string()),
+    ] {
+        assert_round_trip(error);
+    }
+}
+
+#[test]
+fn test_full_state_round_trip() {
+    assert_round_trip(FullState {
+        orders: vec![(0, HtlcDetail::default())],
+        next_order_no: 1,
+        client_order_ids: vec![(Principal::anonymous(), "client-1".to_string(), 0)],
+        people: vec![("Alice".to_string(), Person::new("Alice".to_string(), 30))],
+        config: Config::default(),
+    });
+}
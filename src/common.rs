@@ -7,11 +7,54 @@ use bitcoin::{
     self, absolute::LockTime, blockdata::witness::Witness, hashes::Hash, transaction::Version,
     Address, Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid,
 };
+use candid::{CandidType, Deserialize};
 use ic_cdk::bitcoin_canister::{
-    bitcoin_get_current_fee_percentiles, GetCurrentFeePercentilesRequest, Utxo,
+    bitcoin_get_current_fee_percentiles, GetCurrentFeePercentilesRequest, MillisatoshiPerByte, Utxo,
 };
 use std::fmt;
 
+/// Dust threshold below which change outputs are discarded.
+/// This prevents creating outputs that cost more to spend than they're worth.
+pub const DUST_THRESHOLD: u64 = 1_000;
+
+/// Smallest value of a P2WSH output that Bitcoin Core relays at the default dust relay
+/// fee rate. Smaller HTLC outputs would be rejected by the network.
+pub const DUST_THRESHOLD_P2WSH: u64 = 330;
+
+/// Bitcoin Core's default minimum relay fee rate of 1 sat/vB, in millisatoshi per vbyte.
+/// Transactions paying less are not relayed and never confirm.
+pub const MIN_RELAY_FEE_PER_BYTE: MillisatoshiPerByte = 1_000;
+
+/// Virtual size of the fixed transaction fields: version, lock time, input and output
+/// counts, and the SegWit marker and flag (10.5 vbytes, rounded up).
+pub const TX_OVERHEAD_VBYTES: u64 = 11;
+
+/// Virtual size of a signed P2WPKH input: outpoint, sequence, empty `script_sig` and a
+/// witness holding a DER signature and a compressed public key.
+pub const P2WPKH_INPUT_VBYTES: u64 = 68;
+
+/// Returns the BIP-141 weight of `transaction` in weight units.
+///
+/// Unlike the virtual size, which is the weight divided by four and rounded up, the
+/// weight is exact and is what fee-rate policies are ultimately defined over.
+pub fn tx_weight(transaction: &Transaction) -> u64 {
+    transaction.weight().to_wu()
+}
+
+/// Returns the fee in satoshi for `vsize` vbytes at `fee_per_byte` millisatoshi per vbyte.
+///
+/// The amount is rounded up to the next satoshi so the effective rate is never below
+/// `fee_per_byte`.
+pub fn fee_for_vsize(vsize: u64, fee_per_byte: MillisatoshiPerByte) -> u64 {
+    (vsize * fee_per_byte).div_ceil(1000)
+}
+
+/// Returns the fee in satoshi for a transaction of `weight` weight units, charging for
+/// `ceil(weight / 4)` vbytes at `fee_per_byte`.
+pub fn fee_for_weight(weight: u64, fee_per_byte: MillisatoshiPerByte) -> u64 {
+    fee_for_vsize(weight.div_ceil(4), fee_per_byte)
+}
+
 /// Selects UTXOs using a greedy algorithm to cover the required amount plus fee.
 ///
 /// This function iterates through UTXOs in reverse order (oldest last) and accumulates
@@ -46,6 +89,100 @@ pub fn select_utxos_greedy(
     Ok(utxos_to_spend)
 }
 
+/// Strategy for picking the UTXOs that fund a payment, see `p2wpkh::build_transaction`.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum CoinSelection {
+    /// Largest UTXOs first, which minimizes the number of inputs and thus the fee.
+    #[default]
+    LargestFirst,
+    /// Smallest UTXOs first, which consolidates small UTXOs at the cost of a higher fee.
+    SmallestFirst,
+    /// A selection leaving no change, see `select_utxos_without_change`, falling back to
+    /// largest first if there is none.
+    BranchAndBound,
+}
+
+/// Selects the UTXOs `coin_selection` picks to pay `amount` plus `fee`.
+///
+/// `exact` is the selection without change found up front for
+/// `CoinSelection::BranchAndBound`, see `select_utxos_without_change`. It is based on
+/// estimated input sizes and only used while it covers the fee; otherwise UTXOs are
+/// taken by value. The transaction builder and the fee estimate both select through
+/// here, so the estimate describes the transaction that gets built.
+pub fn select_funding_utxos<'a>(
+    own_utxos: &'a [Utxo],
+    amount: u64,
+    fee: u64,
+    exact: Option<&[&'a Utxo]>,
+    coin_selection: CoinSelection,
+) -> Result<Vec<&'a Utxo>, String> {
+    match exact {
+        Some(utxos) if utxos.iter().map(|utxo| utxo.value).sum::<u64>() >= amount + fee => {
+            Ok(utxos.to_vec())
+        }
+        _ => select_utxos_by_value(
+            own_utxos,
+            amount,
+            fee,
+            coin_selection != CoinSelection::SmallestFirst,
+        ),
+    }
+}
+
+/// Returns the selection without change `select_funding_utxos` prefers for
+/// `coin_selection`, if any. Only `CoinSelection::BranchAndBound` looks for one.
+pub fn exact_funding_selection<'a>(
+    own_utxos: &'a [Utxo],
+    amount: u64,
+    fee_per_byte: MillisatoshiPerByte,
+    destination: &Address,
+    coin_selection: CoinSelection,
+) -> Option<Vec<&'a Utxo>> {
+    match coin_selection {
+        CoinSelection::BranchAndBound => {
+            select_utxos_without_change(own_utxos, amount, fee_per_byte, output_vbytes(destination))
+        }
+        _ => None,
+    }
+}
+
+/// Selects UTXOs by value, largest first or smallest first, until they cover the
+/// required amount plus fee.
+///
+/// Returns an error if the total UTXO value is insufficient to cover the payment and fee.
+pub fn select_utxos_by_value(
+    own_utxos: &[Utxo],
+    amount: u64,
+    fee: u64,
+    largest_first: bool,
+) -> Result<Vec<&Utxo>, String> {
+    let mut utxos: Vec<&Utxo> = own_utxos.iter().collect();
+    if largest_first {
+        utxos.sort_by_key(|utxo| std::cmp::Reverse(utxo.value));
+    } else {
+        utxos.sort_by_key(|utxo| utxo.value);
+    }
+
+    let mut utxos_to_spend = vec![];
+    let mut total_spent = 0;
+    for utxo in utxos {
+        if total_spent >= amount + fee {
+            break;
+        }
+        total_spent += utxo.value;
+        utxos_to_spend.push(utxo);
+    }
+
+    if total_spent < amount + fee {
+        return Err(format!(
+            "Insufficient balance: {}, trying to transfer {} satoshi with fee {}",
+            total_spent, amount, fee
+        ));
+    }
+
+    Ok(utxos_to_spend)
+}
+
 /// Selects a single UTXO that can cover the required amount plus fee.
 ///
 /// This function is used when you need to tie a specific operation to a single UTXO,
@@ -102,10 +239,6 @@ pub fn build_transaction_with_fee(
     primary_output: &PrimaryOutput,
     fee: u64,
 ) -> Result<(Transaction, Vec<TxOut>), String> {
-    // Define a dust threshold below which change outputs are discarded.
-    // This prevents creating outputs that cost more to spend than they're worth.
-    const DUST_THRESHOLD: u64 = 1_000;
-
     // --- Build Inputs ---
     // Convert UTXOs into transaction inputs, preparing them for signing.
     let inputs: Vec<TxIn> = utxos_to_spend
@@ -115,8 +248,8 @@ pub fn build_transaction_with_fee(
                 txid: Txid::from_raw_hash(Hash::from_slice(&utxo.outpoint.txid).unwrap()),
                 vout: utxo.outpoint.vout,
             },
-            sequence: Sequence::MAX,      // No relative timelock constraints
-            witness: Witness::new(),      // Will be filled in during signing
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME, // Replaceable (BIP-125), no timelock
+            witness: Witness::new(),                    // Will be filled in during signing
             script_sig: ScriptBuf::new(), // Empty for SegWit and Taproot (uses witness)
         })
         .collect();
@@ -175,11 +308,173 @@ pub fn build_transaction_with_fee(
     ))
 }
 
+/// Virtual size of an output paying to a P2WPKH address.
+pub const P2WPKH_OUTPUT_VBYTES: u64 = 31;
+
+/// Returns the virtual size of an output paying to `address`.
+///
+/// An output consists of its 8-byte value, a 1-byte script length and the script itself,
+/// so its size depends on the destination type (31 vbytes for P2WPKH, 43 for P2WSH and P2TR).
+pub fn output_vbytes(address: &Address) -> u64 {
+    8 + 1 + address.script_pubkey().len() as u64
+}
+
+/// Estimates the fee for sending `amount` from P2WPKH UTXOs to `destination`.
+///
+/// Mirrors the iterative approach of `p2wpkh::build_transaction` without signing
+/// anything: UTXOs are selected as `coin_selection` picks them, see
+/// `select_funding_utxos`, and the size accounts for the destination's output type and
+/// for a change output back to `own_address` when the change is above dust.
+/// The loop stops once the fee covers the size, so it terminates even if a higher fee
+/// switches to a smaller input set.
+pub fn estimate_p2wpkh_fee(
+    own_utxos: &[Utxo],
+    own_address: &Address,
+    destination: &Address,
+    amount: u64,
+    fee_per_byte: MillisatoshiPerByte,
+    coin_selection: CoinSelection,
+) -> Result<u64, String> {
+    let exact =
+        exact_funding_selection(own_utxos, amount, fee_per_byte, destination, coin_selection);
+    let mut fee = 0;
+    loop {
+        let utxos_to_spend =
+            select_funding_utxos(own_utxos, amount, fee, exact.as_deref(), coin_selection)?;
+        let total_in: u64 = utxos_to_spend.iter().map(|utxo| utxo.value).sum();
+
+        let mut vsize = TX_OVERHEAD_VBYTES
+            + utxos_to_spend.len() as u64 * P2WPKH_INPUT_VBYTES
+            + output_vbytes(destination);
+        if total_in - amount - fee >= DUST_THRESHOLD {
+            vsize += output_vbytes(own_address);
+        }
+
+        let new_fee = fee_for_vsize(vsize, fee_per_byte);
+        if new_fee <= fee {
+            return Ok(fee);
+        }
+        fee = new_fee;
+    }
+}
+
+/// Maximum number of search steps `select_utxos_without_change` takes.
+const MAX_EXACT_SELECTION_STEPS: usize = 100_000;
+
+/// Selects P2WPKH UTXOs that pay `amount` to a single output of `output_vbytes` without
+/// a change output.
+///
+/// A selection qualifies if what is left after `amount` and the fee for its size is below
+/// the dust threshold, so that the leftover can go to the fee. The selection with the
+/// smallest leftover is returned. UTXOs worth less than the fee of spending them are
+/// ignored. The search is a depth-first branch and bound over the UTXOs sorted by value,
+/// cut short after `MAX_EXACT_SELECTION_STEPS` steps; `None` means no qualifying
+/// selection was found.
+pub fn select_utxos_without_change(
+    own_utxos: &[Utxo],
+    amount: u64,
+    fee_per_byte: MillisatoshiPerByte,
+    output_vbytes: u64,
+) -> Option<Vec<&Utxo>> {
+    let input_fee = fee_for_vsize(P2WPKH_INPUT_VBYTES, fee_per_byte);
+    let mut utxos: Vec<&Utxo> = own_utxos
+        .iter()
+        .filter(|utxo| utxo.value > input_fee)
+        .collect();
+    utxos.sort_unstable_by_key(|utxo| std::cmp::Reverse(utxo.value));
+
+    // remaining[i] is the total value of utxos[i..], used to prune hopeless branches.
+    let mut remaining = vec![0; utxos.len() + 1];
+    for i in (0..utxos.len()).rev() {
+        remaining[i] = remaining[i + 1] + utxos[i].value;
+    }
+
+    let mut search = ExactSelection {
+        utxos: &utxos,
+        remaining: &remaining,
+        amount,
+        fee_per_byte,
+        output_vbytes,
+        steps: 0,
+        selected: vec![],
+        best: None,
+    };
+    search.run(0, 0);
+
+    let (_, selected) = search.best?;
+    Some(selected.into_iter().map(|index| utxos[index]).collect())
+}
+
+/// State of the depth-first search in `select_utxos_without_change`.
+struct ExactSelection<'a> {
+    utxos: &'a [&'a Utxo],
+    remaining: &'a [u64],
+    amount: u64,
+    fee_per_byte: MillisatoshiPerByte,
+    output_vbytes: u64,
+    steps: usize,
+    selected: Vec<usize>,
+    /// Smallest leftover found so far, with the selected indices.
+    best: Option<(u64, Vec<usize>)>,
+}
+
+impl ExactSelection<'_> {
+    /// Returns the amount plus fee a selection of `inputs` UTXOs has to cover.
+    fn target(&self, inputs: usize) -> u64 {
+        let vsize = TX_OVERHEAD_VBYTES + inputs as u64 * P2WPKH_INPUT_VBYTES + self.output_vbytes;
+        self.amount + fee_for_vsize(vsize, self.fee_per_byte)
+    }
+
+    fn run(&mut self, index: usize, total: u64) {
+        self.steps += 1;
+        if self.steps > MAX_EXACT_SELECTION_STEPS {
+            return;
+        }
+
+        let target = self.target(self.selected.len());
+        if !self.selected.is_empty() && total >= target {
+            // Every further input adds more value than fee, so the leftover only grows.
+            let leftover = total - target;
+            if leftover < DUST_THRESHOLD
+                && self.best.as_ref().is_none_or(|(best, _)| leftover < *best)
+            {
+                self.best = Some((leftover, self.selected.clone()));
+            }
+            return;
+        }
+        if index == self.utxos.len() || total + self.remaining[index] < target {
+            return;
+        }
+
+        self.selected.push(index);
+        self.run(index + 1, total + self.utxos[index].value);
+        self.selected.pop();
+        self.run(index + 1, total);
+    }
+}
+
+/// Returns the largest amount that can be sent from all `own_utxos` to `destination`.
+///
+/// All UTXOs are spent into a single output with no change, so the result is the total
+/// value minus the fee for that transaction, or 0 if the fee exceeds the total.
+pub fn max_spendable_p2wpkh(
+    own_utxos: &[Utxo],
+    destination: &Address,
+    fee_per_byte: MillisatoshiPerByte,
+) -> u64 {
+    let total: u64 = own_utxos.iter().map(|utxo| utxo.value).sum();
+    let vsize = TX_OVERHEAD_VBYTES
+        + own_utxos.len() as u64 * P2WPKH_INPUT_VBYTES
+        + output_vbytes(destination);
+    total.saturating_sub(fee_for_vsize(vsize, fee_per_byte))
+}
+
 /// Estimates a reasonable fee rate for Bitcoin transactions based on network conditions.
 ///
 /// This function queries the Bitcoin network for recent fee percentiles and returns
-/// the median (50th percentile) fee rate, which provides a good balance between
-/// confirmation time and cost. The fee rate is returned in millisatoshis per byte.
+/// the fee rate at the configured percentile (`Config::fee_percentile`, the median by
+/// default), which provides a good balance between confirmation time and cost. The fee
+/// rate is returned in millisatoshis per byte.
 ///
 /// On regtest networks (local development), fee data is typically unavailable since
 /// there are no standard transactions, so the function falls back to a static rate
@@ -196,14 +491,20 @@ pub async fn get_fee_per_byte(ctx: &BitcoinContext) -> u64 {
     .await
     .unwrap();
 
-    if fee_percentiles.is_empty() {
-        // Empty percentiles indicate that we're likely on regtest with no standard transactions.
-        // Use a reasonable fallback that works for development and testing.
-        2000 // 2 sat/vB in millisatoshis
-    } else {
-        // Use the 50th percentile (median) for balanced confirmation time and cost.
-        // This avoids both overpaying (high percentiles) and slow confirmation (low percentiles).
-        fee_percentiles[50]
+    fee_at_percentile(&fee_percentiles, crate::config::current().fee_percentile)
+}
+
+/// Picks the fee rate at `percentile` from the percentiles reported by the Bitcoin
+/// canister, falling back to the highest reported rate if the list is shorter.
+fn fee_at_percentile(fee_percentiles: &[MillisatoshiPerByte], percentile: u8) -> u64 {
+    match fee_percentiles.get(usize::from(percentile)) {
+        Some(fee) => *fee,
+        None => match fee_percentiles.last() {
+            Some(fee) => *fee,
+            // Empty percentiles indicate that we're likely on regtest with no standard
+            // transactions. Use a reasonable fallback for development and testing.
+            None => 2000, // 2 sat/vB in millisatoshis
+        },
     }
 }
 
@@ -298,6 +599,11 @@ impl DerivationPath {
         Self::new(Purpose::P2TR, account, address_index)
     }
 
+    /// Returns the same path on chain `change` instead of the external chain 0.
+    pub fn on_chain(self, change: u32) -> Self {
+        Self { change, ..self }
+    }
+
     /// Converts the derivation path to the binary format expected by IC's key derivation APIs.
     ///
     /// Returns a Vec<Vec<u8>> where each inner Vec represents one level of the path
@@ -326,3 +632,223 @@ impl fmt::Display for DerivationPath {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{
+        key::Secp256k1,
+        secp256k1::{PublicKey, SecretKey},
+        CompressedPublicKey, Network,
+    };
+    use ic_cdk::bitcoin_canister::Outpoint;
+
+    fn utxo(value: u64) -> Utxo {
+        Utxo {
+            outpoint: Outpoint {
+                txid: vec![1; 32],
+                vout: 0,
+            },
+            value,
+            height: 1,
+        }
+    }
+
+    fn public_key(seed: u8) -> PublicKey {
+        PublicKey::from_secret_key(
+            &Secp256k1::new(),
+            &SecretKey::from_slice(&[seed; 32]).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_fee_estimate_depends_on_destination_type() {
+        let own_address = Address::p2wpkh(&CompressedPublicKey(public_key(1)), Network::Regtest);
+        let p2wpkh_destination =
+            Address::p2wpkh(&CompressedPublicKey(public_key(2)), Network::Regtest);
+        let p2tr_destination = Address::p2tr(
+            &Secp256k1::new(),
+            public_key(3).x_only_public_key().0,
+            None,
+            Network::Regtest,
+        );
+        assert_eq!(output_vbytes(&p2wpkh_destination), 31);
+        assert_eq!(output_vbytes(&p2tr_destination), 43);
+
+        let utxos = [utxo(100_000)];
+        let fee_per_byte = 10_000; // 10 sat/vB
+        let p2wpkh_fee = estimate_p2wpkh_fee(
+            &utxos,
+            &own_address,
+            &p2wpkh_destination,
+            50_000,
+            fee_per_byte,
+            CoinSelection::default(),
+        )
+        .unwrap();
+        let p2tr_fee = estimate_p2wpkh_fee(
+            &utxos,
+            &own_address,
+            &p2tr_destination,
+            50_000,
+            fee_per_byte,
+            CoinSelection::default(),
+        )
+        .unwrap();
+        assert_eq!(p2tr_fee - p2wpkh_fee, 12 * 10);
+
+        assert_eq!(
+            max_spendable_p2wpkh(&utxos, &p2wpkh_destination, fee_per_byte)
+                - max_spendable_p2wpkh(&utxos, &p2tr_destination, fee_per_byte),
+            12 * 10
+        );
+    }
+
+    #[test]
+    fn test_fee_estimate_follows_coin_selection() {
+        let own_address = Address::p2wpkh(&CompressedPublicKey(public_key(1)), Network::Regtest);
+        let destination = Address::p2wpkh(&CompressedPublicKey(public_key(2)), Network::Regtest);
+        let utxos = [utxo(30_000), utxo(20_000)];
+        let estimate = |coin_selection| {
+            estimate_p2wpkh_fee(
+                &utxos,
+                &own_address,
+                &destination,
+                19_500,
+                2_000,
+                coin_selection,
+            )
+            .unwrap()
+        };
+
+        // Largest first spends the 30_000 UTXO and pays for a change output, while the
+        // other strategies spend the 20_000 UTXO and leave no change.
+        let largest_first = estimate(CoinSelection::LargestFirst);
+        assert_eq!(
+            largest_first - estimate(CoinSelection::BranchAndBound),
+            output_vbytes(&own_address) * 2
+        );
+        assert_eq!(
+            estimate(CoinSelection::SmallestFirst),
+            estimate(CoinSelection::BranchAndBound)
+        );
+    }
+
+    #[test]
+    fn test_weight_based_fee() {
+        let mut witness = Witness::new();
+        witness.push([0u8; 71]);
+        witness.push([0u8; 33]);
+        let destination = Address::p2wpkh(&CompressedPublicKey(public_key(1)), Network::Regtest);
+        let transaction = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness,
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(10_000),
+                script_pubkey: destination.script_pubkey(),
+            }],
+        };
+
+        // 82 non-witness bytes count four times, the marker, flag and 107 witness bytes once.
+        let weight = tx_weight(&transaction);
+        assert_eq!(weight, 82 * 4 + 2 + 107);
+        assert_eq!(weight % 4, 1);
+        assert_eq!(transaction.vsize() as u64, weight.div_ceil(4));
+
+        // At 1.5 sat/vB the exact fee is 164.5 satoshi, which must round up.
+        assert_eq!(fee_for_weight(weight, 1_500), 165);
+        assert_eq!(fee_for_weight(weight, 1_000), 110);
+    }
+
+    #[test]
+    fn test_select_utxos_without_change() {
+        let fee_per_byte = 1_000; // 1 sat/vB
+        let fee = |inputs: u64| TX_OVERHEAD_VBYTES + inputs * P2WPKH_INPUT_VBYTES + 43;
+
+        // 30_000 + 20_000 covers 50_000 only without a fee; 40_000 + 10_500 leaves 500
+        // minus the fee, which is below dust.
+        let utxos = [utxo(30_000), utxo(20_000), utxo(40_000), utxo(10_500)];
+        let selected = select_utxos_without_change(&utxos, 50_000, fee_per_byte, 43).unwrap();
+        let total: u64 = selected.iter().map(|utxo| utxo.value).sum();
+        assert!(total >= 50_000 + fee(selected.len() as u64));
+        assert!(total - 50_000 - fee(selected.len() as u64) < DUST_THRESHOLD);
+
+        // A single UTXO far above the amount always leaves change.
+        assert!(select_utxos_without_change(&[utxo(100_000)], 50_000, fee_per_byte, 43).is_none());
+        assert!(select_utxos_without_change(&[], 50_000, fee_per_byte, 43).is_none());
+    }
+
+    #[test]
+    fn test_select_utxos_by_value() {
+        let utxos = [utxo(5_000), utxo(800), utxo(20_000), utxo(1_200)];
+        let values =
+            |selected: Vec<&Utxo>| -> Vec<u64> { selected.iter().map(|utxo| utxo.value).collect() };
+
+        assert_eq!(
+            values(select_utxos_by_value(&utxos, 15_000, 500, true).unwrap()),
+            [20_000]
+        );
+        assert_eq!(
+            values(select_utxos_by_value(&utxos, 6_000, 500, false).unwrap()),
+            [800, 1_200, 5_000]
+        );
+        assert_eq!(
+            values(select_utxos_by_value(&utxos, 0, 0, true).unwrap()),
+            Vec::<u64>::new()
+        );
+        assert!(select_utxos_by_value(&utxos, 27_000, 1, false).is_err());
+    }
+
+    #[test]
+    fn test_change_below_dust_is_dropped() {
+        let own_address = Address::p2wpkh(&CompressedPublicKey(public_key(1)), Network::Regtest);
+        let utxos = [utxo(50_000)];
+        let output = |amount| PrimaryOutput::Address(own_address.clone(), amount);
+
+        // 999 satoshi of change would be dust, so it goes to the fee instead.
+        let (transaction, _) = build_transaction_with_fee(
+            utxos.iter().collect(),
+            &own_address,
+            &output(48_001),
+            1_000,
+        )
+        .unwrap();
+        assert_eq!(transaction.output.len(), 1);
+
+        let (transaction, _) = build_transaction_with_fee(
+            utxos.iter().collect(),
+            &own_address,
+            &output(48_000),
+            1_000,
+        )
+        .unwrap();
+        assert_eq!(transaction.output.len(), 2);
+        assert_eq!(transaction.output[1].value.to_sat(), DUST_THRESHOLD);
+        assert!(transaction.is_explicitly_rbf());
+    }
+
+    #[test]
+    fn test_fee_at_percentile() {
+        let fee_percentiles: Vec<u64> = (0..=100).map(|p| 1_000 + p * 100).collect();
+        let fee_at_config =
+            || fee_at_percentile(&fee_percentiles, crate::config::current().fee_percentile);
+
+        crate::config::restore(crate::config::Config::default());
+        assert_eq!(fee_at_config(), 6_000);
+        crate::config::restore(crate::config::Config {
+            fee_percentile: 90,
+            ..Default::default()
+        });
+        assert_eq!(fee_at_config(), 10_000);
+        crate::config::restore(crate::config::Config::default());
+
+        assert_eq!(fee_at_percentile(&[1_000, 2_000], 50), 2_000);
+        assert_eq!(fee_at_percentile(&[], 50), 2_000);
+    }
+}
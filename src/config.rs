@@ -0,0 +1,227 @@
+// This module holds the canister's runtime configuration.
+// Settings default to conservative values and can be changed by the canister's controllers.
+
+use crate::stable_memory;
+use candid::{CandidType, Deserialize, Reserved};
+use ic_cdk::{query, update};
+use std::cell::RefCell;
+
+/// Operator-tunable settings of the canister.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Config {
+    /// Whether withdrawals may spend change outputs of the canister's own transactions
+    /// before they are confirmed. Disabled by default to avoid long unconfirmed chains.
+    pub allow_spend_unconfirmed_change: bool,
+    /// Whether withdrawals are rejected if the HTLC output would not cover the fee of
+    /// claiming it plus dust at the current fee rate. Enabled by default.
+    pub enforce_claimable_amount: bool,
+    /// Upper bound on the total value locked in HTLCs and not yet claimed or refunded,
+    /// in satoshi. `None` means no limit.
+    pub max_total_inflight_value: Option<u64>,
+    /// Smallest timelock (in blocks) new orders may use, on top of the network minimum.
+    /// `None` means no additional bound.
+    pub min_time_lock: Option<u64>,
+    /// Largest timelock (in blocks) new orders may use, on top of the CSV maximum.
+    /// `None` means no additional bound.
+    pub max_time_lock: Option<u64>,
+    /// Withdrawals of more than this many satoshi must be confirmed with a one-time
+    /// token, see `withdraw_from_order`. `None` disables the confirmation step.
+    pub large_withdrawal_threshold: Option<u64>,
+    /// Confirmations a deposit to an order's funding address needs before withdrawals
+    /// may spend it. 0 (the default) allows spending unconfirmed deposits.
+    pub min_deposit_confirmations: u32,
+    /// Attempts made to obtain an ECDSA signature before giving up, when the signing
+    /// API fails with a transient error such as subnet congestion. Defaults to 3.
+    pub max_signing_attempts: u32,
+    /// Percentile of recent fee rates, between 0 and 100, that `get_fee_per_byte` bids.
+    /// Higher values confirm faster at a higher cost. Defaults to the median (50).
+    pub fee_percentile: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            allow_spend_unconfirmed_change: false,
+            enforce_claimable_amount: true,
+            max_total_inflight_value: None,
+            min_time_lock: None,
+            max_time_lock: None,
+            large_withdrawal_threshold: None,
+            min_deposit_confirmations: 0,
+            max_signing_attempts: 3,
+            fee_percentile: 50,
+        }
+    }
+}
+
+// Global configuration. It is carried across upgrades in the upgrade message, see
+// `restore_config_state`.
+thread_local! {
+    static CONFIG: RefCell<Config> = RefCell::new(Config::default());
+}
+
+/// Guard that only lets the canister's controllers through.
+pub fn caller_is_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::api::msg_caller()) {
+        Ok(())
+    } else {
+        Err("Only controllers of the canister can call this method".to_string())
+    }
+}
+
+/// Returns a copy of the current configuration.
+pub fn current() -> Config {
+    CONFIG.with_borrow(|config| config.clone())
+}
+
+/// Replaces the whole configuration, e.g. when restoring a state backup.
+pub fn restore(config: Config) {
+    CONFIG.with_borrow_mut(|current| *current = config);
+}
+
+/// Helper function to restore the configuration from the message saved before the
+/// upgrade, see `htlc_orders::save_orders_state`.
+/// Call this from the post_upgrade hook in lib.rs.
+///
+/// Messages saved before the configuration was persisted leave the defaults in place.
+pub fn restore_config_state(message: &[u8]) {
+    if let Some(config) = decode_config_state(message) {
+        restore(config);
+    }
+}
+
+/// Decodes the configuration, which follows the people, orders, next order number and
+/// client order ids in the upgrade message.
+fn decode_config_state(bytes: &[u8]) -> Option<Config> {
+    let (_people, _orders, _next_order_no, _client_order_ids, config) =
+        stable_memory::decode_message::<(Reserved, Reserved, Reserved, Reserved, Option<Config>)>(
+            bytes,
+        )?;
+    config
+}
+
+/// Returns the current configuration.
+#[query]
+pub fn get_config() -> Config {
+    current()
+}
+
+/// Sets whether withdrawals may spend the canister's own unconfirmed change outputs.
+#[update(guard = "caller_is_controller")]
+pub fn set_allow_spend_unconfirmed_change(allow: bool) {
+    CONFIG.with_borrow_mut(|config| config.allow_spend_unconfirmed_change = allow);
+}
+
+/// Sets whether withdrawals too small to be claimed economically are rejected.
+#[update(guard = "caller_is_controller")]
+pub fn set_enforce_claimable_amount(enforce: bool) {
+    CONFIG.with_borrow_mut(|config| config.enforce_claimable_amount = enforce);
+}
+
+/// Sets the cap on the total in-flight value, or removes it with `None`.
+#[update(guard = "caller_is_controller")]
+pub fn set_max_total_inflight_value(max: Option<u64>) {
+    CONFIG.with_borrow_mut(|config| config.max_total_inflight_value = max);
+}
+
+/// Sets the amount above which withdrawals require confirmation, or disables the
+/// confirmation step with `None`. The threshold is kept across upgrades, while
+/// outstanding confirmation tokens are not.
+#[update(guard = "caller_is_controller")]
+pub fn set_large_withdrawal_threshold(threshold: Option<u64>) {
+    CONFIG.with_borrow_mut(|config| config.large_withdrawal_threshold = threshold);
+}
+
+/// Sets the confirmations deposits need before withdrawals may spend them.
+#[update(guard = "caller_is_controller")]
+pub fn set_min_deposit_confirmations(min_confirmations: u32) {
+    CONFIG.with_borrow_mut(|config| config.min_deposit_confirmations = min_confirmations);
+}
+
+/// Largest value `set_max_signing_attempts` accepts. Every attempt costs cycles.
+const MAX_SIGNING_ATTEMPTS: u32 = 10;
+
+/// Sets how many times an ECDSA signature is attempted on transient errors.
+///
+/// Fails unless `attempts` is between 1 and `MAX_SIGNING_ATTEMPTS`.
+#[update(guard = "caller_is_controller")]
+pub fn set_max_signing_attempts(attempts: u32) -> Result<(), String> {
+    crate::validate::in_range(
+        attempts.into(),
+        1,
+        MAX_SIGNING_ATTEMPTS.into(),
+        "Signing attempts",
+    )?;
+    CONFIG.with_borrow_mut(|config| config.max_signing_attempts = attempts);
+    Ok(())
+}
+
+/// Largest value `set_fee_percentile` accepts.
+pub const MAX_FEE_PERCENTILE: u8 = 100;
+
+/// Sets the percentile of recent fee rates that transactions bid.
+///
+/// Fails unless `percentile` is between 0 and `MAX_FEE_PERCENTILE`.
+#[update(guard = "caller_is_controller")]
+pub fn set_fee_percentile(percentile: u8) -> Result<(), String> {
+    crate::validate::in_range(
+        percentile.into(),
+        0,
+        MAX_FEE_PERCENTILE.into(),
+        "Fee percentile",
+    )?;
+    CONFIG.with_borrow_mut(|config| config.fee_percentile = percentile);
+    Ok(())
+}
+
+/// Sets the timelock bounds orders must respect, or removes a bound with `None`.
+///
+/// Fails if both bounds are set and `min` exceeds `max`.
+#[update(guard = "caller_is_controller")]
+pub fn set_time_lock_bounds(min: Option<u64>, max: Option<u64>) -> Result<(), String> {
+    if let (Some(min), Some(max)) = (min, max) {
+        if min > max {
+            return Err(format!(
+                "Minimum timelock {} exceeds the maximum timelock {}",
+                min, max
+            ));
+        }
+    }
+    CONFIG.with_borrow_mut(|config| {
+        config.min_time_lock = min;
+        config.max_time_lock = max;
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_config_state() {
+        let config = Config {
+            max_total_inflight_value: Some(5_000_000),
+            large_withdrawal_threshold: Some(1_000_000),
+            ..Default::default()
+        };
+        let people: Vec<(String, u32)> = vec![];
+        let orders: Vec<(u64, u32)> = vec![];
+        let ids: Vec<(String, u64)> = vec![];
+
+        let bytes = candid::encode_args((
+            people.clone(),
+            orders.clone(),
+            7u64,
+            ids.clone(),
+            config.clone(),
+        ))
+        .unwrap();
+        assert_eq!(decode_config_state(&bytes), Some(config));
+
+        // Messages saved before the configuration was persisted end with the client
+        // order ids.
+        let bytes = candid::encode_args((people, orders, 7u64, ids)).unwrap();
+        assert_eq!(decode_config_state(&bytes), None);
+    }
+}
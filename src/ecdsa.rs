@@ -1,9 +1,12 @@
-use crate::BitcoinContext;
+use crate::{config, BitcoinContext};
 use bitcoin::secp256k1::ecdsa::Signature;
-use ic_cdk::management_canister::{
-    self, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgs, SignWithEcdsaArgs,
+use ic_cdk::{
+    call::CallErrorExt,
+    management_canister::{
+        self, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgs, SignCallError, SignWithEcdsaArgs,
+    },
 };
-use std::{cell::RefCell, collections::HashMap};
+use std::{cell::RefCell, collections::HashMap, fmt::Display, future::Future};
 
 type DerivationPath = Vec<Vec<u8>>;
 type EcdsaKey = Vec<u8>;
@@ -17,11 +20,22 @@ thread_local! {
 ///
 /// This function checks the local in-memory cache first. If no cached key exists,
 /// it queries the ECDSA API for the public key at the given derivation path
-/// and stores the result in the cache.
+/// and stores the result in the cache. Traps if the ECDSA API call fails.
 pub async fn get_ecdsa_public_key(ctx: &BitcoinContext, derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
+    try_get_ecdsa_public_key(ctx, derivation_path)
+        .await
+        .unwrap()
+}
+
+/// Like `get_ecdsa_public_key`, but returns an error instead of trapping if the
+/// ECDSA API call fails. Failed lookups are not cached.
+pub async fn try_get_ecdsa_public_key(
+    ctx: &BitcoinContext,
+    derivation_path: Vec<Vec<u8>>,
+) -> Result<Vec<u8>, String> {
     // Check in-memory cache first.
     if let Some(key) = ECDSA_KEY_CACHE.with_borrow(|map| map.get(&derivation_path).cloned()) {
-        return key;
+        return Ok(key);
     }
 
     // Request the ECDSA public key from the ECDSA API.
@@ -34,7 +48,7 @@ pub async fn get_ecdsa_public_key(ctx: &BitcoinContext, derivation_path: Vec<Vec
         },
     })
     .await
-    .unwrap()
+    .map_err(|e| format!("Failed to derive ECDSA public key: {}", e))?
     .public_key;
 
     // Store it in the in-memory cache for future reuse.
@@ -42,32 +56,75 @@ pub async fn get_ecdsa_public_key(ctx: &BitcoinContext, derivation_path: Vec<Vec
         map.insert(derivation_path, public_key.clone());
     });
 
-    public_key
+    Ok(public_key)
 }
 
 /// Signs a 32-byte message hash using the ECDSA key derived from the given path.
 ///
 /// This function uses the ICP ECDSA signing API to produce a compact, 64-byte signature.
+/// Transient failures, e.g. under subnet congestion, are retried up to the configured
+/// `max_signing_attempts` in total. Traps if signing still fails, reporting the
+/// number of attempts made.
 pub async fn sign_with_ecdsa(
     key_name: String,
     derivation_path: Vec<Vec<u8>>,
     message_hash: Vec<u8>,
 ) -> Signature {
-    let signature = management_canister::sign_with_ecdsa(&SignWithEcdsaArgs {
+    let args = SignWithEcdsaArgs {
         message_hash,
         derivation_path,
         key_id: EcdsaKeyId {
             curve: EcdsaCurve::Secp256k1,
             name: key_name,
         },
-    })
+    };
+    let signature = retry_transient(
+        config::current().max_signing_attempts,
+        is_transient_sign_error,
+        || management_canister::sign_with_ecdsa(&args),
+    )
     .await
-    .unwrap()
+    .unwrap_or_else(|e| ic_cdk::trap(format!("Failed to sign with ECDSA: {}", e)))
     .signature;
 
     Signature::from_compact(&signature).unwrap()
 }
 
+/// Returns whether a failed signing call may succeed if made again right away.
+///
+/// Only rejections the system reports as transient qualify; permanent failures such as
+/// an unknown key or a bad derivation path are not retried.
+fn is_transient_sign_error(error: &SignCallError) -> bool {
+    match error {
+        SignCallError::CallFailed(e) => e.is_immediately_retryable(),
+        SignCallError::SignCostError(_) | SignCallError::CandidDecodeFailed(_) => false,
+    }
+}
+
+/// Runs `attempt` until it succeeds, it fails with an error `is_transient` rejects, or
+/// `max_attempts` attempts were made (at least one is always made). The error names the
+/// number of attempts made.
+async fn retry_transient<T, E, AttemptFun, Fut>(
+    max_attempts: u32,
+    is_transient: impl Fn(&E) -> bool,
+    attempt: AttemptFun,
+) -> Result<T, String>
+where
+    E: Display,
+    AttemptFun: Fn() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(e) if is_transient(&e) && attempts < max_attempts => continue,
+            Err(e) => return Err(format!("{} (after {} attempts)", e, attempts)),
+        }
+    }
+}
+
 /// Returns a mock ECDSA signature used solely for **transaction size estimation**.
 ///
 /// This function returns a fixed-size, syntactically valid but cryptographically invalid
@@ -84,3 +141,69 @@ pub async fn mock_sign_with_ecdsa(
     let r_s = [1u8; 64];
     Signature::from_compact(&r_s).unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_retry_transient() {
+        // A transient failure followed by success completes the signing.
+        let calls = Cell::new(0);
+        let signature = block_on(retry_transient(
+            3,
+            |e: &String| e == "transient",
+            || {
+                calls.set(calls.get() + 1);
+                let fail = calls.get() == 1;
+                async move {
+                    if fail {
+                        Err("transient".to_string())
+                    } else {
+                        Ok(mock_sign_with_ecdsa(String::new(), vec![], vec![]).await)
+                    }
+                }
+            },
+        ));
+        assert_eq!(
+            signature,
+            Ok(block_on(mock_sign_with_ecdsa(
+                String::new(),
+                vec![],
+                vec![]
+            )))
+        );
+        assert_eq!(calls.get(), 2);
+
+        // Permanent failures are not retried.
+        calls.set(0);
+        let result: Result<(), String> = block_on(retry_transient(
+            3,
+            |e: &String| e == "transient",
+            || {
+                calls.set(calls.get() + 1);
+                async { Err("bad derivation path".to_string()) }
+            },
+        ));
+        assert_eq!(
+            result,
+            Err("bad derivation path (after 1 attempts)".to_string())
+        );
+        assert_eq!(calls.get(), 1);
+
+        // Transient failures give up after the last attempt.
+        calls.set(0);
+        let result: Result<(), String> = block_on(retry_transient(
+            3,
+            |_: &String| true,
+            || {
+                calls.set(calls.get() + 1);
+                async { Err("transient".to_string()) }
+            },
+        ));
+        assert_eq!(result, Err("transient (after 3 attempts)".to_string()));
+        assert_eq!(calls.get(), 3);
+    }
+}
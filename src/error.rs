@@ -0,0 +1,184 @@
+// This module defines the structured errors returned by HTLC endpoints.
+//
+// Most failures are still reported as plain messages and wrapped in `HtlcError::Other`;
+// cases clients are expected to react to programmatically get their own variant.
+
+use candid::{CandidType, Deserialize};
+use std::fmt;
+
+/// Error returned by HTLC endpoints.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum HtlcError {
+    /// The transaction spends an input that does not exist or was already spent.
+    InputsMissingOrSpent(String),
+    /// The transaction's fee rate is below what the network relays; retry with a higher fee.
+    FeeTooLow(String),
+    /// The transaction violates the network's standardness rules.
+    NonStandard(String),
+    /// The transaction is already in the mempool, i.e. it was broadcast before.
+    AlreadyInMempool(String),
+    /// The transaction was rejected for a reason not covered by another variant.
+    BroadcastRejected(String),
+    /// The order's funding address holds no UTXOs at all; funds must be sent to it first.
+    NoUtxos,
+    /// The order's funding address only holds deposits without enough confirmations
+    /// yet, worth `unconfirmed_value` satoshi in total.
+    FundingUnconfirmed { unconfirmed_value: u64 },
+    /// The withdrawal exceeds the large withdrawal threshold; call again with `token`
+    /// to confirm it.
+    ConfirmationRequired { token: String },
+    /// An input failed validation, see `validate.rs`.
+    Validation(String),
+    /// Any other failure, described by its message.
+    Other(String),
+}
+
+impl HtlcError {
+    /// Maps the rejection reason of a failed broadcast to the matching variant.
+    ///
+    /// Reasons are matched case-insensitively against the substrings Bitcoin nodes use
+    /// in their reject messages, e.g. `bad-txns-inputs-missingorspent` or
+    /// `min relay fee not met`. The full reason is kept in the variant.
+    pub fn from_broadcast_rejection(reason: &str) -> Self {
+        let lowercase = reason.to_lowercase();
+        let matches_any = |patterns: &[&str]| patterns.iter().any(|p| lowercase.contains(p));
+        let reason = reason.to_string();
+
+        if matches_any(&["already-in-mempool", "already in mempool", "already-known"]) {
+            HtlcError::AlreadyInMempool(reason)
+        } else if matches_any(&[
+            "missingorspent",
+            "missing-inputs",
+            "missing inputs",
+            "already spent",
+            "mempool-conflict",
+        ]) {
+            HtlcError::InputsMissingOrSpent(reason)
+        } else if matches_any(&[
+            "fee not met",
+            "insufficient fee",
+            "fee too low",
+            "fee-too-low",
+        ]) {
+            HtlcError::FeeTooLow(reason)
+        } else if matches_any(&["non-standard", "nonstandard", "dust"]) {
+            HtlcError::NonStandard(reason)
+        } else {
+            HtlcError::BroadcastRejected(reason)
+        }
+    }
+}
+
+impl fmt::Display for HtlcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HtlcError::InputsMissingOrSpent(reason) => {
+                write!(
+                    f,
+                    "Failed to send transaction: inputs missing or spent: {}",
+                    reason
+                )
+            }
+            HtlcError::FeeTooLow(reason) => {
+                write!(f, "Failed to send transaction: fee too low: {}", reason)
+            }
+            HtlcError::NonStandard(reason) => {
+                write!(f, "Failed to send transaction: non-standard: {}", reason)
+            }
+            HtlcError::AlreadyInMempool(reason) => {
+                write!(
+                    f,
+                    "Failed to send transaction: already in mempool: {}",
+                    reason
+                )
+            }
+            HtlcError::BroadcastRejected(reason) => {
+                write!(f, "Failed to send transaction: {}", reason)
+            }
+            HtlcError::NoUtxos => f.write_str("No UTXOs available for this order"),
+            HtlcError::FundingUnconfirmed { unconfirmed_value } => write!(
+                f,
+                "The order's funding of {} satoshi is not confirmed yet",
+                unconfirmed_value
+            ),
+            HtlcError::ConfirmationRequired { token } => {
+                write!(
+                    f,
+                    "Withdrawal exceeds the large withdrawal threshold; confirm with token {}",
+                    token
+                )
+            }
+            HtlcError::Validation(message) | HtlcError::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl From<String> for HtlcError {
+    fn from(message: String) -> Self {
+        HtlcError::Other(message)
+    }
+}
+
+impl From<HtlcError> for String {
+    fn from(error: HtlcError) -> Self {
+        error.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_broadcast_rejection() {
+        let cases = [
+            (
+                "bad-txns-inputs-missingorspent",
+                HtlcError::InputsMissingOrSpent("bad-txns-inputs-missingorspent".to_string()),
+            ),
+            (
+                "txn-mempool-conflict",
+                HtlcError::InputsMissingOrSpent("txn-mempool-conflict".to_string()),
+            ),
+            (
+                "min relay fee not met, 110 < 141",
+                HtlcError::FeeTooLow("min relay fee not met, 110 < 141".to_string()),
+            ),
+            (
+                "mempool min fee not met",
+                HtlcError::FeeTooLow("mempool min fee not met".to_string()),
+            ),
+            ("dust", HtlcError::NonStandard("dust".to_string())),
+            (
+                "Transaction is non-standard",
+                HtlcError::NonStandard("Transaction is non-standard".to_string()),
+            ),
+            (
+                "txn-already-in-mempool",
+                HtlcError::AlreadyInMempool("txn-already-in-mempool".to_string()),
+            ),
+            (
+                "Transaction already in mempool",
+                HtlcError::AlreadyInMempool("Transaction already in mempool".to_string()),
+            ),
+            (
+                "MalformedTransaction",
+                HtlcError::BroadcastRejected("MalformedTransaction".to_string()),
+            ),
+        ];
+        for (reason, expected) in cases {
+            assert_eq!(HtlcError::from_broadcast_rejection(reason), expected);
+        }
+    }
+
+    #[test]
+    fn test_string_conversions() {
+        let error = HtlcError::from("Order 7 not found".to_string());
+        assert_eq!(error, HtlcError::Other("Order 7 not found".to_string()));
+        assert_eq!(String::from(error), "Order 7 not found");
+        assert_eq!(
+            String::from(HtlcError::FeeTooLow("min relay fee not met".to_string())),
+            "Failed to send transaction: fee too low: min relay fee not met"
+        );
+    }
+}
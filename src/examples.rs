@@ -1,7 +1,7 @@
 // Example usage of the state management system
 // This file shows how to interact with the person storage system
 
-use crate::state::{Person, store_person, get_age_by_name, get_person_by_name};
+use crate::state::{store_person, get_age_by_name, get_person_by_name};
 
 /// Example function showing how to use the state management
 pub async fn example_usage() {
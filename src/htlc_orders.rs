@@ -0,0 +1,7948 @@
+// This module implements the HTLC order book.
+//
+// Every order gets a sequential order number which doubles as the BIP-32 account used to
+// derive the order's funding P2WPKH address. The canister controls the keys of that funding
+// address and can move its coins into a P2WSH hash-time-locked contract (HTLC) shared
+// between the order's initiator and a responder:
+//
+//   OP_IF
+//       OP_SHA256 <secret_hash> OP_EQUALVERIFY <responder_pubkey>
+//   OP_ELSE
+//       <time_lock> OP_CSV OP_DROP <initiator_pubkey>
+//   OP_ENDIF
+//   OP_CHECKSIG
+//
+// The responder can claim the funds by revealing the preimage of `secret_hash`, while the
+// initiator can take them back once `time_lock` blocks have passed since funding.
+
+use crate::{
+    bitcoin_network,
+    common::{
+        build_transaction_with_fee, estimate_p2wpkh_fee, fee_for_vsize, get_fee_per_byte,
+        max_spendable_p2wpkh, output_vbytes, select_utxos_without_change, tx_weight, CoinSelection,
+        DerivationPath, PrimaryOutput, DUST_THRESHOLD, DUST_THRESHOLD_P2WSH,
+        MIN_RELAY_FEE_PER_BYTE, P2WPKH_INPUT_VBYTES, P2WPKH_OUTPUT_VBYTES, TX_OVERHEAD_VBYTES,
+    },
+    config::{self, Config},
+    ecdsa::{
+        get_ecdsa_public_key, mock_sign_with_ecdsa, sign_with_ecdsa, try_get_ecdsa_public_key,
+    },
+    error::HtlcError,
+    htlc_script::{
+        build_custom_htlc_script, normalize_pubkey, HtlcAddressFormat, HtlcHashType,
+        HtlcScriptParams, HtlcTimelockType, LOCKTIME_THRESHOLD, MAX_CSV_BLOCKS,
+    },
+    p2wpkh,
+    p2wsh::{self, HtlcBranch},
+    stable_memory::{
+        self, Memory, BROADCAST_LOG_DATA_MEMORY_ID, BROADCAST_LOG_INDEX_MEMORY_ID, ORDERS_MEMORY_ID,
+    },
+    state::Person,
+    usage, validate, BitcoinContext, BTC_CONTEXT,
+};
+use bitcoin::{
+    absolute::LockTime,
+    consensus::{deserialize, serialize},
+    hashes::{sha256, Hash},
+    sighash::{EcdsaSighashType, SighashCache},
+    sign_message::signed_msg_hash,
+    Address, Amount, CompressedPublicKey, Psbt, PublicKey, Script, ScriptBuf, Sequence,
+    Transaction, TxOut, Witness,
+};
+use candid::{CandidType, Deserialize, Principal, Reserved};
+use futures::future::join_all;
+use ic_cdk::{
+    api::msg_caller,
+    bitcoin_canister::{
+        bitcoin_get_balance, bitcoin_get_utxos, bitcoin_send_transaction, GetBalanceRequest,
+        GetUtxosRequest, GetUtxosResponse, Network, Outpoint, SendTransactionRequest, Utxo,
+        UtxosFilter,
+    },
+    query,
+    stable::{stable_size, WASM_PAGE_SIZE_IN_BYTES},
+    update,
+};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, StableLog, Storable};
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet, VecDeque},
+    ops::Bound::{Excluded, Unbounded},
+    str::FromStr,
+};
+
+/// Fee decision recorded for a transaction the canister broadcast for an order.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct FeeDetail {
+    pub txid: String,
+    /// Fee paid in satoshi.
+    pub fee: u64,
+    /// Fee rate applied when building the transaction, in millisatoshi per vbyte.
+    pub fee_rate: u64,
+    /// Virtual size of the signed transaction.
+    pub vsize: u64,
+}
+
+/// Lifecycle state of an order.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum OrderStatus {
+    /// The order exists but nothing has been observed on-chain for it yet.
+    #[default]
+    Created,
+    /// The order's funding address has been handed out.
+    AddressGenerated,
+    /// Confirmed funds are locked in the order's HTLC.
+    Funded,
+    /// The HTLC was spent through the hashlock branch.
+    Claimed,
+    /// The HTLC was spent back to the initiator through the timelock branch.
+    Refunded,
+}
+
+impl OrderStatus {
+    /// Returns whether the order is settled and should no longer hold any funds.
+    pub fn is_terminal(self) -> bool {
+        matches!(self, OrderStatus::Claimed | OrderStatus::Refunded)
+    }
+}
+
+/// Represents an HTLC order as stored in the canister's state.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct HtlcDetail {
+    /// Hex-encoded public key of the initiator, who can refund after the timelock.
+    pub initiator_pubkey: String,
+    /// Timelock guarding the refund branch: a number of blocks since funding for a
+    /// relative timelock, or a block height for an absolute one, see `timelock_type`.
+    pub time_lock: u64,
+    /// Hex-encoded SHA256 hash whose preimage unlocks the claim branch.
+    pub secret_hash: String,
+    /// HTLC address the order was funded into, recorded by `withdraw_from_order`.
+    pub htlc_address: Option<String>,
+    /// Hex-encoded public key of the responder the order was funded for.
+    pub responder_pubkey: Option<String>,
+    /// Fee details of every transaction broadcast for this order, oldest first.
+    pub fee_details: Vec<FeeDetail>,
+    /// Labeled deposit addresses of the order as `(label, address)`, in creation order.
+    /// The n-th sub-account is derived at address index n of the order's account.
+    pub sub_accounts: Vec<(String, String)>,
+    /// Height of the earliest block confirming a funding output of the HTLC, once observed.
+    pub funding_height: Option<u32>,
+    /// Current lifecycle state of the order.
+    pub status: OrderStatus,
+    /// Signed funding transaction awaiting `broadcast_prepared`, if any.
+    pub prepared_withdraw: Option<PreparedWithdraw>,
+    /// Satoshi the canister has locked in the order's HTLC and that were not claimed
+    /// or refunded yet.
+    pub inflight_value: u64,
+    /// Time the order was created, in nanoseconds since the Unix epoch.
+    pub created_at_ns: u64,
+    /// Outpoint of the earliest confirmed HTLC funding output as `txid:vout`, recorded
+    /// when `poll_and_update` detects the funding.
+    pub funding_outpoint: Option<String>,
+    /// Amount in satoshi the initiator intends to trade, if given at creation.
+    pub requested_amount: Option<u64>,
+    /// Responder holding exclusivity on the order, see `reserve_order`. Expired
+    /// reservations may linger here but have no effect.
+    pub reservation: Option<Reservation>,
+    /// Latest transaction the canister broadcast to spend the HTLC, see
+    /// `get_spend_confirmations`.
+    pub spend: Option<SpendRecord>,
+    /// Kind of the timelock; `None` stands for a relative (CSV) timelock, which all
+    /// orders created before absolute timelocks were supported use.
+    pub timelock_type: Option<HtlcTimelockType>,
+    /// Principal that created the order and may move the funds of its funding address.
+    /// `None` for orders created before owners were recorded, which anyone may fund.
+    pub owner: Option<Principal>,
+    /// Additional funding addresses handed out by `get_fresh_funding_address`, in
+    /// derivation order. `None` if none were handed out.
+    pub funding_addresses: Option<Vec<String>>,
+    /// Hash function of the hashlock; `None` stands for SHA256, which all orders created
+    /// before HASH160 hashlocks were supported use.
+    pub hash_type: Option<HtlcHashType>,
+    /// Network `htlc_address` was recorded on. `None` for addresses recorded before the
+    /// network was tagged, which are taken to be on the current network.
+    pub htlc_address_network: Option<Network>,
+    /// Consensus-serialized latest funding transaction broadcast for the order, kept so
+    /// `replace_order_funding` can bump its fee. `None` if none was recorded.
+    pub funding_transaction: Option<Vec<u8>>,
+    /// How the HTLC address commits to the witness script; `None` stands for native
+    /// P2WSH, which all orders created before nested addresses were supported use.
+    pub address_format: Option<HtlcAddressFormat>,
+}
+
+impl HtlcDetail {
+    /// Returns the kind of the order's timelock.
+    pub fn timelock_type(&self) -> HtlcTimelockType {
+        self.timelock_type.unwrap_or_default()
+    }
+
+    /// Returns the hash function of the order's hashlock.
+    pub fn hash_type(&self) -> HtlcHashType {
+        self.hash_type.unwrap_or_default()
+    }
+
+    /// Returns the format of the order's HTLC address.
+    pub fn address_format(&self) -> HtlcAddressFormat {
+        self.address_format.unwrap_or_default()
+    }
+
+    /// Returns the HTLC address recorded for the order, if any, checking that it was
+    /// recorded on `network`.
+    ///
+    /// Fails if the canister switched networks since, as the address would not be valid
+    /// on the current one.
+    pub fn stored_htlc_address(&self, network: Network) -> Result<Option<&String>, String> {
+        match self.htlc_address_network {
+            Some(recorded) if recorded != network => {
+                Err("cached address is for a different network; regenerate".to_string())
+            }
+            _ => Ok(self.htlc_address.as_ref()),
+        }
+    }
+}
+
+/// A transaction spending an order's HTLC, i.e. a claim, redeem or refund.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct SpendRecord {
+    pub txid: String,
+    /// Address the transaction's first output pays to.
+    pub destination: String,
+}
+
+/// A responder's temporary exclusivity on an order.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Reservation {
+    /// Hex-encoded public key of the responder holding the reservation.
+    pub responder_pubkey: String,
+    /// Time the reservation ends, in nanoseconds since the Unix epoch.
+    pub expires_at_ns: u64,
+    /// Principal that made the reservation and may renew it. `None` for reservations
+    /// made before holders were recorded, which nobody can renew.
+    pub holder: Option<Principal>,
+    /// How often the holder renewed the reservation, see `MAX_RESERVATION_RENEWALS`.
+    /// `None` stands for 0.
+    pub renewals: Option<u32>,
+}
+
+// Orders are stored Candid-encoded, so fields added as `Option`s keep older orders readable.
+impl Storable for HtlcDetail {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.clone().into_bytes())
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        candid::encode_one(self).expect("Failed to encode order")
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode order")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Global order book: all orders keyed by order number, plus the next number to hand out.
+///
+/// The orders live in stable memory and are written one at a time as they change, so
+/// they survive upgrades without being serialized. The other fields are saved by
+/// `save_orders_state`.
+pub struct OrderStorage {
+    pub orders: StableBTreeMap<u64, HtlcDetail, Memory>,
+    pub next_order_no: u64,
+    /// Order numbers created through `create_order_with_key`, keyed by the calling
+    /// principal and the client's id.
+    pub client_order_ids: HashMap<(Principal, String), u64>,
+}
+
+impl Default for OrderStorage {
+    fn default() -> Self {
+        Self {
+            orders: StableBTreeMap::init(stable_memory::memory(ORDERS_MEMORY_ID)),
+            next_order_no: 0,
+            client_order_ids: HashMap::new(),
+        }
+    }
+}
+
+impl OrderStorage {
+    /// Applies `update` to the order `order_no` and stores the result. Returns `None`
+    /// without calling `update` if the order does not exist.
+    ///
+    /// Stored orders are copies, so changes must go through here to be kept.
+    fn update_order<R>(
+        &mut self,
+        order_no: u64,
+        update: impl FnOnce(&mut HtlcDetail) -> R,
+    ) -> Option<R> {
+        let mut order = self.orders.get(&order_no)?;
+        let result = update(&mut order);
+        self.orders.insert(order_no, order);
+        Some(result)
+    }
+
+    /// Removes the order `order_no` together with the client order ids that point at it,
+    /// so that a retried `create_order_with_key` does not return a deleted order and
+    /// backups stay consistent. Returns the removed order, if it existed.
+    ///
+    /// Every path that deletes an order must go through here.
+    fn remove_order(&mut self, order_no: u64) -> Option<HtlcDetail> {
+        self.client_order_ids
+            .retain(|_, mapped_order_no| *mapped_order_no != order_no);
+        self.orders.remove(&order_no)
+    }
+}
+
+// Global state storage for HTLC orders.
+thread_local! {
+    static STORAGE: RefCell<OrderStorage> = RefCell::new(OrderStorage::default());
+}
+
+// Orders that currently have a funds-moving operation in progress.
+//
+// IC messages interleave at await points, so two calls for the same order could both
+// read the same UTXO set and try to spend the same coins. The set guards that critical
+// section. Note: this is not persisted across upgrades, which cannot happen mid-call anyway.
+thread_local! {
+    static IN_FLIGHT_ORDERS: RefCell<HashSet<u64>> = RefCell::new(HashSet::new());
+}
+
+/// Marks an order as in flight for as long as the guard is alive.
+///
+/// The flag is cleared on drop, so every exit path releases it: early returns, errors
+/// and traps (ic-cdk drops the pending future of a trapped call during cleanup).
+struct OrderGuard {
+    order_no: u64,
+}
+
+impl OrderGuard {
+    fn acquire(order_no: u64) -> Result<Self, String> {
+        if IN_FLIGHT_ORDERS.with_borrow_mut(|orders| orders.insert(order_no)) {
+            Ok(Self { order_no })
+        } else {
+            Err("operation already in progress for this order".to_string())
+        }
+    }
+}
+
+impl Drop for OrderGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT_ORDERS.with_borrow_mut(|orders| orders.remove(&self.order_no));
+    }
+}
+
+/// Smallest timelock (in blocks) accepted on mainnet and testnet, leaving the responder
+/// a reasonable window to claim before the initiator can refund.
+const MIN_CSV_BLOCKS: u64 = 6;
+
+/// Smallest timelock (in blocks) accepted on regtest, where blocks are mined on demand
+/// and developers want to exercise the refund path quickly.
+const MIN_CSV_BLOCKS_REGTEST: u64 = 1;
+
+/// Returns the smallest timelock accepted on `network`.
+fn min_time_lock(network: bitcoin::Network) -> u64 {
+    match network {
+        bitcoin::Network::Regtest => MIN_CSV_BLOCKS_REGTEST,
+        _ => MIN_CSV_BLOCKS,
+    }
+}
+
+/// BIP-68 flag in an input's sequence that turns the relative lock off entirely.
+const SEQUENCE_LOCKTIME_DISABLE_FLAG: u64 = 1 << 31;
+
+/// BIP-68 flag in an input's sequence that makes the relative lock count units of 512
+/// seconds instead of blocks.
+const SEQUENCE_LOCKTIME_TYPE_FLAG: u64 = 1 << 22;
+
+/// Checks that `time_lock` is a relative timelock accepted on `network`.
+///
+/// The upper bound is the same everywhere, but the minimum depends on the network:
+/// regtest accepts a single block while mainnet and testnet require `MIN_CSV_BLOCKS`.
+/// Values with the BIP-68 disable flag set get a dedicated error, since as a sequence
+/// they would make the refund branch spendable immediately. So do values with the
+/// time-based flag set or beyond the 16-bit block count, as the refund could never
+/// satisfy the CSV check of the script.
+fn validate_time_lock(time_lock: u64, network: bitcoin::Network) -> Result<(), String> {
+    if time_lock & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+        return Err("timelock value disables the relative lock".to_string());
+    }
+    if time_lock & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+        return Err(
+            "timelock value sets the time-based flag; relative timelocks count blocks".to_string(),
+        );
+    }
+    if time_lock > MAX_CSV_BLOCKS {
+        return Err(format!(
+            "Relative timelock {} does not fit the 16-bit block count of BIP-68 (at most {})",
+            time_lock, MAX_CSV_BLOCKS
+        ));
+    }
+    let min = min_time_lock(network);
+    if time_lock < min {
+        return Err(format!(
+            "Relative timelock must be between {} and {} blocks after funding on {}, got {}",
+            min, MAX_CSV_BLOCKS, network, time_lock
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that `time_lock` is an absolute timelock the HTLC script supports, i.e. a
+/// block height.
+///
+/// Lock times from `LOCKTIME_THRESHOLD` on are unix timestamps, compared against the
+/// median time past of the last 11 blocks rather than the wall clock; these are rejected.
+fn validate_absolute_time_lock(time_lock: u64) -> Result<(), String> {
+    if time_lock == 0 || time_lock >= LOCKTIME_THRESHOLD {
+        return Err(format!(
+            "Absolute timelock must be a block height between 1 and {}, got {}; values from \
+             {} on are unix timestamps compared against the median time past, which are \
+             not supported",
+            LOCKTIME_THRESHOLD - 1,
+            time_lock,
+            LOCKTIME_THRESHOLD
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that `time_lock` lies within the deployment's configured bounds, if any.
+fn validate_time_lock_policy(
+    time_lock: u64,
+    min: Option<u64>,
+    max: Option<u64>,
+) -> Result<(), String> {
+    let below_min = min.is_some_and(|min| time_lock < min);
+    let above_max = max.is_some_and(|max| time_lock > max);
+    if below_min || above_max {
+        let bound = |bound: Option<u64>| bound.map_or("none".to_string(), |b| b.to_string());
+        return Err(format!(
+            "Timelock {} is outside the configured bounds (min: {}, max: {})",
+            time_lock,
+            bound(min),
+            bound(max)
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that `time_lock` is accepted for a new order with a timelock of kind
+/// `timelock_type`.
+///
+/// Relative timelocks must be valid on `network`, see `validate_time_lock`, and within
+/// the bounds set in the config. Absolute timelocks must be block heights, see
+/// `validate_absolute_time_lock`; the configured bounds are durations and do not apply.
+fn validate_order_time_lock(
+    time_lock: u64,
+    timelock_type: HtlcTimelockType,
+    network: bitcoin::Network,
+) -> Result<(), String> {
+    match timelock_type {
+        HtlcTimelockType::Csv => {
+            validate_time_lock(time_lock, network)?;
+            let config = config::current();
+            validate_time_lock_policy(time_lock, config.min_time_lock, config.max_time_lock)
+        }
+        HtlcTimelockType::Cltv => validate_absolute_time_lock(time_lock),
+    }
+}
+
+/// Checks that `secret_hash` is a hex-encoded hash of the length `hash_type` produces,
+/// 32 bytes for SHA256 and 20 for HASH160, as the hashlock of the HTLC script requires.
+fn validate_secret_hash(secret_hash: &str, hash_type: HtlcHashType) -> Result<(), String> {
+    let hash_name = match hash_type {
+        HtlcHashType::Sha256 => "SHA256",
+        HtlcHashType::Hash160 => "HASH160 (RIPEMD160 of SHA256)",
+    };
+    validate::hex_bytes(secret_hash, "Secret hash", Some(hash_type.hash_len()))
+        .map(|_| ())
+        .map_err(|e| {
+            format!(
+                "{}; expected the hex-encoded {} hash of the secret",
+                e, hash_name
+            )
+        })
+}
+
+/// Creates a new HTLC order and returns its order number.
+///
+/// The timelock is validated against the canister's network and the configured
+/// bounds, see `validate_order_time_lock`, and `secret_hash` must be a hex-encoded
+/// hash of the secret under `hash_type`. `initiator_pubkey` must be a compressed public key, as SegWit scripts
+/// reject uncompressed ones, and is stored as lowercase hex.
+/// `requested_amount` optionally records the amount in satoshi the initiator intends to
+/// trade, which takers can filter on with `get_orders_by_amount_range`.
+/// `timelock_type` selects a relative timelock (`Csv`, the default), where `time_lock`
+/// counts blocks after funding, or an absolute one (`Cltv`), where it is a block height.
+/// `hash_type` selects the hashlock's hash function: SHA256 (the default) or HASH160,
+/// which Lightning HTLCs use.
+/// `address_format` selects a native P2WSH HTLC address (the default) or one wrapped in
+/// P2SH, which wallets without bech32 support can pay to.
+#[update]
+pub fn create_order(
+    initiator_pubkey: String,
+    time_lock: u64,
+    secret_hash: String,
+    requested_amount: Option<u64>,
+    timelock_type: Option<HtlcTimelockType>,
+    hash_type: Option<HtlcHashType>,
+    address_format: Option<HtlcAddressFormat>,
+) -> Result<u64, String> {
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let initiator_pubkey = normalize_pubkey(&initiator_pubkey, "initiator")?;
+    let hash_type = hash_type.unwrap_or_default();
+    validate_secret_hash(&secret_hash, hash_type)?;
+    let timelock_type = timelock_type.unwrap_or_default();
+    validate_order_time_lock(time_lock, timelock_type, ctx.bitcoin_network)?;
+    if let Some(amount) = requested_amount {
+        validate::positive(amount, "Requested amount")?;
+    }
+
+    Ok(insert_order(HtlcDetail {
+        initiator_pubkey,
+        time_lock,
+        secret_hash,
+        created_at_ns: ic_cdk::api::time(),
+        requested_amount,
+        timelock_type: Some(timelock_type),
+        hash_type: Some(hash_type),
+        owner: Some(msg_caller()),
+        address_format: Some(address_format.unwrap_or_default()),
+        ..Default::default()
+    }))
+}
+
+/// Maximum number of orders `create_orders` creates per call.
+const MAX_ORDERS_PER_BATCH: usize = 100;
+
+/// Runs `create` on each of `requests` as `(initiator_pubkey, time_lock, secret_hash)`
+/// and returns the results in the same order. Requests beyond the first
+/// `MAX_ORDERS_PER_BATCH` are not run and get an error instead.
+fn create_orders_batch(
+    requests: Vec<(String, u64, String)>,
+    mut create: impl FnMut(String, u64, String) -> Result<u64, String>,
+) -> Vec<Result<u64, String>> {
+    requests
+        .into_iter()
+        .enumerate()
+        .map(|(index, (initiator_pubkey, time_lock, secret_hash))| {
+            if index >= MAX_ORDERS_PER_BATCH {
+                return Err(format!(
+                    "Not created: at most {} orders are created per call",
+                    MAX_ORDERS_PER_BATCH
+                ));
+            }
+            create(initiator_pubkey, time_lock, secret_hash)
+        })
+        .collect()
+}
+
+/// Creates an order like `create_order` for each `(initiator_pubkey, time_lock,
+/// secret_hash)` in `requests`, with the default timelock and hash types, and returns
+/// the order number or error of each in the order given.
+///
+/// Each request is handled independently: a malformed one gets an error while the others
+/// are still created, so a client can retry just the failures. Only the first
+/// `MAX_ORDERS_PER_BATCH` requests are handled; any beyond that are reported as not
+/// created.
+#[update]
+pub fn create_orders(requests: Vec<(String, u64, String)>) -> Vec<Result<u64, String>> {
+    create_orders_batch(requests, |initiator_pubkey, time_lock, secret_hash| {
+        create_order(
+            initiator_pubkey,
+            time_lock,
+            secret_hash,
+            None,
+            None,
+            None,
+            None,
+        )
+    })
+}
+
+/// Creates an order like `create_order`, deduplicated on `client_order_id`.
+///
+/// A client that retries after a timeout cannot tell whether its first call went
+/// through. If the caller already created an order with this `client_order_id`, its
+/// order number is returned and nothing else happens, so retrying is always safe.
+///
+/// Each principal has its own id namespace, so clients cannot collide with or squat on
+/// each other's ids.
+#[update]
+pub fn create_order_with_key(
+    client_order_id: String,
+    initiator_pubkey: String,
+    time_lock: u64,
+    secret_hash: String,
+) -> Result<u64, String> {
+    validate::non_empty(&client_order_id, "Client order id")?;
+    let caller = msg_caller();
+    if let Some(order_no) = order_no_for_client_id(caller, &client_order_id) {
+        return Ok(order_no);
+    }
+
+    let order_no = create_order(
+        initiator_pubkey,
+        time_lock,
+        secret_hash,
+        None,
+        None,
+        None,
+        None,
+    )?;
+    STORAGE.with_borrow_mut(|storage| {
+        storage
+            .client_order_ids
+            .insert((caller, client_order_id), order_no)
+    });
+    Ok(order_no)
+}
+
+/// Returns the order number `caller` created for `client_order_id`, if any.
+fn order_no_for_client_id(caller: Principal, client_order_id: &str) -> Option<u64> {
+    STORAGE.with_borrow(|storage| {
+        storage
+            .client_order_ids
+            .get(&(caller, client_order_id.to_string()))
+            .copied()
+    })
+}
+
+/// Stores `order` under the next order number and returns that number.
+fn insert_order(order: HtlcDetail) -> u64 {
+    let created_at_ns = order.created_at_ns;
+    let order_no = STORAGE.with_borrow_mut(|storage| {
+        let order_no = storage.next_order_no;
+        storage.orders.insert(order_no, order);
+        storage.next_order_no += 1;
+        order_no
+    });
+    record_event(order_no, OrderEventKind::Created, None, created_at_ns);
+    order_no
+}
+
+/// Lists all stored orders together with their order numbers.
+#[query]
+pub fn get_all_orders() -> Vec<(u64, HtlcDetail)> {
+    STORAGE.with_borrow(|storage| {
+        storage
+            .orders
+            .iter()
+            .map(|entry| entry.into_pair())
+            .collect()
+    })
+}
+
+/// Maximum number of orders `get_orders_page` returns per call.
+const MAX_ORDERS_PER_PAGE: u32 = 1000;
+
+/// Lists up to `limit` orders with an order number above `start_after`, sorted by order
+/// number. Without `start_after`, the page starts at the first order.
+///
+/// `limit` is capped at `MAX_ORDERS_PER_PAGE`. To iterate over the whole order book,
+/// pass the last order number of each page as `start_after` for the next one until a
+/// page comes back empty.
+#[query]
+pub fn get_orders_page(start_after: Option<u64>, limit: u32) -> Vec<(u64, HtlcDetail)> {
+    STORAGE.with_borrow(|storage| orders_page(&storage.orders, start_after, limit))
+}
+
+/// Selects the page of `orders` `get_orders_page` returns.
+fn orders_page<M: ic_stable_structures::Memory>(
+    orders: &StableBTreeMap<u64, HtlcDetail, M>,
+    start_after: Option<u64>,
+    limit: u32,
+) -> Vec<(u64, HtlcDetail)> {
+    let start = start_after.map_or(Unbounded, Excluded);
+    orders
+        .range((start, Unbounded))
+        .take(limit.min(MAX_ORDERS_PER_PAGE) as usize)
+        .map(|entry| entry.into_pair())
+        .collect()
+}
+
+/// Returns all orders sorted by order number, along with the next order number.
+/// Used by the state backup in `backup.rs`.
+pub fn export_orders() -> (Vec<(u64, HtlcDetail)>, u64) {
+    (
+        get_all_orders(),
+        STORAGE.with_borrow(|storage| storage.next_order_no),
+    )
+}
+
+/// Replaces the whole order book and its client order ids, e.g. when restoring a state
+/// backup.
+pub fn restore_orders(
+    orders: Vec<(u64, HtlcDetail)>,
+    next_order_no: u64,
+    client_order_ids: Vec<(Principal, String, u64)>,
+) {
+    STORAGE.with_borrow_mut(|storage| {
+        storage.orders.clear_new();
+        for (order_no, order) in orders {
+            storage.orders.insert(order_no, order);
+        }
+        storage.next_order_no = next_order_no;
+        storage.client_order_ids = client_order_ids
+            .into_iter()
+            .map(|(caller, id, order_no)| ((caller, id), order_no))
+            .collect();
+    });
+}
+
+/// Returns the client order ids sorted by principal and id, for saving to stable memory
+/// and for the state backup in `backup.rs`.
+pub fn export_client_order_ids() -> Vec<(Principal, String, u64)> {
+    let mut ids: Vec<(Principal, String, u64)> = STORAGE.with_borrow(|storage| {
+        storage
+            .client_order_ids
+            .iter()
+            .map(|((caller, id), order_no)| (*caller, id.clone(), *order_no))
+            .collect()
+    });
+    ids.sort_unstable();
+    ids
+}
+
+/// Helper function to save the order book state to stable memory, together with `people`.
+/// Call this from the pre_upgrade hook in lib.rs.
+///
+/// The orders themselves are already in stable memory, see `OrderStorage`. The rest is
+/// saved as a single Candid message: the people records go first, where
+/// `state::restore_people_state` expects them, followed by an empty list of orders and
+/// the next order number. Saving `next_order_no` keeps new orders from reusing a number
+/// after an upgrade. The client order ids follow so that `create_order_with_key`
+/// retries stay deduplicated across upgrades, and the configuration comes last, see
+/// `config::restore_config_state`.
+pub fn save_orders_state(people: Vec<(String, Person)>) {
+    stable_memory::save_upgrade_message(orders_state_message(people, config::current()));
+}
+
+/// Encodes the message `save_orders_state` writes to stable memory.
+fn orders_state_message(people: Vec<(String, Person)>, config: Config) -> Vec<u8> {
+    let next_order_no = STORAGE.with_borrow(|storage| storage.next_order_no);
+    let orders: Vec<(u64, HtlcDetail)> = Vec::new();
+    candid::encode_args((
+        people,
+        orders,
+        next_order_no,
+        export_client_order_ids(),
+        config,
+    ))
+    .expect("Failed to save orders state")
+}
+
+/// Helper function to restore the order book state from the message saved before the
+/// upgrade, see `stable_memory::load_upgrade_message`.
+/// Call this from the post_upgrade hook in lib.rs.
+///
+/// Versions that kept the orders on the heap saved them in the message. Those orders are
+/// moved into stable memory here.
+pub fn restore_orders_state(message: &[u8]) {
+    if let Some((orders, next_order_no, client_order_ids)) = decode_orders_state(message) {
+        STORAGE.with_borrow_mut(|storage| {
+            for (order_no, order) in orders {
+                storage.orders.insert(order_no, order);
+            }
+            storage.next_order_no = next_order_no;
+            storage.client_order_ids = client_order_ids
+                .into_iter()
+                .map(|(caller, id, order_no)| ((caller, id), order_no))
+                .collect();
+        });
+    }
+    // Stable memory written before orders were persisted only holds the people records,
+    // in which case there are no orders to restore.
+}
+
+/// Decodes the orders, next order number and client order ids from the message
+/// `save_orders_state` wrote. Messages saved before client order ids existed decode
+/// with none, and so do messages whose ids were not yet keyed by principal: their
+/// owners are unknown, so they cannot be attributed to anyone.
+#[allow(clippy::type_complexity)]
+fn decode_orders_state(
+    bytes: &[u8],
+) -> Option<(Vec<(u64, HtlcDetail)>, u64, Vec<(Principal, String, u64)>)> {
+    let (_people, orders, next_order_no, client_order_ids) = stable_memory::decode_message::<(
+        Reserved,
+        Option<Vec<(u64, HtlcDetail)>>,
+        Option<u64>,
+        Option<Vec<(Principal, String, u64)>>,
+    )>(bytes)?;
+    Some((
+        orders?,
+        next_order_no?,
+        client_order_ids.unwrap_or_default(),
+    ))
+}
+
+/// Returns whether the order's requested amount lies within `min..=max`. Orders without
+/// a requested amount never match.
+fn requested_amount_in_range(order: &HtlcDetail, min: u64, max: u64) -> bool {
+    order
+        .requested_amount
+        .is_some_and(|amount| (min..=max).contains(&amount))
+}
+
+/// Lists the orders whose requested amount lies within `min..=max` satoshi, inclusive,
+/// sorted by order number.
+///
+/// Orders created without a requested amount are left out. Fails if `min` exceeds `max`.
+#[query]
+pub fn get_orders_by_amount_range(min: u64, max: u64) -> Result<Vec<(u64, HtlcDetail)>, String> {
+    if min > max {
+        return Err(format!(
+            "Minimum amount {} exceeds the maximum amount {}",
+            min, max
+        ));
+    }
+
+    let orders: Vec<(u64, HtlcDetail)> = STORAGE.with_borrow(|storage| {
+        storage
+            .orders
+            .iter()
+            .map(|entry| entry.into_pair())
+            .filter(|(_, order)| requested_amount_in_range(order, min, max))
+            .collect()
+    });
+    Ok(orders)
+}
+
+/// Lists the orders in `status`, sorted by order number.
+#[query]
+pub fn get_orders_by_status(status: OrderStatus) -> Vec<(u64, HtlcDetail)> {
+    get_orders_by_statuses(vec![status])
+}
+
+/// Lists the orders in any of `statuses`, sorted by order number.
+///
+/// Duplicate statuses have no effect. An empty list matches no orders.
+#[query]
+pub fn get_orders_by_statuses(statuses: Vec<OrderStatus>) -> Vec<(u64, HtlcDetail)> {
+    STORAGE.with_borrow(|storage| orders_with_statuses(&storage.orders, &statuses))
+}
+
+/// Selects the orders of `orders` in any of `statuses`, sorted by order number.
+fn orders_with_statuses<M: ic_stable_structures::Memory>(
+    orders: &StableBTreeMap<u64, HtlcDetail, M>,
+    statuses: &[OrderStatus],
+) -> Vec<(u64, HtlcDetail)> {
+    let statuses: HashSet<OrderStatus> = statuses.iter().copied().collect();
+    orders
+        .iter()
+        .map(|entry| entry.into_pair())
+        .filter(|(_, order)| statuses.contains(&order.status))
+        .collect()
+}
+
+/// Returns the order with the given number, or an error if it does not exist.
+fn get_order(order_no: u64) -> Result<HtlcDetail, String> {
+    STORAGE
+        .with_borrow(|storage| storage.orders.get(&order_no))
+        .ok_or_else(|| format!("Order {} not found", order_no))
+}
+
+/// Returns the responder key to use for an order: the supplied one, or else the stored one.
+///
+/// The key is validated and normalized with `normalize_pubkey`.
+fn resolve_responder(supplied: Option<String>, order: &HtlcDetail) -> Result<String, String> {
+    let responder_pubkey = supplied
+        .or_else(|| order.responder_pubkey.clone())
+        .ok_or_else(|| "no responder pubkey available; supply one".to_string())?;
+    normalize_pubkey(&responder_pubkey, "responder")
+}
+
+/// Returns the derivation path of the order's funding key.
+///
+/// The order number is used as the BIP-32 account, so each order gets its own key.
+fn order_derivation(order_no: u64) -> DerivationPath {
+    DerivationPath::p2wpkh(order_no as u32, 0)
+}
+
+/// Returns the derivation path of the order's funding key in the binary form passed
+/// to the IC's key derivation APIs.
+fn order_derivation_path(order_no: u64) -> Vec<Vec<u8>> {
+    order_derivation(order_no).to_vec_u8_path()
+}
+
+/// Returns the derivation path of the order's funding key as a BIP-32 path string.
+///
+/// The string lists exactly the components passed to `get_ecdsa_public_key`, e.g.
+/// `m/84/0/7/0/0` for order 7. All components are unhardened, as hardened derivation
+/// is not available for threshold keys, and the coin type is 0 on every network, so
+/// wallets must be configured with this path rather than their network default.
+#[query]
+pub fn get_order_path_string(order_no: u64) -> String {
+    order_derivation(order_no).to_string()
+}
+
+/// Returns whether two order numbers derive the same funding key.
+///
+/// The order number is truncated to 32 bits when used as the BIP-32 account, so distinct
+/// order numbers that differ by a multiple of 2^32 share a derivation path and thus every
+/// address. The orders do not need to exist.
+#[query]
+pub fn would_collide(order_a: u64, order_b: u64) -> bool {
+    order_derivation_path(order_a) == order_derivation_path(order_b)
+}
+
+/// Derives the order's funding public key and P2WPKH address.
+async fn order_funding_key(
+    ctx: &BitcoinContext,
+    order_no: u64,
+) -> Result<(PublicKey, Address), String> {
+    let public_key = get_ecdsa_public_key(ctx, order_derivation_path(order_no)).await;
+    let public_key = CompressedPublicKey::from_slice(&public_key)
+        .map_err(|e| format!("Invalid derived public key: {}", e))?;
+    let address = Address::p2wpkh(&public_key, ctx.bitcoin_network);
+    Ok((public_key.into(), address))
+}
+
+/// Returns the funding P2WPKH address of an order.
+///
+/// Coins sent to this address are controlled by the canister and can be moved into
+/// the order's HTLC with `withdraw_from_order`. A `Created` order advances to
+/// `AddressGenerated`.
+#[update]
+pub async fn get_htlc_address(order_no: u64) -> Result<String, String> {
+    get_order(order_no)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let (_, address) = order_funding_key(&ctx, order_no).await?;
+    mark_address_generated(order_no, ic_cdk::api::time());
+    Ok(address.to_string())
+}
+
+/// BIP-32 chain of an order's account holding the addresses `get_fresh_funding_address`
+/// hands out. Chain 0 holds the funding address at index 0 and the sub-accounts.
+const FRESH_FUNDING_CHAIN: u32 = 1;
+
+/// Returns the derivation path of the order's `index`-th fresh funding address.
+fn fresh_funding_derivation(order_no: u64, index: u32) -> DerivationPath {
+    DerivationPath::p2wpkh(order_no as u32, index).on_chain(FRESH_FUNDING_CHAIN)
+}
+
+/// A P2WPKH funding address of an order, together with the key that spends from it.
+struct FundingKey {
+    key: p2wpkh::InputKey,
+    address: Address,
+}
+
+/// Derives the order's `index`-th fresh funding key and address.
+async fn fresh_funding_key(
+    ctx: &BitcoinContext,
+    order_no: u64,
+    index: u32,
+) -> Result<FundingKey, String> {
+    let derivation_path = fresh_funding_derivation(order_no, index).to_vec_u8_path();
+    let public_key = try_get_ecdsa_public_key(ctx, derivation_path.clone()).await?;
+    let public_key = CompressedPublicKey::from_slice(&public_key)
+        .map_err(|e| format!("Invalid derived public key: {}", e))?;
+    Ok(FundingKey {
+        key: p2wpkh::InputKey {
+            public_key: public_key.into(),
+            derivation_path,
+        },
+        address: Address::p2wpkh(&public_key, ctx.bitcoin_network),
+    })
+}
+
+/// Derives a new funding address for an order and returns it.
+///
+/// Every call hands out the next address on the order's fresh funding chain,
+/// `m/84/0/<order_no>/1/<n>`, so repeated deposits need not reuse an address. Deposits
+/// to any of them are spent together with those to the main funding address by
+/// `withdraw_from_order` and the other funding endpoints. A `Created` order advances
+/// to `AddressGenerated`.
+///
+/// Only the order's owner can call this, and at most `MAX_FRESH_FUNDING_ADDRESSES`
+/// addresses are handed out per order, since every withdrawal looks up each of them.
+#[update]
+pub async fn get_fresh_funding_address(order_no: u64) -> Result<String, String> {
+    let order = get_owned_order(order_no, msg_caller(), "add funding addresses to it")?;
+    // Holding the guard across the key derivation keeps the address index unique.
+    let _guard = OrderGuard::acquire(order_no)?;
+
+    check_fresh_funding_address_limit(order_no, &order)?;
+    let index = order.funding_addresses.as_ref().map_or(0, Vec::len) as u32;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let address = fresh_funding_key(&ctx, order_no, index)
+        .await?
+        .address
+        .to_string();
+
+    STORAGE.with_borrow_mut(|storage| {
+        storage.update_order(order_no, |order| {
+            order
+                .funding_addresses
+                .get_or_insert_with(Vec::new)
+                .push(address.clone());
+        });
+    });
+    mark_address_generated(order_no, ic_cdk::api::time());
+
+    Ok(address)
+}
+
+/// Maximum number of fresh funding addresses per order, see `get_fresh_funding_address`.
+const MAX_FRESH_FUNDING_ADDRESSES: usize = 20;
+
+/// Returns an error if `order` already has `MAX_FRESH_FUNDING_ADDRESSES` fresh funding
+/// addresses.
+fn check_fresh_funding_address_limit(order_no: u64, order: &HtlcDetail) -> Result<(), String> {
+    if order.funding_addresses.as_ref().map_or(0, Vec::len) >= MAX_FRESH_FUNDING_ADDRESSES {
+        return Err(format!(
+            "Order {} already has the maximum of {} fresh funding addresses",
+            order_no, MAX_FRESH_FUNDING_ADDRESSES
+        ));
+    }
+    Ok(())
+}
+
+/// Lists the fresh funding addresses of an order in derivation order, see
+/// `get_fresh_funding_address`. The main funding address is returned by
+/// `get_htlc_address`.
+///
+/// Returns an empty list for unknown orders.
+#[query]
+pub fn get_funding_addresses(order_no: u64) -> Vec<String> {
+    STORAGE.with_borrow(|storage| {
+        storage
+            .orders
+            .get(&order_no)
+            .and_then(|order| order.funding_addresses)
+            .unwrap_or_default()
+    })
+}
+
+/// Returns the key signing each input of `transaction`: the key of the fresh funding
+/// address or sub-account `owners` assigns the input's outpoint to, or else `main`. The
+/// prevouts of inputs from those addresses are updated to pay to them, as the
+/// transaction builders assume every input comes from the main funding address.
+fn funding_input_keys(
+    transaction: &Transaction,
+    prevouts: &mut [TxOut],
+    main: &p2wpkh::InputKey,
+    extra: &[FundingKey],
+    owners: &HashMap<(Vec<u8>, u32), usize>,
+) -> Vec<p2wpkh::InputKey> {
+    transaction
+        .input
+        .iter()
+        .zip(prevouts.iter_mut())
+        .map(|(input, prevout)| {
+            let outpoint = &input.previous_output;
+            match owners.get(&(outpoint.txid.to_byte_array().to_vec(), outpoint.vout)) {
+                Some(&index) => {
+                    prevout.script_pubkey = extra[index].address.script_pubkey();
+                    extra[index].key.clone()
+                }
+                None => main.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Advances the order to `AddressGenerated` if it is still `Created`.
+fn mark_address_generated(order_no: u64, now_ns: u64) {
+    let advanced = STORAGE.with_borrow_mut(|storage| {
+        storage.update_order(order_no, |order| {
+            let created = order.status == OrderStatus::Created;
+            if created {
+                order.status = OrderStatus::AddressGenerated;
+            }
+            created
+        })
+    });
+    if advanced == Some(true) {
+        record_event(order_no, OrderEventKind::AddressGenerated, None, now_ns);
+    }
+}
+
+/// Maximum number of funding addresses derived per `generate_addresses_range` call.
+///
+/// Every address not cached yet costs a call to the ECDSA API.
+const MAX_ADDRESSES_PER_CALL: u64 = 50;
+
+/// Derives the funding P2WPKH address of an order, returning an error instead of
+/// trapping if the key derivation fails.
+async fn try_order_funding_address(ctx: &BitcoinContext, order_no: u64) -> Result<String, String> {
+    get_order(order_no)?;
+    let public_key = try_get_ecdsa_public_key(ctx, order_derivation_path(order_no)).await?;
+    let public_key = CompressedPublicKey::from_slice(&public_key)
+        .map_err(|e| format!("Invalid derived public key: {}", e))?;
+    Ok(Address::p2wpkh(&public_key, ctx.bitcoin_network).to_string())
+}
+
+/// Runs `derive` for every order in `order_nos` concurrently and pairs each order number
+/// with its own result, in the order given. A failure for one order does not affect the
+/// others.
+async fn derive_addresses<DeriveFun, Fut>(
+    order_nos: impl IntoIterator<Item = u64>,
+    derive: DeriveFun,
+) -> Vec<(u64, Result<String, String>)>
+where
+    DeriveFun: Fn(u64) -> Fut,
+    Fut: std::future::Future<Output = Result<String, String>>,
+{
+    join_all(order_nos.into_iter().map(|order_no| {
+        let result = derive(order_no);
+        async move { (order_no, result.await) }
+    }))
+    .await
+}
+
+/// Returns the funding addresses of the orders `start..end` (end exclusive), as
+/// `(order_no, result)` in order-number order.
+///
+/// Each order is derived independently: unknown orders and failed key derivations are
+/// reported as an error for that order only, while the others still get their address.
+/// At most `MAX_ADDRESSES_PER_CALL` orders can be requested per call.
+#[update]
+pub async fn generate_addresses_range(
+    start: u64,
+    end: u64,
+) -> Result<Vec<(u64, Result<String, String>)>, String> {
+    if start > end {
+        return Err(format!("Range start {} exceeds its end {}", start, end));
+    }
+    if end - start > MAX_ADDRESSES_PER_CALL {
+        return Err(format!(
+            "At most {} addresses can be generated per call, requested {}",
+            MAX_ADDRESSES_PER_CALL,
+            end - start
+        ));
+    }
+
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    Ok(derive_addresses(start..end, |order_no| {
+        try_order_funding_address(&ctx, order_no)
+    })
+    .await)
+}
+
+/// Returns the hex-encoded compressed public key of an order's funding address.
+///
+/// This is the key `sign_order_message` signs with.
+#[update]
+pub async fn get_order_public_key(order_no: u64) -> Result<String, String> {
+    get_order(order_no)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let (public_key, _) = order_funding_key(&ctx, order_no).await?;
+    Ok(public_key.to_string())
+}
+
+/// Returns the hash `sign_order_message` signs for `message`: the Bitcoin Core
+/// `signmessage` digest, i.e. the double SHA256 of `"\x18Bitcoin Signed Message:\n"`,
+/// the message length as a compact size and the UTF-8 message bytes.
+fn order_message_hash(message: &str) -> [u8; 32] {
+    signed_msg_hash(message).to_byte_array()
+}
+
+/// Signs `message` with the key of the order's funding address, proving that the
+/// canister controls that address.
+///
+/// The message is hashed as described in `order_message_hash` and signed with ECDSA;
+/// the result is the hex-encoded 64-byte compact signature (`r || s`, low S). To verify,
+/// hash the message the same way and check the signature against the key returned by
+/// `get_order_public_key`, whose P2WPKH address is the order's funding address.
+///
+/// Only the order's owner can call this, so a signature attests to a statement of the
+/// owner and nobody else can spend signing cycles on the order.
+#[update]
+pub async fn sign_order_message(order_no: u64, message: String) -> Result<String, String> {
+    get_owned_order(order_no, msg_caller(), "sign messages with its key")?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+
+    let mut signature = sign_with_ecdsa(
+        ctx.key_name.to_string(),
+        order_derivation_path(order_no),
+        order_message_hash(&message).to_vec(),
+    )
+    .await;
+    signature.normalize_s();
+    usage::record_cycles(msg_caller(), usage::ecdsa_signature_cost(&ctx));
+    Ok(hex::encode(signature.serialize_compact()))
+}
+
+/// Maximum number of sub-accounts per order, see `create_sub_account`.
+const MAX_SUB_ACCOUNTS: usize = 20;
+
+/// Checks that `label` can be used for a new sub-account of `order`, and that the order
+/// has room for another one.
+fn validate_sub_account_label(order: &HtlcDetail, label: &str) -> Result<(), String> {
+    validate::non_empty(label, "Sub-account label")?;
+    if order.sub_accounts.len() >= MAX_SUB_ACCOUNTS {
+        return Err(format!(
+            "The order already has the maximum of {} sub-accounts",
+            MAX_SUB_ACCOUNTS
+        ));
+    }
+    if order
+        .sub_accounts
+        .iter()
+        .any(|(existing, _)| existing == label)
+    {
+        return Err(format!("Sub-account label {} is already in use", label));
+    }
+    Ok(())
+}
+
+/// Derives the key and P2WPKH address of an order's sub-account at address index
+/// `index`.
+async fn sub_account_key(
+    ctx: &BitcoinContext,
+    order_no: u64,
+    index: u32,
+) -> Result<FundingKey, String> {
+    let derivation_path = DerivationPath::p2wpkh(order_no as u32, index).to_vec_u8_path();
+    let public_key = try_get_ecdsa_public_key(ctx, derivation_path.clone()).await?;
+    let public_key = CompressedPublicKey::from_slice(&public_key)
+        .map_err(|e| format!("Invalid derived public key: {}", e))?;
+    Ok(FundingKey {
+        key: p2wpkh::InputKey {
+            public_key: public_key.into(),
+            derivation_path,
+        },
+        address: Address::p2wpkh(&public_key, ctx.bitcoin_network),
+    })
+}
+
+/// Derives the P2WPKH address of an order's sub-account at address index `index`.
+async fn sub_account_address(
+    ctx: &BitcoinContext,
+    order_no: u64,
+    index: u32,
+) -> Result<String, String> {
+    Ok(sub_account_key(ctx, order_no, index)
+        .await?
+        .address
+        .to_string())
+}
+
+/// Derives a new labeled P2WPKH deposit address for an order and returns it.
+///
+/// Sub-accounts use the order number as BIP-32 account like the funding address, at
+/// address index 1, 2, ... in creation order (index 0 is the funding address). Labels
+/// must be unique within an order. Deposits to sub-accounts are spent together with
+/// those to the funding addresses by `withdraw_from_order` and the other funding
+/// endpoints.
+///
+/// Only the order's owner can call this, and at most `MAX_SUB_ACCOUNTS` are created per
+/// order, since every withdrawal looks up each of them.
+#[update]
+pub async fn create_sub_account(order_no: u64, label: String) -> Result<String, String> {
+    let order = get_owned_order(order_no, msg_caller(), "add sub-accounts to it")?;
+    validate_sub_account_label(&order, &label)?;
+    // Holding the guard across the key derivation keeps the address index unique.
+    let _guard = OrderGuard::acquire(order_no)?;
+
+    let index = order.sub_accounts.len() as u32 + 1;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let address = sub_account_address(&ctx, order_no, index).await?;
+
+    STORAGE.with_borrow_mut(|storage| {
+        storage.update_order(order_no, |order| {
+            order.sub_accounts.push((label, address.clone()));
+        });
+    });
+
+    Ok(address)
+}
+
+/// Returns the sub-accounts of an order as `(label, address)`, in creation order.
+///
+/// Returns an empty list for unknown orders.
+#[query]
+pub fn get_sub_accounts(order_no: u64) -> Vec<(String, String)> {
+    STORAGE.with_borrow(|storage| {
+        storage
+            .orders
+            .get(&order_no)
+            .map(|order| order.sub_accounts)
+            .unwrap_or_default()
+    })
+}
+
+/// Longest reservation `reserve_order` grants, in nanoseconds.
+const MAX_RESERVATION_TTL_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// How often the holder of a reservation can renew it before it must lapse.
+const MAX_RESERVATION_RENEWALS: u32 = 2;
+
+/// Returns the reservation of `order` if it is still active at `now_ns`.
+fn active_reservation(order: &HtlcDetail, now_ns: u64) -> Option<&Reservation> {
+    order
+        .reservation
+        .as_ref()
+        .filter(|reservation| now_ns < reservation.expires_at_ns)
+}
+
+/// Checks that no other responder than `responder_pubkey` holds an active reservation
+/// on `order` at `now_ns`.
+fn check_reservation(
+    order: &HtlcDetail,
+    responder_pubkey: &str,
+    now_ns: u64,
+) -> Result<(), String> {
+    match active_reservation(order, now_ns) {
+        Some(reservation) if reservation.responder_pubkey != responder_pubkey => Err(format!(
+            "The order is reserved by responder {} until {} ns",
+            reservation.responder_pubkey, reservation.expires_at_ns
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Checks that `caller` owns `order` and may thus perform `action`. Orders without a
+/// recorded owner are not restricted.
+///
+/// Every endpoint that signs with the order's key or changes what it signs applies this
+/// check: the withdraw family, `prepare_withdraw` and `broadcast_prepared`,
+/// `discard_prepared`, `replace_order_funding`, `claim_htlc`, `redeem_to_multiple`,
+/// `refund_htlc`, `extend_timelock_via_refund`, `sign_order_message`, `patch_order` and
+/// `cancel_order`. So do `get_fresh_funding_address` and `create_sub_account`, since
+/// every address they add is looked up by each withdrawal.
+fn check_owner(order: &HtlcDetail, caller: Principal, action: &str) -> Result<(), String> {
+    match order.owner {
+        Some(owner) if owner != caller => {
+            Err(format!("Only the order's owner {} can {}", owner, action))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Returns the order, checking that `caller` owns it and may thus perform `action`, see
+/// `check_owner`.
+fn get_owned_order(order_no: u64, caller: Principal, action: &str) -> Result<HtlcDetail, String> {
+    let order = get_order(order_no)?;
+    check_owner(&order, caller, action)?;
+    Ok(order)
+}
+
+/// Returns the principal that created the order, if it was recorded.
+#[query]
+pub fn get_order_owner(order_no: u64) -> Option<Principal> {
+    get_order(order_no).ok()?.owner
+}
+
+/// Checks that `order` was not funded yet, i.e. no HTLC address was recorded and it is
+/// still `Created` or `AddressGenerated`.
+fn ensure_unfunded(order_no: u64, order: &HtlcDetail) -> Result<(), String> {
+    if order.htlc_address.is_some()
+        || !matches!(
+            order.status,
+            OrderStatus::Created | OrderStatus::AddressGenerated
+        )
+    {
+        return Err(format!("Order {} is already funded", order_no));
+    }
+    Ok(())
+}
+
+/// Changes to apply to an order with `patch_order`. Fields left `None` are kept.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct OrderPatch {
+    /// New amount in satoshi the initiator intends to trade.
+    pub requested_amount: Option<u64>,
+    /// New timelock, of the order's timelock kind. Only accepted before the order is
+    /// funded, as it changes the HTLC script.
+    pub time_lock: Option<u64>,
+    /// Hex-encoded public key of the responder to fund the order for. Only accepted
+    /// before the order is funded, as it changes the HTLC script.
+    pub responder_pubkey: Option<String>,
+}
+
+/// Returns `order` with `patch` applied, checking every patched field the way
+/// `create_order` does on `network`. Nothing is applied unless all fields are valid.
+fn apply_order_patch(
+    order_no: u64,
+    order: &HtlcDetail,
+    patch: OrderPatch,
+    network: bitcoin::Network,
+) -> Result<HtlcDetail, String> {
+    let mut patched = order.clone();
+    if let Some(amount) = patch.requested_amount {
+        validate::positive(amount, "Requested amount")?;
+        patched.requested_amount = Some(amount);
+    }
+    if let Some(time_lock) = patch.time_lock {
+        ensure_unfunded(order_no, order)?;
+        validate_order_time_lock(time_lock, order.timelock_type(), network)?;
+        patched.time_lock = time_lock;
+    }
+    if let Some(responder_pubkey) = patch.responder_pubkey {
+        ensure_unfunded(order_no, order)?;
+        patched.responder_pubkey = Some(normalize_pubkey(&responder_pubkey, "responder")?);
+    }
+    Ok(patched)
+}
+
+/// Updates several attributes of an order at once, see `OrderPatch`.
+///
+/// Only the order's owner can patch it. The patch is applied atomically: if any field is
+/// rejected, the order is left unchanged. An empty patch changes nothing.
+#[update]
+pub fn patch_order(order_no: u64, patch: OrderPatch) -> Result<(), HtlcError> {
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let order = get_order(order_no)?;
+    check_owner(&order, msg_caller(), "patch it")?;
+    let _guard = OrderGuard::acquire(order_no)?;
+    let patched = apply_order_patch(order_no, &order, patch, ctx.bitcoin_network)?;
+    STORAGE.with_borrow_mut(|storage| storage.orders.insert(order_no, patched));
+    Ok(())
+}
+
+/// Records `responder_pubkey` as the order's responder, so its HTLC address can be
+/// derived without supplying the key again, e.g. by `withdraw_from_order`.
+///
+/// The key must be a compressed public key and is stored as lowercase hex. It can be
+/// changed until the order is funded.
+#[update]
+pub fn set_order_responder(order_no: u64, responder_pubkey: String) -> Result<(), String> {
+    patch_order(
+        order_no,
+        OrderPatch {
+            responder_pubkey: Some(responder_pubkey),
+            ..Default::default()
+        },
+    )
+    .map_err(String::from)
+}
+
+/// Reserves the order for `responder_pubkey` for the next `ttl_ns` nanoseconds.
+///
+/// While the reservation is active, funding the order for any other responder is
+/// rejected. Only the principal that made the reservation can renew it, for the same
+/// responder and at most `MAX_RESERVATION_RENEWALS` times; others can only reserve the
+/// order once it has expired. The order's owner can lift it with `cancel_reservation`.
+/// Only orders that are not funded yet can be reserved, and `ttl_ns` is capped at
+/// `MAX_RESERVATION_TTL_NS` so an abandoned reservation frees the order again.
+#[update]
+pub fn reserve_order(order_no: u64, responder_pubkey: String, ttl_ns: u64) -> Result<(), String> {
+    let responder_pubkey = normalize_pubkey(&responder_pubkey, "responder")?;
+    validate::in_range(ttl_ns, 1, MAX_RESERVATION_TTL_NS, "Reservation TTL (ns)")?;
+
+    let caller = msg_caller();
+    let now_ns = ic_cdk::api::time();
+    STORAGE.with_borrow_mut(|storage| {
+        let mut order = storage
+            .orders
+            .get(&order_no)
+            .ok_or_else(|| format!("Order {} not found", order_no))?;
+        ensure_unfunded(order_no, &order)?;
+        order.reservation = Some(next_reservation(
+            &order,
+            caller,
+            responder_pubkey,
+            ttl_ns,
+            now_ns,
+        )?);
+        storage.orders.insert(order_no, order);
+        Ok(())
+    })
+}
+
+/// Returns the reservation `caller` gets for `responder_pubkey` when reserving `order`
+/// for `ttl_ns` at `now_ns`: a new one if no reservation is active, otherwise a renewal
+/// of the active one, which only its holder can make for the same responder.
+fn next_reservation(
+    order: &HtlcDetail,
+    caller: Principal,
+    responder_pubkey: String,
+    ttl_ns: u64,
+    now_ns: u64,
+) -> Result<Reservation, String> {
+    let renewals = match active_reservation(order, now_ns) {
+        None => 0,
+        Some(active) => {
+            if active.holder != Some(caller) || active.responder_pubkey != responder_pubkey {
+                return Err(format!(
+                    "The order is reserved by responder {} until {} ns",
+                    active.responder_pubkey, active.expires_at_ns
+                ));
+            }
+            let renewals = active.renewals.unwrap_or(0);
+            if renewals >= MAX_RESERVATION_RENEWALS {
+                return Err(format!(
+                    "The reservation was already renewed the maximum of {} times",
+                    MAX_RESERVATION_RENEWALS
+                ));
+            }
+            renewals + 1
+        }
+    };
+    Ok(Reservation {
+        responder_pubkey,
+        expires_at_ns: now_ns.saturating_add(ttl_ns),
+        holder: Some(caller),
+        renewals: Some(renewals),
+    })
+}
+
+/// Lifts the reservation of an order, see `reserve_order`, so it can be funded for any
+/// responder again.
+///
+/// Only the order's owner and the reservation's holder can call this.
+#[update]
+pub fn cancel_reservation(order_no: u64) -> Result<(), String> {
+    let caller = msg_caller();
+    STORAGE.with_borrow_mut(|storage| {
+        let mut order = storage
+            .orders
+            .get(&order_no)
+            .ok_or_else(|| format!("Order {} not found", order_no))?;
+        check_reservation_canceller(&order, caller)?;
+        if order.reservation.take().is_none() {
+            return Err(format!("Order {} has no reservation", order_no));
+        }
+        storage.orders.insert(order_no, order);
+        Ok(())
+    })
+}
+
+/// Checks that `caller` may lift the reservation of `order`: it is the order's owner or
+/// the reservation's holder.
+fn check_reservation_canceller(order: &HtlcDetail, caller: Principal) -> Result<(), String> {
+    let is_holder = order
+        .reservation
+        .as_ref()
+        .is_some_and(|reservation| reservation.holder == Some(caller));
+    if is_holder {
+        return Ok(());
+    }
+    check_owner(order, caller, "cancel its reservation")
+}
+
+/// Returns the active reservation of an order, or `None` if it has none, it expired or
+/// the order does not exist.
+#[query]
+pub fn get_reservation(order_no: u64) -> Option<Reservation> {
+    let now_ns = ic_cdk::api::time();
+    STORAGE
+        .with_borrow(|storage| active_reservation(&storage.orders.get(&order_no)?, now_ns).cloned())
+}
+
+/// Builds the HTLC witness script for the given parties, secret hash and timelock.
+///
+/// This is the variant of `build_custom_htlc_script` orders use: a SHA256 or HASH160
+/// hashlock, a CSV or CLTV timelock and no cooperative branch.
+fn generate_p2wsh_htlc_script(
+    initiator_pubkey: &str,
+    responder_pubkey: &str,
+    secret_hash: &str,
+    time_lock: u64,
+    timelock_type: HtlcTimelockType,
+    hash_type: HtlcHashType,
+) -> Result<ScriptBuf, String> {
+    let mut params = HtlcScriptParams::new(
+        initiator_pubkey.to_string(),
+        responder_pubkey.to_string(),
+        secret_hash.to_string(),
+        time_lock,
+    );
+    params.timelock_type = timelock_type;
+    params.hash_type = hash_type;
+    build_custom_htlc_script(&params)
+}
+
+/// Computes the address committing to the HTLC witness script in `address_format`.
+#[allow(clippy::too_many_arguments)]
+fn generate_p2wsh_htlc_address(
+    initiator_pubkey: &str,
+    responder_pubkey: &str,
+    secret_hash: &str,
+    time_lock: u64,
+    timelock_type: HtlcTimelockType,
+    hash_type: HtlcHashType,
+    address_format: HtlcAddressFormat,
+    network: bitcoin::Network,
+) -> Result<Address, String> {
+    let script = generate_p2wsh_htlc_script(
+        initiator_pubkey,
+        responder_pubkey,
+        secret_hash,
+        time_lock,
+        timelock_type,
+        hash_type,
+    )?;
+    Ok(address_format.address(&script, network))
+}
+
+/// Every intermediate value of deriving an HTLC's P2WSH address, for debugging.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ScriptHashDebug {
+    /// Hex-encoded HTLC witness script.
+    pub script_hex: String,
+    /// Hex-encoded single SHA256 of the witness script, the P2WSH witness program.
+    pub script_sha256_hex: String,
+    /// Hex-encoded P2SH redeem script wrapping the P2WSH program, `OP_0 <script_sha256>`,
+    /// for orders in the nested P2SH-P2WSH format. `None` for native P2WSH orders.
+    pub redeem_script_hex: Option<String>,
+    /// Hex-encoded script pubkey of the order's HTLC address: `OP_0 <script_sha256>` for
+    /// native P2WSH, `OP_HASH160 <hash160(redeem_script)> OP_EQUAL` for nested P2SH-P2WSH.
+    pub scriptpubkey_hex: String,
+    /// The order's HTLC address on the canister's network, in its address format.
+    pub address: String,
+}
+
+/// Computes every step of deriving the address of `witness_script` in `format`.
+fn script_hash_debug(
+    witness_script: &Script,
+    format: HtlcAddressFormat,
+    network: bitcoin::Network,
+) -> ScriptHashDebug {
+    let address = format.address(witness_script, network);
+    let redeem_script_hex = match format {
+        HtlcAddressFormat::NativeP2wsh => None,
+        HtlcAddressFormat::NestedP2shP2wsh => {
+            Some(hex::encode(witness_script.to_p2wsh().as_bytes()))
+        }
+    };
+    ScriptHashDebug {
+        script_hex: hex::encode(witness_script.as_bytes()),
+        script_sha256_hex: hex::encode(sha256::Hash::hash(witness_script.as_bytes())),
+        redeem_script_hex,
+        scriptpubkey_hex: hex::encode(address.script_pubkey().as_bytes()),
+        address: address.to_string(),
+    }
+}
+
+/// Returns each intermediate value of deriving the order's HTLC address, so developers
+/// can find where an externally computed address diverges from the canister's.
+///
+/// The responder key defaults to the order's stored responder when `None` is given.
+/// Nothing is stored or changed.
+#[query]
+pub fn debug_script_hashing(
+    order_no: u64,
+    responder_pubkey: Option<String>,
+) -> Result<ScriptHashDebug, String> {
+    let order = get_order(order_no)?;
+    let responder_pubkey = resolve_responder(responder_pubkey, &order)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+
+    let witness_script = generate_p2wsh_htlc_script(
+        &order.initiator_pubkey,
+        &responder_pubkey,
+        &order.secret_hash,
+        order.time_lock,
+        order.timelock_type(),
+        order.hash_type(),
+    )?;
+    Ok(script_hash_debug(
+        &witness_script,
+        order.address_format(),
+        ctx.bitcoin_network,
+    ))
+}
+
+/// An order's HTLC address together with the witness script it commits to.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct HtlcPreview {
+    /// Address of the HTLC, in the order's address format.
+    pub address: String,
+    /// Hex-encoded HTLC witness script.
+    pub witness_script_hex: String,
+}
+
+/// Computes the HTLC of `order` with `responder_pubkey` on `network`.
+fn htlc_preview(
+    order: &HtlcDetail,
+    responder_pubkey: &str,
+    network: bitcoin::Network,
+) -> Result<HtlcPreview, String> {
+    let witness_script = generate_p2wsh_htlc_script(
+        &order.initiator_pubkey,
+        responder_pubkey,
+        &order.secret_hash,
+        order.time_lock,
+        order.timelock_type(),
+        order.hash_type(),
+    )?;
+    Ok(HtlcPreview {
+        address: order
+            .address_format()
+            .address(&witness_script, network)
+            .to_string(),
+        witness_script_hex: hex::encode(witness_script.as_bytes()),
+    })
+}
+
+/// Returns the network to derive addresses for: `network_override` if given, otherwise
+/// the canister's network.
+fn address_network(ctx: &BitcoinContext, network_override: Option<Network>) -> bitcoin::Network {
+    network_override.map_or(ctx.bitcoin_network, bitcoin_network)
+}
+
+/// Checks that `network_override`, if given, is the canister's network. UTXOs are only
+/// ever queried and spent there, so any other network must be rejected before funds
+/// are moved.
+fn check_network_override(
+    ctx: &BitcoinContext,
+    network_override: Option<Network>,
+) -> Result<(), String> {
+    match network_override {
+        Some(network) if network != ctx.network => Err(format!(
+            "Network {:?} does not match the canister's network {:?}",
+            network, ctx.network
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the HTLC address and witness script the order would use with
+/// `responder_pubkey`, without touching UTXOs or storing anything.
+///
+/// A counterparty can rebuild the script from the order's parameters to verify the
+/// escrow before depositing into it. The address is for the canister's network unless
+/// `network_override` names another one, e.g. to compare against a mainnet escrow from
+/// a testnet canister. The canister can only spend on its own network, so funds sent to
+/// an address for another network are out of its reach.
+#[query]
+pub fn preview_htlc_address(
+    order_no: u64,
+    responder_pubkey: String,
+    network_override: Option<Network>,
+) -> Result<HtlcPreview, String> {
+    let order = get_order(order_no)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    htlc_preview(
+        &order,
+        &responder_pubkey,
+        address_network(&ctx, network_override),
+    )
+}
+
+/// Maximum number of responders `list_candidate_htlc_addresses` accepts per call.
+const MAX_CANDIDATE_RESPONDERS: usize = 100;
+
+/// Returns the HTLC addresses of `order` on `network` for each of `responder_pubkeys`,
+/// as `(responder_pubkey, address)` with the keys normalized, in the order given.
+fn candidate_htlc_addresses(
+    order: &HtlcDetail,
+    responder_pubkeys: &[String],
+    network: bitcoin::Network,
+) -> Result<Vec<(String, String)>, String> {
+    if responder_pubkeys.len() > MAX_CANDIDATE_RESPONDERS {
+        return Err(format!(
+            "At most {} responder pubkeys are accepted per call, got {}",
+            MAX_CANDIDATE_RESPONDERS,
+            responder_pubkeys.len()
+        ));
+    }
+    responder_pubkeys
+        .iter()
+        .map(|responder_pubkey| {
+            let responder_pubkey = normalize_pubkey(responder_pubkey, "responder")?;
+            let preview = htlc_preview(order, &responder_pubkey, network)?;
+            Ok((responder_pubkey, preview.address))
+        })
+        .collect()
+}
+
+/// Returns the HTLC address the order would use with each of `responder_pubkeys`, as
+/// `(responder_pubkey, address)` in the order given.
+///
+/// The same secret hash can be offered to several responders, each getting its own
+/// escrow address; the initiator can publish these and watch which one gets funded. The
+/// order is not modified. Fails if any key is invalid.
+#[query]
+pub fn list_candidate_htlc_addresses(
+    order_no: u64,
+    responder_pubkeys: Vec<String>,
+) -> Result<Vec<(String, String)>, String> {
+    let order = get_order(order_no)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    candidate_htlc_addresses(&order, &responder_pubkeys, ctx.bitcoin_network)
+}
+
+/// Returns the hex-encoded witness script of the order's HTLC with `responder_pubkey`.
+///
+/// This is the script claim and refund transactions put in the witness, so external
+/// tooling can check its branches or build spending transactions off-canister.
+#[query]
+pub fn get_htlc_script_hex(order_no: u64, responder_pubkey: String) -> Result<String, String> {
+    let order = get_order(order_no)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    Ok(htlc_preview(&order, &responder_pubkey, ctx.bitcoin_network)?.witness_script_hex)
+}
+
+/// Returns the confirmed balance in satoshi of the order's HTLC with `responder_pubkey`.
+///
+/// The HTLC address is recomputed from the order's parameters, so this also works before
+/// `withdraw_from_order` recorded it. An HTLC without confirmed UTXOs has a balance of 0;
+/// an error means the lookup itself failed.
+#[update]
+pub async fn get_htlc_balance(order_no: u64, responder_pubkey: String) -> Result<u64, String> {
+    let order = get_order(order_no)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let htlc_address = generate_p2wsh_htlc_address(
+        &order.initiator_pubkey,
+        &responder_pubkey,
+        &order.secret_hash,
+        order.time_lock,
+        order.timelock_type(),
+        order.hash_type(),
+        order.address_format(),
+        ctx.bitcoin_network,
+    )?;
+
+    let utxos = get_utxos(&ctx, &htlc_address.to_string(), Some(1)).await?;
+    Ok(utxos.iter().map(|utxo| utxo.value).sum())
+}
+
+/// Returns the worst-case virtual size of a transaction spending a single HTLC output
+/// with `witness_script` in `address_format` to a single P2WPKH output.
+///
+/// The claim branch is the larger of the two spend paths: its witness holds a signature
+/// (at most 73 bytes including the sighash type), the 32-byte preimage, the branch
+/// selector and the witness script, each with a length prefix.
+fn worst_case_spend_vsize(witness_script: &Script, address_format: HtlcAddressFormat) -> u64 {
+    let script_len = witness_script.len() as u64;
+    let script_len_prefix = if script_len < 0xfd { 1 } else { 3 };
+    let witness_bytes = 1 + (1 + 73) + (1 + 32) + (1 + 1) + script_len_prefix + script_len;
+    // Outpoint, script_sig and sequence, serialized without the witness. The script_sig
+    // is at most a 35-byte push, so its length prefix is a single byte.
+    let script_sig_len = address_format.script_sig(witness_script).len() as u64;
+    let input_weight = (32 + 4 + 1 + script_sig_len + 4) * 4 + witness_bytes;
+    TX_OVERHEAD_VBYTES + input_weight.div_ceil(4) + P2WPKH_OUTPUT_VBYTES
+}
+
+/// Fetches the UTXOs of `address` that have at least `min_confirmations` confirmations,
+/// along with the tip height they were reported at.
+async fn get_utxos_response(
+    ctx: &BitcoinContext,
+    address: &str,
+    min_confirmations: Option<u32>,
+) -> Result<GetUtxosResponse, String> {
+    bitcoin_get_utxos(&GetUtxosRequest {
+        address: address.to_string(),
+        network: ctx.network,
+        filter: min_confirmations.map(UtxosFilter::MinConfirmations),
+    })
+    .await
+    .map_err(|e| format!("Failed to fetch UTXOs for {}: {:?}", address, e))
+}
+
+/// Fetches the UTXOs of `address` that have at least `min_confirmations` confirmations.
+async fn get_utxos(
+    ctx: &BitcoinContext,
+    address: &str,
+    min_confirmations: Option<u32>,
+) -> Result<Vec<Utxo>, String> {
+    get_utxos_response(ctx, address, min_confirmations)
+        .await
+        .map(|response| response.utxos)
+}
+
+/// Returns the number of confirmations of `utxo` at `tip_height` (0 if not yet mined).
+fn confirmations(utxo: &Utxo, tip_height: u32) -> u32 {
+    if utxo.height == 0 || utxo.height > tip_height {
+        0
+    } else {
+        tip_height - utxo.height + 1
+    }
+}
+
+// Change outputs of the canister's own funding transactions, per order, that have not been
+// seen confirmed yet. Their height is 0 until the Bitcoin canister reports them.
+// Note: this is not persisted across upgrades.
+thread_local! {
+    static PENDING_CHANGE: RefCell<HashMap<u64, Vec<Utxo>>> = RefCell::new(HashMap::new());
+}
+
+/// Returns the UTXOs a withdrawal may select from.
+///
+/// Deposits, i.e. reported UTXOs other than the canister's own change, need at least
+/// `min_deposit_confirmations` confirmations. With `allow_spend_unconfirmed_change`
+/// disabled, the canister's own change outputs in `pending_change` are excluded until
+/// they have at least one confirmation, so withdrawals never build on top of an
+/// unconfirmed transaction. When enabled, pending change that the Bitcoin canister does
+/// not report yet is added, allowing withdrawals to chain off it.
+fn spendable_utxos(
+    reported: Vec<Utxo>,
+    tip_height: u32,
+    pending_change: &[Utxo],
+    allow_spend_unconfirmed_change: bool,
+    min_deposit_confirmations: u32,
+) -> Vec<Utxo> {
+    let is_pending = |utxo: &Utxo| {
+        pending_change
+            .iter()
+            .any(|change| change.outpoint == utxo.outpoint)
+    };
+
+    let mut utxos: Vec<Utxo> = reported
+        .into_iter()
+        .filter(|utxo| {
+            if is_pending(utxo) {
+                allow_spend_unconfirmed_change || confirmations(utxo, tip_height) > 0
+            } else {
+                confirmations(utxo, tip_height) >= min_deposit_confirmations
+            }
+        })
+        .collect();
+
+    if allow_spend_unconfirmed_change {
+        for change in pending_change {
+            if !utxos.iter().any(|utxo| utxo.outpoint == change.outpoint) {
+                utxos.push(change.clone());
+            }
+        }
+    }
+
+    utxos
+}
+
+/// Checks that a withdrawal has UTXOs to spend, telling apart a funding address that
+/// holds nothing from one whose funds are not confirmed enough yet.
+///
+/// `reported` are all UTXOs of the funding address regardless of confirmations and
+/// `spendable` the ones `spendable_utxos` kept.
+fn ensure_funding_available(spendable: &[Utxo], reported: &[Utxo]) -> Result<(), HtlcError> {
+    if !spendable.is_empty() {
+        return Ok(());
+    }
+    if reported.is_empty() {
+        return Err(HtlcError::NoUtxos);
+    }
+    Err(HtlcError::FundingUnconfirmed {
+        unconfirmed_value: reported.iter().map(|utxo| utxo.value).sum(),
+    })
+}
+
+/// A UTXO of an order's funding address, as listed by `get_order_coins`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct CoinInfo {
+    /// The UTXO's outpoint as `txid:vout`.
+    pub outpoint: String,
+    /// Value in satoshi.
+    pub value: u64,
+    /// Confirmations at the tip height the UTXOs were reported at.
+    pub confirmations: u32,
+    /// Whether a withdrawal may currently select this UTXO, see `spendable_utxos`.
+    pub spendable: bool,
+}
+
+/// Annotates the `reported` UTXOs with their confirmations at `tip_height` and whether
+/// they are among `spendable`, sorted by value from largest to smallest.
+///
+/// Spendable UTXOs not reported yet, i.e. unconfirmed change, are listed as well.
+fn coin_infos(
+    reported: &[Utxo],
+    tip_height: u32,
+    spendable: &[Utxo],
+) -> Result<Vec<CoinInfo>, String> {
+    let unreported = spendable.iter().filter(|utxo| {
+        !reported
+            .iter()
+            .any(|reported| reported.outpoint == utxo.outpoint)
+    });
+    let mut coins = reported
+        .iter()
+        .chain(unreported)
+        .map(|utxo| {
+            Ok(CoinInfo {
+                outpoint: outpoint_string(&utxo.outpoint)?,
+                value: utxo.value,
+                confirmations: confirmations(utxo, tip_height),
+                spendable: spendable
+                    .iter()
+                    .any(|spendable| spendable.outpoint == utxo.outpoint),
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    coins.sort_by_key(|coin| std::cmp::Reverse(coin.value));
+    Ok(coins)
+}
+
+/// Lists the UTXOs of an order's funding address, largest first, with their
+/// confirmations and whether a withdrawal may spend them under the current policy.
+#[update]
+pub async fn get_order_coins(order_no: u64) -> Result<Vec<CoinInfo>, String> {
+    get_order(order_no)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let (_, own_address) = order_funding_key(&ctx, order_no).await?;
+
+    let response = get_utxos_response(&ctx, &own_address.to_string(), None).await?;
+    let pending_change =
+        PENDING_CHANGE.with_borrow(|pending| pending.get(&order_no).cloned().unwrap_or_default());
+    let spendable = spendable_utxos(
+        response.utxos.clone(),
+        response.tip_height,
+        &pending_change,
+        config::current().allow_spend_unconfirmed_change,
+        config::current().min_deposit_confirmations,
+    );
+    coin_infos(&response.utxos, response.tip_height, &spendable)
+}
+
+/// Remembers the outputs of `transaction` paying back to `own_address` as pending change.
+fn record_pending_change(order_no: u64, transaction: &Transaction, own_address: &Address) {
+    let txid = transaction.compute_txid().as_byte_array().to_vec();
+    let own_script = own_address.script_pubkey();
+    let change: Vec<Utxo> = transaction
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| output.script_pubkey == own_script)
+        .map(|(vout, output)| Utxo {
+            outpoint: Outpoint {
+                txid: txid.clone(),
+                vout: vout as u32,
+            },
+            value: output.value.to_sat(),
+            height: 0,
+        })
+        .collect();
+
+    PENDING_CHANGE.with_borrow_mut(|pending| pending.entry(order_no).or_default().extend(change));
+}
+
+/// Forgets pending change of an order that the Bitcoin canister now reports as confirmed.
+fn prune_pending_change(order_no: u64, reported: &[Utxo], tip_height: u32) {
+    PENDING_CHANGE.with_borrow_mut(|pending| {
+        if let Some(change) = pending.get_mut(&order_no) {
+            change.retain(|change| {
+                !reported.iter().any(|utxo| {
+                    utxo.outpoint == change.outpoint && confirmations(utxo, tip_height) > 0
+                })
+            });
+        }
+    });
+}
+
+/// A signed funding transaction prepared by `prepare_withdraw` for later broadcast.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PreparedWithdraw {
+    /// Consensus-serialized signed transaction.
+    pub transaction: Vec<u8>,
+    /// Responder key the HTLC output was derived for.
+    pub responder_pubkey: String,
+    /// Fee details of the transaction, recorded once it is broadcast.
+    pub fee_detail: FeeDetail,
+}
+
+/// A signed funding transaction along with everything needed to record it once broadcast.
+struct SignedWithdraw {
+    transaction: Transaction,
+    htlc_address: Address,
+    own_address: Address,
+    responder_pubkey: String,
+    fee_detail: FeeDetail,
+}
+
+impl SignedWithdraw {
+    /// Returns the receipt `withdraw_from_order` reports for this transaction.
+    fn receipt(&self) -> WithdrawResult {
+        WithdrawResult {
+            txid: self.fee_detail.txid.clone(),
+            htlc_address: self.htlc_address.to_string(),
+            amount: value_paid_to(&self.transaction, &self.htlc_address),
+            fee_paid: self.fee_detail.fee,
+            inputs: self.transaction.input.len() as u64,
+        }
+    }
+}
+
+/// Receipt of a funding transaction broadcast by `withdraw_from_order`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct WithdrawResult {
+    pub txid: String,
+    /// Address of the HTLC the funds were sent to.
+    pub htlc_address: String,
+    /// Satoshi locked in the HTLC.
+    pub amount: u64,
+    /// Fee paid by the transaction in satoshi.
+    pub fee_paid: u64,
+    /// Number of the order's UTXOs the transaction spends.
+    pub inputs: u64,
+}
+
+/// Returns the smallest HTLC output that still leaves more than dust after paying
+/// `claim_fee` for claiming it.
+fn min_claimable_amount(claim_fee: u64) -> u64 {
+    claim_fee + DUST_THRESHOLD + 1
+}
+
+/// Checks that an HTLC output of `amount` can be claimed economically, i.e. that paying
+/// `claim_fee` for the claim still leaves more than dust.
+fn ensure_claimable(amount: u64, claim_fee: u64) -> Result<(), String> {
+    if amount >= min_claimable_amount(claim_fee) {
+        return Ok(());
+    }
+    Err(format!(
+        "Amount {} does not cover the estimated claim fee of {} satoshi plus the dust threshold of {}",
+        amount, claim_fee, DUST_THRESHOLD
+    ))
+}
+
+/// Checks that an HTLC output of `amount` is not dust, which the network would refuse
+/// to relay.
+fn ensure_above_p2wsh_dust(amount: u64) -> Result<(), HtlcError> {
+    if amount < DUST_THRESHOLD_P2WSH {
+        return Err(HtlcError::Validation(format!(
+            "Amount {} is below the P2WSH dust threshold of {} satoshi",
+            amount, DUST_THRESHOLD_P2WSH
+        )));
+    }
+    Ok(())
+}
+
+/// Returns the smallest amount worth locking in an HTLC for `responder_pubkey` at the
+/// current fee rate.
+///
+/// The amount covers the worst-case fee of claiming the HTLC to a P2WPKH address and
+/// still leaves the claimed output above dust; `withdraw_from_order` rejects smaller
+/// amounts unless that check is disabled in the config. The funding fee is paid on top
+/// by the order's funding UTXOs. The result is derived from live fee percentiles, so it
+/// changes with network conditions and should be refreshed before use.
+#[update]
+pub async fn min_htlc_amount(responder_pubkey: String) -> Result<u64, String> {
+    // The claim size only depends on the script's size and the address format, so a
+    // placeholder initiator and secret hash, the largest timelock encoding and the
+    // nested format give an upper bound for any order.
+    let witness_script = generate_p2wsh_htlc_script(
+        &responder_pubkey,
+        &responder_pubkey,
+        &hex::encode([0; 32]),
+        LOCKTIME_THRESHOLD - 1,
+        HtlcTimelockType::Cltv,
+        HtlcHashType::Sha256,
+    )?;
+
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let fee_per_byte = get_fee_per_byte(&ctx).await;
+    let claim_fee = fee_for_vsize(
+        worst_case_spend_vsize(&witness_script, HtlcAddressFormat::NestedP2shP2wsh),
+        fee_per_byte,
+    );
+    Ok(min_claimable_amount(claim_fee))
+}
+
+/// How much `sign_withdraw` moves into the HTLC.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FundingAmount {
+    /// The given amount in satoshi, with change back to the funding address.
+    WithChange(u64),
+    /// The given amount in satoshi without a change output, see `fund_exact`.
+    WithoutChange(u64),
+    /// All confirmed funds minus the fee, without a change output.
+    Sweep,
+}
+
+/// Selects the UTXOs a sweep spends, all confirmed ones among `own_utxos` at
+/// `tip_height`, and the amount they send to `destination` after the fee.
+///
+/// Fails if nothing is confirmed or if the amount would be below dust.
+fn sweep_selection<'a>(
+    own_utxos: &'a [Utxo],
+    tip_height: u32,
+    destination: &Address,
+    fee_per_byte: u64,
+) -> Result<(Vec<&'a Utxo>, u64), HtlcError> {
+    let selected: Vec<&Utxo> = own_utxos
+        .iter()
+        .filter(|utxo| confirmations(utxo, tip_height) > 0)
+        .collect();
+    if selected.is_empty() {
+        return Err(HtlcError::Other(
+            "The order has no confirmed funds to sweep".to_string(),
+        ));
+    }
+
+    let confirmed: Vec<Utxo> = selected.iter().map(|utxo| (*utxo).clone()).collect();
+    let amount = max_spendable_p2wpkh(&confirmed, destination, fee_per_byte);
+    if amount < DUST_THRESHOLD {
+        return Err(HtlcError::Other(format!(
+            "Sweeping would send {} satoshi after fees, below the dust threshold of {} satoshi",
+            amount, DUST_THRESHOLD
+        )));
+    }
+    Ok((selected, amount))
+}
+
+/// Checks that `fee_per_byte`, a fee rate in millisatoshi per vbyte supplied by the
+/// caller, is at least the minimum relay fee rate.
+fn validate_fee_rate_override(fee_per_byte: u64) -> Result<(), String> {
+    if fee_per_byte < MIN_RELAY_FEE_PER_BYTE {
+        return Err(format!(
+            "Fee rate {} msat/vB is below the minimum relay fee rate of {} msat/vB; the \
+             transaction would never confirm",
+            fee_per_byte, MIN_RELAY_FEE_PER_BYTE
+        ));
+    }
+    Ok(())
+}
+
+/// Derives the keys of the order's fresh funding addresses, in derivation order,
+/// followed by those of its sub-accounts, in creation order. Funding transactions spend
+/// deposits to all of them.
+async fn extra_funding_keys(
+    ctx: &BitcoinContext,
+    order_no: u64,
+    order: &HtlcDetail,
+) -> Result<Vec<FundingKey>, String> {
+    let mut keys = Vec::new();
+    for index in 0..order.funding_addresses.as_ref().map_or(0, Vec::len) {
+        keys.push(fresh_funding_key(ctx, order_no, index as u32).await?);
+    }
+    for index in 0..order.sub_accounts.len() {
+        keys.push(sub_account_key(ctx, order_no, index as u32 + 1).await?);
+    }
+    Ok(keys)
+}
+
+/// UTXOs a funding transaction of an order may spend, see `order_funding_utxos`.
+struct FundingUtxos {
+    /// Spendable UTXOs of the main funding address, followed by those of the fresh
+    /// funding addresses and sub-accounts.
+    utxos: Vec<Utxo>,
+    /// Every UTXO reported for these addresses, spendable or not.
+    reported: Vec<Utxo>,
+    /// Keys of the order's fresh funding addresses and sub-accounts, see
+    /// `extra_funding_keys`.
+    extra: Vec<FundingKey>,
+    /// Index into `extra` of the address holding each outpoint not at the main address.
+    owners: HashMap<(Vec<u8>, u32), usize>,
+    /// Height of the chain tip when the UTXOs were fetched.
+    tip_height: u32,
+}
+
+/// Collects the UTXOs `withdraw_from_order` may spend for `order`: the spendable ones of
+/// its main funding address `own_address`, its fresh funding addresses and its
+/// sub-accounts. The fee,
+/// weight and PSBT previews use the same set, so they describe the transaction a
+/// withdrawal would build.
+async fn order_funding_utxos(
+    ctx: &BitcoinContext,
+    order_no: u64,
+    order: &HtlcDetail,
+    own_address: &Address,
+) -> Result<FundingUtxos, String> {
+    let response = get_utxos_response(ctx, &own_address.to_string(), None).await?;
+    prune_pending_change(order_no, &response.utxos, response.tip_height);
+    let pending_change =
+        PENDING_CHANGE.with_borrow(|pending| pending.get(&order_no).cloned().unwrap_or_default());
+    let mut own_utxos = spendable_utxos(
+        response.utxos.clone(),
+        response.tip_height,
+        &pending_change,
+        config::current().allow_spend_unconfirmed_change,
+        config::current().min_deposit_confirmations,
+    );
+    let mut reported = response.utxos;
+
+    // Deposits to the fresh funding addresses and sub-accounts are spent alongside the
+    // main ones. Change only ever goes to the main funding address, so there is no
+    // pending change here.
+    let extra = extra_funding_keys(ctx, order_no, order).await?;
+    let mut owners = HashMap::new();
+    for (position, funding_key) in extra.iter().enumerate() {
+        let utxos = get_utxos(ctx, &funding_key.address.to_string(), None).await?;
+        for utxo in spendable_utxos(
+            utxos.clone(),
+            response.tip_height,
+            &[],
+            false,
+            config::current().min_deposit_confirmations,
+        ) {
+            owners.insert((utxo.outpoint.txid.clone(), utxo.outpoint.vout), position);
+            own_utxos.push(utxo);
+        }
+        reported.extend(utxos);
+    }
+    Ok(FundingUtxos {
+        utxos: own_utxos,
+        reported,
+        extra,
+        owners,
+        tip_height: response.tip_height,
+    })
+}
+
+/// Builds and signs a transaction moving `amount` from the order's funding address into
+/// the order's HTLC.
+///
+/// The fee rate is `fee_per_byte_override` if given, see `validate_fee_rate_override`,
+/// and the current network estimate otherwise. Unless disabled in the config, amounts too
+/// small to be claimed economically at that fee rate are rejected, see `ensure_claimable`.
+/// `coin_selection` picks the UTXOs of a `FundingAmount::WithChange` transaction.
+///
+/// Amounts above the configured large withdrawal threshold must be confirmed with
+/// `confirmation_token`, see `confirm_large_withdrawal`. The check uses the amount of the
+/// built transaction, so every funding endpoint is covered, sweeps included, and it runs
+/// once the transaction was built, so a token is not used up by a withdrawal that could
+/// not be built anyway.
+#[allow(clippy::too_many_arguments)]
+async fn sign_withdraw(
+    ctx: &BitcoinContext,
+    order_no: u64,
+    order: &HtlcDetail,
+    responder_pubkey: String,
+    amount: FundingAmount,
+    fee_per_byte_override: Option<u64>,
+    coin_selection: CoinSelection,
+    confirmation_token: Option<String>,
+) -> Result<SignedWithdraw, HtlcError> {
+    if let Some(fee_per_byte) = fee_per_byte_override {
+        validate_fee_rate_override(fee_per_byte)?;
+    }
+    if let FundingAmount::WithChange(amount) | FundingAmount::WithoutChange(amount) = amount {
+        validate::positive(amount, "Amount")?;
+        ensure_above_p2wsh_dust(amount)?;
+    }
+
+    let witness_script = generate_p2wsh_htlc_script(
+        &order.initiator_pubkey,
+        &responder_pubkey,
+        &order.secret_hash,
+        order.time_lock,
+        order.timelock_type(),
+        order.hash_type(),
+    )?;
+    let htlc_address = order
+        .address_format()
+        .address(&witness_script, ctx.bitcoin_network);
+
+    let (own_public_key, own_address) = order_funding_key(ctx, order_no).await?;
+
+    let FundingUtxos {
+        utxos: own_utxos,
+        reported,
+        extra,
+        owners,
+        tip_height,
+    } = order_funding_utxos(ctx, order_no, order, &own_address).await?;
+    ensure_funding_available(&own_utxos, &reported)?;
+
+    let fee_per_byte = match fee_per_byte_override {
+        Some(fee_per_byte) => fee_per_byte,
+        None => get_fee_per_byte(ctx).await,
+    };
+    let (sweep_utxos, amount_in_satoshi) = match amount {
+        FundingAmount::WithChange(amount) | FundingAmount::WithoutChange(amount) => (None, amount),
+        FundingAmount::Sweep => {
+            let (utxos, amount) =
+                sweep_selection(&own_utxos, tip_height, &htlc_address, fee_per_byte)?;
+            (Some(utxos), amount)
+        }
+    };
+    check_inflight_cap(
+        total_inflight_value(),
+        amount_in_satoshi,
+        config::current().max_total_inflight_value,
+    )?;
+    if config::current().enforce_claimable_amount {
+        let claim_fee = fee_for_vsize(
+            worst_case_spend_vsize(&witness_script, order.address_format()),
+            fee_per_byte,
+        );
+        ensure_claimable(amount_in_satoshi, claim_fee)?;
+    }
+
+    let (transaction, mut prevouts) = if let Some(utxos_to_spend) = sweep_utxos {
+        // Spending everything beyond the amount as fee leaves no change output.
+        let total_in: u64 = utxos_to_spend.iter().map(|utxo| utxo.value).sum();
+        build_transaction_with_fee(
+            utxos_to_spend,
+            &own_address,
+            &PrimaryOutput::Address(htlc_address.clone(), amount_in_satoshi),
+            total_in - amount_in_satoshi,
+        )?
+    } else if let FundingAmount::WithoutChange(_) = amount {
+        let utxos_to_spend = select_utxos_without_change(
+            &own_utxos,
+            amount_in_satoshi,
+            fee_per_byte,
+            output_vbytes(&htlc_address),
+        )
+        .ok_or_else(|| {
+            format!(
+                "No selection of the order's UTXOs funds {} satoshi without change",
+                amount_in_satoshi
+            )
+        })?;
+        // Spending everything beyond the amount as fee leaves no change output.
+        let total_in: u64 = utxos_to_spend.iter().map(|utxo| utxo.value).sum();
+        build_transaction_with_fee(
+            utxos_to_spend,
+            &own_address,
+            &PrimaryOutput::Address(htlc_address.clone(), amount_in_satoshi),
+            total_in - amount_in_satoshi,
+        )?
+    } else {
+        p2wpkh::build_transaction(
+            ctx,
+            &own_public_key,
+            &own_address,
+            &own_utxos,
+            &htlc_address,
+            amount_in_satoshi,
+            fee_per_byte,
+            coin_selection,
+        )
+        .await?
+    };
+    confirm_large_withdrawal(
+        order_no,
+        amount_in_satoshi,
+        msg_caller(),
+        confirmation_token,
+        config::current().large_withdrawal_threshold,
+        ic_cdk::api::time(),
+    )?;
+
+    let main_key = p2wpkh::InputKey {
+        public_key: own_public_key,
+        derivation_path: order_derivation_path(order_no),
+    };
+    let input_keys = funding_input_keys(&transaction, &mut prevouts, &main_key, &extra, &owners);
+    let signed_transaction = p2wpkh::sign_transaction_with_keys(
+        ctx,
+        transaction,
+        &prevouts,
+        &input_keys,
+        sign_with_ecdsa,
+    )
+    .await;
+
+    Ok(SignedWithdraw {
+        fee_detail: fee_detail(&signed_transaction, &prevouts, fee_per_byte),
+        transaction: signed_transaction,
+        htlc_address,
+        own_address,
+        responder_pubkey,
+    })
+}
+
+/// Charges the caller the estimated cycles of signing every input of `transaction`,
+/// plus broadcasting it if `broadcast` is set.
+fn charge_spend(ctx: &BitcoinContext, transaction: &Transaction, broadcast: bool) {
+    let cycles = usage::estimate_spend_cycles(
+        ctx,
+        transaction,
+        transaction.input.len(),
+        usage::ecdsa_signature_cost(ctx),
+        broadcast,
+    );
+    usage::record_cycles(msg_caller(), cycles);
+}
+
+/// Returns the total value the canister paid to `address` in `transaction`.
+fn value_paid_to(transaction: &Transaction, address: &Address) -> u64 {
+    let script_pubkey = address.script_pubkey();
+    transaction
+        .output
+        .iter()
+        .filter(|output| output.script_pubkey == script_pubkey)
+        .map(|output| output.value.to_sat())
+        .sum()
+}
+
+/// Returns the total in-flight value across all orders.
+fn total_inflight_value() -> u64 {
+    STORAGE.with_borrow(|storage| {
+        storage
+            .orders
+            .values()
+            .map(|order| order.inflight_value)
+            .sum()
+    })
+}
+
+/// Checks that locking another `amount` in an HTLC keeps the total in-flight value
+/// within `cap`, if one is set.
+fn check_inflight_cap(total: u64, amount: u64, cap: Option<u64>) -> Result<(), String> {
+    match cap {
+        Some(cap) if total.saturating_add(amount) > cap => {
+            Err("in-flight value cap exceeded".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks the in-flight cap and adds `amount` to the order's in-flight value.
+///
+/// This is done right before broadcasting, so that concurrent withdrawals of other
+/// orders see the reserved amount; `release_inflight` undoes it if broadcasting fails.
+fn reserve_inflight(order_no: u64, amount: u64) -> Result<(), String> {
+    check_inflight_cap(
+        total_inflight_value(),
+        amount,
+        config::current().max_total_inflight_value,
+    )?;
+    STORAGE.with_borrow_mut(|storage| {
+        storage.update_order(order_no, |order| {
+            order.inflight_value += amount;
+        });
+    });
+    Ok(())
+}
+
+/// Removes `amount` from the order's in-flight value.
+fn release_inflight(order_no: u64, amount: u64) {
+    STORAGE.with_borrow_mut(|storage| {
+        storage.update_order(order_no, |order| {
+            order.inflight_value = order.inflight_value.saturating_sub(amount);
+        });
+    });
+}
+
+/// Returns the total value currently locked in HTLCs by the canister and not yet
+/// claimed or refunded, in satoshi.
+#[query]
+pub fn get_inflight_value() -> u64 {
+    total_inflight_value()
+}
+
+/// Broadcasts a funding transaction, reserving the value it locks in the HTLC against
+/// the in-flight cap.
+async fn broadcast_withdraw(
+    ctx: &BitcoinContext,
+    order_no: u64,
+    withdraw: &SignedWithdraw,
+) -> Result<String, HtlcError> {
+    let amount = value_paid_to(&withdraw.transaction, &withdraw.htlc_address);
+    reserve_inflight(order_no, amount)?;
+    send_transaction(ctx, &withdraw.transaction)
+        .await
+        .inspect_err(|_| release_inflight(order_no, amount))
+}
+
+/// Records a broadcast funding transaction on the order.
+fn record_withdraw(ctx: &BitcoinContext, order_no: u64, withdraw: SignedWithdraw, now_ns: u64) {
+    record_pending_change(order_no, &withdraw.transaction, &withdraw.own_address);
+
+    STORAGE.with_borrow_mut(|storage| {
+        storage.update_order(order_no, |order| {
+            order.htlc_address = Some(withdraw.htlc_address.to_string());
+            order.htlc_address_network = Some(ctx.network);
+            order.responder_pubkey = Some(withdraw.responder_pubkey);
+            order.funding_transaction = Some(serialize(&withdraw.transaction));
+        });
+    });
+    record_event(
+        order_no,
+        OrderEventKind::FundingBroadcast,
+        Some(withdraw.fee_detail.txid.clone()),
+        now_ns,
+    );
+    record_broadcast(order_no, "funding", withdraw.fee_detail, now_ns);
+}
+
+/// Returns the fee in satoshi a transaction of `vsize` vbytes pays at `fee_per_byte`,
+/// checking that this is enough to replace a transaction paying `old_fee`.
+///
+/// BIP-125 requires the replacement to pay at least the original fee plus the minimum
+/// relay fee for its own size.
+fn replacement_fee(vsize: u64, old_fee: u64, fee_per_byte: u64) -> Result<u64, String> {
+    let fee = fee_for_vsize(vsize, fee_per_byte);
+    let min_fee = old_fee + fee_for_vsize(vsize, MIN_RELAY_FEE_PER_BYTE);
+    if fee < min_fee {
+        return Err(format!(
+            "Fee rate {} msat/vB pays {} satoshi, but replacing a transaction paying {} \
+             satoshi requires at least {} satoshi",
+            fee_per_byte, fee, old_fee, min_fee
+        ));
+    }
+    Ok(fee)
+}
+
+/// Returns an unsigned copy of `transaction` paying `new_fee` instead of `old_fee`.
+///
+/// The inputs and the HTLC output stay the same; the fee increase is taken from the
+/// change output to `own_address`, which must remain above `DUST_THRESHOLD`.
+fn bump_funding_fee(
+    transaction: &Transaction,
+    own_address: &Address,
+    old_fee: u64,
+    new_fee: u64,
+) -> Result<Transaction, String> {
+    let increase = new_fee.saturating_sub(old_fee);
+    let own_script = own_address.script_pubkey();
+    let mut replacement = transaction.clone();
+    let change = replacement
+        .output
+        .iter_mut()
+        .find(|output| output.script_pubkey == own_script)
+        .ok_or("The funding transaction has no change output to pay a higher fee from")?;
+    let new_change = change
+        .value
+        .to_sat()
+        .checked_sub(increase)
+        .filter(|&value| value >= DUST_THRESHOLD)
+        .ok_or_else(|| {
+            format!(
+                "The change of {} satoshi cannot cover a fee increase of {} satoshi",
+                change.value.to_sat(),
+                increase
+            )
+        })?;
+    change.value = Amount::from_sat(new_change);
+    for input in &mut replacement.input {
+        input.witness = Witness::new();
+    }
+    Ok(replacement)
+}
+
+/// Rebroadcasts the order's unconfirmed funding transaction at `new_fee_per_byte`
+/// millisatoshi per vbyte and returns the txid of the replacement.
+///
+/// Funding transactions signal replaceability (BIP-125), so one stuck in the mempool
+/// can be replaced by a copy spending the same inputs to the same HTLC output at a
+/// higher fee, paid from its change. Fails if the transaction has no change to pay
+/// from, or if its inputs are no longer reported unspent, i.e. it was confirmed.
+/// Only the order's owner can call this.
+#[update]
+pub async fn replace_order_funding(
+    order_no: u64,
+    new_fee_per_byte: u64,
+) -> Result<String, HtlcError> {
+    let order = get_order(order_no)?;
+    check_owner(&order, msg_caller(), "move its funds")?;
+    validate_fee_rate_override(new_fee_per_byte)?;
+    let _guard = OrderGuard::acquire(order_no)?;
+    let original: Transaction = order
+        .funding_transaction
+        .as_deref()
+        .map(deserialize)
+        .transpose()
+        .map_err(|e| format!("Invalid recorded funding transaction: {}", e))?
+        .ok_or_else(|| format!("No funding transaction recorded for order {}", order_no))?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+
+    // The inputs of an unconfirmed transaction are still reported as unspent.
+    let (own_public_key, own_address) = order_funding_key(&ctx, order_no).await?;
+    let main_key = p2wpkh::InputKey {
+        public_key: own_public_key,
+        derivation_path: order_derivation_path(order_no),
+    };
+    let mut coins = HashMap::new();
+    let mut add_coins = |utxos: Vec<Utxo>, address: &Address, key: &p2wpkh::InputKey| {
+        for utxo in utxos {
+            let prevout = TxOut {
+                value: Amount::from_sat(utxo.value),
+                script_pubkey: address.script_pubkey(),
+            };
+            coins.insert(
+                (utxo.outpoint.txid, utxo.outpoint.vout),
+                (prevout, key.clone()),
+            );
+        }
+    };
+    add_coins(
+        get_utxos(&ctx, &own_address.to_string(), None).await?,
+        &own_address,
+        &main_key,
+    );
+    for funding_key in extra_funding_keys(&ctx, order_no, &order).await? {
+        let address = funding_key.address.to_string();
+        add_coins(
+            get_utxos(&ctx, &address, None).await?,
+            &funding_key.address,
+            &funding_key.key,
+        );
+    }
+    let (prevouts, input_keys): (Vec<TxOut>, Vec<p2wpkh::InputKey>) = original
+        .input
+        .iter()
+        .map(|input| {
+            let outpoint = &input.previous_output;
+            coins
+                .remove(&(outpoint.txid.to_byte_array().to_vec(), outpoint.vout))
+                .ok_or_else(|| {
+                    "The funding transaction's inputs are spent; it was confirmed or replaced"
+                        .to_string()
+                })
+        })
+        .collect::<Result<Vec<_>, String>>()?
+        .into_iter()
+        .unzip();
+
+    let total_in: u64 = prevouts.iter().map(|prevout| prevout.value.to_sat()).sum();
+    let total_out: u64 = original
+        .output
+        .iter()
+        .map(|output| output.value.to_sat())
+        .sum();
+    let old_fee = total_in.saturating_sub(total_out);
+    let new_fee = replacement_fee(original.vsize() as u64, old_fee, new_fee_per_byte)?;
+    let replacement = bump_funding_fee(&original, &own_address, old_fee, new_fee)?;
+
+    charge_spend(&ctx, &replacement, true);
+    let signed_transaction = p2wpkh::sign_transaction_with_keys(
+        &ctx,
+        replacement,
+        &prevouts,
+        &input_keys,
+        sign_with_ecdsa,
+    )
+    .await;
+    let txid = send_transaction(&ctx, &signed_transaction).await?;
+
+    let original_txid = original.compute_txid().as_byte_array().to_vec();
+    PENDING_CHANGE.with_borrow_mut(|pending| {
+        if let Some(change) = pending.get_mut(&order_no) {
+            change.retain(|change| change.outpoint.txid != original_txid);
+        }
+    });
+    record_pending_change(order_no, &signed_transaction, &own_address);
+    let fee_detail = fee_detail(&signed_transaction, &prevouts, new_fee_per_byte);
+    STORAGE.with_borrow_mut(|storage| {
+        storage.update_order(order_no, |order| {
+            order.funding_transaction = Some(serialize(&signed_transaction));
+        });
+    });
+    record_event(
+        order_no,
+        OrderEventKind::FundingBroadcast,
+        Some(txid.clone()),
+        ic_cdk::api::time(),
+    );
+    record_broadcast(order_no, "funding", fee_detail, ic_cdk::api::time());
+
+    Ok(txid)
+}
+
+/// How long a large withdrawal confirmation token stays valid, in nanoseconds.
+const CONFIRMATION_TOKEN_TTL_NS: u64 = 5 * 60 * 1_000_000_000;
+
+/// A large withdrawal waiting to be confirmed with its token.
+struct PendingConfirmation {
+    order_no: u64,
+    amount: u64,
+    caller: Principal,
+    expires_at_ns: u64,
+}
+
+// Outstanding large withdrawal confirmations keyed by token, plus a counter making every
+// token unique. Note: this is not persisted across upgrades, which invalidates all tokens.
+thread_local! {
+    static PENDING_CONFIRMATIONS: RefCell<HashMap<String, PendingConfirmation>> =
+        RefCell::new(HashMap::new());
+    static CONFIRMATION_COUNTER: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Lets a withdrawal of `amount` from `order_no` by `caller` proceed if it is at most
+/// `threshold` or `token` confirms it.
+///
+/// Without a valid token, a withdrawal above the threshold is rejected with
+/// `HtlcError::ConfirmationRequired` carrying a new token that confirms exactly this
+/// order, amount and caller until `CONFIRMATION_TOKEN_TTL_NS` after `now_ns`. Tokens are
+/// single-use: a token is consumed by the call presenting it, whether or not it is valid.
+/// `sign_withdraw` calls this once the transaction is built, with its final amount.
+/// They guard against mistakes, not against a malicious caller.
+fn confirm_large_withdrawal(
+    order_no: u64,
+    amount: u64,
+    caller: Principal,
+    token: Option<String>,
+    threshold: Option<u64>,
+    now_ns: u64,
+) -> Result<(), HtlcError> {
+    if threshold.is_none_or(|threshold| amount <= threshold) {
+        return Ok(());
+    }
+
+    if let Some(token) = token {
+        let pending = PENDING_CONFIRMATIONS.with_borrow_mut(|pending| pending.remove(&token));
+        return match pending {
+            Some(pending)
+                if pending.order_no == order_no
+                    && pending.amount == amount
+                    && pending.caller == caller
+                    && now_ns < pending.expires_at_ns =>
+            {
+                Ok(())
+            }
+            _ => Err(HtlcError::Other(
+                "Invalid or expired confirmation token; call again without a token for a new one"
+                    .to_string(),
+            )),
+        };
+    }
+
+    let counter = CONFIRMATION_COUNTER.replace(CONFIRMATION_COUNTER.get() + 1);
+    let mut preimage = counter.to_be_bytes().to_vec();
+    preimage.extend_from_slice(&now_ns.to_be_bytes());
+    preimage.extend_from_slice(&order_no.to_be_bytes());
+    preimage.extend_from_slice(&amount.to_be_bytes());
+    preimage.extend_from_slice(caller.as_slice());
+    let token = hex::encode(sha256::Hash::hash(&preimage).as_byte_array());
+
+    PENDING_CONFIRMATIONS.with_borrow_mut(|pending| {
+        pending.retain(|_, pending| now_ns < pending.expires_at_ns);
+        pending.insert(
+            token.clone(),
+            PendingConfirmation {
+                order_no,
+                amount,
+                caller,
+                expires_at_ns: now_ns.saturating_add(CONFIRMATION_TOKEN_TTL_NS),
+            },
+        );
+    });
+    Err(HtlcError::ConfirmationRequired { token })
+}
+
+/// Moves `amount_in_satoshi` from the order's funding address into the order's HTLC.
+///
+/// The HTLC address is derived from the order's initiator key, secret hash and timelock
+/// together with the responder key, which defaults to the order's stored responder when
+/// `None` is given. Returns a receipt with the txid of the funding transaction, the HTLC
+/// address, the amount locked in it and the fee paid. Amounts below
+/// `DUST_THRESHOLD_P2WSH` are rejected, and change below `DUST_THRESHOLD` is left to the
+/// fee rather than creating a dust output.
+///
+/// Only one funds-moving operation may run per order at a time; concurrent calls for
+/// the same order are rejected until the first one completes. While another responder
+/// holds a reservation on the order, see `reserve_order`, the call is rejected.
+///
+/// Only the principal that created the order can call this, see `get_order_owner`.
+///
+/// Amounts above the configured large withdrawal threshold need two calls: the first
+/// fails with `HtlcError::ConfirmationRequired`, and repeating the call with the returned
+/// token as `confirmation_token` within a few minutes performs the withdrawal, see
+/// `confirm_large_withdrawal`.
+///
+/// The fee rate is estimated from recent network activity unless `fee_per_byte_override`
+/// gives one in millisatoshi per vbyte, e.g. to bid higher for a faster confirmation.
+/// Overrides below `MIN_RELAY_FEE_PER_BYTE` are rejected.
+///
+/// `coin_selection` picks which of the order's UTXOs fund the transaction, largest first
+/// by default; the receipt reports how many were spent.
+#[update]
+pub async fn withdraw_from_order(
+    order_no: u64,
+    responder_pubkey: Option<String>,
+    amount_in_satoshi: u64,
+    confirmation_token: Option<String>,
+    fee_per_byte_override: Option<u64>,
+    coin_selection: Option<CoinSelection>,
+) -> Result<WithdrawResult, HtlcError> {
+    let order = get_order(order_no)?;
+    check_owner(&order, msg_caller(), "move its funds")?;
+    let responder_pubkey = resolve_responder(responder_pubkey, &order)?;
+    check_reservation(&order, &responder_pubkey, ic_cdk::api::time())?;
+    let _guard = OrderGuard::acquire(order_no)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+
+    let withdraw = sign_withdraw(
+        &ctx,
+        order_no,
+        &order,
+        responder_pubkey,
+        FundingAmount::WithChange(amount_in_satoshi),
+        fee_per_byte_override,
+        coin_selection.unwrap_or_default(),
+        confirmation_token,
+    )
+    .await?;
+    charge_spend(&ctx, &withdraw.transaction, true);
+    broadcast_withdraw(&ctx, order_no, &withdraw).await?;
+    let receipt = withdraw.receipt();
+    record_withdraw(&ctx, order_no, withdraw, ic_cdk::api::time());
+
+    Ok(receipt)
+}
+
+/// Moves exactly `amount_in_satoshi` into the order's HTLC without a change output.
+///
+/// Like `withdraw_from_order`, but the funding UTXOs are chosen so that what is left
+/// after the amount and the fee is below dust, and that leftover is added to the fee.
+/// The effective fee rate can therefore exceed the current rate. Fails if no selection
+/// of the order's UTXOs achieves that. Returns the fee details of the transaction,
+/// including its txid and the fee actually paid. Large amounts need a
+/// `confirmation_token` as for `withdraw_from_order`.
+#[update]
+pub async fn fund_exact(
+    order_no: u64,
+    responder_pubkey: Option<String>,
+    amount_in_satoshi: u64,
+    confirmation_token: Option<String>,
+) -> Result<FeeDetail, HtlcError> {
+    let order = get_order(order_no)?;
+    check_owner(&order, msg_caller(), "move its funds")?;
+    let responder_pubkey = resolve_responder(responder_pubkey, &order)?;
+    check_reservation(&order, &responder_pubkey, ic_cdk::api::time())?;
+    let _guard = OrderGuard::acquire(order_no)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+
+    let withdraw = sign_withdraw(
+        &ctx,
+        order_no,
+        &order,
+        responder_pubkey,
+        FundingAmount::WithoutChange(amount_in_satoshi),
+        None,
+        CoinSelection::default(),
+        confirmation_token,
+    )
+    .await?;
+    charge_spend(&ctx, &withdraw.transaction, true);
+    broadcast_withdraw(&ctx, order_no, &withdraw).await?;
+    let fee_detail = withdraw.fee_detail.clone();
+    record_withdraw(&ctx, order_no, withdraw, ic_cdk::api::time());
+
+    Ok(fee_detail)
+}
+
+/// Moves all confirmed funds of the order's funding address into the order's HTLC,
+/// minus the fee, without a change output.
+///
+/// Like `withdraw_from_order`, but the amount is the total value of the confirmed
+/// UTXOs less the fee at the current rate, so nothing is left behind. Unconfirmed
+/// deposits are not swept. Fails if the amount would be below the dust threshold.
+/// Returns the fee details of the transaction, including its txid.
+///
+/// A swept amount above the large withdrawal threshold needs a `confirmation_token` as
+/// for `withdraw_from_order`; the token only confirms the same amount, so it fails if
+/// the balance or the fee rate changed in between.
+#[update]
+pub async fn withdraw_all_from_order(
+    order_no: u64,
+    responder_pubkey: Option<String>,
+    confirmation_token: Option<String>,
+) -> Result<FeeDetail, HtlcError> {
+    let order = get_order(order_no)?;
+    check_owner(&order, msg_caller(), "move its funds")?;
+    let responder_pubkey = resolve_responder(responder_pubkey, &order)?;
+    check_reservation(&order, &responder_pubkey, ic_cdk::api::time())?;
+    let _guard = OrderGuard::acquire(order_no)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+
+    let withdraw = sign_withdraw(
+        &ctx,
+        order_no,
+        &order,
+        responder_pubkey,
+        FundingAmount::Sweep,
+        None,
+        CoinSelection::default(),
+        confirmation_token,
+    )
+    .await?;
+    charge_spend(&ctx, &withdraw.transaction, true);
+    broadcast_withdraw(&ctx, order_no, &withdraw).await?;
+    let fee_detail = withdraw.fee_detail.clone();
+    record_withdraw(&ctx, order_no, withdraw, ic_cdk::api::time());
+
+    Ok(fee_detail)
+}
+
+/// Builds and signs a funding transaction like `withdraw_from_order`, but stores it on
+/// the order instead of broadcasting it. Returns its txid.
+///
+/// This separates the expensive signing from the timing-sensitive broadcast, which is
+/// done later with `broadcast_prepared`. A previously prepared transaction is replaced.
+/// The prepared transaction becomes invalid if its inputs are spent in the meantime,
+/// e.g. by another withdrawal. Large amounts need a `confirmation_token` as for
+/// `withdraw_from_order`; the prepared transaction is then already confirmed.
+#[update]
+pub async fn prepare_withdraw(
+    order_no: u64,
+    responder_pubkey: Option<String>,
+    amount_in_satoshi: u64,
+    confirmation_token: Option<String>,
+) -> Result<String, String> {
+    let order = get_order(order_no)?;
+    check_owner(&order, msg_caller(), "move its funds")?;
+    let responder_pubkey = resolve_responder(responder_pubkey, &order)?;
+    check_reservation(&order, &responder_pubkey, ic_cdk::api::time())?;
+    let _guard = OrderGuard::acquire(order_no)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+
+    let withdraw = sign_withdraw(
+        &ctx,
+        order_no,
+        &order,
+        responder_pubkey,
+        FundingAmount::WithChange(amount_in_satoshi),
+        None,
+        CoinSelection::default(),
+        confirmation_token,
+    )
+    .await?;
+    charge_spend(&ctx, &withdraw.transaction, false);
+    let txid = withdraw.fee_detail.txid.clone();
+    let prepared = PreparedWithdraw {
+        transaction: serialize(&withdraw.transaction),
+        responder_pubkey: withdraw.responder_pubkey,
+        fee_detail: withdraw.fee_detail,
+    };
+
+    STORAGE.with_borrow_mut(|storage| {
+        storage.update_order(order_no, |order| {
+            order.prepared_withdraw = Some(prepared);
+        });
+    });
+
+    Ok(txid)
+}
+
+/// Broadcasts the funding transaction stored by `prepare_withdraw` and returns its txid.
+///
+/// On success the transaction is recorded like a regular withdrawal and removed from the
+/// order; if broadcasting fails it stays stored so it can be retried. Only the order's
+/// owner can call this.
+#[update]
+pub async fn broadcast_prepared(order_no: u64) -> Result<String, HtlcError> {
+    let order = get_owned_order(order_no, msg_caller(), "move its funds")?;
+    let prepared = order
+        .prepared_withdraw
+        .clone()
+        .ok_or_else(|| format!("Order {} has no prepared transaction", order_no))?;
+    let _guard = OrderGuard::acquire(order_no)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+
+    let transaction: Transaction = deserialize(&prepared.transaction)
+        .map_err(|e| format!("Invalid prepared transaction: {}", e))?;
+    let htlc_address = generate_p2wsh_htlc_address(
+        &order.initiator_pubkey,
+        &prepared.responder_pubkey,
+        &order.secret_hash,
+        order.time_lock,
+        order.timelock_type(),
+        order.hash_type(),
+        order.address_format(),
+        ctx.bitcoin_network,
+    )?;
+    let (_, own_address) = order_funding_key(&ctx, order_no).await?;
+
+    usage::record_cycles(
+        msg_caller(),
+        usage::estimate_broadcast_cycles(&ctx, &transaction),
+    );
+    let withdraw = SignedWithdraw {
+        transaction,
+        htlc_address,
+        own_address,
+        responder_pubkey: prepared.responder_pubkey,
+        fee_detail: prepared.fee_detail,
+    };
+    let txid = broadcast_withdraw(&ctx, order_no, &withdraw).await?;
+    STORAGE.with_borrow_mut(|storage| {
+        storage.update_order(order_no, |order| {
+            order.prepared_withdraw = None;
+        });
+    });
+    record_withdraw(&ctx, order_no, withdraw, ic_cdk::api::time());
+
+    Ok(txid)
+}
+
+/// Discards the funding transaction stored by `prepare_withdraw` without broadcasting it.
+///
+/// The signed transaction is removed from the order, so it can no longer be broadcast
+/// with `broadcast_prepared`; a new one can be prepared, e.g. at a better fee rate. Note
+/// that anyone who obtained the signed bytes could still broadcast them until its inputs
+/// are spent. Fails if the order has no prepared transaction or a funds-moving operation
+/// on it is in progress. Only the order's owner can call this.
+#[update]
+pub fn discard_prepared(order_no: u64) -> Result<(), String> {
+    remove_prepared(order_no, msg_caller())
+}
+
+/// Removes the order's prepared transaction on behalf of `caller`, see
+/// `discard_prepared`.
+fn remove_prepared(order_no: u64, caller: Principal) -> Result<(), String> {
+    let _guard = OrderGuard::acquire(order_no)?;
+    STORAGE.with_borrow_mut(|storage| {
+        let mut order = storage
+            .orders
+            .get(&order_no)
+            .ok_or_else(|| format!("Order {} not found", order_no))?;
+        check_owner(&order, caller, "discard its prepared transaction")?;
+        if order.prepared_withdraw.take().is_none() {
+            return Err(format!("Order {} has no prepared transaction", order_no));
+        }
+        storage.orders.insert(order_no, order);
+        Ok(())
+    })
+}
+
+/// Returns the BIP-141 weight of the transaction `withdraw_from_order` would broadcast.
+///
+/// The transaction is built from the same UTXOs and fee rate as a withdrawal and signed
+/// with a mock signer, which produces signatures of maximum size, so the result is an
+/// upper bound for the actual weight. Nothing is broadcast.
+///
+/// `coin_selection` picks the UTXOs as for `withdraw_from_order`, largest first by default.
+#[update]
+pub async fn get_withdraw_weight(
+    order_no: u64,
+    responder_pubkey: Option<String>,
+    amount: u64,
+    coin_selection: Option<CoinSelection>,
+) -> Result<u64, String> {
+    validate::positive(amount, "Amount")?;
+
+    let order = get_order(order_no)?;
+    let responder_pubkey = resolve_responder(responder_pubkey, &order)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+
+    let htlc_address = generate_p2wsh_htlc_address(
+        &order.initiator_pubkey,
+        &responder_pubkey,
+        &order.secret_hash,
+        order.time_lock,
+        order.timelock_type(),
+        order.hash_type(),
+        order.address_format(),
+        ctx.bitcoin_network,
+    )?;
+
+    let (own_public_key, own_address) = order_funding_key(&ctx, order_no).await?;
+
+    let own_utxos = order_funding_utxos(&ctx, order_no, &order, &own_address)
+        .await?
+        .utxos;
+
+    let fee_per_byte = get_fee_per_byte(&ctx).await;
+    let (transaction, prevouts) = p2wpkh::build_transaction(
+        &ctx,
+        &own_public_key,
+        &own_address,
+        &own_utxos,
+        &htlc_address,
+        amount,
+        fee_per_byte,
+        coin_selection.unwrap_or_default(),
+    )
+    .await?;
+
+    let signed_transaction = p2wpkh::sign_transaction(
+        &ctx,
+        &own_public_key,
+        &own_address,
+        transaction,
+        &prevouts,
+        vec![], // mock derivation path
+        mock_sign_with_ecdsa,
+    )
+    .await;
+
+    Ok(tx_weight(&signed_transaction))
+}
+
+/// Returns the value of the change output the transaction `withdraw_from_order` would
+/// broadcast for moving `amount` into the order's HTLC.
+///
+/// The transaction is built from the same UTXOs and fee rate as a withdrawal, so the
+/// result is total inputs minus `amount` minus the fee. Returns 0 when that leftover is
+/// below the dust threshold and goes to the fee instead of a change output. Nothing is
+/// signed or broadcast, and the result changes with the order's UTXOs and the fee rate.
+///
+/// `coin_selection` picks the UTXOs as for `withdraw_from_order`, largest first by default.
+#[update]
+pub async fn change_amount(
+    order_no: u64,
+    responder_pubkey: Option<String>,
+    amount: u64,
+    coin_selection: Option<CoinSelection>,
+) -> Result<u64, String> {
+    validate::positive(amount, "Amount")?;
+
+    let order = get_order(order_no)?;
+    let responder_pubkey = resolve_responder(responder_pubkey, &order)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+
+    let htlc_address = generate_p2wsh_htlc_address(
+        &order.initiator_pubkey,
+        &responder_pubkey,
+        &order.secret_hash,
+        order.time_lock,
+        order.timelock_type(),
+        order.hash_type(),
+        order.address_format(),
+        ctx.bitcoin_network,
+    )?;
+    let (own_public_key, own_address) = order_funding_key(&ctx, order_no).await?;
+
+    let own_utxos = order_funding_utxos(&ctx, order_no, &order, &own_address)
+        .await?
+        .utxos;
+
+    let fee_per_byte = get_fee_per_byte(&ctx).await;
+    let (transaction, _) = p2wpkh::build_transaction(
+        &ctx,
+        &own_public_key,
+        &own_address,
+        &own_utxos,
+        &htlc_address,
+        amount,
+        fee_per_byte,
+        coin_selection.unwrap_or_default(),
+    )
+    .await?;
+
+    Ok(value_paid_to(&transaction, &own_address))
+}
+
+/// Computes the fee details of a signed transaction spending `prevouts`.
+fn fee_detail(transaction: &Transaction, prevouts: &[TxOut], fee_rate: u64) -> FeeDetail {
+    let total_in: u64 = prevouts.iter().map(|prevout| prevout.value.to_sat()).sum();
+    let total_out: u64 = transaction
+        .output
+        .iter()
+        .map(|output| output.value.to_sat())
+        .sum();
+
+    FeeDetail {
+        txid: transaction.compute_txid().to_string(),
+        fee: total_in.saturating_sub(total_out),
+        fee_rate,
+        vsize: transaction.vsize() as u64,
+    }
+}
+
+/// A transaction the canister broadcast, as listed by `get_broadcast_history`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct BroadcastRecord {
+    pub txid: String,
+    /// Order the transaction was broadcast for.
+    pub order_no: u64,
+    /// What the transaction did: "funding" for moving funds into an HTLC, "redeem" for
+    /// claiming an HTLC and "refund" for refunding one.
+    pub kind: String,
+    /// Time of the broadcast, in nanoseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Fee paid in satoshi.
+    pub fee: u64,
+}
+
+impl Storable for BroadcastRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.clone().into_bytes())
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        candid::encode_one(self).expect("Failed to encode broadcast record")
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode broadcast record")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Every transaction the canister broadcast, oldest first. The log lives in stable
+// memory, so it survives upgrades, and is never truncated.
+thread_local! {
+    static BROADCAST_HISTORY: RefCell<StableLog<BroadcastRecord, Memory, Memory>> =
+        RefCell::new(StableLog::init(
+            stable_memory::memory(BROADCAST_LOG_INDEX_MEMORY_ID),
+            stable_memory::memory(BROADCAST_LOG_DATA_MEMORY_ID),
+        ));
+}
+
+/// Records a transaction broadcast for an order at `now_ns`: its fee details go to the
+/// order and an entry of `kind` to the broadcast history.
+fn record_broadcast(order_no: u64, kind: &str, fee_detail: FeeDetail, now_ns: u64) {
+    let record = BroadcastRecord {
+        txid: fee_detail.txid.clone(),
+        order_no,
+        kind: kind.to_string(),
+        timestamp: now_ns,
+        fee: fee_detail.fee,
+    };
+    BROADCAST_HISTORY.with_borrow(|history| {
+        history
+            .append(&record)
+            .expect("Failed to append to the broadcast history")
+    });
+    STORAGE.with_borrow_mut(|storage| {
+        storage.update_order(order_no, |order| {
+            order.fee_details.push(fee_detail);
+        });
+    });
+}
+
+/// Maximum number of records `get_broadcast_history` returns per call.
+const MAX_BROADCAST_RECORDS_PER_PAGE: u64 = 1000;
+
+/// Lists up to `limit` transactions the canister broadcast, skipping the first `offset`,
+/// in the order they were broadcast.
+///
+/// Funding, redeem and refund transactions of all orders are included, even of orders
+/// that were removed since. `limit` is capped at `MAX_BROADCAST_RECORDS_PER_PAGE`.
+#[query]
+pub fn get_broadcast_history(limit: u64, offset: u64) -> Vec<BroadcastRecord> {
+    BROADCAST_HISTORY.with_borrow(|history| {
+        let end = offset
+            .saturating_add(limit.min(MAX_BROADCAST_RECORDS_PER_PAGE))
+            .min(history.len());
+        (offset..end)
+            .filter_map(|index| history.get(index))
+            .collect()
+    })
+}
+
+/// What happened to an order in an `OrderEvent`.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum OrderEventKind {
+    /// The order was created.
+    Created,
+    /// The order's funding address was handed out for the first time.
+    AddressGenerated,
+    /// The canister broadcast a transaction funding the HTLC.
+    FundingBroadcast,
+    /// A confirmed funding of the HTLC was observed.
+    Funded,
+    /// The HTLC was spent through the hashlock branch.
+    Claimed,
+    /// The HTLC was spent back to the initiator through the timelock branch.
+    Refunded,
+}
+
+impl From<OrderStatus> for OrderEventKind {
+    fn from(status: OrderStatus) -> Self {
+        match status {
+            OrderStatus::Created => OrderEventKind::Created,
+            OrderStatus::AddressGenerated => OrderEventKind::AddressGenerated,
+            OrderStatus::Funded => OrderEventKind::Funded,
+            OrderStatus::Claimed => OrderEventKind::Claimed,
+            OrderStatus::Refunded => OrderEventKind::Refunded,
+        }
+    }
+}
+
+/// A step in the lifecycle of an order, as listed by `get_order_events`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct OrderEvent {
+    pub order_no: u64,
+    pub kind: OrderEventKind,
+    /// Transaction behind the event, if any.
+    pub txid: Option<String>,
+    /// Time of the event, in nanoseconds since the Unix epoch.
+    pub timestamp_ns: u64,
+}
+
+/// Number of events `ORDER_EVENTS` keeps before dropping the oldest.
+const MAX_ORDER_EVENTS: usize = 10_000;
+
+// The latest order events across all orders, oldest first.
+// Note: this is not persisted across upgrades.
+thread_local! {
+    static ORDER_EVENTS: RefCell<VecDeque<OrderEvent>> =
+        const { RefCell::new(VecDeque::new()) };
+}
+
+/// Appends an event for the order, dropping the oldest event if the buffer is full.
+fn record_event(order_no: u64, kind: OrderEventKind, txid: Option<String>, now_ns: u64) {
+    ORDER_EVENTS.with_borrow_mut(|events| {
+        if events.len() == MAX_ORDER_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(OrderEvent {
+            order_no,
+            kind,
+            txid,
+            timestamp_ns: now_ns,
+        });
+    });
+}
+
+/// Returns the recorded events of an order, oldest first.
+///
+/// Only the latest `MAX_ORDER_EVENTS` events across all orders are kept, and none
+/// survive an upgrade, so older steps of long-lived orders may be missing.
+#[query]
+pub fn get_order_events(order_no: u64) -> Vec<OrderEvent> {
+    ORDER_EVENTS.with_borrow(|events| {
+        events
+            .iter()
+            .filter(|event| event.order_no == order_no)
+            .cloned()
+            .collect()
+    })
+}
+
+/// Returns the fee details of every transaction broadcast for an order, oldest first.
+///
+/// This is an audit trail of the fee decisions made for the order: the fee paid, the
+/// fee rate applied and the resulting transaction size. Unknown orders yield an empty list.
+#[query]
+pub fn get_transaction_fee_details(order_no: u64) -> Vec<FeeDetail> {
+    STORAGE.with_borrow(|storage| {
+        storage
+            .orders
+            .get(&order_no)
+            .map(|order| order.fee_details)
+            .unwrap_or_default()
+    })
+}
+
+/// Broadcasts a signed transaction and returns its txid.
+///
+/// Rejections are mapped to an `HtlcError` variant by their reason, see
+/// `HtlcError::from_broadcast_rejection`.
+async fn send_transaction(
+    ctx: &BitcoinContext,
+    transaction: &Transaction,
+) -> Result<String, HtlcError> {
+    bitcoin_send_transaction(&SendTransactionRequest {
+        network: ctx.network,
+        transaction: serialize(transaction),
+    })
+    .await
+    .map_err(|e| match e {
+        ic_cdk::call::Error::CallRejected(rejected) => {
+            HtlcError::from_broadcast_rejection(rejected.reject_message())
+        }
+        e => HtlcError::BroadcastRejected(e.to_string()),
+    })?;
+
+    Ok(transaction.compute_txid().to_string())
+}
+
+/// Checks that `preimage` hashes to the order's hex-encoded `secret_hash` under
+/// `hash_type`.
+fn check_preimage(
+    preimage: &[u8],
+    secret_hash: &str,
+    hash_type: HtlcHashType,
+) -> Result<(), String> {
+    if hex::encode(hash_type.hash(preimage)) == secret_hash.to_lowercase() {
+        Ok(())
+    } else {
+        Err("Preimage does not match the order's secret hash".to_string())
+    }
+}
+
+/// Returns whether the hex-encoded `preimage` unlocks the order's hashlock, i.e. hashes
+/// to its `secret_hash` under the order's hash function.
+///
+/// Lets a party check a revealed preimage without building a transaction. Malformed hex
+/// is an error, while a well-formed preimage that does not match yields `false`.
+#[query]
+pub fn verify_preimage(order_no: u64, preimage: String) -> Result<bool, String> {
+    let order = get_order(order_no)?;
+    let preimage = validate::hex_bytes(&preimage, "Preimage", None)?;
+    Ok(check_preimage(&preimage, &order.secret_hash, order.hash_type()).is_ok())
+}
+
+/// Checks that the canister controls `pubkey`, i.e. that it is the order's derived key.
+///
+/// HTLC spends are signed with the order's key, so a spend path can only be taken by
+/// the canister if the key the script requires for it is that key.
+fn ensure_order_key(pubkey: &str, order_key: &PublicKey, role: &str) -> Result<(), String> {
+    let pubkey =
+        PublicKey::from_str(pubkey).map_err(|e| format!("Invalid {} public key: {}", role, e))?;
+    if pubkey == *order_key {
+        Ok(())
+    } else {
+        Err(format!(
+            "The {} public key is not controlled by this canister for the order",
+            role
+        ))
+    }
+}
+
+/// Records the height of the earliest confirmed UTXO at the order's HTLC address.
+///
+/// The refund branch of each funding output unlocks `time_lock` blocks after it was
+/// confirmed, so the earliest one determines when the order first becomes refundable.
+fn record_funding_height(order_no: u64, htlc_utxos: &[Utxo]) {
+    let Some(height) = htlc_utxos
+        .iter()
+        .map(|utxo| utxo.height)
+        .filter(|height| *height > 0)
+        .min()
+    else {
+        return;
+    };
+
+    STORAGE.with_borrow_mut(|storage| {
+        storage.update_order(order_no, |order| {
+            order.funding_height = Some(order.funding_height.map_or(height, |h| h.min(height)));
+        });
+    });
+}
+
+/// Returns the number of blocks from `current_height` until the refund branch of `order`
+/// can be used: positive while the responder can still safely claim, zero at the
+/// boundary, and negative once the order is already refundable.
+///
+/// A relative timelock counts from the funding height, which must be known; an absolute
+/// timelock is the height itself.
+fn refund_deadline(order: &HtlcDetail, current_height: u32) -> Result<i64, String> {
+    let unlock_height = match order.timelock_type() {
+        HtlcTimelockType::Csv => {
+            let funding_height = order.funding_height.ok_or_else(|| {
+                "The order's funding has not been observed confirmed yet".to_string()
+            })?;
+            funding_height as i64 + order.time_lock as i64
+        }
+        HtlcTimelockType::Cltv => order.time_lock as i64,
+    };
+    Ok(unlock_height - current_height as i64)
+}
+
+/// Returns how many blocks remain at `current_height` until an order becomes refundable.
+///
+/// A positive number means the responder still has that many blocks to claim, zero
+/// means the refund becomes valid now, and a negative number means the order has been
+/// refundable for that many blocks. Orders with a relative timelock require the funding
+/// height, which is recorded whenever the canister looks up the HTLC's UTXOs.
+#[query]
+pub fn blocks_until_refundable(order_no: u64, current_height: u32) -> Result<i64, String> {
+    refund_deadline(&get_order(order_no)?, current_height)
+}
+
+/// Returns the address the remainder of a redeem goes to.
+///
+/// By default (`change_destination` is `None`) the remainder returns to `htlc_address`,
+/// so it stays locked under the same script and can be claimed or refunded later. A
+/// responder may instead name an address of its own, which must pay to `responder_key`
+/// as P2WPKH, P2SH-P2WPKH or P2PKH. Any other address is rejected, since the canister
+/// cannot tell whether the responder controls it, and a typo would send the remainder
+/// to someone else.
+fn redeem_change_address(
+    change_destination: Option<&str>,
+    htlc_address: &Address,
+    responder_key: &PublicKey,
+    network: bitcoin::Network,
+) -> Result<Address, String> {
+    let Some(change_destination) = change_destination else {
+        return Ok(htlc_address.clone());
+    };
+    let address = parse_address(change_destination, network)?;
+    let compressed_key = CompressedPublicKey::try_from(*responder_key)
+        .map_err(|e| format!("Invalid responder public key: {}", e))?;
+    let responder_addresses = [
+        Address::p2wpkh(&compressed_key, network),
+        Address::p2shwpkh(&compressed_key, network),
+        Address::p2pkh(*responder_key, network),
+    ];
+    if responder_addresses.contains(&address) {
+        Ok(address)
+    } else {
+        Err(format!(
+            "Change destination {} is not an address of the responder key",
+            change_destination
+        ))
+    }
+}
+
+/// Claims the order's HTLC with `preimage`, splitting the funds across `targets`.
+///
+/// Each target is a destination address and an amount in satoshi. The transaction
+/// spends every UTXO at the HTLC address through the hashlock branch; whatever is left
+/// after the targets and the fee goes to the change destination if above dust, or is
+/// added to the fee otherwise. See `redeem_change_address` for the change destination.
+///
+/// The canister signs with the order's derived key, so the responder key (`responder_pubkey`,
+/// or the order's stored responder when `None`) must be that key. Only the order's owner
+/// can call this, as with `claim_htlc`. On success the order becomes `Claimed`. Returns
+/// the txid of the redeem transaction.
+#[update]
+pub async fn redeem_to_multiple(
+    order_no: u64,
+    preimage: String,
+    responder_pubkey: Option<String>,
+    targets: Vec<(String, u64)>,
+    change_destination: Option<String>,
+) -> Result<String, HtlcError> {
+    if targets.is_empty() {
+        return Err(HtlcError::Other(
+            "At least one target is required".to_string(),
+        ));
+    }
+
+    let order = get_owned_order(order_no, msg_caller(), "claim it")?;
+    let responder_pubkey = resolve_responder(responder_pubkey, &order)?;
+    let _guard = OrderGuard::acquire(order_no)?;
+
+    let preimage = validate::hex_bytes(&preimage, "Preimage", None)?;
+    check_preimage(&preimage, &order.secret_hash, order.hash_type())?;
+
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let outputs = targets
+        .iter()
+        .map(|(address, amount)| {
+            if *amount < DUST_THRESHOLD {
+                return Err(format!(
+                    "Amount {} for {} is below the dust threshold of {} satoshi",
+                    amount, address, DUST_THRESHOLD
+                ));
+            }
+            Ok((parse_address(address, ctx.bitcoin_network)?, *amount))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let witness_script = generate_p2wsh_htlc_script(
+        &order.initiator_pubkey,
+        &responder_pubkey,
+        &order.secret_hash,
+        order.time_lock,
+        order.timelock_type(),
+        order.hash_type(),
+    )?;
+    let htlc_address = order
+        .address_format()
+        .address(&witness_script, ctx.bitcoin_network);
+
+    let (order_key, _) = order_funding_key(&ctx, order_no).await?;
+    ensure_order_key(&responder_pubkey, &order_key, "responder")?;
+    let change_address = redeem_change_address(
+        change_destination.as_deref(),
+        &htlc_address,
+        &order_key,
+        ctx.bitcoin_network,
+    )?;
+
+    let htlc_utxos = get_utxos(&ctx, &htlc_address.to_string(), None).await?;
+    record_funding_height(order_no, &htlc_utxos);
+    if htlc_utxos.is_empty() {
+        return Err(HtlcError::Other(
+            "No UTXOs available at the HTLC address".to_string(),
+        ));
+    }
+    let available: u64 = htlc_utxos.iter().map(|utxo| utxo.value).sum();
+    let requested: u64 = outputs.iter().map(|(_, amount)| amount).sum();
+    if requested > available {
+        return Err(HtlcError::Other(format!(
+            "Targets total {} satoshi but the HTLC only holds {}",
+            requested, available
+        )));
+    }
+
+    let fee_per_byte = get_fee_per_byte(&ctx).await;
+    let branch = HtlcBranch::Claim(preimage);
+    let (transaction, prevouts, _) = p2wsh::build_transaction(
+        &ctx,
+        &htlc_address,
+        &htlc_utxos,
+        &witness_script,
+        &branch,
+        &outputs,
+        &change_address,
+        Sequence::MAX,
+        LockTime::ZERO,
+        fee_per_byte,
+        0,
+    )
+    .await?;
+
+    let signed_transaction = p2wsh::sign_transaction(
+        &ctx,
+        transaction,
+        &prevouts,
+        &witness_script,
+        &branch,
+        order_derivation_path(order_no),
+        sign_with_ecdsa,
+    )
+    .await;
+
+    charge_spend(&ctx, &signed_transaction, true);
+    let txid = send_transaction(&ctx, &signed_transaction).await?;
+    // Only a remainder returned to the HTLC stays locked.
+    let remainder = value_paid_to(&signed_transaction, &htlc_address);
+    STORAGE.with_borrow_mut(|storage| {
+        storage.update_order(order_no, |order| {
+            order.status = OrderStatus::Claimed;
+            order.inflight_value = order.inflight_value.min(remainder);
+            order.spend = Some(SpendRecord {
+                txid: txid.clone(),
+                destination: outputs[0].0.to_string(),
+            });
+        });
+    });
+    record_event(
+        order_no,
+        OrderEventKind::Claimed,
+        Some(txid.clone()),
+        ic_cdk::api::time(),
+    );
+    record_broadcast(
+        order_no,
+        "redeem",
+        fee_detail(&signed_transaction, &prevouts, fee_per_byte),
+        ic_cdk::api::time(),
+    );
+
+    Ok(txid)
+}
+
+/// Returns the UTXOs whose refund branch can be spent in the next block at `tip_height`:
+/// for a relative timelock those with at least `time_lock` confirmations, for an
+/// absolute timelock all of them once the tip has reached height `time_lock`.
+///
+/// Fails if there are no UTXOs, or none of them has matured yet, in which case the
+/// network would reject the refund as non-final.
+fn refundable_utxos(
+    utxos: Vec<Utxo>,
+    tip_height: u32,
+    time_lock: u64,
+    timelock_type: HtlcTimelockType,
+) -> Result<Vec<Utxo>, String> {
+    if utxos.is_empty() {
+        return Err("No UTXOs available at the HTLC address".to_string());
+    }
+    if timelock_type == HtlcTimelockType::Cltv {
+        // A lock time below the height of the next block is final.
+        if (tip_height as u64) < time_lock {
+            return Err(format!(
+                "The absolute timelock expires at block height {} but the tip is at {}; \
+                 the network would reject the refund",
+                time_lock, tip_height
+            ));
+        }
+        return Ok(utxos);
+    }
+    let most_confirmations = utxos
+        .iter()
+        .map(|utxo| confirmations(utxo, tip_height))
+        .max()
+        .unwrap_or(0);
+    let matured: Vec<Utxo> = utxos
+        .into_iter()
+        .filter(|utxo| confirmations(utxo, tip_height) as u64 >= time_lock)
+        .collect();
+    if matured.is_empty() {
+        return Err(format!(
+            "The HTLC UTXOs have at most {} confirmations but the timelock requires {}; \
+             the network would reject the refund",
+            most_confirmations, time_lock
+        ));
+    }
+    Ok(matured)
+}
+
+/// Returns the sequence of the inputs and the lock time of a transaction spending the
+/// refund branch of an HTLC with the given timelock.
+///
+/// Relative timelocks go into the sequence. Absolute timelocks go into the lock time,
+/// which is only enforced if an input's sequence is not final.
+fn refund_locks(time_lock: u64, timelock_type: HtlcTimelockType) -> (Sequence, LockTime) {
+    match timelock_type {
+        // `validate_time_lock` bounds the timelock by `MAX_CSV_BLOCKS`, so it fits.
+        HtlcTimelockType::Csv => (Sequence::from_height(time_lock as u16), LockTime::ZERO),
+        // `validate_absolute_time_lock` keeps it below `LOCKTIME_THRESHOLD`.
+        HtlcTimelockType::Cltv => (
+            Sequence::ENABLE_LOCKTIME_NO_RBF,
+            LockTime::from_height(time_lock as u32).expect("time_lock should be a block height"),
+        ),
+    }
+}
+
+/// Spends every matured HTLC output of the order through the timelock branch to
+/// `destination` and records the refund on the order, which becomes `Refunded`.
+///
+/// The order's timelock goes into the inputs' sequence or the transaction's lock time,
+/// see `refund_locks`, and the inputs carry the witness `[signature, 0x, witness_script]`. The canister signs with the order's derived key,
+/// so the order's initiator key must be that key. Returns the txid of the refund
+/// transaction. The caller must hold the order's guard.
+async fn refund_order_htlc(
+    ctx: &BitcoinContext,
+    order_no: u64,
+    order: &HtlcDetail,
+    destination: &Address,
+) -> Result<String, HtlcError> {
+    let responder_pubkey = resolve_responder(None, order)?;
+    let witness_script = generate_p2wsh_htlc_script(
+        &order.initiator_pubkey,
+        &responder_pubkey,
+        &order.secret_hash,
+        order.time_lock,
+        order.timelock_type(),
+        order.hash_type(),
+    )?;
+    let htlc_address = order
+        .address_format()
+        .address(&witness_script, ctx.bitcoin_network);
+
+    let (order_key, own_address) = order_funding_key(ctx, order_no).await?;
+    ensure_order_key(&order.initiator_pubkey, &order_key, "initiator")?;
+
+    let response = get_utxos_response(ctx, &htlc_address.to_string(), None).await?;
+    record_funding_height(order_no, &response.utxos);
+    let htlc_utxos = refundable_utxos(
+        response.utxos,
+        response.tip_height,
+        order.time_lock,
+        order.timelock_type(),
+    )?;
+
+    let (sequence, lock_time) = refund_locks(order.time_lock, order.timelock_type());
+    let fee_per_byte = get_fee_per_byte(ctx).await;
+    let (transaction, prevouts, _) = p2wsh::build_transaction(
+        ctx,
+        &htlc_address,
+        &htlc_utxos,
+        &witness_script,
+        &HtlcBranch::Refund,
+        &[],
+        destination,
+        sequence,
+        lock_time,
+        fee_per_byte,
+        0,
+    )
+    .await?;
+    if transaction.output.is_empty() {
+        return Err(HtlcError::Other(
+            "The HTLC balance does not cover the refund fee".to_string(),
+        ));
+    }
+
+    let signed_transaction = p2wsh::sign_transaction(
+        ctx,
+        transaction,
+        &prevouts,
+        &witness_script,
+        &HtlcBranch::Refund,
+        order_derivation_path(order_no),
+        sign_with_ecdsa,
+    )
+    .await;
+
+    charge_spend(ctx, &signed_transaction, true);
+    let txid = send_transaction(ctx, &signed_transaction).await?;
+    // Only refunds to the funding address produce outputs the order can spend again.
+    record_pending_change(order_no, &signed_transaction, &own_address);
+    STORAGE.with_borrow_mut(|storage| {
+        storage.update_order(order_no, |order| {
+            order.status = OrderStatus::Refunded;
+            order.inflight_value = 0;
+            order.spend = Some(SpendRecord {
+                txid: txid.clone(),
+                destination: destination.to_string(),
+            });
+        });
+    });
+    record_event(
+        order_no,
+        OrderEventKind::Refunded,
+        Some(txid.clone()),
+        ic_cdk::api::time(),
+    );
+    record_broadcast(
+        order_no,
+        "refund",
+        fee_detail(&signed_transaction, &prevouts, fee_per_byte),
+        ic_cdk::api::time(),
+    );
+
+    Ok(txid)
+}
+
+/// Refunds the order's HTLC to `refund_destination` once its timelock has expired.
+///
+/// Every HTLC output with at least `time_lock` confirmations is spent through the
+/// timelock branch, see `refund_order_htlc`; if none has matured yet, the error states
+/// how many confirmations they have and how many the timelock requires. The order's
+/// initiator key must be the order's derived key. Returns the txid of the refund.
+/// Only the order's owner can call this.
+///
+/// `network_override`, if given, must be the canister's network, see
+/// `check_network_override`.
+#[update]
+pub async fn refund_htlc(
+    order_no: u64,
+    refund_destination: String,
+    network_override: Option<Network>,
+) -> Result<String, HtlcError> {
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    check_network_override(&ctx, network_override)?;
+    let order = get_owned_order(order_no, msg_caller(), "refund it")?;
+    let _guard = OrderGuard::acquire(order_no)?;
+    let destination = parse_address(&refund_destination, ctx.bitcoin_network)?;
+
+    refund_order_htlc(&ctx, order_no, &order, &destination).await
+}
+
+/// Refunds the order's HTLC to its funding address and re-creates the order with the
+/// longer `new_time_lock`, returning the new order number.
+///
+/// This is the only safe way to lengthen the timelock of a funded order. The refund
+/// spends every HTLC output whose timelock has passed through the timelock branch, see
+/// `refund_order_htlc`, and fails if the refund branch is still locked at
+/// `current_height`. Once the refund is broadcast, a new order is created with the same
+/// initiator, secret hash, responder and requested amount. No order is created if the
+/// refund fails.
+///
+/// The refunded coins arrive at the old order's funding address; the new order has its
+/// own funding address and is funded separately.
+///
+/// Only the order's owner can call this. `current_height` only serves the early check;
+/// the refund itself only spends outputs that have matured at the chain tip the Bitcoin
+/// canister reports.
+#[update]
+pub async fn extend_timelock_via_refund(
+    order_no: u64,
+    new_time_lock: u64,
+    current_height: u32,
+) -> Result<u64, HtlcError> {
+    let order = get_owned_order(order_no, msg_caller(), "refund it")?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    validate_order_time_lock(new_time_lock, order.timelock_type(), ctx.bitcoin_network)?;
+    if new_time_lock <= order.time_lock {
+        return Err(HtlcError::Other(format!(
+            "New timelock {} must be later than the current timelock of {}",
+            new_time_lock, order.time_lock
+        )));
+    }
+    let blocks_left = refund_deadline(&order, current_height)?;
+    if blocks_left > 0 {
+        return Err(HtlcError::Other(format!(
+            "The refund branch unlocks in {} blocks",
+            blocks_left
+        )));
+    }
+    let responder_pubkey = resolve_responder(None, &order)?;
+    let _guard = OrderGuard::acquire(order_no)?;
+
+    let (_, own_address) = order_funding_key(&ctx, order_no).await?;
+    refund_order_htlc(&ctx, order_no, &order, &own_address).await?;
+
+    Ok(insert_order(HtlcDetail {
+        initiator_pubkey: order.initiator_pubkey,
+        time_lock: new_time_lock,
+        secret_hash: order.secret_hash,
+        responder_pubkey: Some(responder_pubkey),
+        created_at_ns: ic_cdk::api::time(),
+        requested_amount: order.requested_amount,
+        timelock_type: order.timelock_type,
+        hash_type: order.hash_type,
+        owner: order.owner,
+        address_format: order.address_format,
+        ..Default::default()
+    }))
+}
+
+/// Returns the outputs of the order's recorded funding transaction that pay to
+/// `htlc_address` but are not among the `reported` UTXOs, i.e. are still unconfirmed,
+/// along with the fee in satoshi the funding transaction falls short of paying at
+/// `fee_per_byte`.
+///
+/// Fails if there is no such output, or if the funding transaction's fee was not
+/// recorded.
+fn unconfirmed_funding(
+    order: &HtlcDetail,
+    htlc_address: &Address,
+    reported: &[Utxo],
+    fee_per_byte: u64,
+) -> Result<(Vec<Utxo>, u64), String> {
+    let funding: Transaction = order
+        .funding_transaction
+        .as_deref()
+        .map(deserialize)
+        .transpose()
+        .map_err(|e| format!("Invalid recorded funding transaction: {}", e))?
+        .ok_or_else(|| "No unconfirmed funding UTXO found for the HTLC address".to_string())?;
+    let txid = funding.compute_txid();
+    let txid_bytes = txid.as_byte_array().to_vec();
+    let utxos: Vec<Utxo> = funding
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| output.script_pubkey == htlc_address.script_pubkey())
+        .map(|(vout, output)| Utxo {
+            outpoint: Outpoint {
+                txid: txid_bytes.clone(),
+                vout: vout as u32,
+            },
+            value: output.value.to_sat(),
+            height: 0,
+        })
+        .filter(|utxo| !reported.iter().any(|known| known.outpoint == utxo.outpoint))
+        .collect();
+    if utxos.is_empty() {
+        return Err("No unconfirmed funding UTXO found for the HTLC address".to_string());
+    }
+
+    let txid = txid.to_string();
+    let parent = order
+        .fee_details
+        .iter()
+        .rev()
+        .find(|detail| detail.txid == txid)
+        .ok_or_else(|| format!("No fee recorded for funding transaction {}", txid))?;
+    let deficit = fee_for_vsize(parent.vsize, fee_per_byte).saturating_sub(parent.fee);
+    Ok((utxos, deficit))
+}
+
+/// Claims the whole balance of the order's HTLC with `preimage`, sending it minus the
+/// fee to `responder_destination`.
+///
+/// Every UTXO at the HTLC address is spent through the hashlock branch with the witness
+/// `[signature, preimage, 0x01, witness_script]`. The preimage is checked against the
+/// order's secret hash before anything is signed. The canister signs with the order's
+/// derived key, so the order's stored responder must be that key. On success the order
+/// becomes `Claimed`. Returns the txid of the claim transaction.
+///
+/// Only the order's owner can call this: the preimage becomes public once the other leg
+/// of the swap settles, so it cannot be what authorizes the claim.
+///
+/// `network_override`, if given, must be the canister's network, see
+/// `check_network_override`.
+///
+/// With `include_unconfirmed`, the HTLC outputs of the order's latest funding transaction
+/// are spent as well while still unconfirmed, and the claim pays enough fee to bring that
+/// transaction up to the current fee rate too (child pays for parent). This only helps
+/// if the funding transaction is still in the mempool: a child of a transaction that was
+/// dropped or replaced is invalid. Fails if there is no unconfirmed funding UTXO.
+#[update]
+pub async fn claim_htlc(
+    order_no: u64,
+    preimage: String,
+    responder_destination: String,
+    network_override: Option<Network>,
+    include_unconfirmed: bool,
+) -> Result<String, HtlcError> {
+    check_network_override(&BTC_CONTEXT.with(|ctx| ctx.get()), network_override)?;
+    let order = get_owned_order(order_no, msg_caller(), "claim it")?;
+    let responder_pubkey = resolve_responder(None, &order)?;
+    let _guard = OrderGuard::acquire(order_no)?;
+
+    let preimage = validate::hex_bytes(&preimage, "Preimage", None)?;
+    check_preimage(&preimage, &order.secret_hash, order.hash_type())?;
+
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let destination = parse_address(&responder_destination, ctx.bitcoin_network)?;
+    let witness_script = generate_p2wsh_htlc_script(
+        &order.initiator_pubkey,
+        &responder_pubkey,
+        &order.secret_hash,
+        order.time_lock,
+        order.timelock_type(),
+        order.hash_type(),
+    )?;
+    let htlc_address = order
+        .address_format()
+        .address(&witness_script, ctx.bitcoin_network);
+
+    let (order_key, _) = order_funding_key(&ctx, order_no).await?;
+    ensure_order_key(&responder_pubkey, &order_key, "responder")?;
+
+    let mut htlc_utxos = get_utxos(&ctx, &htlc_address.to_string(), None).await?;
+    record_funding_height(order_no, &htlc_utxos);
+    let fee_per_byte = get_fee_per_byte(&ctx).await;
+    // The canister only sees UTXOs in blocks, so unconfirmed ones are taken from the
+    // funding transaction it recorded.
+    let mut parent_deficit = 0;
+    if include_unconfirmed {
+        let (unconfirmed, deficit) =
+            unconfirmed_funding(&order, &htlc_address, &htlc_utxos, fee_per_byte)?;
+        htlc_utxos.extend(unconfirmed);
+        parent_deficit = deficit;
+    }
+    if htlc_utxos.is_empty() {
+        return Err(HtlcError::Other(
+            "No UTXOs available at the HTLC address".to_string(),
+        ));
+    }
+
+    let branch = HtlcBranch::Claim(preimage);
+    let (transaction, prevouts, _) = p2wsh::build_transaction(
+        &ctx,
+        &htlc_address,
+        &htlc_utxos,
+        &witness_script,
+        &branch,
+        &[],
+        &destination,
+        Sequence::MAX,
+        LockTime::ZERO,
+        fee_per_byte,
+        parent_deficit,
+    )
+    .await?;
+    if transaction.output.is_empty() {
+        return Err(HtlcError::Other(
+            "The HTLC balance does not cover the claim fee".to_string(),
+        ));
+    }
+
+    let signed_transaction = p2wsh::sign_transaction(
+        &ctx,
+        transaction,
+        &prevouts,
+        &witness_script,
+        &branch,
+        order_derivation_path(order_no),
+        sign_with_ecdsa,
+    )
+    .await;
+
+    charge_spend(&ctx, &signed_transaction, true);
+    let txid = send_transaction(&ctx, &signed_transaction).await?;
+    STORAGE.with_borrow_mut(|storage| {
+        storage.update_order(order_no, |order| {
+            order.status = OrderStatus::Claimed;
+            order.inflight_value = 0;
+            order.spend = Some(SpendRecord {
+                txid: txid.clone(),
+                destination: destination.to_string(),
+            });
+        });
+    });
+    record_event(
+        order_no,
+        OrderEventKind::Claimed,
+        Some(txid.clone()),
+        ic_cdk::api::time(),
+    );
+    record_broadcast(
+        order_no,
+        "redeem",
+        fee_detail(&signed_transaction, &prevouts, fee_per_byte),
+        ic_cdk::api::time(),
+    );
+
+    Ok(txid)
+}
+
+/// Everything an external signer needs to sign and complete a claim of an order's HTLC.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct RedeemTemplate {
+    /// Hex-encoded consensus serialization of the transaction, without witness.
+    pub unsigned_tx_hex: String,
+    /// Hex-encoded BIP-143 message to sign with SIGHASH_ALL for the single input.
+    pub sighash: String,
+    /// Hex-encoded HTLC witness script.
+    pub witness_script_hex: String,
+    /// Value of the spent HTLC output in satoshi.
+    pub input_amount: u64,
+}
+
+/// Returns the confirmations at `tip_height` of the output of transaction `txid` among
+/// `utxos`, or 0 if there is none, i.e. the transaction is still in the mempool.
+fn spend_confirmations(utxos: &[Utxo], tip_height: u32, txid: &str) -> Result<u32, String> {
+    let txid =
+        bitcoin::Txid::from_str(txid).map_err(|e| format!("Invalid spend txid {}: {}", txid, e))?;
+    Ok(utxos
+        .iter()
+        .filter(|utxo| utxo.outpoint.txid == txid.as_byte_array().as_slice())
+        .map(|utxo| confirmations(utxo, tip_height))
+        .max()
+        .unwrap_or(0))
+}
+
+/// Returns how many confirmations the latest claim, redeem or refund transaction of the
+/// order has.
+///
+/// The confirmations are read from the transaction's output at its destination, so 0 is
+/// returned while the transaction is in the mempool, and also once that output has been
+/// spent. Fails if the canister has not broadcast a spend for the order.
+#[update]
+pub async fn get_spend_confirmations(order_no: u64) -> Result<u32, String> {
+    let spend = get_order(order_no)?
+        .spend
+        .ok_or_else(|| format!("No spend transaction recorded for order {}", order_no))?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let response = get_utxos_response(&ctx, &spend.destination, None).await?;
+    spend_confirmations(&response.utxos, response.tip_height, &spend.txid)
+}
+
+/// Returns the smallest number of confirmations at `tip_height` among the HTLC's
+/// `utxos`, or an error if the HTLC holds none.
+fn funding_confirmations(utxos: &[Utxo], tip_height: u32) -> Result<u32, String> {
+    utxos
+        .iter()
+        .map(|utxo| confirmations(utxo, tip_height))
+        .min()
+        .ok_or_else(|| "The HTLC address holds no UTXOs".to_string())
+}
+
+/// Returns how many confirmations the funding of the order's HTLC with
+/// `responder_pubkey` has.
+///
+/// If the HTLC was funded by several transactions, the least confirmed one counts, so a
+/// claimant can wait for this to reach its threshold before releasing the other leg of
+/// the swap. 0 is returned while a funding transaction is still in the mempool. Fails if
+/// the HTLC holds no UTXOs, i.e. it was not funded yet or has been spent.
+#[update]
+pub async fn get_order_confirmations(
+    order_no: u64,
+    responder_pubkey: String,
+) -> Result<u32, String> {
+    let order = get_order(order_no)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let htlc_address = generate_p2wsh_htlc_address(
+        &order.initiator_pubkey,
+        &responder_pubkey,
+        &order.secret_hash,
+        order.time_lock,
+        order.timelock_type(),
+        order.hash_type(),
+        order.address_format(),
+        ctx.bitcoin_network,
+    )?;
+
+    let response = get_utxos_response(&ctx, &htlc_address.to_string(), None).await?;
+    funding_confirmations(&response.utxos, response.tip_height)
+}
+
+/// Builds the template for a transaction spending a single HTLC output.
+fn redeem_template(
+    transaction: &Transaction,
+    prevouts: &[TxOut],
+    witness_script: &ScriptBuf,
+) -> Result<RedeemTemplate, String> {
+    let [prevout] = prevouts else {
+        return Err("A redeem template spends exactly one HTLC output".to_string());
+    };
+    let sighash = SighashCache::new(transaction)
+        .p2wsh_signature_hash(0, witness_script, prevout.value, EcdsaSighashType::All)
+        .map_err(|e| format!("Failed to compute the sighash: {}", e))?;
+
+    Ok(RedeemTemplate {
+        unsigned_tx_hex: hex::encode(serialize(transaction)),
+        sighash: hex::encode(sighash.as_byte_array()),
+        witness_script_hex: hex::encode(witness_script.as_bytes()),
+        input_amount: prevout.value.to_sat(),
+    })
+}
+
+/// Builds, but does not sign, a transaction claiming the order's HTLC to `destination`.
+///
+/// The transaction spends the largest UTXO at the HTLC address through the hashlock
+/// branch and sends its value minus the fee to `destination`. The fee is sized for the
+/// witness `[signature, preimage, 0x01, witness_script]`, which the caller assembles after
+/// signing `sighash` with the responder key. The canister does not sign anything, so
+/// `responder_pubkey` (or the order's stored responder when `None`) may be any key.
+#[update]
+pub async fn build_redeem_template(
+    order_no: u64,
+    preimage: String,
+    responder_pubkey: Option<String>,
+    destination: String,
+) -> Result<RedeemTemplate, String> {
+    let order = get_order(order_no)?;
+    let responder_pubkey = resolve_responder(responder_pubkey, &order)?;
+
+    let preimage = validate::hex_bytes(&preimage, "Preimage", None)?;
+    check_preimage(&preimage, &order.secret_hash, order.hash_type())?;
+
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let destination = parse_address(&destination, ctx.bitcoin_network)?;
+    let witness_script = generate_p2wsh_htlc_script(
+        &order.initiator_pubkey,
+        &responder_pubkey,
+        &order.secret_hash,
+        order.time_lock,
+        order.timelock_type(),
+        order.hash_type(),
+    )?;
+    let htlc_address = order
+        .address_format()
+        .address(&witness_script, ctx.bitcoin_network);
+
+    let htlc_utxos = get_utxos(&ctx, &htlc_address.to_string(), None).await?;
+    record_funding_height(order_no, &htlc_utxos);
+    let htlc_utxo = htlc_utxos
+        .into_iter()
+        .max_by_key(|utxo| utxo.value)
+        .ok_or_else(|| "No UTXOs available at the HTLC address".to_string())?;
+
+    // Without explicit outputs the whole value minus the fee goes to the remainder
+    // address; an error is returned if that would be dust.
+    let fee_per_byte = get_fee_per_byte(&ctx).await;
+    let (transaction, prevouts, _) = p2wsh::build_transaction(
+        &ctx,
+        &htlc_address,
+        &[htlc_utxo],
+        &witness_script,
+        &HtlcBranch::Claim(preimage),
+        &[],
+        &destination,
+        Sequence::MAX,
+        LockTime::ZERO,
+        fee_per_byte,
+        0,
+    )
+    .await?;
+    if transaction.output.is_empty() {
+        return Err("The HTLC output does not cover the claim fee".to_string());
+    }
+
+    redeem_template(&transaction, &prevouts, &witness_script)
+}
+
+/// Wraps an unsigned `transaction` into a PSBT whose inputs carry everything an external
+/// signer needs: the spent output (`witness_utxo`) and, for P2WSH spends, the
+/// `witness_script`.
+fn unsigned_psbt(
+    transaction: Transaction,
+    prevouts: Vec<TxOut>,
+    witness_script: Option<&ScriptBuf>,
+) -> Result<Psbt, String> {
+    let mut psbt =
+        Psbt::from_unsigned_tx(transaction).map_err(|e| format!("Failed to create PSBT: {}", e))?;
+    for (input, prevout) in psbt.inputs.iter_mut().zip(prevouts) {
+        input.witness_utxo = Some(prevout);
+        input.witness_script = witness_script.cloned();
+    }
+    Ok(psbt)
+}
+
+/// Returns a hex-encoded PSBT of the transaction `withdraw_from_order` would broadcast,
+/// moving `amount_in_satoshi` from the order's funding addresses into its HTLC.
+///
+/// Nothing is signed or broadcast. Each input's `witness_utxo` holds the script of the
+/// funding address it spends and the input amount, as required to compute P2WPKH
+/// sighashes.
+///
+/// `coin_selection` picks the UTXOs as for `withdraw_from_order`, largest first by default.
+#[update]
+pub async fn build_funding_psbt(
+    order_no: u64,
+    responder_pubkey: Option<String>,
+    amount_in_satoshi: u64,
+    coin_selection: Option<CoinSelection>,
+) -> Result<String, String> {
+    validate::positive(amount_in_satoshi, "Amount")?;
+
+    let order = get_order(order_no)?;
+    let responder_pubkey = resolve_responder(responder_pubkey, &order)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+
+    let htlc_address = generate_p2wsh_htlc_address(
+        &order.initiator_pubkey,
+        &responder_pubkey,
+        &order.secret_hash,
+        order.time_lock,
+        order.timelock_type(),
+        order.hash_type(),
+        order.address_format(),
+        ctx.bitcoin_network,
+    )?;
+    let (own_public_key, own_address) = order_funding_key(&ctx, order_no).await?;
+
+    let funding = order_funding_utxos(&ctx, order_no, &order, &own_address).await?;
+    let fee_per_byte = get_fee_per_byte(&ctx).await;
+    let (transaction, mut prevouts) = p2wpkh::build_transaction(
+        &ctx,
+        &own_public_key,
+        &own_address,
+        &funding.utxos,
+        &htlc_address,
+        amount_in_satoshi,
+        fee_per_byte,
+        coin_selection.unwrap_or_default(),
+    )
+    .await?;
+    // Only the prevouts matter here: inputs from fresh funding addresses spend their
+    // scripts, not the main one.
+    let main_key = p2wpkh::InputKey {
+        public_key: own_public_key,
+        derivation_path: order_derivation_path(order_no),
+    };
+    funding_input_keys(
+        &transaction,
+        &mut prevouts,
+        &main_key,
+        &funding.extra,
+        &funding.owners,
+    );
+
+    let psbt = unsigned_psbt(transaction, prevouts, None)?;
+    Ok(hex::encode(psbt.serialize()))
+}
+
+/// Returns a hex-encoded PSBT withdrawing all funds of the order's HTLC to `destination`
+/// through the hashlock branch.
+///
+/// Nothing is signed or broadcast. Each input carries its `witness_utxo` and the HTLC
+/// `witness_script`; the signer completes the witness with the signature, the preimage
+/// and the branch selector, which the fee already accounts for.
+#[update]
+pub async fn build_withdraw_psbt(
+    order_no: u64,
+    responder_pubkey: Option<String>,
+    destination: String,
+) -> Result<String, String> {
+    let order = get_order(order_no)?;
+    let responder_pubkey = resolve_responder(responder_pubkey, &order)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let destination = parse_address(&destination, ctx.bitcoin_network)?;
+
+    let witness_script = generate_p2wsh_htlc_script(
+        &order.initiator_pubkey,
+        &responder_pubkey,
+        &order.secret_hash,
+        order.time_lock,
+        order.timelock_type(),
+        order.hash_type(),
+    )?;
+    let htlc_address = order
+        .address_format()
+        .address(&witness_script, ctx.bitcoin_network);
+
+    let htlc_utxos = get_utxos(&ctx, &htlc_address.to_string(), None).await?;
+    record_funding_height(order_no, &htlc_utxos);
+    if htlc_utxos.is_empty() {
+        return Err("No UTXOs available at the HTLC address".to_string());
+    }
+
+    // A placeholder preimage of the right size is enough to size the witness.
+    let fee_per_byte = get_fee_per_byte(&ctx).await;
+    let (transaction, prevouts, _) = p2wsh::build_transaction(
+        &ctx,
+        &htlc_address,
+        &htlc_utxos,
+        &witness_script,
+        &HtlcBranch::Claim(vec![0; 32]),
+        &[],
+        &destination,
+        Sequence::MAX,
+        LockTime::ZERO,
+        fee_per_byte,
+        0,
+    )
+    .await?;
+    if transaction.output.is_empty() {
+        return Err("The HTLC balance does not cover the claim fee".to_string());
+    }
+
+    let psbt = unsigned_psbt(transaction, prevouts, Some(&witness_script))?;
+    Ok(hex::encode(psbt.serialize()))
+}
+
+/// Parses `address` and checks that it belongs to `network`.
+fn parse_address(address: &str, network: bitcoin::Network) -> Result<Address, String> {
+    Address::from_str(address)
+        .map_err(|e| format!("Invalid address {}: {}", address, e))?
+        .require_network(network)
+        .map_err(|e| format!("Invalid address {}: {}", address, e))
+}
+
+/// Estimates the fee for sending `amount_in_satoshi` from the order's funding addresses
+/// to `destination_address`, spending the same UTXOs as `withdraw_from_order`.
+///
+/// The estimate accounts for the destination's output type, since paying to P2TR or
+/// P2WSH produces a larger output than paying to P2WPKH.
+///
+/// `coin_selection` picks the UTXOs as for `withdraw_from_order`, largest first by default.
+#[update]
+pub async fn estimate_withdraw_fee(
+    order_no: u64,
+    destination_address: String,
+    amount_in_satoshi: u64,
+    coin_selection: Option<CoinSelection>,
+) -> Result<u64, String> {
+    let order = get_order(order_no)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let destination = parse_address(&destination_address, ctx.bitcoin_network)?;
+
+    let (_, own_address) = order_funding_key(&ctx, order_no).await?;
+    let own_utxos = order_funding_utxos(&ctx, order_no, &order, &own_address)
+        .await?
+        .utxos;
+    let fee_per_byte = get_fee_per_byte(&ctx).await;
+
+    estimate_p2wpkh_fee(
+        &own_utxos,
+        &own_address,
+        &destination,
+        amount_in_satoshi,
+        fee_per_byte,
+        coin_selection.unwrap_or_default(),
+    )
+}
+
+/// Returns the largest amount the order's funding addresses can send to
+/// `destination_address` after fees, taking the destination's output type into account.
+/// Only the UTXOs `withdraw_from_order` may spend count.
+#[update]
+pub async fn max_spendable(order_no: u64, destination_address: String) -> Result<u64, String> {
+    let order = get_order(order_no)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let destination = parse_address(&destination_address, ctx.bitcoin_network)?;
+
+    let (_, own_address) = order_funding_key(&ctx, order_no).await?;
+    let own_utxos = order_funding_utxos(&ctx, order_no, &order, &own_address)
+        .await?
+        .utxos;
+    let fee_per_byte = get_fee_per_byte(&ctx).await;
+
+    Ok(max_spendable_p2wpkh(&own_utxos, &destination, fee_per_byte))
+}
+
+/// Returns an error if `utxos` (the UTXOs of `address`) hold any funds.
+fn ensure_no_funds(address: &str, utxos: &[Utxo]) -> Result<(), String> {
+    if utxos.is_empty() {
+        return Ok(());
+    }
+
+    let total: u64 = utxos.iter().map(|utxo| utxo.value).sum();
+    Err(format!(
+        "Address {} still holds {} satoshi in {} UTXO(s); refusing to cancel",
+        address,
+        total,
+        utxos.len()
+    ))
+}
+
+/// Returns every address of `order` that may hold funds: its funding address
+/// `funding_address`, the fresh funding addresses and sub-accounts handed out for it, and
+/// its recorded HTLC address `htlc_address`, if any.
+fn order_addresses(
+    order: &HtlcDetail,
+    funding_address: String,
+    htlc_address: Option<&String>,
+) -> Vec<String> {
+    let mut addresses = vec![funding_address];
+    addresses.extend(order.funding_addresses.iter().flatten().cloned());
+    addresses.extend(
+        order
+            .sub_accounts
+            .iter()
+            .map(|(_, address)| address.clone()),
+    );
+    addresses.extend(htlc_address.cloned());
+    addresses
+}
+
+/// Cancels an order and removes it from storage.
+///
+/// Before removing anything, this performs a live UTXO lookup on every address of the
+/// order, see `order_addresses`. Cancellation is refused while any of them holds UTXOs,
+/// including unconfirmed ones, so an order can never be forgotten while it still
+/// controls funds on-chain. Like `withdraw_from_order`, it is rejected while another
+/// operation on the same order is in progress. Only the order's owner and the canister's
+/// controllers can call this.
+#[update]
+pub async fn cancel_order(order_no: u64) -> Result<(), String> {
+    let order = get_order(order_no)?;
+    let caller = msg_caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        check_owner(&order, caller, "cancel it")?;
+    }
+    let _guard = OrderGuard::acquire(order_no)?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+
+    let htlc_address = order.stored_htlc_address(ctx.network)?;
+    let (_, funding_address) = order_funding_key(&ctx, order_no).await?;
+
+    for address in order_addresses(&order, funding_address.to_string(), htlc_address) {
+        let utxos = get_utxos(&ctx, &address, None).await?;
+        ensure_no_funds(&address, &utxos)?;
+    }
+
+    STORAGE.with_borrow_mut(|storage| storage.remove_order(order_no));
+    Ok(())
+}
+
+/// Returns whether `order` was created more than `older_than_ns` before `now_ns` and
+/// has never been funded: nothing was sent to an HTLC for it and no funding was observed.
+fn is_stale_unfunded(order: &HtlcDetail, now_ns: u64, older_than_ns: u64) -> bool {
+    matches!(
+        order.status,
+        OrderStatus::Created | OrderStatus::AddressGenerated
+    ) && order.htlc_address.is_none()
+        && order.fee_details.is_empty()
+        && order.prepared_withdraw.is_none()
+        && now_ns.saturating_sub(order.created_at_ns) > older_than_ns
+}
+
+/// Returns the numbers of stale orders, see `is_stale_unfunded`, in ascending order.
+fn stale_orders(now_ns: u64, older_than_ns: u64) -> Vec<u64> {
+    STORAGE.with_borrow(|storage| {
+        storage
+            .orders
+            .iter()
+            .map(|entry| entry.into_pair())
+            .filter(|(_, order)| is_stale_unfunded(order, now_ns, older_than_ns))
+            .map(|(order_no, _)| order_no)
+            .collect()
+    })
+}
+
+/// Lists orders created more than `older_than_ns` nanoseconds ago that were never funded.
+///
+/// This only looks at the stored state; `prune_stale_orders` additionally checks the
+/// chain before removing anything.
+#[query]
+pub fn stale_unfunded_orders(older_than_ns: u64) -> Vec<u64> {
+    stale_orders(ic_cdk::api::time(), older_than_ns)
+}
+
+/// Selects the numbers of the orders of `orders` created more than `age_ns` nanoseconds
+/// before `now_ns`, in ascending order.
+fn orders_older_than<M: ic_stable_structures::Memory>(
+    orders: &StableBTreeMap<u64, HtlcDetail, M>,
+    now_ns: u64,
+    age_ns: u64,
+) -> Vec<u64> {
+    orders
+        .iter()
+        .map(|entry| entry.into_pair())
+        .filter(|(_, order)| now_ns.saturating_sub(order.created_at_ns) > age_ns)
+        .map(|(order_no, _)| order_no)
+        .collect()
+}
+
+/// Lists the orders created more than `age_seconds` seconds ago, whatever their status.
+///
+/// Unlike `stale_unfunded_orders`, this includes funded and settled orders, e.g. to
+/// find settled orders old enough to be cleaned up with `cancel_order`.
+#[query]
+pub fn get_orders_older_than(age_seconds: u64) -> Vec<u64> {
+    let age_ns = age_seconds.saturating_mul(1_000_000_000);
+    STORAGE.with_borrow(|storage| orders_older_than(&storage.orders, ic_cdk::api::time(), age_ns))
+}
+
+/// Removes the orders `stale_unfunded_orders` reports and returns how many were removed.
+///
+/// Each order is only removed after live UTXO lookups show that none of its addresses,
+/// see `order_addresses`, holds any UTXOs, unconfirmed ones included, so an order whose
+/// funding was missed by the stored state is never deleted. Orders with funds, orders with another
+/// operation in progress and orders whose lookup fails are kept. Only controllers can
+/// call this method.
+#[update(guard = "crate::config::caller_is_controller")]
+pub async fn prune_stale_orders(older_than_ns: u64) -> u64 {
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let mut pruned = 0;
+    for order_no in stale_orders(ic_cdk::api::time(), older_than_ns) {
+        let Ok(_guard) = OrderGuard::acquire(order_no) else {
+            continue;
+        };
+        let Ok(order) = get_order(order_no) else {
+            continue;
+        };
+        let Ok((_, funding_address)) = order_funding_key(&ctx, order_no).await else {
+            continue;
+        };
+        let mut unfunded = true;
+        for address in order_addresses(&order, funding_address.to_string(), None) {
+            match get_utxos(&ctx, &address, None).await {
+                Ok(utxos) if utxos.is_empty() => {}
+                _ => {
+                    unfunded = false;
+                    break;
+                }
+            }
+        }
+        if !unfunded {
+            continue;
+        }
+
+        // The guard kept other funds-moving calls out, but re-check the stored state
+        // in case the order changed while the lookups were pending.
+        let removed = STORAGE.with_borrow_mut(|storage| {
+            let still_stale = storage
+                .orders
+                .get(&order_no)
+                .is_some_and(|order| is_stale_unfunded(&order, ic_cdk::api::time(), older_than_ns));
+            still_stale && storage.remove_order(order_no).is_some()
+        });
+        if removed {
+            pruned += 1;
+        }
+    }
+    pruned
+}
+
+/// Maximum number of orders reconciled per `reconcile_all` call.
+///
+/// Each reconciliation makes a Bitcoin canister call, so the batch size is capped
+/// regardless of the requested limit to stay within the instruction budget.
+const MAX_RECONCILIATIONS_PER_CALL: u64 = 20;
+
+/// Returns the status an order should have given what the HTLC address holds.
+///
+/// `htlc_utxos` are the UTXOs currently at the HTLC address and `blocks_until_refundable`
+/// is the refund deadline at the reported tip, if the funding height is known. An order
+/// becomes `Funded` once the HTLC holds confirmed funds. A funded HTLC that is empty
+/// again was claimed if that happened before the refund branch unlocked, since the
+/// hashlock branch was the only way to spend it; afterwards the spend is ambiguous and
+/// the status is left unchanged.
+fn reconciled_status(
+    status: OrderStatus,
+    htlc_utxos: &[Utxo],
+    blocks_until_refundable: Option<i64>,
+) -> OrderStatus {
+    match status {
+        OrderStatus::Created | OrderStatus::AddressGenerated
+            if htlc_utxos.iter().any(|utxo| utxo.height > 0) =>
+        {
+            OrderStatus::Funded
+        }
+        OrderStatus::Funded
+            if htlc_utxos.is_empty()
+                && blocks_until_refundable.is_some_and(|blocks| blocks > 0) =>
+        {
+            OrderStatus::Claimed
+        }
+        status => status,
+    }
+}
+
+/// Brings the stored status of an order in line with its HTLC's on-chain state.
+async fn reconcile_order(ctx: &BitcoinContext, order_no: u64) -> Result<OrderStatus, String> {
+    let order = get_order(order_no)?;
+    let Some(htlc_address) = order.stored_htlc_address(ctx.network)? else {
+        return Ok(order.status);
+    };
+    let _guard = OrderGuard::acquire(order_no)?;
+
+    let response = get_utxos_response(ctx, htlc_address, None).await?;
+    record_funding_height(order_no, &response.utxos);
+    let order = get_order(order_no)?;
+    let status = reconciled_status(
+        order.status,
+        &response.utxos,
+        refund_deadline(&order, response.tip_height).ok(),
+    );
+
+    STORAGE.with_borrow_mut(|storage| {
+        storage.update_order(order_no, |order| {
+            order.status = status;
+        });
+    });
+    if status != order.status {
+        record_event(order_no, status.into(), None, ic_cdk::api::time());
+    }
+    Ok(status)
+}
+
+/// Returns the earliest confirmed UTXO among `htlc_utxos`, the one `record_funding_height`
+/// takes the funding height from.
+fn funding_utxo(htlc_utxos: &[Utxo]) -> Option<&Utxo> {
+    htlc_utxos
+        .iter()
+        .filter(|utxo| utxo.height > 0)
+        .min_by_key(|utxo| utxo.height)
+}
+
+/// Formats an outpoint reported by the Bitcoin canister as `txid:vout`, with the txid in
+/// the usual display byte order.
+fn outpoint_string(outpoint: &Outpoint) -> Result<String, String> {
+    let txid = bitcoin::Txid::from_slice(&outpoint.txid)
+        .map_err(|e| format!("Invalid txid in outpoint: {}", e))?;
+    Ok(format!("{}:{}", txid, outpoint.vout))
+}
+
+/// Checks the HTLC of an order for confirmed funds and advances it to `Funded` once
+/// they arrive, returning the resulting status.
+///
+/// The HTLC address is derived from the order's parameters and `responder_pubkey`; if
+/// the order already has an HTLC address it must match. When an order that is not funded
+/// yet has confirmed UTXOs at that address, its status becomes `Funded` and the funding
+/// outpoint and height are recorded, together with the HTLC address and responder if
+/// they were not known yet. Orders in any other state are returned unchanged, so a
+/// keeper can call this repeatedly for every order.
+#[update]
+pub async fn poll_and_update(
+    order_no: u64,
+    responder_pubkey: String,
+) -> Result<OrderStatus, String> {
+    let order = get_order(order_no)?;
+    let responder_pubkey = normalize_pubkey(&responder_pubkey, "responder")?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let htlc_address = generate_p2wsh_htlc_address(
+        &order.initiator_pubkey,
+        &responder_pubkey,
+        &order.secret_hash,
+        order.time_lock,
+        order.timelock_type(),
+        order.hash_type(),
+        order.address_format(),
+        ctx.bitcoin_network,
+    )?
+    .to_string();
+    if order
+        .stored_htlc_address(ctx.network)?
+        .is_some_and(|stored| *stored != htlc_address)
+    {
+        return Err(format!(
+            "HTLC address {} for this responder does not match the order's HTLC address",
+            htlc_address
+        ));
+    }
+    let _guard = OrderGuard::acquire(order_no)?;
+
+    let htlc_utxos = get_utxos(&ctx, &htlc_address, Some(1)).await?;
+    record_funding_height(order_no, &htlc_utxos);
+    let funding_outpoint = funding_utxo(&htlc_utxos)
+        .map(|utxo| outpoint_string(&utxo.outpoint))
+        .transpose()?;
+
+    STORAGE.with_borrow_mut(|storage| {
+        let mut order = storage
+            .orders
+            .get(&order_no)
+            .ok_or_else(|| format!("Order {} not found", order_no))?;
+        if let (OrderStatus::Created | OrderStatus::AddressGenerated, Some(outpoint)) =
+            (order.status, funding_outpoint)
+        {
+            order.status = OrderStatus::Funded;
+            let txid = outpoint.split_once(':').map(|(txid, _)| txid.to_string());
+            record_event(order_no, OrderEventKind::Funded, txid, ic_cdk::api::time());
+            order.funding_outpoint = Some(outpoint);
+            if order.htlc_address.is_none() {
+                order.htlc_address = Some(htlc_address);
+                order.htlc_address_network = Some(ctx.network);
+            }
+            order.responder_pubkey.get_or_insert(responder_pubkey);
+            storage.orders.insert(order_no, order.clone());
+        }
+        Ok(order.status)
+    })
+}
+
+/// Reconciles up to `limit` orders, starting at order number `cursor`.
+///
+/// Returns the status of every order visited after reconciliation, plus the cursor to
+/// continue from, or `None` once the end of the order book is reached. Orders that could
+/// not be reconciled, e.g. because another operation on them is in progress, keep and
+/// report their stored status. At most `MAX_RECONCILIATIONS_PER_CALL` orders are visited
+/// per call. Only controllers can call this method.
+#[update(guard = "crate::config::caller_is_controller")]
+pub async fn reconcile_all(limit: u64, cursor: u64) -> (Vec<(u64, OrderStatus)>, Option<u64>) {
+    let limit = limit.min(MAX_RECONCILIATIONS_PER_CALL) as usize;
+    let order_nos: Vec<u64> = STORAGE.with_borrow(|storage| {
+        storage
+            .orders
+            .range(cursor..)
+            .map(|entry| *entry.key())
+            .collect()
+    });
+
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let mut statuses = Vec::new();
+    for order_no in order_nos.iter().copied().take(limit) {
+        let status = match reconcile_order(&ctx, order_no).await {
+            Ok(status) => status,
+            Err(_) => match get_order(order_no) {
+                Ok(order) => order.status,
+                // Cancelled while the batch was running.
+                Err(_) => continue,
+            },
+        };
+        statuses.push((order_no, status));
+    }
+
+    let next_cursor = order_nos.get(limit).copied();
+    (statuses, next_cursor)
+}
+
+/// Checks that the order's stored HTLC address matches the one derived from its
+/// parameters now. Orders without a stored HTLC address trivially match.
+fn htlc_address_matches(order: &HtlcDetail, network: bitcoin::Network) -> bool {
+    let Some(stored) = &order.htlc_address else {
+        return true;
+    };
+    let Some(responder_pubkey) = &order.responder_pubkey else {
+        return false;
+    };
+    generate_p2wsh_htlc_address(
+        &order.initiator_pubkey,
+        responder_pubkey,
+        &order.secret_hash,
+        order.time_lock,
+        order.timelock_type(),
+        order.hash_type(),
+        order.address_format(),
+        network,
+    )
+    .is_ok_and(|derived| derived.to_string() == *stored)
+}
+
+/// Re-derives every address stored on each order and reports whether all of them still
+/// match, as `(order_no, matches)` sorted by order number.
+///
+/// This covers the HTLC address and the sub-account addresses. Address encoding can
+/// change subtly between versions of the `bitcoin` crate, so this should be run after
+/// dependency upgrades, and orders reported with `false` reviewed manually before
+/// resuming operations. Only controllers can call this method.
+#[update(guard = "crate::config::caller_is_controller")]
+pub async fn verify_all_addresses() -> Vec<(u64, bool)> {
+    let orders: Vec<(u64, HtlcDetail)> = STORAGE.with_borrow(|storage| {
+        storage
+            .orders
+            .iter()
+            .map(|entry| entry.into_pair())
+            .collect()
+    });
+
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let mut results = Vec::with_capacity(orders.len());
+    for (order_no, order) in orders {
+        let mut matches = htlc_address_matches(&order, ctx.bitcoin_network);
+        for (index, (_, stored)) in order.sub_accounts.iter().enumerate() {
+            if !matches {
+                break;
+            }
+            matches = sub_account_address(&ctx, order_no, index as u32 + 1)
+                .await
+                .is_ok_and(|derived| derived == *stored);
+        }
+        results.push((order_no, matches));
+    }
+
+    results
+}
+
+/// Returns the cached HTLC address if it differs from `current`, the address derived
+/// from the order's parameters now.
+fn stale_htlc_addresses(cached: Option<&str>, current: &str) -> Vec<String> {
+    cached
+        .filter(|cached| *cached != current)
+        .map(str::to_string)
+        .into_iter()
+        .collect()
+}
+
+/// Reports HTLC addresses of the order that no longer match its parameters but still
+/// hold funds.
+///
+/// The cached HTLC address is compared with the one derived from the order's current
+/// parameters and `responder_pubkey`. If they differ, e.g. because the responder was
+/// swapped after funding, funds at the cached address can only be spent with the old
+/// script. Returns the stale addresses holding UTXOs, confirmed or not; an empty list
+/// means all funds are at the expected address.
+#[update]
+pub async fn check_address_consistency(
+    order_no: u64,
+    responder_pubkey: String,
+) -> Result<Vec<String>, String> {
+    let order = get_order(order_no)?;
+    let responder_pubkey = normalize_pubkey(&responder_pubkey, "responder")?;
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let current = generate_p2wsh_htlc_address(
+        &order.initiator_pubkey,
+        &responder_pubkey,
+        &order.secret_hash,
+        order.time_lock,
+        order.timelock_type(),
+        order.hash_type(),
+        order.address_format(),
+        ctx.bitcoin_network,
+    )?;
+
+    let mut funded = Vec::new();
+    let stored = order.stored_htlc_address(ctx.network)?;
+    for address in stale_htlc_addresses(stored.map(String::as_str), &current.to_string()) {
+        if !get_utxos(&ctx, &address, None).await?.is_empty() {
+            funded.push(address);
+        }
+    }
+    Ok(funded)
+}
+
+/// Maximum number of terminal orders checked per `scan_orphaned_funds` call.
+///
+/// Checking an order derives its funding key and looks up two addresses.
+const MAX_ORPHAN_SCANS_PER_CALL: u64 = 10;
+
+/// Selects up to `limit` orders in a terminal state, in order-number order, starting at
+/// `cursor`. Returns them together with the cursor to continue from, or `None` if no
+/// orders are left after the batch.
+fn terminal_orders_batch(
+    orders: &[(u64, OrderStatus)],
+    cursor: u64,
+    limit: u64,
+) -> (Vec<u64>, Option<u64>) {
+    let terminal = orders
+        .iter()
+        .filter(|(_, status)| status.is_terminal())
+        .map(|(order_no, _)| *order_no)
+        .collect();
+    orders_batch(terminal, cursor, limit)
+}
+
+/// Selects up to `limit` of `order_nos`, in order-number order, starting at `cursor`.
+/// Returns them together with the cursor to continue from, or `None` if no orders are
+/// left after the batch.
+fn orders_batch(order_nos: Vec<u64>, cursor: u64, limit: u64) -> (Vec<u64>, Option<u64>) {
+    let mut candidates: Vec<u64> = order_nos
+        .into_iter()
+        .filter(|order_no| *order_no >= cursor)
+        .collect();
+    candidates.sort_unstable();
+
+    let limit = limit as usize;
+    let next_cursor = candidates.get(limit).copied();
+    candidates.truncate(limit);
+    (candidates, next_cursor)
+}
+
+/// Reports orders in a terminal state where any address from `order_addresses` still
+/// holds confirmed UTXOs, as `(order_no, balance)` with the combined balance in satoshi.
+///
+/// Up to `limit` terminal orders starting at order number `cursor` are checked, capped
+/// at `MAX_ORPHAN_SCANS_PER_CALL`; the returned cursor continues the scan, or is `None`
+/// once all orders were checked. Only controllers can call this method.
+#[update(guard = "crate::config::caller_is_controller")]
+pub async fn scan_orphaned_funds(limit: u64, cursor: u64) -> (Vec<(u64, u64)>, Option<u64>) {
+    let orders: Vec<(u64, OrderStatus)> = STORAGE.with_borrow(|storage| {
+        storage
+            .orders
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().status))
+            .collect()
+    });
+    let (batch, next_cursor) =
+        terminal_orders_batch(&orders, cursor, limit.min(MAX_ORPHAN_SCANS_PER_CALL));
+
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let mut orphaned = Vec::new();
+    for order_no in batch {
+        let Ok(order) = get_order(order_no) else {
+            continue;
+        };
+        let Ok((_, funding_address)) = order_funding_key(&ctx, order_no).await else {
+            continue;
+        };
+
+        // An HTLC address from another network cannot hold funds on this one.
+        let htlc_address = order.stored_htlc_address(ctx.network).ok().flatten();
+        let addresses = order_addresses(&order, funding_address.to_string(), htlc_address);
+        let mut balance = 0;
+        for address in addresses {
+            if let Ok(utxos) = get_utxos(&ctx, &address, Some(1)).await {
+                balance += utxos.iter().map(|utxo| utxo.value).sum::<u64>();
+            }
+        }
+
+        if balance > 0 {
+            orphaned.push((order_no, balance));
+        }
+    }
+
+    (orphaned, next_cursor)
+}
+
+/// Maximum number of balances fetched from the Bitcoin canister per `top_orders_by_balance` call.
+const MAX_FRESH_BALANCE_FETCHES: usize = 10;
+
+/// A balance fetched from the Bitcoin canister, along with when it was fetched.
+struct CachedBalance {
+    balance: u64,
+    fetched_at_ns: u64,
+}
+
+// In-memory cache of confirmed address balances. Note: this cache is not persistent across smart contract upgrades.
+thread_local! {
+    static BALANCE_CACHE: RefCell<HashMap<String, CachedBalance>> = RefCell::new(HashMap::new());
+}
+
+/// Fetches the balance of `address` with at least one confirmation.
+async fn get_confirmed_balance(ctx: &BitcoinContext, address: &str) -> Result<u64, String> {
+    get_address_balance(ctx, address, 1).await
+}
+
+/// Fetches the balance of `address` with at least `min_confirmations` confirmations.
+async fn get_address_balance(
+    ctx: &BitcoinContext,
+    address: &str,
+    min_confirmations: u32,
+) -> Result<u64, String> {
+    bitcoin_get_balance(&GetBalanceRequest {
+        address: address.to_string(),
+        network: ctx.network,
+        min_confirmations: Some(min_confirmations),
+    })
+    .await
+    .map_err(|e| format!("Failed to fetch balance for {}: {:?}", address, e))
+}
+
+/// Maximum number of addresses whose balance `get_balances` fetches per call.
+const MAX_BALANCE_QUERIES_PER_CALL: usize = 20;
+
+/// Checks which of `addresses` `get_balances` should query. Each entry is `Ok` if the
+/// address is valid on `network` and within the first `MAX_BALANCE_QUERIES_PER_CALL`,
+/// otherwise the error to report for it.
+fn balance_query_plan(addresses: &[String], network: bitcoin::Network) -> Vec<Result<(), String>> {
+    addresses
+        .iter()
+        .enumerate()
+        .map(|(index, address)| {
+            if index >= MAX_BALANCE_QUERIES_PER_CALL {
+                return Err(format!(
+                    "Not queried: at most {} addresses are queried per call",
+                    MAX_BALANCE_QUERIES_PER_CALL
+                ));
+            }
+            parse_address(address, network).map(|_| ())
+        })
+        .collect()
+}
+
+/// Returns the balance of each of `addresses`, counting only UTXOs with at least
+/// `min_confirmations` confirmations (default 1), as `(address, result)` in the order
+/// given.
+///
+/// Each address is handled independently: an address that is invalid for the
+/// canister's network, or whose balance cannot be fetched, gets an error while the
+/// others still get their balance. Only the first `MAX_BALANCE_QUERIES_PER_CALL`
+/// addresses are queried; any beyond that are reported as not queried.
+#[update]
+pub async fn get_balances(
+    addresses: Vec<String>,
+    min_confirmations: Option<u32>,
+) -> Vec<(String, Result<u64, String>)> {
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let min_confirmations = min_confirmations.unwrap_or(1);
+    let plan = balance_query_plan(&addresses, ctx.bitcoin_network);
+
+    join_all(addresses.into_iter().zip(plan).map(|(address, planned)| {
+        let ctx = &ctx;
+        async move {
+            let result = match planned {
+                Ok(()) => get_address_balance(ctx, &address, min_confirmations).await,
+                Err(e) => Err(e),
+            };
+            (address, result)
+        }
+    }))
+    .await
+}
+
+/// Picks up to `max` of `addresses` to refresh: never-fetched ones first, then the stalest.
+fn addresses_to_refresh(
+    addresses: &[String],
+    cache: &HashMap<String, CachedBalance>,
+    max: usize,
+) -> Vec<String> {
+    let mut candidates: Vec<(Option<u64>, &String)> = addresses
+        .iter()
+        .map(|address| (cache.get(address).map(|c| c.fetched_at_ns), address))
+        .collect();
+    candidates.sort();
+    candidates
+        .into_iter()
+        .take(max)
+        .map(|(_, address)| address.clone())
+        .collect()
+}
+
+/// Sorts `(order_no, balance)` pairs by balance descending (ties by order number) and
+/// keeps the first `limit`.
+fn rank_by_balance(mut balances: Vec<(u64, u64)>, limit: u64) -> Vec<(u64, u64)> {
+    balances.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    balances.truncate(limit as usize);
+    balances
+}
+
+/// Returns up to `limit` funded orders with the largest confirmed HTLC balance, as
+/// `(order_no, balance)` pairs sorted by balance descending.
+///
+/// Balances are cached in memory. Each call fetches at most `MAX_FRESH_BALANCE_FETCHES`
+/// balances from the Bitcoin canister, preferring orders that were never fetched and
+/// then the stalest entries; all other orders use their cached value. Results can
+/// therefore be stale, and orders whose balance was never fetched yet are left out
+/// until a later call gets to them.
+#[update]
+pub async fn top_orders_by_balance(limit: u64) -> Vec<(u64, u64)> {
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let funded: Vec<(u64, String)> = STORAGE.with_borrow(|storage| {
+        storage
+            .orders
+            .iter()
+            .filter_map(|entry| {
+                let order = entry.value();
+                let htlc_address = order.stored_htlc_address(ctx.network).ok()??;
+                Some((*entry.key(), htlc_address.clone()))
+            })
+            .collect()
+    });
+
+    let addresses: Vec<String> = funded.iter().map(|(_, address)| address.clone()).collect();
+    let to_refresh = BALANCE_CACHE
+        .with_borrow(|cache| addresses_to_refresh(&addresses, cache, MAX_FRESH_BALANCE_FETCHES));
+    for address in to_refresh {
+        // On failure, keep the previous value (if any) rather than failing the whole call.
+        if let Ok(balance) = get_confirmed_balance(&ctx, &address).await {
+            BALANCE_CACHE.with_borrow_mut(|cache| {
+                cache.insert(
+                    address,
+                    CachedBalance {
+                        balance,
+                        fetched_at_ns: ic_cdk::api::time(),
+                    },
+                )
+            });
+        }
+    }
+
+    let balances = BALANCE_CACHE.with_borrow(|cache| {
+        funded
+            .into_iter()
+            .filter_map(|(order_no, address)| Some((order_no, cache.get(&address)?.balance)))
+            .collect()
+    });
+    rank_by_balance(balances, limit)
+}
+
+/// Maximum number of orders summed per `total_controlled_balance` call.
+const MAX_CONTROLLED_BALANCE_SCANS_PER_CALL: u64 = 10;
+
+/// How long a cached balance is reused by `total_controlled_balance` (10 minutes).
+const CONTROLLED_BALANCE_MAX_AGE_NS: u64 = 10 * 60 * 1_000_000_000;
+
+/// Returns the cached balance of `address` if it was fetched at most `max_age_ns` before
+/// `now_ns`.
+fn fresh_cached_balance(
+    cache: &HashMap<String, CachedBalance>,
+    address: &str,
+    now_ns: u64,
+    max_age_ns: u64,
+) -> Option<u64> {
+    cache
+        .get(address)
+        .filter(|cached| now_ns.saturating_sub(cached.fetched_at_ns) <= max_age_ns)
+        .map(|cached| cached.balance)
+}
+
+/// Sums the confirmed balances of the orders' funding P2WPKH addresses: the main one,
+/// the fresh ones and the sub-accounts.
+///
+/// Funding addresses are controlled by the canister's own keys, so these are coins the
+/// canister can spend at will, unlike funds locked in an HTLC, which only leave through
+/// its claim or refund branch. Together the two make up the complete balance sheet.
+///
+/// Up to `limit` orders starting at order number `cursor` are summed, capped at
+/// `MAX_CONTROLLED_BALANCE_SCANS_PER_CALL`. Returns the sum of the batch and the cursor
+/// of the next batch, or `None` once all orders were summed; the total is the sum over
+/// all batches. Balances are cached in memory and reused for up to
+/// `CONTROLLED_BALANCE_MAX_AGE_NS`, and addresses whose balance cannot be fetched count
+/// as 0. Only controllers can call this method.
+#[update(guard = "crate::config::caller_is_controller")]
+pub async fn total_controlled_balance(limit: u64, cursor: u64) -> (u64, Option<u64>) {
+    let order_nos = STORAGE.with_borrow(|storage| storage.orders.keys().collect());
+    let (batch, next_cursor) = orders_batch(
+        order_nos,
+        cursor,
+        limit.min(MAX_CONTROLLED_BALANCE_SCANS_PER_CALL),
+    );
+
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let mut total = 0;
+    for order_no in batch {
+        let Ok(order) = get_order(order_no) else {
+            continue;
+        };
+        let Ok((_, funding_address)) = order_funding_key(&ctx, order_no).await else {
+            continue;
+        };
+        for address in order_addresses(&order, funding_address.to_string(), None) {
+            let now_ns = ic_cdk::api::time();
+            let cached = BALANCE_CACHE.with_borrow(|cache| {
+                fresh_cached_balance(cache, &address, now_ns, CONTROLLED_BALANCE_MAX_AGE_NS)
+            });
+            let balance = match cached {
+                Some(balance) => balance,
+                None => match get_confirmed_balance(&ctx, &address).await {
+                    Ok(balance) => {
+                        BALANCE_CACHE.with_borrow_mut(|cache| {
+                            cache.insert(
+                                address,
+                                CachedBalance {
+                                    balance,
+                                    fetched_at_ns: ic_cdk::api::time(),
+                                },
+                            )
+                        });
+                        balance
+                    }
+                    Err(_) => 0,
+                },
+            };
+            total += balance;
+        }
+    }
+
+    (total, next_cursor)
+}
+
+/// Every derived artifact of a prospective swap, as computed by `simulate_swap`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct SwapSimulation {
+    /// Funding P2WPKH address the next created order would get.
+    pub funding_address: String,
+    /// P2WSH address of the HTLC.
+    pub htlc_address: String,
+    /// Hex-encoded HTLC witness script.
+    pub redeem_script_hex: String,
+    /// Estimated fee for funding the HTLC from a single P2WPKH UTXO, with change.
+    pub estimated_funding_fee: u64,
+    /// Estimated fee for the responder to claim the HTLC to a P2WPKH address.
+    pub estimated_claim_fee: u64,
+}
+
+/// Previews every artifact of a swap without creating an order or moving funds.
+///
+/// The funding address is the one the next order created with `create_order` would
+/// receive. Fees are estimated from the current fee percentiles, assuming a single
+/// funding input and a P2WPKH claim destination.
+#[update]
+pub async fn simulate_swap(
+    initiator_pubkey: String,
+    responder_pubkey: String,
+    secret_hash: String,
+    time_lock: u64,
+    amount: u64,
+) -> Result<SwapSimulation, String> {
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    validate_order_time_lock(time_lock, HtlcTimelockType::Csv, ctx.bitcoin_network)?;
+    if amount < DUST_THRESHOLD {
+        return Err(format!(
+            "Amount {} is below the dust threshold of {} satoshi",
+            amount, DUST_THRESHOLD
+        ));
+    }
+
+    let responder_pubkey = normalize_pubkey(&responder_pubkey, "responder")?;
+    let script = generate_p2wsh_htlc_script(
+        &initiator_pubkey,
+        &responder_pubkey,
+        &secret_hash,
+        time_lock,
+        HtlcTimelockType::Csv,
+        HtlcHashType::Sha256,
+    )?;
+    let htlc_address = Address::p2wsh(&script, ctx.bitcoin_network);
+
+    let next_order_no = STORAGE.with_borrow(|storage| storage.next_order_no);
+    let (_, funding_address) = order_funding_key(&ctx, next_order_no).await?;
+
+    let fee_per_byte = get_fee_per_byte(&ctx).await;
+    let funding_vsize = TX_OVERHEAD_VBYTES
+        + P2WPKH_INPUT_VBYTES
+        + output_vbytes(&htlc_address)
+        + output_vbytes(&funding_address);
+    let estimated_funding_fee = fee_for_vsize(funding_vsize, fee_per_byte);
+    let estimated_claim_fee = fee_for_vsize(
+        worst_case_spend_vsize(&script, HtlcAddressFormat::NativeP2wsh),
+        fee_per_byte,
+    );
+    if estimated_claim_fee >= amount {
+        return Err(format!(
+            "Amount {} does not cover the estimated claim fee of {} satoshi",
+            amount, estimated_claim_fee
+        ));
+    }
+
+    Ok(SwapSimulation {
+        funding_address: funding_address.to_string(),
+        htlc_address: htlc_address.to_string(),
+        redeem_script_hex: hex::encode(script.as_bytes()),
+        estimated_funding_fee,
+        estimated_claim_fee,
+    })
+}
+
+/// Timelock (in blocks) of the orders created by `run_self_test`, short enough to
+/// exercise the refund branch by mining a few regtest blocks.
+const SELF_TEST_TIME_LOCK: u64 = 6;
+
+/// Every artifact a regtest harness needs to drive a swap through an order created by
+/// `run_self_test`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct SelfTestReport {
+    pub order_no: u64,
+    /// BIP-32 path of the order's key, see `get_order_path_string`.
+    pub derivation_path: String,
+    /// Hex-encoded public key of the order, used as both initiator and responder key.
+    pub order_pubkey: String,
+    /// Funding P2WPKH address to send the test coins to.
+    pub funding_address: String,
+    /// P2WSH address of the HTLC.
+    pub htlc_address: String,
+    /// Hex-encoded HTLC witness script.
+    pub witness_script_hex: String,
+    /// Hex-encoded SHA256 hash of the preimage, as stored in the order.
+    pub secret_hash: String,
+    /// Hex-encoded preimage to pass to `claim_htlc`.
+    pub preimage_hex: String,
+    pub time_lock: u64,
+}
+
+/// Computes the self-test report of order `order_no` whose key is `order_key`.
+fn self_test_report(
+    order_no: u64,
+    order_key: &CompressedPublicKey,
+    preimage: &[u8],
+    time_lock: u64,
+    network: bitcoin::Network,
+) -> Result<SelfTestReport, String> {
+    let order_pubkey = order_key.to_string();
+    let secret_hash = hex::encode(sha256::Hash::hash(preimage).as_byte_array());
+    let script = generate_p2wsh_htlc_script(
+        &order_pubkey,
+        &order_pubkey,
+        &secret_hash,
+        time_lock,
+        HtlcTimelockType::Csv,
+        HtlcHashType::Sha256,
+    )?;
+    Ok(SelfTestReport {
+        order_no,
+        derivation_path: order_derivation(order_no).to_string(),
+        order_pubkey,
+        funding_address: Address::p2wpkh(order_key, network).to_string(),
+        htlc_address: Address::p2wsh(&script, network).to_string(),
+        witness_script_hex: hex::encode(script.as_bytes()),
+        secret_hash,
+        preimage_hex: hex::encode(preimage),
+        time_lock,
+    })
+}
+
+/// Creates an order for an end-to-end swap test and returns everything needed to run it.
+///
+/// Only available on regtest. The order uses its own derived key as both initiator and
+/// responder key, so the canister can take either branch: after funding the returned
+/// funding address, a harness moves the coins into the HTLC with `withdraw_from_order`
+/// and sweeps them with `claim_htlc` and the returned preimage, or with `refund_htlc`
+/// once `SELF_TEST_TIME_LOCK` blocks are mined. The preimage is derived from the order
+/// number and the current time, and is not secret.
+#[update]
+pub async fn run_self_test() -> Result<SelfTestReport, String> {
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    if ctx.bitcoin_network != bitcoin::Network::Regtest {
+        return Err("The self test is only available on regtest".to_string());
+    }
+    validate_order_time_lock(
+        SELF_TEST_TIME_LOCK,
+        HtlcTimelockType::Csv,
+        ctx.bitcoin_network,
+    )?;
+
+    // Reserve the order number first, as the key depends on it. The keys are filled in
+    // once it is derived.
+    let now_ns = ic_cdk::api::time();
+    let order_no = insert_order(HtlcDetail {
+        time_lock: SELF_TEST_TIME_LOCK,
+        created_at_ns: now_ns,
+        owner: Some(msg_caller()),
+        ..Default::default()
+    });
+    let order_key = match try_get_ecdsa_public_key(&ctx, order_derivation_path(order_no))
+        .await
+        .and_then(|key| {
+            CompressedPublicKey::from_slice(&key)
+                .map_err(|e| format!("Invalid derived public key: {}", e))
+        }) {
+        Ok(order_key) => order_key,
+        Err(e) => {
+            STORAGE.with_borrow_mut(|storage| storage.remove_order(order_no));
+            return Err(e);
+        }
+    };
+
+    let preimage = sha256::Hash::hash(format!("self-test:{}:{}", order_no, now_ns).as_bytes());
+    let report = self_test_report(
+        order_no,
+        &order_key,
+        preimage.as_byte_array(),
+        SELF_TEST_TIME_LOCK,
+        ctx.bitcoin_network,
+    )?;
+    STORAGE.with_borrow_mut(|storage| {
+        storage.update_order(order_no, |order| {
+            order.initiator_pubkey = report.order_pubkey.clone();
+            order.responder_pubkey = Some(report.order_pubkey.clone());
+            order.secret_hash = report.secret_hash.clone();
+        });
+    });
+    Ok(report)
+}
+
+/// Returns the number of bytes `order` occupies when Candid-encoded.
+fn encoded_size(order: &HtlcDetail) -> u64 {
+    candid::encode_one(order).map_or(0, |bytes| bytes.len() as u64)
+}
+
+/// Returns the Candid-encoded size of an order in bytes, or `None` for unknown orders.
+///
+/// Responses are limited to 2 MB, so this helps to understand how close a
+/// `get_all_orders` call gets to that limit and how to size pages accordingly.
+#[query]
+pub fn order_encoded_size(order_no: u64) -> Option<u64> {
+    STORAGE.with_borrow(|storage| storage.orders.get(&order_no).as_ref().map(encoded_size))
+}
+
+/// Stable memory budget the order book is allowed to use, chosen by the operator when
+/// estimating the order capacity.
+///
+/// This is not a platform limit: stable memory can grow to several hundred GiB on the
+/// IC, subject to the subnet's capacity. The budget caps what this canister plans to
+/// use, and the storage cost that comes with it.
+const STABLE_MEMORY_BUDGET_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Share of the budget kept free as a safety margin (in percent).
+const STABLE_MEMORY_RESERVE_PERCENT: u64 = 10;
+
+/// Returns how many orders of `average_order_size` bytes fit into the budget when
+/// `used_bytes` are already taken, keeping the reserve free. Returns 0 once the
+/// reserve is reached.
+fn remaining_order_capacity(used_bytes: u64, average_order_size: u64) -> u64 {
+    let usable = STABLE_MEMORY_BUDGET_BYTES * (100 - STABLE_MEMORY_RESERVE_PERCENT) / 100;
+    usable.saturating_sub(used_bytes) / average_order_size.max(1)
+}
+
+/// Maximum number of orders `estimate_remaining_order_capacity` encodes to compute the
+/// average order size, so that its cost does not grow with the order book.
+const MAX_CAPACITY_SAMPLE_ORDERS: usize = 100;
+
+/// Returns the average Candid-encoded size of the `MAX_CAPACITY_SAMPLE_ORDERS` most
+/// recent orders, or of an empty order when there are none yet.
+fn sampled_average_order_size<M: ic_stable_structures::Memory>(
+    orders: &StableBTreeMap<u64, HtlcDetail, M>,
+) -> u64 {
+    let sizes: Vec<u64> = orders
+        .values()
+        .rev()
+        .take(MAX_CAPACITY_SAMPLE_ORDERS)
+        .map(|order| encoded_size(&order))
+        .collect();
+    if sizes.is_empty() {
+        encoded_size(&HtlcDetail::default())
+    } else {
+        sizes.iter().sum::<u64>() / sizes.len() as u64
+    }
+}
+
+/// Estimates how many more orders can be stored before hitting the stable memory budget.
+///
+/// This is a rough estimate based on the following assumptions:
+/// - The budget is `STABLE_MEMORY_BUDGET_BYTES` minus a `STABLE_MEMORY_RESERVE_PERCENT` reserve.
+/// - Current usage is the stable memory size (in 64 KiB pages) as reported by the system.
+/// - Future orders are as large as the average Candid-encoded size of the most recent
+///   ones, see `sampled_average_order_size`.
+///
+/// Returns 0 if usage is already within the reserve.
+#[query]
+pub fn estimate_remaining_order_capacity() -> u64 {
+    let average_order_size =
+        STORAGE.with_borrow(|storage| sampled_average_order_size(&storage.orders));
+    let used_bytes = stable_size() * WASM_PAGE_SIZE_IN_BYTES;
+    remaining_order_capacity(used_bytes, average_order_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{
+        absolute::LockTime,
+        secp256k1::{Secp256k1, SecretKey},
+        transaction::Version,
+        Amount, OutPoint, Sequence, Transaction, TxIn, TxOut, Witness,
+    };
+
+    fn pubkey_hex(seed: u8) -> String {
+        let secret_key = SecretKey::from_slice(&[seed; 32]).unwrap();
+        secret_key.public_key(&Secp256k1::new()).to_string()
+    }
+
+    fn secret_hash_hex() -> String {
+        hex::encode([0xab; 32])
+    }
+
+    fn utxo(value: u64) -> Utxo {
+        Utxo {
+            outpoint: Outpoint {
+                txid: vec![0; 32],
+                vout: 0,
+            },
+            value,
+            height: 100,
+        }
+    }
+
+    #[test]
+    fn test_cancel_refused_when_funds_present() {
+        assert!(ensure_no_funds("addr", &[]).is_ok());
+
+        let err = ensure_no_funds("addr", &[utxo(1_000), utxo(2_500)]).unwrap_err();
+        assert!(err.contains("3500 satoshi"));
+        assert!(err.contains("refusing to cancel"));
+    }
+
+    #[test]
+    fn test_order_addresses() {
+        let mut order = HtlcDetail::default();
+        assert_eq!(
+            order_addresses(&order, "funding".to_string(), None),
+            ["funding"]
+        );
+
+        order.funding_addresses = Some(vec!["fresh-1".to_string()]);
+        order.sub_accounts = vec![("savings".to_string(), "sub-1".to_string())];
+        let htlc_address = "htlc".to_string();
+        assert_eq!(
+            order_addresses(&order, "funding".to_string(), Some(&htlc_address)),
+            ["funding", "fresh-1", "sub-1", "htlc"]
+        );
+    }
+
+    #[test]
+    fn test_remaining_order_capacity() {
+        let usable = STABLE_MEMORY_BUDGET_BYTES * 9 / 10;
+        assert_eq!(remaining_order_capacity(0, 100), usable / 100);
+        assert_eq!(remaining_order_capacity(usable - 250, 100), 2);
+        assert_eq!(remaining_order_capacity(usable, 100), 0);
+        assert_eq!(remaining_order_capacity(STABLE_MEMORY_BUDGET_BYTES, 100), 0);
+    }
+
+    #[test]
+    fn test_sampled_average_order_size() {
+        let empty = order_map(std::iter::empty());
+        assert_eq!(
+            sampled_average_order_size(&empty),
+            encoded_size(&HtlcDetail::default())
+        );
+
+        // Only the most recent orders are sampled, so the large oldest order is ignored.
+        let large = HtlcDetail {
+            secret_hash: "ab".repeat(1000),
+            ..Default::default()
+        };
+        let orders = order_map(
+            std::iter::once((0, large)).chain(
+                (1..=MAX_CAPACITY_SAMPLE_ORDERS as u64)
+                    .map(|order_no| (order_no, HtlcDetail::default())),
+            ),
+        );
+        assert_eq!(
+            sampled_average_order_size(&orders),
+            encoded_size(&HtlcDetail::default())
+        );
+    }
+
+    #[test]
+    fn test_worst_case_spend_vsize_matches_claim_transaction() {
+        let script = generate_p2wsh_htlc_script(
+            &pubkey_hex(1),
+            &pubkey_hex(2),
+            &secret_hash_hex(),
+            144,
+            HtlcTimelockType::Csv,
+            HtlcHashType::Sha256,
+        )
+        .unwrap();
+        let destination = PublicKey::from_str(&pubkey_hex(3)).unwrap();
+
+        let mut witness = Witness::new();
+        witness.push([0u8; 73]);
+        witness.push([0u8; 32]);
+        witness.push([1u8]);
+        witness.push(script.as_bytes());
+        let mut claim = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness,
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(10_000),
+                script_pubkey: ScriptBuf::new_p2wpkh(&destination.wpubkey_hash().unwrap()),
+            }],
+        };
+
+        assert_eq!(
+            worst_case_spend_vsize(&script, HtlcAddressFormat::NativeP2wsh),
+            claim.vsize() as u64
+        );
+
+        claim.input[0].script_sig = HtlcAddressFormat::NestedP2shP2wsh.script_sig(&script);
+        assert_eq!(
+            worst_case_spend_vsize(&script, HtlcAddressFormat::NestedP2shP2wsh),
+            claim.vsize() as u64
+        );
+    }
+
+    #[test]
+    fn test_order_guard_rejects_reentrancy() {
+        let guard = OrderGuard::acquire(7).unwrap();
+        assert_eq!(
+            OrderGuard::acquire(7).err().unwrap(),
+            "operation already in progress for this order"
+        );
+        assert!(OrderGuard::acquire(8).is_ok());
+
+        drop(guard);
+        assert!(OrderGuard::acquire(7).is_ok());
+    }
+
+    #[test]
+    fn test_check_preimage() {
+        let preimage = [0x42u8; 32];
+        let secret_hash = hex::encode(sha256::Hash::hash(&preimage).as_byte_array());
+        let sha256 = HtlcHashType::Sha256;
+        assert!(check_preimage(&preimage, &secret_hash, sha256).is_ok());
+        assert!(check_preimage(&preimage, &secret_hash.to_uppercase(), sha256).is_ok());
+        assert!(check_preimage(&[0x43u8; 32], &secret_hash, sha256).is_err());
+
+        let hash160 = hex::encode(HtlcHashType::Hash160.hash(&preimage));
+        assert!(check_preimage(&preimage, &hash160, HtlcHashType::Hash160).is_ok());
+        assert!(check_preimage(&preimage, &hash160, sha256).is_err());
+        assert!(check_preimage(&preimage, &secret_hash, HtlcHashType::Hash160).is_err());
+    }
+
+    #[test]
+    fn test_fee_detail() {
+        let transaction = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(9_000),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+        let prevouts = [TxOut {
+            value: Amount::from_sat(10_000),
+            script_pubkey: ScriptBuf::new(),
+        }];
+
+        let detail = fee_detail(&transaction, &prevouts, 2_000);
+        assert_eq!(detail.txid, transaction.compute_txid().to_string());
+        assert_eq!(detail.fee, 1_000);
+        assert_eq!(detail.fee_rate, 2_000);
+        assert_eq!(detail.vsize, transaction.vsize() as u64);
+    }
+
+    #[test]
+    fn test_resolve_responder() {
+        let mut order = HtlcDetail::default();
+        assert_eq!(
+            resolve_responder(None, &order).unwrap_err(),
+            "no responder pubkey available; supply one"
+        );
+        assert_eq!(
+            resolve_responder(Some(pubkey_hex(1)), &order).unwrap(),
+            pubkey_hex(1)
+        );
+
+        order.responder_pubkey = Some(pubkey_hex(2));
+        assert_eq!(resolve_responder(None, &order).unwrap(), pubkey_hex(2));
+        assert_eq!(
+            resolve_responder(Some(pubkey_hex(1)), &order).unwrap(),
+            pubkey_hex(1)
+        );
+    }
+
+    #[test]
+    fn test_responder_pubkey_is_normalized() {
+        let lowercase = pubkey_hex(2);
+        let uppercase = lowercase.to_uppercase();
+        let order = HtlcDetail::default();
+        assert_eq!(
+            resolve_responder(Some(uppercase.clone()), &order).unwrap(),
+            lowercase
+        );
+
+        let address = |responder: &str| {
+            generate_p2wsh_htlc_address(
+                &pubkey_hex(1),
+                responder,
+                &secret_hash_hex(),
+                144,
+                HtlcTimelockType::Csv,
+                HtlcHashType::Sha256,
+                HtlcAddressFormat::NativeP2wsh,
+                bitcoin::Network::Regtest,
+            )
+            .unwrap()
+        };
+        assert_eq!(address(&uppercase), address(&lowercase));
+
+        let secret_key = SecretKey::from_slice(&[2; 32]).unwrap();
+        let uncompressed = hex::encode(
+            secret_key
+                .public_key(&Secp256k1::new())
+                .serialize_uncompressed(),
+        );
+        assert!(normalize_pubkey(&uncompressed, "responder")
+            .unwrap_err()
+            .contains("33-byte"));
+        let bad_prefix = format!("04{}", &lowercase[2..]);
+        assert!(normalize_pubkey(&bad_prefix, "responder")
+            .unwrap_err()
+            .contains("02 or 03"));
+    }
+
+    #[test]
+    fn test_time_lock_minimum_depends_on_network() {
+        assert!(validate_time_lock(1, bitcoin::Network::Regtest).is_ok());
+        assert!(validate_time_lock(1, bitcoin::Network::Bitcoin).is_err());
+        assert!(validate_time_lock(1, bitcoin::Network::Testnet).is_err());
+        assert!(validate_time_lock(MIN_CSV_BLOCKS, bitcoin::Network::Bitcoin).is_ok());
+
+        assert!(validate_time_lock(0, bitcoin::Network::Regtest).is_err());
+        for network in [bitcoin::Network::Regtest, bitcoin::Network::Bitcoin] {
+            assert!(validate_time_lock(MAX_CSV_BLOCKS, network).is_ok());
+            assert!(validate_time_lock(MAX_CSV_BLOCKS + 1, network).is_err());
+        }
+    }
+
+    #[test]
+    fn test_time_lock_policy() {
+        assert!(validate_time_lock_policy(1, None, None).is_ok());
+        assert!(validate_time_lock_policy(144, Some(144), Some(1_008)).is_ok());
+        assert!(validate_time_lock_policy(1_008, Some(144), Some(1_008)).is_ok());
+
+        let below = validate_time_lock_policy(143, Some(144), Some(1_008)).unwrap_err();
+        assert!(below.contains("min: 144, max: 1008"));
+        let above = validate_time_lock_policy(1_009, None, Some(1_008)).unwrap_err();
+        assert!(above.contains("min: none, max: 1008"));
+
+        crate::config::set_time_lock_bounds(Some(144), Some(1_008)).unwrap();
+        assert!(
+            validate_order_time_lock(100, HtlcTimelockType::Csv, bitcoin::Network::Regtest)
+                .is_err()
+        );
+        assert!(
+            validate_order_time_lock(2_000, HtlcTimelockType::Csv, bitcoin::Network::Regtest)
+                .is_err()
+        );
+        assert!(
+            validate_order_time_lock(144, HtlcTimelockType::Csv, bitcoin::Network::Regtest).is_ok()
+        );
+        assert!(crate::config::set_time_lock_bounds(Some(2), Some(1)).is_err());
+    }
+
+    #[test]
+    fn test_balance_refresh_and_ranking() {
+        let addresses: Vec<String> = ["a", "b", "c"].iter().map(|a| a.to_string()).collect();
+        let mut cache = HashMap::new();
+        cache.insert(
+            "a".to_string(),
+            CachedBalance {
+                balance: 5,
+                fetched_at_ns: 20,
+            },
+        );
+        cache.insert(
+            "b".to_string(),
+            CachedBalance {
+                balance: 9,
+                fetched_at_ns: 10,
+            },
+        );
+        assert_eq!(addresses_to_refresh(&addresses, &cache, 2), ["c", "b"]);
+        assert_eq!(addresses_to_refresh(&addresses, &cache, 10).len(), 3);
+
+        let ranked = rank_by_balance(vec![(1, 5), (2, 9), (3, 5), (4, 1)], 3);
+        assert_eq!(ranked, [(2, 9), (1, 5), (3, 5)]);
+    }
+
+    #[test]
+    fn test_unconfirmed_change_policy() {
+        let mut confirmed = utxo(5_000);
+        confirmed.outpoint.vout = 1;
+        let mut fresh_change = utxo(2_000);
+        fresh_change.outpoint.vout = 2;
+        fresh_change.height = 0;
+        let mut unreported_change = utxo(3_000);
+        unreported_change.outpoint.vout = 3;
+        unreported_change.height = 0;
+        let pending = [fresh_change.clone(), unreported_change.clone()];
+        let reported = vec![confirmed.clone(), fresh_change.clone()];
+
+        let spendable = spendable_utxos(reported.clone(), 100, &pending, false, 0);
+        assert_eq!(spendable, [confirmed.clone()]);
+
+        let spendable = spendable_utxos(reported, 100, &pending, true, 0);
+        assert_eq!(
+            spendable,
+            [confirmed.clone(), fresh_change, unreported_change]
+        );
+
+        // Once the change is mined, it is spendable regardless of the policy.
+        let mut mined_change = pending[0].clone();
+        mined_change.height = 100;
+        let spendable = spendable_utxos(vec![mined_change.clone()], 100, &pending, false, 0);
+        assert_eq!(spendable, [mined_change]);
+    }
+
+    #[test]
+    fn test_unconfirmed_deposits_are_told_apart_from_no_funding() {
+        let confirmed = utxo(5_000);
+        let mut unconfirmed = utxo(2_000);
+        unconfirmed.outpoint.vout = 1;
+        unconfirmed.height = 0;
+        let mut shallow = utxo(3_000);
+        shallow.outpoint.vout = 2;
+        shallow.height = 100;
+
+        // Nothing was ever sent to the funding address.
+        let spendable = spendable_utxos(vec![], 100, &[], false, 1);
+        assert_eq!(
+            ensure_funding_available(&spendable, &[]),
+            Err(HtlcError::NoUtxos)
+        );
+
+        // Only deposits without enough confirmations.
+        let reported = vec![unconfirmed.clone(), shallow.clone()];
+        let spendable = spendable_utxos(reported.clone(), 100, &[], false, 2);
+        assert!(spendable.is_empty());
+        assert_eq!(
+            ensure_funding_available(&spendable, &reported),
+            Err(HtlcError::FundingUnconfirmed {
+                unconfirmed_value: 5_000
+            })
+        );
+
+        // The minimum only applies to deposits that have the confirmations.
+        let reported = vec![confirmed.clone(), unconfirmed, shallow.clone()];
+        let spendable = spendable_utxos(reported.clone(), 101, &[], false, 2);
+        assert_eq!(spendable, [confirmed, shallow]);
+        assert!(ensure_funding_available(&spendable, &reported).is_ok());
+    }
+
+    #[test]
+    fn test_script_hash_debug() {
+        let script = generate_p2wsh_htlc_script(
+            &pubkey_hex(1),
+            &pubkey_hex(2),
+            &secret_hash_hex(),
+            144,
+            HtlcTimelockType::Csv,
+            HtlcHashType::Sha256,
+        )
+        .unwrap();
+        let debug = script_hash_debug(
+            &script,
+            HtlcAddressFormat::NativeP2wsh,
+            bitcoin::Network::Regtest,
+        );
+
+        assert_eq!(debug.script_hex, hex::encode(script.as_bytes()));
+        assert_eq!(debug.redeem_script_hex, None);
+        assert_eq!(
+            debug.scriptpubkey_hex,
+            format!("0020{}", debug.script_sha256_hex)
+        );
+        let address = Address::from_str(&debug.address)
+            .unwrap()
+            .require_network(bitcoin::Network::Regtest)
+            .unwrap();
+        assert_eq!(
+            hex::encode(address.script_pubkey().as_bytes()),
+            debug.scriptpubkey_hex
+        );
+
+        // Nested orders report the P2SH wrapping of the same witness program.
+        let nested = script_hash_debug(
+            &script,
+            HtlcAddressFormat::NestedP2shP2wsh,
+            bitcoin::Network::Regtest,
+        );
+        assert_eq!(nested.script_sha256_hex, debug.script_sha256_hex);
+        assert_eq!(
+            nested.redeem_script_hex,
+            Some(format!("0020{}", debug.script_sha256_hex))
+        );
+        let redeem_script =
+            ScriptBuf::from_bytes(hex::decode(nested.redeem_script_hex.unwrap()).unwrap());
+        assert_eq!(
+            nested.scriptpubkey_hex,
+            hex::encode(ScriptBuf::new_p2sh(&redeem_script.script_hash()).as_bytes())
+        );
+        assert_eq!(
+            nested.address,
+            HtlcAddressFormat::NestedP2shP2wsh
+                .address(&script, bitcoin::Network::Regtest)
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_redeem_template() {
+        let script = generate_p2wsh_htlc_script(
+            &pubkey_hex(1),
+            &pubkey_hex(2),
+            &secret_hash_hex(),
+            144,
+            HtlcTimelockType::Csv,
+            HtlcHashType::Sha256,
+        )
+        .unwrap();
+        let htlc_address = Address::p2wsh(&script, bitcoin::Network::Regtest);
+        let destination = PublicKey::from_str(&pubkey_hex(3)).unwrap();
+        let transaction = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(9_000),
+                script_pubkey: ScriptBuf::new_p2wpkh(&destination.wpubkey_hash().unwrap()),
+            }],
+        };
+        let prevout = TxOut {
+            value: Amount::from_sat(10_000),
+            script_pubkey: htlc_address.script_pubkey(),
+        };
+
+        let template =
+            redeem_template(&transaction, std::slice::from_ref(&prevout), &script).unwrap();
+        assert_eq!(template.input_amount, 10_000);
+        assert_eq!(template.witness_script_hex, hex::encode(script.as_bytes()));
+
+        let unsigned: Transaction =
+            bitcoin::consensus::deserialize(&hex::decode(&template.unsigned_tx_hex).unwrap())
+                .unwrap();
+        assert_eq!(unsigned, transaction);
+
+        // The sighash commits to the input amount.
+        let other = redeem_template(
+            &transaction,
+            &[TxOut {
+                value: Amount::from_sat(10_001),
+                ..prevout.clone()
+            }],
+            &script,
+        )
+        .unwrap();
+        assert_ne!(template.sighash, other.sighash);
+
+        assert!(redeem_template(&transaction, &[prevout.clone(), prevout], &script).is_err());
+    }
+
+    #[test]
+    fn test_validate_sub_account_label() {
+        let order = HtlcDetail {
+            sub_accounts: vec![("alice".to_string(), "bcrt1qalice".to_string())],
+            ..Default::default()
+        };
+
+        assert!(validate_sub_account_label(&order, "bob").is_ok());
+        assert!(validate_sub_account_label(&order, "alice")
+            .unwrap_err()
+            .contains("already in use"));
+        assert!(validate_sub_account_label(&order, " ").is_err());
+
+        let full = HtlcDetail {
+            sub_accounts: vec![("alice".to_string(), "bcrt1qalice".to_string()); MAX_SUB_ACCOUNTS],
+            ..Default::default()
+        };
+        assert!(validate_sub_account_label(&full, "bob")
+            .unwrap_err()
+            .contains("maximum"));
+    }
+
+    #[test]
+    fn test_is_stale_unfunded() {
+        let mut order = HtlcDetail {
+            created_at_ns: 1_000,
+            ..Default::default()
+        };
+        assert!(is_stale_unfunded(&order, 2_001, 1_000));
+        assert!(!is_stale_unfunded(&order, 2_000, 1_000));
+
+        order.status = OrderStatus::AddressGenerated;
+        assert!(is_stale_unfunded(&order, 2_001, 1_000));
+
+        order.htlc_address = Some("bcrt1qhtlc".to_string());
+        assert!(!is_stale_unfunded(&order, 2_001, 1_000));
+
+        order.htlc_address = None;
+        order.status = OrderStatus::Funded;
+        assert!(!is_stale_unfunded(&order, 2_001, 1_000));
+    }
+
+    #[test]
+    fn test_order_path_string_matches_derivation_path() {
+        for order_no in [0, 7, u32::MAX as u64] {
+            let components: Vec<String> = order_derivation_path(order_no)
+                .iter()
+                .map(|level| u32::from_be_bytes(level.as_slice().try_into().unwrap()).to_string())
+                .collect();
+            assert_eq!(
+                get_order_path_string(order_no),
+                format!("m/{}", components.join("/"))
+            );
+        }
+        assert_eq!(get_order_path_string(7), "m/84/0/7/0/0");
+    }
+
+    #[test]
+    fn test_would_collide() {
+        assert!(would_collide(1, 1));
+        assert!(!would_collide(1, 2));
+        assert!(would_collide(1, 1 + (1 << 32)));
+        assert!(!would_collide(1, 2 + (1 << 32)));
+        assert!(would_collide(0, u64::MAX - u32::MAX as u64));
+    }
+
+    #[test]
+    fn test_funding_utxo() {
+        let utxo = |txid: u8, height: u32| Utxo {
+            outpoint: Outpoint {
+                txid: vec![txid; 32],
+                vout: 1,
+            },
+            value: 10_000,
+            height,
+        };
+        assert!(funding_utxo(&[utxo(1, 0)]).is_none());
+
+        let utxos = [utxo(1, 0), utxo(2, 120), utxo(3, 100)];
+        let funding = funding_utxo(&utxos).unwrap();
+        assert_eq!(funding.height, 100);
+        assert_eq!(
+            outpoint_string(&funding.outpoint).unwrap(),
+            format!("{}:1", "03".repeat(32))
+        );
+        assert!(outpoint_string(&Outpoint {
+            txid: vec![0; 31],
+            vout: 0
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_order_message_hash() {
+        use bitcoin::secp256k1::{Message, Secp256k1, SecretKey};
+
+        let message = "order 7 is controlled by this canister";
+        let mut preimage = b"\x18Bitcoin Signed Message:\n".to_vec();
+        preimage.push(message.len() as u8);
+        preimage.extend_from_slice(message.as_bytes());
+        let hash = order_message_hash(message);
+        assert_eq!(
+            hash,
+            bitcoin::hashes::sha256d::Hash::hash(&preimage).to_byte_array()
+        );
+
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[7; 32]).unwrap();
+        let signature = secp.sign_ecdsa(&Message::from_digest(hash), &secret_key);
+        let compact = signature.serialize_compact();
+        let decoded = bitcoin::secp256k1::ecdsa::Signature::from_compact(&compact).unwrap();
+        assert!(secp
+            .verify_ecdsa(
+                &Message::from_digest(order_message_hash(message)),
+                &decoded,
+                &secret_key.public_key(&secp),
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn test_refund_deadline() {
+        let mut order = HtlcDetail {
+            time_lock: 144,
+            ..Default::default()
+        };
+        assert!(refund_deadline(&order, 1_000).is_err());
+
+        order.funding_height = Some(1_000);
+        assert_eq!(refund_deadline(&order, 1_100), Ok(44));
+        assert_eq!(refund_deadline(&order, 1_144), Ok(0));
+        assert_eq!(refund_deadline(&order, 1_150), Ok(-6));
+    }
+
+    #[test]
+    fn test_refundable_utxos() {
+        let utxo_at = |height: u32| Utxo {
+            height,
+            ..utxo(10_000)
+        };
+        let utxos = vec![utxo_at(0), utxo_at(1_000), utxo_at(1_001), utxo_at(1_100)];
+
+        // At tip 1_143 the output confirmed at 1_000 has 144 confirmations.
+        let refundable =
+            refundable_utxos(utxos.clone(), 1_143, 144, HtlcTimelockType::Csv).unwrap();
+        assert_eq!(refundable, [utxo_at(1_000)]);
+        assert_eq!(
+            refundable_utxos(utxos.clone(), 1_300, 144, HtlcTimelockType::Csv)
+                .unwrap()
+                .len(),
+            3
+        );
+
+        let err = refundable_utxos(utxos, 1_142, 144, HtlcTimelockType::Csv).unwrap_err();
+        assert!(err.contains("at most 143 confirmations but the timelock requires 144"));
+        assert!(refundable_utxos(vec![], 1_142, 144, HtlcTimelockType::Csv)
+            .unwrap_err()
+            .contains("No UTXOs"));
+    }
+
+    #[test]
+    fn test_reconciled_status() {
+        let mut unconfirmed = utxo(10_000);
+        unconfirmed.height = 0;
+        let confirmed = utxo(10_000);
+
+        // Funding is only recognised once confirmed.
+        assert_eq!(
+            reconciled_status(OrderStatus::AddressGenerated, &[unconfirmed], None),
+            OrderStatus::AddressGenerated
+        );
+        assert_eq!(
+            reconciled_status(OrderStatus::Created, std::slice::from_ref(&confirmed), None),
+            OrderStatus::Funded
+        );
+        assert_eq!(
+            reconciled_status(OrderStatus::Funded, &[confirmed], Some(10)),
+            OrderStatus::Funded
+        );
+
+        // An emptied HTLC was claimed if the refund branch was still locked.
+        assert_eq!(
+            reconciled_status(OrderStatus::Funded, &[], Some(10)),
+            OrderStatus::Claimed
+        );
+        assert_eq!(
+            reconciled_status(OrderStatus::Funded, &[], Some(0)),
+            OrderStatus::Funded
+        );
+        assert_eq!(
+            reconciled_status(OrderStatus::Funded, &[], None),
+            OrderStatus::Funded
+        );
+        assert_eq!(
+            reconciled_status(OrderStatus::Refunded, &[], Some(10)),
+            OrderStatus::Refunded
+        );
+    }
+
+    #[test]
+    fn test_terminal_orders_batch() {
+        let orders = [
+            (4, OrderStatus::Claimed),
+            (0, OrderStatus::Refunded),
+            (1, OrderStatus::Funded),
+            (3, OrderStatus::Refunded),
+            (2, OrderStatus::Created),
+        ];
+
+        assert_eq!(terminal_orders_batch(&orders, 0, 2), (vec![0, 3], Some(4)));
+        assert_eq!(terminal_orders_batch(&orders, 4, 2), (vec![4], None));
+        assert_eq!(terminal_orders_batch(&orders, 1, 2), (vec![3, 4], None));
+        assert_eq!(terminal_orders_batch(&orders, 5, 2), (vec![], None));
+    }
+
+    #[test]
+    fn test_ensure_claimable() {
+        assert!(ensure_claimable(2_501, 1_500).is_ok());
+        assert!(ensure_claimable(2_500, 1_500)
+            .unwrap_err()
+            .contains("claim fee of 1500"));
+        assert!(ensure_claimable(0, 0).is_err());
+        assert!(ensure_claimable(min_claimable_amount(1_500), 1_500).is_ok());
+        assert!(ensure_claimable(min_claimable_amount(1_500) - 1, 1_500).is_err());
+    }
+
+    #[test]
+    fn test_corrupted_htlc_address_is_flagged() {
+        let network = bitcoin::Network::Regtest;
+        let mut order = HtlcDetail {
+            initiator_pubkey: pubkey_hex(1),
+            time_lock: 144,
+            secret_hash: secret_hash_hex(),
+            ..Default::default()
+        };
+        assert!(htlc_address_matches(&order, network));
+
+        let derived = generate_p2wsh_htlc_address(
+            &order.initiator_pubkey,
+            &pubkey_hex(2),
+            &order.secret_hash,
+            order.time_lock,
+            order.timelock_type(),
+            order.hash_type(),
+            order.address_format(),
+            network,
+        )
+        .unwrap()
+        .to_string();
+        order.responder_pubkey = Some(pubkey_hex(2));
+        order.htlc_address = Some(derived.clone());
+        assert!(htlc_address_matches(&order, network));
+
+        // Flip the last character of the cached address.
+        let mut corrupted = derived;
+        let last = if corrupted.ends_with('q') { 'p' } else { 'q' };
+        corrupted.pop();
+        corrupted.push(last);
+        order.htlc_address = Some(corrupted);
+        assert!(!htlc_address_matches(&order, network));
+    }
+
+    #[test]
+    fn test_unsigned_psbt_has_witness_utxos() {
+        let script = generate_p2wsh_htlc_script(
+            &pubkey_hex(1),
+            &pubkey_hex(2),
+            &secret_hash_hex(),
+            144,
+            HtlcTimelockType::Csv,
+            HtlcHashType::Sha256,
+        )
+        .unwrap();
+        let htlc_address = Address::p2wsh(&script, bitcoin::Network::Regtest);
+        let destination = PublicKey::from_str(&pubkey_hex(3)).unwrap();
+        let amounts = [10_000, 25_000];
+
+        let input = |vout| TxIn {
+            previous_output: OutPoint {
+                txid: bitcoin::Txid::all_zeros(),
+                vout,
+            },
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        };
+        let transaction = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![input(0), input(1)],
+            output: vec![TxOut {
+                value: Amount::from_sat(34_000),
+                script_pubkey: ScriptBuf::new_p2wpkh(&destination.wpubkey_hash().unwrap()),
+            }],
+        };
+        let prevouts: Vec<TxOut> = amounts
+            .iter()
+            .map(|amount| TxOut {
+                value: Amount::from_sat(*amount),
+                script_pubkey: htlc_address.script_pubkey(),
+            })
+            .collect();
+
+        let psbt = unsigned_psbt(transaction, prevouts, Some(&script)).unwrap();
+        let parsed = Psbt::deserialize(&psbt.serialize()).unwrap();
+
+        assert_eq!(parsed.inputs.len(), 2);
+        for (input, amount) in parsed.inputs.iter().zip(amounts) {
+            let witness_utxo = input.witness_utxo.as_ref().unwrap();
+            assert_eq!(witness_utxo.value.to_sat(), amount);
+            assert_eq!(witness_utxo.script_pubkey, htlc_address.script_pubkey());
+            assert_eq!(input.witness_script.as_ref(), Some(&script));
+        }
+    }
+
+    #[test]
+    fn test_change_amount_of_withdraw_transaction() {
+        let ctx = BitcoinContext {
+            network: ic_cdk::bitcoin_canister::Network::Regtest,
+            bitcoin_network: bitcoin::Network::Regtest,
+            key_name: "test_key",
+        };
+        let own_public_key = PublicKey::from_str(&pubkey_hex(1)).unwrap();
+        let own_address = Address::p2wpkh(
+            &CompressedPublicKey::try_from(own_public_key).unwrap(),
+            ctx.bitcoin_network,
+        );
+        let htlc_address = generate_p2wsh_htlc_address(
+            &pubkey_hex(1),
+            &pubkey_hex(2),
+            &secret_hash_hex(),
+            144,
+            HtlcTimelockType::Csv,
+            HtlcHashType::Sha256,
+            HtlcAddressFormat::NativeP2wsh,
+            ctx.bitcoin_network,
+        )
+        .unwrap();
+        let own_utxos: Vec<Utxo> = [30_000, 20_000]
+            .into_iter()
+            .enumerate()
+            .map(|(vout, value)| Utxo {
+                outpoint: Outpoint {
+                    txid: vec![1; 32],
+                    vout: vout as u32,
+                },
+                ..utxo(value)
+            })
+            .collect();
+        let try_build = |amount, coin_selection| {
+            futures::executor::block_on(p2wpkh::build_transaction(
+                &ctx,
+                &own_public_key,
+                &own_address,
+                &own_utxos,
+                &htlc_address,
+                amount,
+                2_000,
+                coin_selection,
+            ))
+        };
+        let build = |amount, coin_selection| try_build(amount, coin_selection).unwrap().0;
+
+        // Smallest first spends the 20_000 UTXO first, which covers 15_000 plus the fee
+        // of 306 satoshi for the signed one-input, two-output transaction at 2 sat/vB.
+        let transaction = build(15_000, CoinSelection::SmallestFirst);
+        assert_eq!(transaction.input.len(), 1);
+        assert_eq!(
+            value_paid_to(&transaction, &own_address),
+            20_000 - 15_000 - 306
+        );
+
+        // A leftover below dust is absorbed into the fee.
+        let transaction = build(19_000, CoinSelection::SmallestFirst);
+        assert_eq!(transaction.output.len(), 1);
+        assert_eq!(value_paid_to(&transaction, &own_address), 0);
+
+        // Largest first spends the 30_000 UTXO and leaves change.
+        let transaction = build(19_000, CoinSelection::LargestFirst);
+        assert_eq!(transaction.input.len(), 1);
+        assert_eq!(transaction.input[0].previous_output.vout, 0);
+        assert_eq!(transaction.output.len(), 2);
+
+        // Branch and bound prefers the 20_000 UTXO, which leaves no change, and falls
+        // back to largest first when no selection avoids change.
+        let transaction = build(19_000, CoinSelection::BranchAndBound);
+        assert_eq!(transaction.input.len(), 1);
+        assert_eq!(transaction.input[0].previous_output.vout, 1);
+        assert_eq!(transaction.output.len(), 1);
+        let transaction = build(35_000, CoinSelection::BranchAndBound);
+        assert_eq!(transaction.input.len(), 2);
+        assert_eq!(transaction.input[0].previous_output.vout, 0);
+
+        // An amount the UTXOs cannot cover together with the fee is an error, not a trap.
+        assert!(try_build(49_900, CoinSelection::LargestFirst).is_err());
+    }
+
+    #[test]
+    fn test_discard_prepared() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let order_no = insert_order(HtlcDetail {
+            prepared_withdraw: Some(PreparedWithdraw {
+                transaction: vec![2, 0, 0, 0],
+                responder_pubkey: pubkey_hex(2),
+                fee_detail: FeeDetail {
+                    txid: "ab".repeat(32),
+                    fee: 1_410,
+                    fee_rate: 10_000,
+                    vsize: 141,
+                },
+            }),
+            owner: Some(alice),
+            ..Default::default()
+        });
+
+        {
+            let _guard = OrderGuard::acquire(order_no).unwrap();
+            assert!(remove_prepared(order_no, alice).is_err());
+        }
+        assert_eq!(
+            remove_prepared(order_no, bob),
+            Err(format!(
+                "Only the order's owner {} can discard its prepared transaction",
+                alice
+            ))
+        );
+        assert!(get_order(order_no).unwrap().prepared_withdraw.is_some());
+        remove_prepared(order_no, alice).unwrap();
+        assert_eq!(get_order(order_no).unwrap().prepared_withdraw, None);
+        assert!(remove_prepared(order_no, alice)
+            .unwrap_err()
+            .contains("no prepared transaction"));
+        assert!(remove_prepared(order_no + 1, alice).is_err());
+    }
+
+    #[test]
+    fn test_large_withdrawal_confirmation() {
+        let caller = Principal::anonymous();
+        let confirm = |amount, token, now_ns| {
+            confirm_large_withdrawal(7, amount, caller, token, Some(100_000), now_ns)
+        };
+        let issue = |amount, now_ns| match confirm(amount, None, now_ns) {
+            Err(HtlcError::ConfirmationRequired { token }) => token,
+            other => panic!("expected a confirmation token, got {:?}", other),
+        };
+
+        // Small withdrawals and disabled thresholds need no confirmation.
+        assert!(confirm(100_000, None, 0).is_ok());
+        assert!(confirm_large_withdrawal(7, u64::MAX, caller, None, None, 0).is_ok());
+
+        // The token confirms exactly once.
+        let token = issue(100_001, 0);
+        assert!(confirm(100_001, Some(token.clone()), 1_000).is_ok());
+        assert!(confirm(100_001, Some(token), 2_000).is_err());
+
+        // A token only confirms the withdrawal it was issued for.
+        let token = issue(100_001, 0);
+        assert!(confirm(200_000, Some(token), 1_000).is_err());
+        let token = issue(100_001, 0);
+        assert!(
+            confirm_large_withdrawal(8, 100_001, caller, Some(token), Some(100_000), 1_000)
+                .is_err()
+        );
+
+        // Tokens expire.
+        let token = issue(100_001, 0);
+        assert!(confirm(100_001, Some(token), CONFIRMATION_TOKEN_TTL_NS).is_err());
+        assert_ne!(issue(100_001, 0), issue(100_001, 0));
+    }
+
+    #[test]
+    fn test_reservation_expiry() {
+        let mut order = HtlcDetail::default();
+        assert!(check_reservation(&order, &pubkey_hex(2), 0).is_ok());
+
+        order.reservation = Some(Reservation {
+            responder_pubkey: pubkey_hex(2),
+            expires_at_ns: 1_000,
+            holder: None,
+            renewals: None,
+        });
+        assert!(active_reservation(&order, 999).is_some());
+        assert!(check_reservation(&order, &pubkey_hex(2), 999).is_ok());
+        assert!(check_reservation(&order, &pubkey_hex(3), 999)
+            .unwrap_err()
+            .contains("reserved by responder"));
+
+        // Once expired, the reservation no longer blocks anyone.
+        assert!(active_reservation(&order, 1_000).is_none());
+        assert!(check_reservation(&order, &pubkey_hex(3), 1_000).is_ok());
+    }
+
+    #[test]
+    fn test_next_reservation() {
+        let alice = Principal::from_slice(&[1]);
+        let mallory = Principal::from_slice(&[2]);
+        let mut order = HtlcDetail::default();
+
+        order.reservation = Some(next_reservation(&order, alice, pubkey_hex(2), 100, 0).unwrap());
+        assert_eq!(
+            order.reservation,
+            Some(Reservation {
+                responder_pubkey: pubkey_hex(2),
+                expires_at_ns: 100,
+                holder: Some(alice),
+                renewals: Some(0),
+            })
+        );
+
+        // Others cannot take over or renew an active reservation, not even for the same
+        // responder, and the holder cannot switch responders.
+        assert!(next_reservation(&order, mallory, pubkey_hex(2), 100, 50)
+            .unwrap_err()
+            .contains("reserved by responder"));
+        assert!(next_reservation(&order, alice, pubkey_hex(3), 100, 50).is_err());
+
+        // The holder can renew a bounded number of times.
+        for renewal in 1..=MAX_RESERVATION_RENEWALS {
+            let renewed = next_reservation(&order, alice, pubkey_hex(2), 100, 50).unwrap();
+            assert_eq!(renewed.renewals, Some(renewal));
+            order.reservation = Some(renewed);
+        }
+        assert!(next_reservation(&order, alice, pubkey_hex(2), 100, 50)
+            .unwrap_err()
+            .contains("maximum"));
+
+        // Once it lapses, anyone can reserve the order afresh.
+        let fresh = next_reservation(&order, mallory, pubkey_hex(3), 100, 150).unwrap();
+        assert_eq!(fresh.holder, Some(mallory));
+        assert_eq!(fresh.renewals, Some(0));
+    }
+
+    #[test]
+    fn test_check_reservation_canceller() {
+        let owner = Principal::from_slice(&[1]);
+        let holder = Principal::from_slice(&[2]);
+        let other = Principal::from_slice(&[3]);
+        let order = HtlcDetail {
+            owner: Some(owner),
+            reservation: Some(Reservation {
+                responder_pubkey: pubkey_hex(2),
+                expires_at_ns: 100,
+                holder: Some(holder),
+                renewals: Some(0),
+            }),
+            ..Default::default()
+        };
+
+        assert!(check_reservation_canceller(&order, owner).is_ok());
+        assert!(check_reservation_canceller(&order, holder).is_ok());
+        assert!(check_reservation_canceller(&order, other).is_err());
+    }
+
+    #[test]
+    fn test_inflight_value_cap() {
+        assert!(check_inflight_cap(0, u64::MAX, None).is_ok());
+        assert!(check_inflight_cap(60_000, 40_000, Some(100_000)).is_ok());
+        assert_eq!(
+            check_inflight_cap(60_000, 40_001, Some(100_000)).unwrap_err(),
+            "in-flight value cap exceeded"
+        );
+
+        let order_no = STORAGE.with_borrow_mut(|storage| {
+            let order_no = storage.next_order_no;
+            storage.orders.insert(order_no, HtlcDetail::default());
+            storage.next_order_no += 1;
+            order_no
+        });
+        reserve_inflight(order_no, 30_000).unwrap();
+        assert_eq!(get_inflight_value(), 30_000);
+        release_inflight(order_no, 30_000);
+        assert_eq!(get_inflight_value(), 0);
+    }
+
+    #[test]
+    fn test_derive_addresses_reports_failures_per_order() {
+        let derive = |order_no: u64| async move {
+            if order_no == 3 {
+                Err("Failed to derive ECDSA public key: SysTransient".to_string())
+            } else {
+                Ok(format!("bcrt1qorder{}", order_no))
+            }
+        };
+
+        let results = futures::executor::block_on(derive_addresses(1..5, derive));
+        assert_eq!(
+            results,
+            [
+                (1, Ok("bcrt1qorder1".to_string())),
+                (2, Ok("bcrt1qorder2".to_string())),
+                (
+                    3,
+                    Err("Failed to derive ECDSA public key: SysTransient".to_string())
+                ),
+                (4, Ok("bcrt1qorder4".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_orders_by_amount_range() {
+        STORAGE.with_borrow_mut(|storage| {
+            for (order_no, requested_amount) in [
+                (0, Some(10_000)),
+                (1, Some(50_000)),
+                (2, None),
+                (3, Some(100_000)),
+                (4, Some(49_999)),
+            ] {
+                storage.orders.insert(
+                    order_no,
+                    HtlcDetail {
+                        requested_amount,
+                        ..Default::default()
+                    },
+                );
+            }
+        });
+        let order_nos = |min, max| -> Vec<u64> {
+            get_orders_by_amount_range(min, max)
+                .unwrap()
+                .into_iter()
+                .map(|(order_no, _)| order_no)
+                .collect()
+        };
+
+        assert_eq!(order_nos(50_000, 100_000), [1, 3]);
+        assert_eq!(order_nos(10_000, 49_999), [0, 4]);
+        assert_eq!(order_nos(0, u64::MAX), [0, 1, 3, 4]);
+        assert_eq!(order_nos(50_000, 50_000), [1]);
+        assert!(order_nos(100_001, u64::MAX).is_empty());
+        assert!(get_orders_by_amount_range(2, 1)
+            .unwrap_err()
+            .contains("exceeds"));
+    }
+
+    #[test]
+    fn test_decode_orders_state() {
+        let people = vec![("alice".to_string(), Person::new("alice".to_string(), 30))];
+        let orders = vec![(
+            4,
+            HtlcDetail {
+                time_lock: 144,
+                ..Default::default()
+            },
+        )];
+
+        let bytes = candid::encode_args((people.clone(), orders.clone(), 7u64)).unwrap();
+        assert_eq!(
+            decode_orders_state(&bytes),
+            Some((orders.clone(), 7, vec![]))
+        );
+
+        let ids = vec![(Principal::anonymous(), "client-1".to_string(), 4u64)];
+        let bytes =
+            candid::encode_args((people.clone(), orders.clone(), 7u64, ids.clone())).unwrap();
+        assert_eq!(decode_orders_state(&bytes), Some((orders.clone(), 7, ids)));
+
+        // Client order ids saved before they were keyed by principal have no known owner
+        // and are dropped.
+        let legacy_ids = vec![("client-1".to_string(), 4u64)];
+        let bytes =
+            candid::encode_args((people.clone(), orders.clone(), 7u64, legacy_ids)).unwrap();
+        assert_eq!(decode_orders_state(&bytes), Some((orders, 7, vec![])));
+
+        // Stable memory saved before orders were persisted only holds the people.
+        let bytes = candid::encode_args((people,)).unwrap();
+        assert_eq!(decode_orders_state(&bytes), None);
+    }
+
+    #[test]
+    fn test_orders_state_message_carries_config() {
+        let people = vec![("alice".to_string(), Person::new("alice".to_string(), 30))];
+        let config = Config {
+            max_total_inflight_value: Some(5_000_000),
+            ..Default::default()
+        };
+
+        let bytes = orders_state_message(people, config.clone());
+        let (_people, _orders, _next_order_no, _client_order_ids, saved) =
+            stable_memory::decode_message::<(Reserved, Reserved, Reserved, Reserved, Config)>(
+                &bytes,
+            )
+            .unwrap();
+        assert_eq!(saved, config);
+        assert!(decode_orders_state(&bytes).is_some());
+    }
+
+    #[test]
+    fn test_large_withdrawal_threshold_survives_upgrade() {
+        config::restore(Config {
+            large_withdrawal_threshold: Some(1_000_000),
+            ..Default::default()
+        });
+        let bytes = orders_state_message(vec![], config::current());
+
+        config::restore(Config::default());
+        config::restore_config_state(&bytes);
+        assert_eq!(
+            config::current().large_withdrawal_threshold,
+            Some(1_000_000)
+        );
+        config::restore(Config::default());
+    }
+
+    #[test]
+    fn test_restore_legacy_orders_state() {
+        let people = vec![("alice".to_string(), Person::new("alice".to_string(), 30))];
+        let orders = vec![
+            (
+                2,
+                HtlcDetail {
+                    time_lock: 144,
+                    ..Default::default()
+                },
+            ),
+            (4, HtlcDetail::default()),
+        ];
+        let ids = vec![(Principal::anonymous(), "client-1".to_string(), 4u64)];
+
+        // Messages written by `stable_save` are followed by the rest of the page.
+        let mut message = candid::encode_args((people, orders.clone(), 7u64, ids.clone())).unwrap();
+        message.resize(message.len() + 1024, 0);
+        restore_orders_state(&message);
+
+        assert_eq!(export_orders(), (orders, 7));
+        assert_eq!(export_client_order_ids(), ids);
+    }
+
+    #[test]
+    fn test_update_order() {
+        let order_no = insert_order(HtlcDetail::default());
+
+        let status = STORAGE.with_borrow_mut(|storage| {
+            storage.update_order(order_no, |order| {
+                order.status = OrderStatus::Funded;
+                order.status
+            })
+        });
+        assert_eq!(status, Some(OrderStatus::Funded));
+        assert_eq!(get_order(order_no).unwrap().status, OrderStatus::Funded);
+
+        let missing = STORAGE.with_borrow_mut(|storage| {
+            storage.update_order(order_no + 1, |_| unreachable!("no such order"))
+        });
+        assert_eq!(missing, None);
+    }
+
+    #[test]
+    fn test_order_storable() {
+        let order = HtlcDetail {
+            secret_hash: secret_hash_hex(),
+            time_lock: 144,
+            owner: Some(Principal::anonymous()),
+            ..Default::default()
+        };
+        assert_eq!(HtlcDetail::from_bytes(order.to_bytes()), order);
+        assert_eq!(
+            HtlcDetail::from_bytes(Cow::Owned(order.clone().into_bytes())),
+            order
+        );
+    }
+
+    #[test]
+    fn test_self_test_report() {
+        let order_key = CompressedPublicKey::from_str(&pubkey_hex(3)).unwrap();
+        let preimage = [0x5a; 32];
+        let report =
+            self_test_report(9, &order_key, &preimage, 6, bitcoin::Network::Regtest).unwrap();
+
+        assert_eq!(report.order_pubkey, pubkey_hex(3));
+        assert_eq!(report.derivation_path, get_order_path_string(9));
+        assert!(check_preimage(
+            &hex::decode(&report.preimage_hex).unwrap(),
+            &report.secret_hash,
+            HtlcHashType::Sha256
+        )
+        .is_ok());
+        let script = generate_p2wsh_htlc_script(
+            &report.order_pubkey,
+            &report.order_pubkey,
+            &report.secret_hash,
+            6,
+            HtlcTimelockType::Csv,
+            HtlcHashType::Sha256,
+        )
+        .unwrap();
+        assert_eq!(report.witness_script_hex, hex::encode(script.as_bytes()));
+        assert!(report.htlc_address.starts_with("bcrt1q"));
+        assert_eq!(
+            report.funding_address,
+            Address::p2wpkh(&order_key, bitcoin::Network::Regtest).to_string()
+        );
+    }
+
+    #[test]
+    fn test_validate_secret_hash() {
+        let sha256 = HtlcHashType::Sha256;
+        assert!(validate_secret_hash(&secret_hash_hex(), sha256).is_ok());
+        assert!(validate_secret_hash(&secret_hash_hex().to_uppercase(), sha256).is_ok());
+
+        let error = validate_secret_hash(&"ab".repeat(20), sha256).unwrap_err();
+        assert!(error.contains("must be 32 bytes, got 20"), "{}", error);
+        assert!(error.contains("SHA256"), "{}", error);
+        assert!(validate_secret_hash("", sha256).is_err());
+        assert!(validate_secret_hash(&format!("0x{}", "ab".repeat(31)), sha256).is_err());
+        assert!(validate_secret_hash(&"zz".repeat(32), sha256).is_err());
+
+        assert!(validate_secret_hash(&"ab".repeat(20), HtlcHashType::Hash160).is_ok());
+        let error = validate_secret_hash(&secret_hash_hex(), HtlcHashType::Hash160).unwrap_err();
+        assert!(error.contains("must be 20 bytes, got 32"), "{}", error);
+        assert!(error.contains("HASH160"), "{}", error);
+    }
+
+    #[test]
+    fn test_coin_infos() {
+        let mut small = utxo(1_000);
+        small.height = 100;
+        let mut large = utxo(9_000);
+        large.outpoint.vout = 1;
+        large.height = 0;
+        let mut change = utxo(4_000);
+        change.outpoint.vout = 2;
+        change.height = 0;
+
+        let reported = vec![small.clone(), large.clone()];
+        let spendable = spendable_utxos(reported.clone(), 101, &[change.clone()], true, 1);
+        let coins = coin_infos(&reported, 101, &spendable).unwrap();
+
+        let summary: Vec<_> = coins
+            .iter()
+            .map(|coin| (coin.value, coin.confirmations, coin.spendable))
+            .collect();
+        assert_eq!(
+            summary,
+            [(9_000, 0, false), (4_000, 0, true), (1_000, 2, true)]
+        );
+        assert_eq!(coins[2].outpoint, outpoint_string(&small.outpoint).unwrap());
+    }
+
+    #[test]
+    fn test_redeem_change_address() {
+        let network = bitcoin::Network::Regtest;
+        let responder_key = PublicKey::from_str(&pubkey_hex(2)).unwrap();
+        let compressed_key = CompressedPublicKey::try_from(responder_key).unwrap();
+        let script = generate_p2wsh_htlc_script(
+            &pubkey_hex(1),
+            &pubkey_hex(2),
+            &secret_hash_hex(),
+            144,
+            HtlcTimelockType::Csv,
+            HtlcHashType::Sha256,
+        )
+        .unwrap();
+        let htlc_address = Address::p2wsh(&script, network);
+
+        // The remainder stays in the HTLC by default.
+        assert_eq!(
+            redeem_change_address(None, &htlc_address, &responder_key, network).unwrap(),
+            htlc_address
+        );
+
+        // The responder's own address is accepted.
+        let own_address = Address::p2wpkh(&compressed_key, network);
+        assert_eq!(
+            redeem_change_address(
+                Some(&own_address.to_string()),
+                &htlc_address,
+                &responder_key,
+                network
+            )
+            .unwrap(),
+            own_address
+        );
+
+        // Addresses of other keys are rejected.
+        let initiator_key = CompressedPublicKey::from_str(&pubkey_hex(1)).unwrap();
+        let other_address = Address::p2wpkh(&initiator_key, network).to_string();
+        let error =
+            redeem_change_address(Some(&other_address), &htlc_address, &responder_key, network)
+                .unwrap_err();
+        assert!(
+            error.contains("not an address of the responder key"),
+            "{}",
+            error
+        );
+        assert!(redeem_change_address(
+            Some("not an address"),
+            &htlc_address,
+            &responder_key,
+            network
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_htlc_preview() {
+        let order = HtlcDetail {
+            initiator_pubkey: pubkey_hex(1),
+            secret_hash: secret_hash_hex(),
+            time_lock: 144,
+            ..Default::default()
+        };
+        let preview = htlc_preview(&order, &pubkey_hex(2), bitcoin::Network::Regtest).unwrap();
+
+        let script = generate_p2wsh_htlc_script(
+            &pubkey_hex(1),
+            &pubkey_hex(2),
+            &secret_hash_hex(),
+            144,
+            HtlcTimelockType::Csv,
+            HtlcHashType::Sha256,
+        )
+        .unwrap();
+        assert_eq!(preview.witness_script_hex, hex::encode(script.as_bytes()));
+        assert_eq!(
+            preview.address,
+            generate_p2wsh_htlc_address(
+                &pubkey_hex(1),
+                &pubkey_hex(2),
+                &secret_hash_hex(),
+                144,
+                HtlcTimelockType::Csv,
+                HtlcHashType::Sha256,
+                HtlcAddressFormat::NativeP2wsh,
+                bitcoin::Network::Regtest
+            )
+            .unwrap()
+            .to_string()
+        );
+        assert!(htlc_preview(&order, "02abcd", bitcoin::Network::Regtest).is_err());
+
+        let nested = HtlcDetail {
+            address_format: Some(HtlcAddressFormat::NestedP2shP2wsh),
+            ..order
+        };
+        let nested_preview =
+            htlc_preview(&nested, &pubkey_hex(2), bitcoin::Network::Regtest).unwrap();
+        assert_eq!(
+            nested_preview.witness_script_hex,
+            preview.witness_script_hex
+        );
+        assert_eq!(
+            nested_preview.address,
+            Address::p2shwsh(&script, bitcoin::Network::Regtest).to_string()
+        );
+    }
+
+    #[test]
+    fn test_orders_batch() {
+        assert_eq!(orders_batch(vec![5, 1, 3], 0, 2), (vec![1, 3], Some(5)));
+        assert_eq!(orders_batch(vec![5, 1, 3], 4, 2), (vec![5], None));
+        assert_eq!(orders_batch(vec![], 0, 2), (vec![], None));
+    }
+
+    #[test]
+    fn test_fresh_cached_balance() {
+        let mut cache = HashMap::new();
+        cache.insert(
+            "bcrt1qfunding".to_string(),
+            CachedBalance {
+                balance: 7_000,
+                fetched_at_ns: 1_000,
+            },
+        );
+
+        assert_eq!(
+            fresh_cached_balance(&cache, "bcrt1qfunding", 1_500, 500),
+            Some(7_000)
+        );
+        assert_eq!(
+            fresh_cached_balance(&cache, "bcrt1qfunding", 1_501, 500),
+            None
+        );
+        assert_eq!(
+            fresh_cached_balance(&cache, "bcrt1qother", 1_000, 500),
+            None
+        );
+    }
+
+    #[test]
+    fn test_orders_by_status() {
+        STORAGE.with_borrow_mut(|storage| {
+            for (order_no, status) in [
+                (0, OrderStatus::Created),
+                (1, OrderStatus::Funded),
+                (2, OrderStatus::AddressGenerated),
+                (3, OrderStatus::Created),
+                (4, OrderStatus::Funded),
+            ] {
+                storage.orders.insert(
+                    order_no,
+                    HtlcDetail {
+                        status,
+                        ..Default::default()
+                    },
+                );
+            }
+        });
+        mark_address_generated(3, 0);
+        mark_address_generated(4, 0);
+        let order_nos = |status| -> Vec<u64> {
+            get_orders_by_status(status)
+                .into_iter()
+                .map(|(order_no, _)| order_no)
+                .collect()
+        };
+
+        assert_eq!(order_nos(OrderStatus::Created), [0]);
+        assert_eq!(order_nos(OrderStatus::AddressGenerated), [2, 3]);
+        assert_eq!(order_nos(OrderStatus::Funded), [1, 4]);
+        assert!(order_nos(OrderStatus::Claimed).is_empty());
+    }
+
+    #[test]
+    fn test_spend_confirmations() {
+        let txid = bitcoin::Txid::from_byte_array([0x11; 32]);
+        let mut spend_output = utxo(9_000);
+        spend_output.outpoint.txid = txid.as_byte_array().to_vec();
+        spend_output.height = 100;
+        let mut other = utxo(1_000);
+        other.height = 50;
+
+        let utxos = vec![other, spend_output];
+        assert_eq!(spend_confirmations(&utxos, 102, &txid.to_string()), Ok(3));
+        // Not confirmed yet, or the output was spent already.
+        let mempool_txid = bitcoin::Txid::from_byte_array([0x22; 32]);
+        assert_eq!(
+            spend_confirmations(&utxos, 102, &mempool_txid.to_string()),
+            Ok(0)
+        );
+        assert!(spend_confirmations(&utxos, 102, "not a txid").is_err());
+    }
+
+    #[test]
+    fn test_absolute_timelock() {
+        let network = bitcoin::Network::Bitcoin;
+        assert!(validate_order_time_lock(900_000, HtlcTimelockType::Cltv, network).is_ok());
+        assert!(validate_order_time_lock(0, HtlcTimelockType::Cltv, network).is_err());
+        let error =
+            validate_order_time_lock(1_700_000_000, HtlcTimelockType::Cltv, network).unwrap_err();
+        assert!(error.contains("median time past"), "{}", error);
+        // Block heights beyond the CSV range are fine for absolute timelocks only.
+        assert!(validate_order_time_lock(900_000, HtlcTimelockType::Csv, network).is_err());
+
+        let (sequence, lock_time) = refund_locks(900_000, HtlcTimelockType::Cltv);
+        assert!(sequence.enables_absolute_lock_time());
+        assert_eq!(lock_time, LockTime::from_height(900_000).unwrap());
+        let (sequence, lock_time) = refund_locks(144, HtlcTimelockType::Csv);
+        assert_eq!(sequence, Sequence::from_height(144));
+        assert_eq!(lock_time, LockTime::ZERO);
+
+        // The refund is final once the tip has reached the lock height.
+        let mut unconfirmed = utxo(10_000);
+        unconfirmed.height = 0;
+        let utxos = vec![unconfirmed];
+        assert!(
+            refundable_utxos(utxos.clone(), 899_999, 900_000, HtlcTimelockType::Cltv)
+                .unwrap_err()
+                .contains("block height 900000")
+        );
+        assert_eq!(
+            refundable_utxos(utxos.clone(), 900_000, 900_000, HtlcTimelockType::Cltv).unwrap(),
+            utxos
+        );
+
+        let order = HtlcDetail {
+            time_lock: 900_000,
+            timelock_type: Some(HtlcTimelockType::Cltv),
+            ..Default::default()
+        };
+        assert_eq!(refund_deadline(&order, 899_990), Ok(10));
+    }
+
+    #[test]
+    fn test_time_lock_with_disable_flag_is_rejected() {
+        for network in [bitcoin::Network::Regtest, bitcoin::Network::Bitcoin] {
+            assert_eq!(
+                validate_time_lock(0x8000_0001, network),
+                Err("timelock value disables the relative lock".to_string())
+            );
+            assert!(validate_order_time_lock(0x8000_0090, HtlcTimelockType::Csv, network).is_err());
+        }
+    }
+
+    #[test]
+    fn test_time_lock_outside_bip68_encoding_is_rejected() {
+        let network = bitcoin::Network::Bitcoin;
+        assert_eq!(
+            validate_time_lock(SEQUENCE_LOCKTIME_TYPE_FLAG | 144, network),
+            Err(
+                "timelock value sets the time-based flag; relative timelocks count blocks"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            validate_time_lock(70_000, network),
+            Err(
+                "Relative timelock 70000 does not fit the 16-bit block count of BIP-68 (at most 65535)"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_client_order_ids() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        restore_orders(vec![], 0, vec![]);
+        assert_eq!(order_no_for_client_id(alice, "client-1"), None);
+
+        STORAGE.with_borrow_mut(|storage| {
+            storage
+                .client_order_ids
+                .insert((alice, "client-1".to_string()), 3);
+            storage
+                .client_order_ids
+                .insert((alice, "client-0".to_string()), 5);
+        });
+        assert_eq!(order_no_for_client_id(alice, "client-1"), Some(3));
+        // Another principal using the same id does not get alice's order.
+        assert_eq!(order_no_for_client_id(bob, "client-1"), None);
+        let ids = vec![
+            (alice, "client-0".to_string(), 5),
+            (alice, "client-1".to_string(), 3),
+        ];
+        assert_eq!(export_client_order_ids(), ids);
+
+        // Restoring an order book replaces the ids with the ones that belong to it.
+        restore_orders(vec![], 0, vec![]);
+        assert_eq!(order_no_for_client_id(alice, "client-1"), None);
+        restore_orders(vec![], 6, ids.clone());
+        assert_eq!(export_client_order_ids(), ids);
+        restore_orders(vec![], 0, vec![]);
+    }
+
+    #[test]
+    fn test_balance_query_plan() {
+        let valid = bitcoin::Address::p2wpkh(
+            &CompressedPublicKey::from_str(&pubkey_hex(1)).unwrap(),
+            bitcoin::Network::Regtest,
+        )
+        .to_string();
+        let mainnet = bitcoin::Address::p2wpkh(
+            &CompressedPublicKey::from_str(&pubkey_hex(1)).unwrap(),
+            bitcoin::Network::Bitcoin,
+        )
+        .to_string();
+
+        let plan = balance_query_plan(
+            &[valid.clone(), "not-an-address".to_string(), mainnet],
+            bitcoin::Network::Regtest,
+        );
+        assert!(plan[0].is_ok());
+        assert!(plan[1].is_err());
+        assert!(plan[2].is_err());
+
+        let addresses = vec![valid; MAX_BALANCE_QUERIES_PER_CALL + 2];
+        let plan = balance_query_plan(&addresses, bitcoin::Network::Regtest);
+        assert!(plan[..MAX_BALANCE_QUERIES_PER_CALL]
+            .iter()
+            .all(Result::is_ok));
+        assert!(plan[MAX_BALANCE_QUERIES_PER_CALL..]
+            .iter()
+            .all(|result| result.as_ref().unwrap_err().starts_with("Not queried")));
+    }
+
+    #[test]
+    fn test_check_owner() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let order = HtlcDetail {
+            owner: Some(alice),
+            ..Default::default()
+        };
+        assert!(check_owner(&order, alice, "move its funds").is_ok());
+        assert_eq!(
+            check_owner(&order, bob, "move its funds"),
+            Err(format!(
+                "Only the order's owner {} can move its funds",
+                alice
+            ))
+        );
+
+        // Orders created before owners were recorded stay unrestricted.
+        assert!(check_owner(&HtlcDetail::default(), bob, "move its funds").is_ok());
+    }
+
+    #[test]
+    fn test_get_owned_order_rejects_non_owner_refund() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let order_no = insert_order(HtlcDetail {
+            owner: Some(alice),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            get_owned_order(order_no, bob, "refund it"),
+            Err(format!("Only the order's owner {} can refund it", alice))
+        );
+        assert_eq!(
+            get_owned_order(order_no, alice, "refund it").unwrap().owner,
+            Some(alice)
+        );
+        assert!(get_owned_order(order_no + 1, alice, "refund it").is_err());
+    }
+
+    /// Builds an order map in heap memory holding `orders`.
+    fn order_map(
+        orders: impl Iterator<Item = (u64, HtlcDetail)>,
+    ) -> StableBTreeMap<u64, HtlcDetail, ic_stable_structures::VectorMemory> {
+        let mut map = StableBTreeMap::new(ic_stable_structures::VectorMemory::default());
+        for (order_no, order) in orders {
+            map.insert(order_no, order);
+        }
+        map
+    }
+
+    #[test]
+    fn test_orders_page() {
+        let orders = order_map([0, 1, 2, 5, 9].into_iter().map(|order_no| {
+            let order = HtlcDetail {
+                time_lock: order_no + 10,
+                ..Default::default()
+            };
+            (order_no, order)
+        }));
+        let order_nos = |page: Vec<(u64, HtlcDetail)>| -> Vec<u64> {
+            page.into_iter().map(|(order_no, _)| order_no).collect()
+        };
+
+        let first = orders_page(&orders, None, 2);
+        assert_eq!(first[1], (1, orders.get(&1).unwrap()));
+        assert_eq!(order_nos(first), vec![0, 1]);
+        assert_eq!(order_nos(orders_page(&orders, Some(1), 2)), vec![2, 5]);
+        assert_eq!(order_nos(orders_page(&orders, Some(5), 2)), vec![9]);
+        assert!(orders_page(&orders, Some(9), 2).is_empty());
+        assert!(orders_page(&orders, None, 0).is_empty());
+        assert_eq!(orders_page(&orders, None, u32::MAX).len(), 5);
+    }
+
+    #[test]
+    fn test_orders_with_statuses() {
+        let orders = order_map(
+            [
+                (0, OrderStatus::Created),
+                (1, OrderStatus::Funded),
+                (2, OrderStatus::AddressGenerated),
+                (3, OrderStatus::Claimed),
+                (4, OrderStatus::Funded),
+            ]
+            .into_iter()
+            .map(|(order_no, status)| {
+                let order = HtlcDetail {
+                    status,
+                    ..Default::default()
+                };
+                (order_no, order)
+            }),
+        );
+
+        let matching = orders_with_statuses(
+            &orders,
+            &[
+                OrderStatus::Funded,
+                OrderStatus::AddressGenerated,
+                OrderStatus::Funded,
+            ],
+        );
+        let order_nos: Vec<u64> = matching.iter().map(|(order_no, _)| *order_no).collect();
+        assert_eq!(order_nos, vec![1, 2, 4]);
+        assert_eq!(matching[1].1, orders.get(&2).unwrap());
+
+        assert!(orders_with_statuses(&orders, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_sweep_selection() {
+        let destination = bitcoin::Address::p2wpkh(
+            &CompressedPublicKey::from_str(&pubkey_hex(1)).unwrap(),
+            bitcoin::Network::Regtest,
+        );
+        let mut unconfirmed = utxo(70_000);
+        unconfirmed.height = 0;
+        let utxos = vec![utxo(50_000), unconfirmed, utxo(30_000)];
+        let tip = utxos[0].height + 5;
+
+        let (selected, amount) = sweep_selection(&utxos, tip, &destination, 2_000).unwrap();
+        assert_eq!(selected, vec![&utxos[0], &utxos[2]]);
+        let fee = fee_for_vsize(
+            TX_OVERHEAD_VBYTES + 2 * P2WPKH_INPUT_VBYTES + output_vbytes(&destination),
+            2_000,
+        );
+        assert_eq!(amount, 80_000 - fee);
+
+        // Sweeping would leave dust after the fee.
+        assert!(sweep_selection(&[utxo(DUST_THRESHOLD)], tip, &destination, 2_000).is_err());
+        // Nothing confirmed.
+        assert!(sweep_selection(&utxos[1..2], tip, &destination, 2_000).is_err());
+    }
+
+    #[test]
+    fn test_fresh_funding_derivation() {
+        assert_eq!(fresh_funding_derivation(7, 0).to_string(), "m/84/0/7/1/0");
+        assert_eq!(fresh_funding_derivation(7, 3).to_string(), "m/84/0/7/1/3");
+        assert_ne!(
+            fresh_funding_derivation(7, 1).to_vec_u8_path(),
+            DerivationPath::p2wpkh(7, 1).to_vec_u8_path()
+        );
+    }
+
+    #[test]
+    fn test_funding_input_keys() {
+        let funding_key = |seed: u8| {
+            let public_key = CompressedPublicKey::from_str(&pubkey_hex(seed)).unwrap();
+            FundingKey {
+                key: p2wpkh::InputKey {
+                    public_key: public_key.into(),
+                    derivation_path: vec![vec![seed]],
+                },
+                address: Address::p2wpkh(&public_key, bitcoin::Network::Regtest),
+            }
+        };
+        let main = funding_key(1);
+        let fresh = vec![funding_key(2), funding_key(3)];
+
+        let mut fresh_utxo = utxo(20_000);
+        fresh_utxo.outpoint.txid = vec![7; 32];
+        let utxos = [utxo(10_000), fresh_utxo];
+        let (transaction, mut prevouts) = build_transaction_with_fee(
+            utxos.iter().collect(),
+            &main.address,
+            &PrimaryOutput::Address(main.address.clone(), 25_000),
+            1_000,
+        )
+        .unwrap();
+        let owners = HashMap::from([((vec![7; 32], 0), 1)]);
+
+        let keys = funding_input_keys(&transaction, &mut prevouts, &main.key, &fresh, &owners);
+        assert_eq!(keys, vec![main.key.clone(), fresh[1].key.clone()]);
+        assert_eq!(prevouts[0].script_pubkey, main.address.script_pubkey());
+        assert_eq!(prevouts[1].script_pubkey, fresh[1].address.script_pubkey());
+    }
+
+    #[test]
+    fn test_ensure_above_p2wsh_dust() {
+        assert!(ensure_above_p2wsh_dust(DUST_THRESHOLD_P2WSH).is_ok());
+        assert_eq!(
+            ensure_above_p2wsh_dust(100),
+            Err(HtlcError::Validation(
+                "Amount 100 is below the P2WSH dust threshold of 330 satoshi".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_stored_htlc_address_network() {
+        let address = "bcrt1qhtlc".to_string();
+        let mut order = HtlcDetail {
+            htlc_address: Some(address.clone()),
+            htlc_address_network: Some(Network::Regtest),
+            ..Default::default()
+        };
+        assert_eq!(
+            order.stored_htlc_address(Network::Regtest),
+            Ok(Some(&address))
+        );
+
+        // The canister was reconfigured for another network after the address was cached.
+        assert_eq!(
+            order.stored_htlc_address(Network::Testnet),
+            Err("cached address is for a different network; regenerate".to_string())
+        );
+
+        // Addresses cached before the network was tagged are trusted as before.
+        order.htlc_address_network = None;
+        assert_eq!(
+            order.stored_htlc_address(Network::Testnet),
+            Ok(Some(&address))
+        );
+        order.htlc_address = None;
+        assert_eq!(order.stored_htlc_address(Network::Mainnet), Ok(None));
+    }
+
+    #[test]
+    fn test_stale_htlc_addresses() {
+        assert!(stale_htlc_addresses(None, "bcrt1qcurrent").is_empty());
+        assert!(stale_htlc_addresses(Some("bcrt1qcurrent"), "bcrt1qcurrent").is_empty());
+        assert_eq!(
+            stale_htlc_addresses(Some("bcrt1qold"), "bcrt1qcurrent"),
+            vec!["bcrt1qold".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_hash160_order_script() {
+        let order = HtlcDetail {
+            initiator_pubkey: pubkey_hex(1),
+            time_lock: 144,
+            secret_hash: "ab".repeat(20),
+            hash_type: Some(HtlcHashType::Hash160),
+            ..Default::default()
+        };
+        let script = generate_p2wsh_htlc_script(
+            &order.initiator_pubkey,
+            &pubkey_hex(2),
+            &order.secret_hash,
+            order.time_lock,
+            order.timelock_type(),
+            order.hash_type(),
+        )
+        .unwrap();
+        assert_eq!(
+            script.as_bytes()[1],
+            bitcoin::opcodes::all::OP_HASH160.to_u8()
+        );
+
+        // Orders stored before HASH160 support default to SHA256.
+        assert_eq!(HtlcDetail::default().hash_type(), HtlcHashType::Sha256);
+    }
+
+    /// A funding transaction paying 40_000 satoshi to an HTLC and 5_000 back as change.
+    fn signed_withdraw() -> SignedWithdraw {
+        let htlc_address = Address::p2wsh(Script::new(), bitcoin::Network::Regtest);
+        let own_address = Address::p2wsh(Script::from_bytes(&[0x51]), bitcoin::Network::Regtest);
+        let output = |address: &Address, value| TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey: address.script_pubkey(),
+        };
+        SignedWithdraw {
+            transaction: Transaction {
+                version: Version::TWO,
+                lock_time: LockTime::ZERO,
+                input: vec![],
+                output: vec![output(&htlc_address, 40_000), output(&own_address, 5_000)],
+            },
+            htlc_address,
+            own_address,
+            responder_pubkey: pubkey_hex(2),
+            fee_detail: FeeDetail {
+                txid: "11".repeat(32),
+                fee: 306,
+                fee_rate: 2_000,
+                vsize: 153,
+            },
+        }
+    }
+
+    #[test]
+    fn test_withdraw_receipt() {
+        let withdraw = signed_withdraw();
+        assert_eq!(
+            withdraw.receipt(),
+            WithdrawResult {
+                txid: "11".repeat(32),
+                htlc_address: withdraw.htlc_address.to_string(),
+                amount: 40_000,
+                fee_paid: 306,
+                inputs: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_withdraw_appends_broadcast_record() {
+        let ctx = BitcoinContext {
+            network: Network::Regtest,
+            bitcoin_network: bitcoin::Network::Regtest,
+            key_name: "test_key",
+        };
+        let order_no = insert_order(HtlcDetail::default());
+        assert!(get_broadcast_history(10, 0).is_empty());
+
+        let withdraw = signed_withdraw();
+        let fee_detail = withdraw.fee_detail.clone();
+        let transaction = serialize(&withdraw.transaction);
+        record_withdraw(&ctx, order_no, withdraw, 1_700_000_000_000_000_000);
+        assert_eq!(
+            get_order(order_no).unwrap().funding_transaction,
+            Some(transaction)
+        );
+
+        let record = BroadcastRecord {
+            txid: "11".repeat(32),
+            order_no,
+            kind: "funding".to_string(),
+            timestamp: 1_700_000_000_000_000_000,
+            fee: 306,
+        };
+        assert_eq!(get_broadcast_history(10, 0), vec![record.clone()]);
+        assert_eq!(
+            get_transaction_fee_details(order_no),
+            vec![fee_detail.clone()]
+        );
+
+        record_broadcast(order_no, "redeem", fee_detail, 1_700_000_000_000_000_001);
+        assert_eq!(get_broadcast_history(10, 0).len(), 2);
+        assert_eq!(get_broadcast_history(1, 0), vec![record]);
+        assert_eq!(get_broadcast_history(10, 1)[0].kind, "redeem");
+        assert!(get_broadcast_history(10, 2).is_empty());
+        assert!(get_broadcast_history(u64::MAX, u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn test_network_override() {
+        let ctx = BitcoinContext {
+            network: Network::Regtest,
+            bitcoin_network: bitcoin::Network::Regtest,
+            key_name: "test_key",
+        };
+        assert_eq!(address_network(&ctx, None), bitcoin::Network::Regtest);
+        assert_eq!(
+            address_network(&ctx, Some(Network::Mainnet)),
+            bitcoin::Network::Bitcoin
+        );
+        assert!(check_network_override(&ctx, None).is_ok());
+        assert!(check_network_override(&ctx, Some(Network::Regtest)).is_ok());
+        assert_eq!(
+            check_network_override(&ctx, Some(Network::Testnet)),
+            Err("Network Testnet does not match the canister's network Regtest".to_string())
+        );
+    }
+
+    #[test]
+    fn test_apply_order_patch() {
+        let network = bitcoin::Network::Regtest;
+        let order = HtlcDetail {
+            time_lock: 144,
+            ..Default::default()
+        };
+
+        let patched = apply_order_patch(
+            1,
+            &order,
+            OrderPatch {
+                requested_amount: Some(50_000),
+                time_lock: Some(288),
+                ..Default::default()
+            },
+            network,
+        )
+        .unwrap();
+        assert_eq!(patched.requested_amount, Some(50_000));
+        assert_eq!(patched.time_lock, 288);
+
+        assert_eq!(
+            apply_order_patch(1, &order, OrderPatch::default(), network),
+            Ok(order.clone())
+        );
+
+        // An invalid field rejects the whole patch.
+        assert!(apply_order_patch(
+            1,
+            &order,
+            OrderPatch {
+                requested_amount: Some(0),
+                time_lock: Some(288),
+                ..Default::default()
+            },
+            network,
+        )
+        .is_err());
+
+        let funded = HtlcDetail {
+            status: OrderStatus::Funded,
+            ..order
+        };
+        assert_eq!(
+            apply_order_patch(
+                1,
+                &funded,
+                OrderPatch {
+                    time_lock: Some(288),
+                    ..Default::default()
+                },
+                network,
+            ),
+            Err("Order 1 is already funded".to_string())
+        );
+        assert!(apply_order_patch(
+            1,
+            &funded,
+            OrderPatch {
+                responder_pubkey: Some(pubkey_hex(2)),
+                ..Default::default()
+            },
+            network,
+        )
+        .is_err());
+        assert!(apply_order_patch(
+            1,
+            &funded,
+            OrderPatch {
+                requested_amount: Some(60_000),
+                ..Default::default()
+            },
+            network,
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_patch_responder_pubkey() {
+        let order = HtlcDetail::default();
+        let patch = |responder_pubkey: String| OrderPatch {
+            responder_pubkey: Some(responder_pubkey),
+            ..Default::default()
+        };
+
+        let patched = apply_order_patch(
+            1,
+            &order,
+            patch(pubkey_hex(2).to_uppercase()),
+            bitcoin::Network::Regtest,
+        )
+        .unwrap();
+        assert_eq!(patched.responder_pubkey, Some(pubkey_hex(2)));
+        assert_eq!(resolve_responder(None, &patched), Ok(pubkey_hex(2)));
+
+        assert!(apply_order_patch(
+            1,
+            &order,
+            patch("02abcd".to_string()),
+            bitcoin::Network::Regtest
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_funding_confirmations() {
+        let mut unconfirmed = utxo(1_000);
+        unconfirmed.height = 0;
+        let mut older = utxo(2_000);
+        older.height = 90;
+
+        assert_eq!(
+            funding_confirmations(&[older.clone(), utxo(5_000)], 102),
+            Ok(3)
+        );
+        assert_eq!(funding_confirmations(&[older, unconfirmed], 102), Ok(0));
+        assert!(funding_confirmations(&[], 102).is_err());
+    }
+
+    #[test]
+    fn test_validate_fee_rate_override() {
+        assert!(validate_fee_rate_override(MIN_RELAY_FEE_PER_BYTE).is_ok());
+        assert!(validate_fee_rate_override(25_000).is_ok());
+        assert_eq!(
+            validate_fee_rate_override(500),
+            Err(
+                "Fee rate 500 msat/vB is below the minimum relay fee rate of 1000 msat/vB; \
+                 the transaction would never confirm"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_replacement_fee() {
+        // 153 vbytes at 2 sat/vB paid 306 satoshi; the replacement must add 153 more.
+        assert_eq!(replacement_fee(153, 306, 3_000), Ok(459));
+        assert_eq!(replacement_fee(153, 306, 10_000), Ok(1_530));
+        assert_eq!(
+            replacement_fee(153, 306, 2_500),
+            Err(
+                "Fee rate 2500 msat/vB pays 383 satoshi, but replacing a transaction paying \
+                 306 satoshi requires at least 459 satoshi"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_bump_funding_fee() {
+        let withdraw = signed_withdraw();
+        let mut transaction = withdraw.transaction.clone();
+        transaction.input.push(TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::from_slice(&[[0u8; 71]]),
+        });
+
+        let replacement =
+            bump_funding_fee(&transaction, &withdraw.own_address, 306, 1_306).unwrap();
+        assert_eq!(replacement.input[0].previous_output, OutPoint::null());
+        assert!(replacement.input[0].witness.is_empty());
+        assert_eq!(replacement.output[0], transaction.output[0]);
+        assert_eq!(replacement.output[1].value.to_sat(), 4_000);
+
+        // The change must stay above dust.
+        assert!(bump_funding_fee(&transaction, &withdraw.own_address, 306, 4_400).is_err());
+        // Without change there is nothing to pay the higher fee from.
+        transaction.output.pop();
+        assert!(bump_funding_fee(&transaction, &withdraw.own_address, 306, 1_306).is_err());
+    }
+
+    #[test]
+    fn test_create_orders_batch() {
+        let mut created = Vec::new();
+        let results = create_orders_batch(
+            vec![
+                (pubkey_hex(1), 144, secret_hash_hex()),
+                ("not a key".to_string(), 144, secret_hash_hex()),
+                (pubkey_hex(3), 288, secret_hash_hex()),
+            ],
+            |initiator_pubkey, time_lock, _| {
+                normalize_pubkey(&initiator_pubkey, "initiator")?;
+                created.push(time_lock);
+                Ok(created.len() as u64 - 1)
+            },
+        );
+        assert_eq!(results[0], Ok(0));
+        assert!(results[1].is_err());
+        assert_eq!(results[2], Ok(1));
+        assert_eq!(created, [144, 288]);
+
+        let requests = vec![(pubkey_hex(1), 144, secret_hash_hex()); MAX_ORDERS_PER_BATCH + 1];
+        let results = create_orders_batch(requests, |_, _, _| Ok(0));
+        assert!(results[..MAX_ORDERS_PER_BATCH].iter().all(Result::is_ok));
+        assert!(results[MAX_ORDERS_PER_BATCH]
+            .as_ref()
+            .unwrap_err()
+            .starts_with("Not created"));
+    }
+
+    #[test]
+    fn test_order_events() {
+        let order_no = insert_order(HtlcDetail {
+            created_at_ns: 1_000,
+            ..Default::default()
+        });
+        mark_address_generated(order_no, 2_000);
+        mark_address_generated(order_no, 3_000);
+        record_event(
+            order_no,
+            OrderEventKind::FundingBroadcast,
+            Some("11".repeat(32)),
+            4_000,
+        );
+        record_event(order_no + 1, OrderEventKind::Created, None, 5_000);
+
+        let events = get_order_events(order_no);
+        assert_eq!(
+            events
+                .iter()
+                .map(|event| (event.kind, event.timestamp_ns))
+                .collect::<Vec<_>>(),
+            [
+                (OrderEventKind::Created, 1_000),
+                (OrderEventKind::AddressGenerated, 2_000),
+                (OrderEventKind::FundingBroadcast, 4_000),
+            ]
+        );
+        assert_eq!(events[2].txid, Some("11".repeat(32)));
+        assert_eq!(
+            OrderEventKind::from(OrderStatus::Claimed),
+            OrderEventKind::Claimed
+        );
+
+        for _ in 0..MAX_ORDER_EVENTS {
+            record_event(order_no + 1, OrderEventKind::Created, None, 6_000);
+        }
+        assert!(get_order_events(order_no).is_empty());
+        assert_eq!(ORDER_EVENTS.with_borrow(VecDeque::len), MAX_ORDER_EVENTS);
+    }
+
+    #[test]
+    fn test_verify_preimage() {
+        let preimage = [0x42u8; 32];
+        let order_no = insert_order(HtlcDetail {
+            secret_hash: hex::encode(HtlcHashType::Hash160.hash(&preimage)),
+            hash_type: Some(HtlcHashType::Hash160),
+            ..Default::default()
+        });
+
+        assert_eq!(verify_preimage(order_no, hex::encode(preimage)), Ok(true));
+        assert_eq!(
+            verify_preimage(order_no, hex::encode([0x43u8; 32])),
+            Ok(false)
+        );
+        assert!(verify_preimage(order_no, "not hex".to_string()).is_err());
+        assert!(verify_preimage(order_no + 1, hex::encode(preimage)).is_err());
+    }
+
+    #[test]
+    fn test_candidate_htlc_addresses() {
+        let network = bitcoin::Network::Regtest;
+        let order = HtlcDetail {
+            initiator_pubkey: pubkey_hex(1),
+            time_lock: 144,
+            secret_hash: secret_hash_hex(),
+            ..Default::default()
+        };
+
+        let candidates = candidate_htlc_addresses(
+            &order,
+            &[pubkey_hex(2).to_uppercase(), pubkey_hex(3)],
+            network,
+        )
+        .unwrap();
+        assert_eq!(candidates.len(), 2);
+        for (responder_pubkey, address) in &candidates {
+            assert_eq!(
+                *address,
+                htlc_preview(&order, responder_pubkey, network)
+                    .unwrap()
+                    .address
+            );
+        }
+        assert_eq!(candidates[0].0, pubkey_hex(2));
+        assert_ne!(candidates[0].1, candidates[1].1);
+
+        assert!(candidate_htlc_addresses(&order, &["02ab".to_string()], network).is_err());
+        assert!(candidate_htlc_addresses(
+            &order,
+            &vec![pubkey_hex(2); MAX_CANDIDATE_RESPONDERS + 1],
+            network
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_orders_older_than() {
+        let orders = order_map([(0, 1_000), (1, 5_000), (2, 9_000)].into_iter().map(
+            |(order_no, created_at_ns)| {
+                (
+                    order_no,
+                    HtlcDetail {
+                        created_at_ns,
+                        status: OrderStatus::Claimed,
+                        ..Default::default()
+                    },
+                )
+            },
+        ));
+        assert_eq!(orders_older_than(&orders, 10_000, 4_000), [0, 1]);
+        assert_eq!(orders_older_than(&orders, 10_000, 5_000), [0]);
+        assert!(orders_older_than(&orders, 10_000, 9_000).is_empty());
+    }
+
+    #[test]
+    fn test_unconfirmed_funding() {
+        let withdraw = signed_withdraw();
+        let txid = withdraw.transaction.compute_txid();
+        let mut order = HtlcDetail {
+            funding_transaction: Some(serialize(&withdraw.transaction)),
+            fee_details: vec![FeeDetail {
+                txid: txid.to_string(),
+                ..withdraw.fee_detail.clone()
+            }],
+            ..Default::default()
+        };
+        let htlc_utxo = Utxo {
+            outpoint: Outpoint {
+                txid: txid.as_byte_array().to_vec(),
+                vout: 0,
+            },
+            value: 40_000,
+            height: 0,
+        };
+
+        // 153 vbytes at 4 sat/vB is 612 satoshi, of which the parent paid 306.
+        assert_eq!(
+            unconfirmed_funding(&order, &withdraw.htlc_address, &[], 4_000),
+            Ok((vec![htlc_utxo.clone()], 306))
+        );
+        assert_eq!(
+            unconfirmed_funding(&order, &withdraw.htlc_address, &[], 1_000),
+            Ok((vec![htlc_utxo.clone()], 0))
+        );
+        assert_eq!(
+            unconfirmed_funding(&order, &withdraw.htlc_address, &[htlc_utxo], 4_000),
+            Err("No unconfirmed funding UTXO found for the HTLC address".to_string())
+        );
+
+        order.fee_details.clear();
+        assert_eq!(
+            unconfirmed_funding(&order, &withdraw.htlc_address, &[], 4_000),
+            Err(format!("No fee recorded for funding transaction {}", txid))
+        );
+        order.funding_transaction = None;
+        assert!(unconfirmed_funding(&order, &withdraw.htlc_address, &[], 4_000).is_err());
+    }
+
+    #[test]
+    fn test_fresh_funding_address_limit() {
+        let mut order = HtlcDetail {
+            funding_addresses: Some(vec!["fresh".to_string(); MAX_FRESH_FUNDING_ADDRESSES - 1]),
+            ..Default::default()
+        };
+        assert!(check_fresh_funding_address_limit(3, &order).is_ok());
+
+        order
+            .funding_addresses
+            .as_mut()
+            .unwrap()
+            .push("fresh".to_string());
+        assert_eq!(
+            check_fresh_funding_address_limit(3, &order),
+            Err("Order 3 already has the maximum of 20 fresh funding addresses".to_string())
+        );
+    }
+
+    #[test]
+    fn test_remove_order_drops_client_order_ids() {
+        let alice = Principal::from_slice(&[1]);
+        restore_orders(vec![], 0, vec![]);
+        let kept = insert_order(HtlcDetail::default());
+        let removed = insert_order(HtlcDetail::default());
+        STORAGE.with_borrow_mut(|storage| {
+            storage
+                .client_order_ids
+                .insert((alice, "kept".to_string()), kept);
+            storage
+                .client_order_ids
+                .insert((alice, "removed".to_string()), removed);
+        });
+
+        assert!(STORAGE
+            .with_borrow_mut(|storage| storage.remove_order(removed))
+            .is_some());
+        assert_eq!(order_no_for_client_id(alice, "removed"), None);
+        assert_eq!(order_no_for_client_id(alice, "kept"), Some(kept));
+        assert_eq!(
+            get_order(removed),
+            Err(format!("Order {} not found", removed))
+        );
+        restore_orders(vec![], 0, vec![]);
+    }
+}
@@ -0,0 +1,540 @@
+// This module builds HTLC witness scripts from a small set of parts, and recognises
+// which branch a spending witness took.
+//
+// Every variant has a hashlock branch for the responder and a timelock branch for the
+// initiator. The hash function and the kind of timelock are configurable, and an optional
+// third branch lets both parties spend cooperatively at any time:
+//
+//   OP_IF
+//       <OP_SHA256 | OP_HASH160> <secret_hash> OP_EQUALVERIFY <responder_pubkey>
+//   OP_ELSE
+//       <time_lock> <OP_CSV | OP_CLTV> OP_DROP <initiator_pubkey>
+//   OP_ENDIF
+//   OP_CHECKSIG
+//
+// With the cooperative branch, the OP_ELSE part becomes a nested choice between the
+// timelock branch and a 2-of-2 multisig of initiator and responder:
+//
+//   OP_IF
+//       <hashlock> <responder_pubkey> OP_CHECKSIG
+//   OP_ELSE
+//       OP_IF
+//           <timelock> <initiator_pubkey> OP_CHECKSIG
+//       OP_ELSE
+//           OP_2 <initiator_pubkey> <responder_pubkey> OP_2 OP_CHECKMULTISIG
+//       OP_ENDIF
+//   OP_ENDIF
+
+use bitcoin::{
+    blockdata::{opcodes, script::Builder},
+    consensus::deserialize,
+    hashes::{hash160, sha256, Hash},
+    script::PushBytes,
+    Address, CompressedPublicKey, Network, PublicKey, Script, ScriptBuf, Witness,
+};
+use candid::{CandidType, Deserialize};
+use ic_cdk::query;
+
+/// Largest relative timelock (in blocks) that BIP-68 can encode in an input's sequence.
+pub const MAX_CSV_BLOCKS: u64 = 0xffff;
+
+/// Absolute lock times below this value are block heights, above it UNIX timestamps.
+pub const LOCKTIME_THRESHOLD: u64 = 500_000_000;
+
+/// Hash function the hashlock branch applies to the preimage.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum HtlcHashType {
+    /// `OP_SHA256`, with a 32-byte secret hash.
+    #[default]
+    Sha256,
+    /// `OP_HASH160` (RIPEMD160 of SHA256), with a 20-byte secret hash.
+    Hash160,
+}
+
+impl HtlcHashType {
+    /// Returns the length in bytes of the hashes this function produces.
+    pub fn hash_len(self) -> usize {
+        match self {
+            HtlcHashType::Sha256 => 32,
+            HtlcHashType::Hash160 => 20,
+        }
+    }
+
+    /// Hashes `preimage` the way the script's hashlock does.
+    pub fn hash(self, preimage: &[u8]) -> Vec<u8> {
+        match self {
+            HtlcHashType::Sha256 => sha256::Hash::hash(preimage).to_byte_array().to_vec(),
+            HtlcHashType::Hash160 => hash160::Hash::hash(preimage).to_byte_array().to_vec(),
+        }
+    }
+}
+
+/// Kind of timelock guarding the initiator's branch.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum HtlcTimelockType {
+    /// Relative timelock in blocks since funding (`OP_CHECKSEQUENCEVERIFY`).
+    #[default]
+    Csv,
+    /// Absolute block height (`OP_CHECKLOCKTIMEVERIFY`).
+    Cltv,
+}
+
+/// How an HTLC's witness script is committed to in its address.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum HtlcAddressFormat {
+    /// Native SegWit: a bech32 P2WSH address.
+    #[default]
+    NativeP2wsh,
+    /// The P2WSH program wrapped in a P2SH redeem script, for wallets that can only pay
+    /// to base58 addresses.
+    NestedP2shP2wsh,
+}
+
+impl HtlcAddressFormat {
+    /// Returns the format of an output locked to `script_pubkey`.
+    pub fn of(script_pubkey: &Script) -> Self {
+        if script_pubkey.is_p2sh() {
+            HtlcAddressFormat::NestedP2shP2wsh
+        } else {
+            HtlcAddressFormat::NativeP2wsh
+        }
+    }
+
+    /// Returns the address committing to `witness_script` in this format.
+    pub fn address(self, witness_script: &Script, network: Network) -> Address {
+        match self {
+            HtlcAddressFormat::NativeP2wsh => Address::p2wsh(witness_script, network),
+            HtlcAddressFormat::NestedP2shP2wsh => Address::p2shwsh(witness_script, network),
+        }
+    }
+
+    /// Returns the scriptSig of an input spending an output locked to `witness_script`
+    /// in this format: empty for native P2WSH, and a single push of the P2WSH program
+    /// (the P2SH redeem script) for the nested format. The witness is the same for both.
+    pub fn script_sig(self, witness_script: &Script) -> ScriptBuf {
+        match self {
+            HtlcAddressFormat::NativeP2wsh => ScriptBuf::new(),
+            HtlcAddressFormat::NestedP2shP2wsh => {
+                let program = witness_script.to_p2wsh();
+                let push = <&PushBytes>::try_from(program.as_bytes())
+                    .expect("a P2WSH program is 34 bytes");
+                Builder::new().push_slice(push).into_script()
+            }
+        }
+    }
+}
+
+/// All inputs of an HTLC witness script.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct HtlcScriptParams {
+    /// Hex-encoded compressed public key of the initiator.
+    pub initiator_pubkey: String,
+    /// Hex-encoded compressed public key of the responder.
+    pub responder_pubkey: String,
+    /// Hex-encoded hash of the secret, of the length `hash_type` produces.
+    pub secret_hash: String,
+    /// Number of blocks for a CSV timelock, or block height for a CLTV timelock.
+    pub time_lock: u64,
+    pub hash_type: HtlcHashType,
+    pub timelock_type: HtlcTimelockType,
+    /// Whether to add a branch where initiator and responder can spend together.
+    pub cooperative: bool,
+}
+
+impl HtlcScriptParams {
+    /// Creates params for the default script: SHA256 hashlock, CSV timelock and no
+    /// cooperative branch.
+    pub fn new(
+        initiator_pubkey: String,
+        responder_pubkey: String,
+        secret_hash: String,
+        time_lock: u64,
+    ) -> Self {
+        Self {
+            initiator_pubkey,
+            responder_pubkey,
+            secret_hash,
+            time_lock,
+            hash_type: HtlcHashType::default(),
+            timelock_type: HtlcTimelockType::default(),
+            cooperative: false,
+        }
+    }
+}
+
+/// Checks that `pubkey` is a hex-encoded 33-byte compressed public key and returns it
+/// as lowercase hex.
+///
+/// Uncompressed keys are rejected since they would silently produce a different script,
+/// and normalizing the encoding makes the same key always yield the same HTLC address.
+pub fn normalize_pubkey(pubkey: &str, role: &str) -> Result<String, String> {
+    let bytes =
+        hex::decode(pubkey).map_err(|e| format!("Invalid {} public key hex: {}", role, e))?;
+    if bytes.len() != 33 {
+        return Err(format!(
+            "The {} public key must be a 33-byte compressed key, got {} bytes",
+            role,
+            bytes.len()
+        ));
+    }
+    if bytes[0] != 0x02 && bytes[0] != 0x03 {
+        return Err(format!(
+            "The {} public key must start with 02 or 03, got {:02x}",
+            role, bytes[0]
+        ));
+    }
+    CompressedPublicKey::from_slice(&bytes)
+        .map_err(|e| format!("Invalid {} public key: {}", role, e))?;
+    Ok(hex::encode(bytes))
+}
+
+fn parse_pubkey(pubkey: &str, role: &str) -> Result<PublicKey, String> {
+    let bytes = hex::decode(normalize_pubkey(pubkey, role)?).unwrap();
+    PublicKey::from_slice(&bytes).map_err(|e| format!("Invalid {} public key: {}", role, e))
+}
+
+/// Builds the HTLC witness script described by `params`.
+///
+/// Both keys must be compressed, since SegWit policy rejects uncompressed keys. CSV
+/// timelocks must fit BIP-68's 16-bit block count and CLTV timelocks must be block
+/// heights; timestamps are not supported.
+pub fn build_custom_htlc_script(params: &HtlcScriptParams) -> Result<ScriptBuf, String> {
+    let initiator = parse_pubkey(&params.initiator_pubkey, "initiator")?;
+    let responder = parse_pubkey(&params.responder_pubkey, "responder")?;
+    let secret_hash =
+        hex::decode(&params.secret_hash).map_err(|e| format!("Invalid secret hash hex: {}", e))?;
+
+    let (hash_opcode, hash_len) = match params.hash_type {
+        HtlcHashType::Sha256 => (opcodes::all::OP_SHA256, 32),
+        HtlcHashType::Hash160 => (opcodes::all::OP_HASH160, 20),
+    };
+    if secret_hash.len() != hash_len {
+        return Err(format!(
+            "Secret hash must be {} bytes for {:?}",
+            hash_len, params.hash_type
+        ));
+    }
+
+    let timelock_opcode = match params.timelock_type {
+        HtlcTimelockType::Csv if (1..=MAX_CSV_BLOCKS).contains(&params.time_lock) => {
+            opcodes::all::OP_CSV
+        }
+        HtlcTimelockType::Cltv if (1..LOCKTIME_THRESHOLD).contains(&params.time_lock) => {
+            opcodes::all::OP_CLTV
+        }
+        timelock_type => {
+            return Err(format!(
+                "Timelock {} is out of range for {:?}",
+                params.time_lock, timelock_type
+            ))
+        }
+    };
+
+    let hashlock = |builder: Builder| {
+        builder
+            .push_opcode(hash_opcode)
+            .push_slice(<&bitcoin::script::PushBytes>::try_from(secret_hash.as_slice()).unwrap())
+            .push_opcode(opcodes::all::OP_EQUALVERIFY)
+            .push_key(&responder)
+    };
+    let timelock = |builder: Builder| {
+        builder
+            .push_int(params.time_lock as i64)
+            .push_opcode(timelock_opcode)
+            .push_opcode(opcodes::all::OP_DROP)
+            .push_key(&initiator)
+    };
+
+    let builder = hashlock(Builder::new().push_opcode(opcodes::all::OP_IF));
+    let builder = if params.cooperative {
+        let builder = builder.push_opcode(opcodes::all::OP_CHECKSIG);
+        let builder = timelock(
+            builder
+                .push_opcode(opcodes::all::OP_ELSE)
+                .push_opcode(opcodes::all::OP_IF),
+        );
+        builder
+            .push_opcode(opcodes::all::OP_CHECKSIG)
+            .push_opcode(opcodes::all::OP_ELSE)
+            .push_int(2)
+            .push_key(&initiator)
+            .push_key(&responder)
+            .push_int(2)
+            .push_opcode(opcodes::all::OP_CHECKMULTISIG)
+            .push_opcode(opcodes::all::OP_ENDIF)
+            .push_opcode(opcodes::all::OP_ENDIF)
+    } else {
+        timelock(builder.push_opcode(opcodes::all::OP_ELSE))
+            .push_opcode(opcodes::all::OP_ENDIF)
+            .push_opcode(opcodes::all::OP_CHECKSIG)
+    };
+
+    Ok(builder.into_script())
+}
+
+/// Returns the hex-encoded HTLC witness script for the given parameters.
+///
+/// Only the default variant (see `HtlcScriptParams::new`) is used by orders; the others
+/// are available for clients that construct and spend their own HTLCs.
+#[query]
+pub fn get_custom_htlc_script(params: HtlcScriptParams) -> Result<String, String> {
+    build_custom_htlc_script(&params).map(|script| hex::encode(script.as_bytes()))
+}
+
+/// How an HTLC output was spent, as recovered from the spending input's witness.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct SpendPath {
+    /// `"hashlock"` or `"timelock"`.
+    pub path: String,
+    /// Hex-encoded preimage revealed by a hashlock spend.
+    pub preimage: Option<String>,
+}
+
+/// Classifies the witness stack of an input spending an HTLC without a cooperative
+/// branch.
+///
+/// A hashlock spend has the stack `[signature, preimage, 0x01, witness_script]` and a
+/// timelock spend `[signature, <empty>, witness_script]`.
+fn classify_witness(witness: &Witness) -> Result<SpendPath, String> {
+    let items: Vec<&[u8]> = witness.iter().collect();
+    let script = items
+        .last()
+        .map(|script| Script::from_bytes(script))
+        .ok_or_else(|| "The witness is empty".to_string())?;
+    if script.as_bytes().first() != Some(&opcodes::all::OP_IF.to_u8()) {
+        return Err("The last witness item is not an HTLC witness script".to_string());
+    }
+
+    match items.as_slice() {
+        [_, preimage, [1], _] => Ok(SpendPath {
+            path: "hashlock".to_string(),
+            preimage: Some(hex::encode(preimage)),
+        }),
+        [_, [], _] => Ok(SpendPath {
+            path: "timelock".to_string(),
+            preimage: None,
+        }),
+        _ => Err(format!(
+            "Unexpected witness with {} items for an HTLC spend",
+            items.len()
+        )),
+    }
+}
+
+/// Returns which branch of an HTLC the given witness spent, and the preimage for
+/// hashlock spends.
+///
+/// `witness_hex` is the consensus encoding of the witness: the number of stack items
+/// followed by each length-prefixed item.
+#[query]
+pub fn classify_spend_witness(witness_hex: String) -> Result<SpendPath, String> {
+    let bytes = hex::decode(&witness_hex).map_err(|e| format!("Invalid witness hex: {}", e))?;
+    let witness: Witness = deserialize(&bytes).map_err(|e| format!("Malformed witness: {}", e))?;
+    classify_witness(&witness)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{
+        opcodes::all::*,
+        script::Instruction,
+        secp256k1::{Secp256k1, SecretKey},
+    };
+
+    fn pubkey_hex(seed: u8) -> String {
+        let secret_key = SecretKey::from_slice(&[seed; 32]).unwrap();
+        secret_key.public_key(&Secp256k1::new()).to_string()
+    }
+
+    fn params(hash_len: usize) -> HtlcScriptParams {
+        HtlcScriptParams::new(
+            pubkey_hex(1),
+            pubkey_hex(2),
+            hex::encode(vec![0xab; hash_len]),
+            144,
+        )
+    }
+
+    fn opcodes(script: &ScriptBuf) -> Vec<opcodes::Opcode> {
+        script
+            .instructions()
+            .filter_map(|instruction| match instruction.unwrap() {
+                Instruction::Op(opcode) => Some(opcode),
+                Instruction::PushBytes(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_default_script() {
+        let script = build_custom_htlc_script(&params(32)).unwrap();
+        assert_eq!(
+            opcodes(&script),
+            [
+                OP_IF,
+                OP_SHA256,
+                OP_EQUALVERIFY,
+                OP_ELSE,
+                OP_CSV,
+                OP_DROP,
+                OP_ENDIF,
+                OP_CHECKSIG
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hash160_and_cltv() {
+        let mut params = params(20);
+        params.hash_type = HtlcHashType::Hash160;
+        params.timelock_type = HtlcTimelockType::Cltv;
+        params.time_lock = 800_000;
+
+        let script = build_custom_htlc_script(&params).unwrap();
+        assert_eq!(
+            opcodes(&script),
+            [
+                OP_IF,
+                OP_HASH160,
+                OP_EQUALVERIFY,
+                OP_ELSE,
+                OP_CLTV,
+                OP_DROP,
+                OP_ENDIF,
+                OP_CHECKSIG
+            ]
+        );
+
+        // A 32-byte hash does not fit HASH160, and CSV cannot encode the height.
+        params.secret_hash = hex::encode([0xab; 32]);
+        assert!(build_custom_htlc_script(&params).is_err());
+        params.secret_hash = hex::encode([0xab; 20]);
+        params.timelock_type = HtlcTimelockType::Csv;
+        assert!(build_custom_htlc_script(&params).is_err());
+    }
+
+    #[test]
+    fn test_cooperative_branch() {
+        let mut params = params(32);
+        params.cooperative = true;
+
+        let script = build_custom_htlc_script(&params).unwrap();
+        assert_eq!(
+            opcodes(&script),
+            [
+                OP_IF,
+                OP_SHA256,
+                OP_EQUALVERIFY,
+                OP_CHECKSIG,
+                OP_ELSE,
+                OP_IF,
+                OP_CSV,
+                OP_DROP,
+                OP_CHECKSIG,
+                OP_ELSE,
+                OP_PUSHNUM_2,
+                OP_PUSHNUM_2,
+                OP_CHECKMULTISIG,
+                OP_ENDIF,
+                OP_ENDIF,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_params() {
+        let mut zero_time_lock = params(32);
+        zero_time_lock.time_lock = 0;
+        assert!(build_custom_htlc_script(&zero_time_lock).is_err());
+
+        let mut bad_key = params(32);
+        bad_key.initiator_pubkey = "02abcd".to_string();
+        assert!(build_custom_htlc_script(&bad_key).is_err());
+    }
+
+    #[test]
+    fn test_classify_spend_witness() {
+        let script = build_custom_htlc_script(&params(32)).unwrap();
+        let witness_hex = |items: &[&[u8]]| {
+            let mut witness = Witness::new();
+            for item in items {
+                witness.push(item);
+            }
+            hex::encode(bitcoin::consensus::serialize(&witness))
+        };
+
+        let claim = witness_hex(&[&[0x30; 72], &[0x42; 32], &[1], script.as_bytes()]);
+        assert_eq!(
+            classify_spend_witness(claim).unwrap(),
+            SpendPath {
+                path: "hashlock".to_string(),
+                preimage: Some("42".repeat(32)),
+            }
+        );
+
+        let refund = witness_hex(&[&[0x30; 72], &[], script.as_bytes()]);
+        assert_eq!(
+            classify_spend_witness(refund).unwrap(),
+            SpendPath {
+                path: "timelock".to_string(),
+                preimage: None,
+            }
+        );
+
+        let no_script = witness_hex(&[&[0x30; 72], &[0x02; 33]]);
+        assert!(classify_spend_witness(no_script).is_err());
+        let bad_selector = witness_hex(&[&[0x30; 72], &[0x42; 32], &[2], script.as_bytes()]);
+        assert!(classify_spend_witness(bad_selector).is_err());
+        assert!(classify_spend_witness("02ff".to_string()).is_err());
+        assert!(classify_spend_witness("zz".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_hash_type_hash() {
+        let preimage = [0x42; 32];
+        for hash_type in [HtlcHashType::Sha256, HtlcHashType::Hash160] {
+            assert_eq!(hash_type.hash(&preimage).len(), hash_type.hash_len());
+        }
+        // HASH160 is RIPEMD160 of the SHA256 hash.
+        let sha = HtlcHashType::Sha256.hash(&preimage);
+        assert_eq!(
+            HtlcHashType::Hash160.hash(&preimage),
+            bitcoin::hashes::ripemd160::Hash::hash(&sha)
+                .to_byte_array()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn test_address_format() {
+        let script = build_custom_htlc_script(&params(32)).unwrap();
+        let native = HtlcAddressFormat::NativeP2wsh.address(&script, Network::Regtest);
+        let nested = HtlcAddressFormat::NestedP2shP2wsh.address(&script, Network::Regtest);
+        assert!(native.to_string().starts_with("bcrt1q"));
+        assert!(nested.to_string().starts_with('2'));
+        assert_eq!(
+            HtlcAddressFormat::of(&native.script_pubkey()),
+            HtlcAddressFormat::NativeP2wsh
+        );
+        assert_eq!(
+            HtlcAddressFormat::of(&nested.script_pubkey()),
+            HtlcAddressFormat::NestedP2shP2wsh
+        );
+
+        assert!(HtlcAddressFormat::NativeP2wsh
+            .script_sig(&script)
+            .is_empty());
+        // The scriptSig pushes the P2WSH program, which the P2SH address hashes.
+        let script_sig = HtlcAddressFormat::NestedP2shP2wsh.script_sig(&script);
+        let pushes: Vec<_> = script_sig.instructions().collect::<Result<_, _>>().unwrap();
+        let [Instruction::PushBytes(program)] = pushes.as_slice() else {
+            panic!("expected a single push, got {:?}", pushes);
+        };
+        assert_eq!(program.as_bytes(), native.script_pubkey().as_bytes());
+        assert_eq!(
+            Script::from_bytes(program.as_bytes()).to_p2sh(),
+            nested.script_pubkey()
+        );
+    }
+}
@@ -1,10 +1,26 @@
+mod backup;
+#[cfg(test)]
+mod candid_tests;
+// Address-type helpers and examples are kept for reference even where no endpoint uses them yet.
+#[allow(dead_code)]
 mod common;
+mod config;
 mod ecdsa;
+mod error;
+mod htlc_orders;
+mod htlc_script;
+#[allow(dead_code)]
 mod p2tr;
 mod p2wpkh;
+mod p2wsh;
+#[allow(dead_code)]
 mod schnorr;
 mod service;
+mod stable_memory;
 mod state;
+mod usage;
+mod validate;
+#[allow(dead_code)]
 mod examples;
 
 use ic_cdk::{bitcoin_canister::Network, init, post_upgrade, pre_upgrade};
@@ -47,21 +63,24 @@ fn init_upgrade(network: Network) {
         Network::Mainnet | Network::Testnet => "test_key_1",
     };
 
-    let bitcoin_network = match network {
-        Network::Mainnet => bitcoin::Network::Bitcoin,
-        Network::Testnet => bitcoin::Network::Testnet,
-        Network::Regtest => bitcoin::Network::Regtest,
-    };
-
     BTC_CONTEXT.with(|ctx| {
         ctx.set(BitcoinContext {
             network,
-            bitcoin_network,
+            bitcoin_network: bitcoin_network(network),
             key_name,
         })
     });
 }
 
+/// Maps an IC Bitcoin network to the corresponding network of the `bitcoin` crate.
+fn bitcoin_network(network: Network) -> bitcoin::Network {
+    match network {
+        Network::Mainnet => bitcoin::Network::Bitcoin,
+        Network::Testnet => bitcoin::Network::Testnet,
+        Network::Regtest => bitcoin::Network::Regtest,
+    }
+}
+
 /// Smart contract init hook.
 /// Sets up the BitcoinContext based on the given IC Bitcoin network.
 #[init]
@@ -73,8 +92,8 @@ pub fn init(network: Network) {
 /// Saves the current state before a canister upgrade.
 #[pre_upgrade]
 fn pre_upgrade() {
-    // Save people state to stable memory
-    state::save_people_state();
+    // Save people, order and configuration state to stable memory
+    htlc_orders::save_orders_state(state::export_people());
 }
 
 /// Post-upgrade hook.
@@ -82,14 +101,17 @@ fn pre_upgrade() {
 #[post_upgrade]
 fn upgrade(network: Network) {
     init_upgrade(network);
-    
-    // Restore people state from stable memory
-    state::restore_people_state();
+
+    // Restore people, order and configuration state from stable memory
+    let message = stable_memory::load_upgrade_message();
+    state::restore_people_state(&message);
+    htlc_orders::restore_orders_state(&message);
+    config::restore_config_state(&message);
 }
 
 /// Input structure for sending Bitcoin.
 /// Used across P2PKH, P2WPKH, and P2TR transfer endpoints.
-#[derive(candid::CandidType, candid::Deserialize)]
+#[derive(candid::CandidType, candid::Deserialize, Debug, PartialEq)]
 pub struct SendRequest {
     pub destination_address: String,
     pub amount_in_satoshi: u64,
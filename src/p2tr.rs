@@ -1,5 +1,8 @@
 use crate::{
-    common::{build_transaction_with_fee, select_one_utxo, select_utxos_greedy, PrimaryOutput},
+    common::{
+        build_transaction_with_fee, fee_for_weight, select_one_utxo, select_utxos_greedy, tx_weight,
+        PrimaryOutput,
+    },
     schnorr::mock_sign_with_schnorr,
     BitcoinContext,
 };
@@ -61,6 +64,82 @@ pub fn create_spend_script(script_key_bytes: &[u8]) -> ScriptBuf {
         .into_script()
 }
 
+/// x-only NUMS ("nothing up my sleeve") point H suggested by BIP-341, the SHA256 of the
+/// uncompressed generator lifted to a point. Nobody knows its discrete logarithm, so
+/// using it as internal key makes the key path unspendable.
+pub const NUMS_INTERNAL_KEY: [u8; 32] = [
+    0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a, 0x5e,
+    0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
+];
+
+/// How a Taproot HTLC output can be spent besides its two script leaves.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TaprootSpendPolicy {
+    /// Only the claim and refund leaves can be used; the internal key is the NUMS point.
+    ScriptOnly,
+    /// The given internal key, e.g. an aggregate of initiator and responder keys, can
+    /// also spend the output cooperatively through the key path.
+    WithCooperativeKeyPath(XOnlyPublicKey),
+}
+
+impl TaprootSpendPolicy {
+    /// Returns the internal key the output key is derived from.
+    pub fn internal_key(self) -> XOnlyPublicKey {
+        match self {
+            TaprootSpendPolicy::ScriptOnly => XOnlyPublicKey::from_slice(&NUMS_INTERNAL_KEY)
+                .expect("the NUMS point should be a valid x-only key"),
+            TaprootSpendPolicy::WithCooperativeKeyPath(internal_key) => internal_key,
+        }
+    }
+}
+
+/// Constructs a Taproot leaf script for the HTLC's hashlock branch:
+/// `OP_SHA256 <secret_hash> OP_EQUALVERIFY <responder_key> OP_CHECKSIG`.
+pub fn create_htlc_claim_script(
+    secret_hash: &[u8; 32],
+    responder_key: &XOnlyPublicKey,
+) -> ScriptBuf {
+    bitcoin::blockdata::script::Builder::new()
+        .push_opcode(bitcoin::blockdata::opcodes::all::OP_SHA256)
+        .push_slice(secret_hash)
+        .push_opcode(bitcoin::blockdata::opcodes::all::OP_EQUALVERIFY)
+        .push_x_only_key(responder_key)
+        .push_opcode(bitcoin::blockdata::opcodes::all::OP_CHECKSIG)
+        .into_script()
+}
+
+/// Constructs a Taproot leaf script for the HTLC's timelock branch:
+/// `<time_lock> OP_CSV OP_DROP <initiator_key> OP_CHECKSIG`.
+pub fn create_htlc_refund_script(time_lock: u16, initiator_key: &XOnlyPublicKey) -> ScriptBuf {
+    bitcoin::blockdata::script::Builder::new()
+        .push_int(time_lock as i64)
+        .push_opcode(bitcoin::blockdata::opcodes::all::OP_CSV)
+        .push_opcode(bitcoin::blockdata::opcodes::all::OP_DROP)
+        .push_x_only_key(initiator_key)
+        .push_opcode(bitcoin::blockdata::opcodes::all::OP_CHECKSIG)
+        .into_script()
+}
+
+/// Constructs the Taproot spend info of an HTLC output.
+///
+/// The claim and refund leaves are committed at depth 1, and `policy` decides whether
+/// the key path can be used: with `ScriptOnly` the internal key is the NUMS point, so
+/// only the leaves can spend the output.
+pub fn create_htlc_taproot_spend_info(
+    policy: TaprootSpendPolicy,
+    claim_script: ScriptBuf,
+    refund_script: ScriptBuf,
+) -> TaprootSpendInfo {
+    let secp256k1_engine = Secp256k1::new();
+    TaprootBuilder::new()
+        .add_leaf(1, claim_script)
+        .expect("adding the claim leaf should work")
+        .add_leaf(1, refund_script)
+        .expect("adding the refund leaf should work")
+        .finalize(&secp256k1_engine, policy.internal_key())
+        .expect("finalizing taproot builder should work")
+}
+
 pub enum SelectUtxosMode {
     Greedy,
     Single,
@@ -118,11 +197,11 @@ pub(crate) async fn build_transaction(
         )
         .await;
 
-        let tx_vsize = signed_transaction.vsize() as u64;
-        if (tx_vsize * fee_per_byte) / 1000 == total_fee {
+        let weight = tx_weight(&signed_transaction);
+        if fee_for_weight(weight, fee_per_byte) == total_fee {
             return (transaction, prevouts);
         } else {
-            total_fee = (tx_vsize * fee_per_byte) / 1000;
+            total_fee = fee_for_weight(weight, fee_per_byte);
         }
     }
 }
@@ -134,6 +213,7 @@ pub(crate) async fn build_transaction(
 //
 // 1. All the inputs are referencing outpoints that are owned by `own_address`.
 // 2. `own_address` is a P2TR address that includes a script.
+#[allow(clippy::too_many_arguments)]
 pub async fn sign_transaction_script_spend<SignFun, Fut>(
     ctx: &BitcoinContext,
     own_address: &Address,
@@ -260,3 +340,40 @@ where
 
     transaction
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::SecretKey;
+
+    fn x_only_key(seed: u8) -> XOnlyPublicKey {
+        let secret_key = SecretKey::from_slice(&[seed; 32]).unwrap();
+        secret_key.x_only_public_key(&Secp256k1::new()).0
+    }
+
+    fn htlc_spend_info(policy: TaprootSpendPolicy) -> TaprootSpendInfo {
+        create_htlc_taproot_spend_info(
+            policy,
+            create_htlc_claim_script(&[0xab; 32], &x_only_key(2)),
+            create_htlc_refund_script(144, &x_only_key(1)),
+        )
+    }
+
+    #[test]
+    fn test_script_only_uses_nums_point() {
+        let spend_info = htlc_spend_info(TaprootSpendPolicy::ScriptOnly);
+        assert_eq!(spend_info.internal_key().serialize(), NUMS_INTERNAL_KEY);
+    }
+
+    #[test]
+    fn test_output_key_depends_on_policy() {
+        let script_only = htlc_spend_info(TaprootSpendPolicy::ScriptOnly);
+        let cooperative =
+            htlc_spend_info(TaprootSpendPolicy::WithCooperativeKeyPath(x_only_key(3)));
+
+        assert_eq!(cooperative.internal_key(), x_only_key(3));
+        assert_ne!(script_only.output_key(), cooperative.output_key());
+        // The leaves are the same under both policies.
+        assert_eq!(script_only.merkle_root(), cooperative.merkle_root());
+    }
+}
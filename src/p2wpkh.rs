@@ -1,5 +1,8 @@
 use crate::{
-    common::{build_transaction_with_fee, select_utxos_greedy, PrimaryOutput},
+    common::{
+        build_transaction_with_fee, exact_funding_selection, fee_for_weight, select_funding_utxos,
+        tx_weight, CoinSelection, PrimaryOutput,
+    },
     ecdsa::mock_sign_with_ecdsa,
     BitcoinContext,
 };
@@ -9,10 +12,19 @@ use bitcoin::{
     sighash::{EcdsaSighashType, SighashCache},
     Address, AddressType, PublicKey, ScriptBuf, Transaction, TxOut, Witness,
 };
+use futures::future::join_all;
 use ic_cdk::bitcoin_canister::{MillisatoshiPerByte, Satoshi, Utxo};
 
+/// Maximum number of signing calls issued concurrently for a single transaction.
+///
+/// Every signing call costs cycles and occupies a slot in the subnet's signing queue,
+/// so large transactions are signed in batches of this size.
+pub const MAX_CONCURRENT_SIGNATURES: usize = 16;
+
 // Builds a transaction to send the given `amount` of satoshis to the
-// destination address.
+// destination address, funded by the `own_utxos` that `coin_selection` picks.
+// Fails if the UTXOs cannot cover the amount plus the fee.
+#[allow(clippy::too_many_arguments)]
 pub async fn build_transaction(
     ctx: &BitcoinContext,
     own_public_key: &PublicKey,
@@ -21,7 +33,8 @@ pub async fn build_transaction(
     dst_address: &Address,
     amount: Satoshi,
     fee_per_vbyte: MillisatoshiPerByte,
-) -> (Transaction, Vec<TxOut>) {
+    coin_selection: CoinSelection,
+) -> Result<(Transaction, Vec<TxOut>), String> {
     // We have a chicken-and-egg problem where we need to know the length
     // of the transaction in order to compute its proper fee, but we need
     // to know the proper fee in order to figure out the inputs needed for
@@ -29,17 +42,31 @@ pub async fn build_transaction(
     //
     // We solve this problem iteratively. We start with a fee of zero, build
     // and sign a transaction, see what its size is, and then update the fee,
-    // rebuild the transaction, until the fee is set to the correct amount.
+    // rebuild the transaction, until the fee covers that size.
+    //
+    // A selection without change does not depend on the fee, so it is found once.
+    let exact_selection = exact_funding_selection(
+        own_utxos,
+        amount,
+        fee_per_vbyte,
+        dst_address,
+        coin_selection,
+    );
     let mut fee = 0;
     loop {
-        let utxos_to_spend = select_utxos_greedy(own_utxos, amount, fee).unwrap();
+        let utxos_to_spend = select_funding_utxos(
+            own_utxos,
+            amount,
+            fee,
+            exact_selection.as_deref(),
+            coin_selection,
+        )?;
         let (transaction, prevouts) = build_transaction_with_fee(
             utxos_to_spend,
             own_address,
             &PrimaryOutput::Address(dst_address.clone(), amount),
             fee,
-        )
-        .unwrap();
+        )?;
 
         // Sign the transaction. In this case, we only care about the size
         // of the signed transaction, so we use a mock signer here for efficiency.
@@ -54,12 +81,16 @@ pub async fn build_transaction(
         )
         .await;
 
-        let tx_vsize = signed_transaction.vsize() as u64;
+        let weight = tx_weight(&signed_transaction);
 
-        if (tx_vsize * fee_per_vbyte) / 1000 == fee {
-            return (transaction, prevouts);
+        // Stop once the fee covers the size rather than on an exact match: a higher fee
+        // can switch between the exact selection and the selection by value, and the
+        // smaller transaction would then ask for a lower fee again, forever.
+        let target_fee = fee_for_weight(weight, fee_per_vbyte);
+        if target_fee <= fee {
+            return Ok((transaction, prevouts));
         } else {
-            fee = (tx_vsize * fee_per_vbyte) / 1000;
+            fee = target_fee;
         }
     }
 }
@@ -75,7 +106,7 @@ pub async fn sign_transaction<SignFun, Fut>(
     ctx: &BitcoinContext,
     own_public_key: &PublicKey,
     own_address: &Address,
-    mut transaction: Transaction,
+    transaction: Transaction,
     prevouts: &[TxOut],
     derivation_path: Vec<Vec<u8>>,
     signer: SignFun,
@@ -90,25 +121,84 @@ where
         "Only P2WPKH addresses are supported"
     );
 
+    let input_keys = vec![
+        InputKey {
+            public_key: *own_public_key,
+            derivation_path,
+        };
+        transaction.input.len()
+    ];
+    sign_transaction_with_keys(ctx, transaction, prevouts, &input_keys, signer).await
+}
+
+/// Public key and derivation path of the key that signs an input.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InputKey {
+    pub public_key: PublicKey,
+    pub derivation_path: Vec<Vec<u8>>,
+}
+
+// Sign a P2WPKH bitcoin transaction whose inputs may belong to different keys.
+//
+// Input `i` is signed with `input_keys[i]`, so `prevouts[i]` must pay to the P2WPKH
+// address of that key.
+pub async fn sign_transaction_with_keys<SignFun, Fut>(
+    ctx: &BitcoinContext,
+    mut transaction: Transaction,
+    prevouts: &[TxOut],
+    input_keys: &[InputKey],
+    signer: SignFun,
+) -> Transaction
+where
+    SignFun: Fn(String, Vec<Vec<u8>>, Vec<u8>) -> Fut,
+    Fut: std::future::Future<Output = SecpSignature>,
+{
+    assert_eq!(
+        input_keys.len(),
+        transaction.input.len(),
+        "Every input needs a key"
+    );
+
     let transaction_clone = transaction.clone();
     let mut sighash_cache = SighashCache::new(&transaction_clone);
 
-    for (index, input) in transaction.input.iter_mut().enumerate() {
-        let script_pubkey = &prevouts[index].script_pubkey;
-        let value = prevouts[index].value;
-        let sighash = sighash_cache
-            .p2wpkh_signature_hash(index, script_pubkey, value, EcdsaSighashType::All)
-            .unwrap();
+    // Compute the sighash of every input up front so that the signing calls,
+    // which are by far the most expensive part, can be issued concurrently.
+    let messages: Vec<(Vec<u8>, &InputKey)> = (0..transaction.input.len())
+        .map(|index| {
+            let script_pubkey = &prevouts[index].script_pubkey;
+            let value = prevouts[index].value;
+            let sighash = sighash_cache
+                .p2wpkh_signature_hash(index, script_pubkey, value, EcdsaSighashType::All)
+                .unwrap();
 
-        let message = Message::from(sighash);
+            (Message::from(sighash).as_ref().to_vec(), &input_keys[index])
+        })
+        .collect();
 
-        let raw_signature = signer(
-            ctx.key_name.to_string(),
-            derivation_path.clone(),
-            message.as_ref().to_vec(),
-        )
-        .await;
+    // Sign in batches of at most `MAX_CONCURRENT_SIGNATURES` inputs. `join_all`
+    // returns the signatures in the order of the messages, so the mapping from
+    // input to signature is preserved regardless of completion order.
+    let mut raw_signatures = Vec::with_capacity(messages.len());
+    for batch in messages.chunks(MAX_CONCURRENT_SIGNATURES) {
+        raw_signatures.extend(
+            join_all(batch.iter().map(|(message, key)| {
+                signer(
+                    ctx.key_name.to_string(),
+                    key.derivation_path.clone(),
+                    message.clone(),
+                )
+            }))
+            .await,
+        );
+    }
 
+    for ((input, raw_signature), key) in transaction
+        .input
+        .iter_mut()
+        .zip(raw_signatures)
+        .zip(input_keys)
+    {
         let signature = BitcoinSignature {
             signature: raw_signature,
             sighash_type: EcdsaSighashType::All,
@@ -117,8 +207,210 @@ where
         input.script_sig = ScriptBuf::new();
         input.witness = Witness::new();
         input.witness.push(signature.to_vec());
-        input.witness.push(own_public_key.to_bytes());
+        input.witness.push(key.public_key.to_bytes());
     }
 
     transaction
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{
+        secp256k1::{Secp256k1, SecretKey},
+        CompressedPublicKey,
+    };
+    use futures::executor::block_on;
+    use ic_cdk::bitcoin_canister::{Network, Outpoint};
+    use std::{
+        future::Future,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    /// A future that yields to the executor `remaining` times before completing.
+    struct YieldTimes {
+        remaining: usize,
+    }
+
+    impl Future for YieldTimes {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.remaining == 0 {
+                return Poll::Ready(());
+            }
+            self.remaining -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    fn secret_key() -> SecretKey {
+        SecretKey::from_slice(&[7u8; 32]).unwrap()
+    }
+
+    // Signs for real with a fixed key, but first yields a message-dependent number
+    // of times so that the signatures complete out of input order.
+    async fn delayed_signer(
+        _key_name: String,
+        _derivation_path: Vec<Vec<u8>>,
+        message_hash: Vec<u8>,
+    ) -> SecpSignature {
+        YieldTimes {
+            remaining: (message_hash[0] % 8) as usize,
+        }
+        .await;
+        let message = Message::from_digest_slice(&message_hash).unwrap();
+        Secp256k1::new().sign_ecdsa(&message, &secret_key())
+    }
+
+    #[test]
+    fn test_concurrent_signing_preserves_input_order() {
+        let ctx = BitcoinContext {
+            network: Network::Regtest,
+            bitcoin_network: bitcoin::Network::Regtest,
+            key_name: "test_key",
+        };
+        let secp = Secp256k1::new();
+        let public_key = CompressedPublicKey(secret_key().public_key(&secp));
+        let own_address = Address::p2wpkh(&public_key, ctx.bitcoin_network);
+
+        let utxos: Vec<Utxo> = (0..5u8)
+            .map(|i| Utxo {
+                outpoint: Outpoint {
+                    txid: vec![i + 1; 32],
+                    vout: i as u32,
+                },
+                value: 10_000,
+                height: 1,
+            })
+            .collect();
+        let (transaction, prevouts) = build_transaction_with_fee(
+            utxos.iter().collect(),
+            &own_address,
+            &PrimaryOutput::Address(own_address.clone(), 45_000),
+            1_000,
+        )
+        .unwrap();
+
+        let signed = block_on(sign_transaction(
+            &ctx,
+            &public_key.into(),
+            &own_address,
+            transaction.clone(),
+            &prevouts,
+            vec![],
+            delayed_signer,
+        ));
+
+        assert_eq!(signed.input.len(), 5);
+        let mut sighash_cache = SighashCache::new(&transaction);
+        for (index, input) in signed.input.iter().enumerate() {
+            let sighash = sighash_cache
+                .p2wpkh_signature_hash(
+                    index,
+                    &prevouts[index].script_pubkey,
+                    prevouts[index].value,
+                    EcdsaSighashType::All,
+                )
+                .unwrap();
+            let signature = BitcoinSignature::from_slice(&input.witness[0]).unwrap();
+            assert!(secp
+                .verify_ecdsa(&Message::from(sighash), &signature.signature, &public_key.0)
+                .is_ok());
+            assert_eq!(input.witness[1], public_key.to_bytes());
+        }
+    }
+
+    // Signs for real with the key whose secret is the derivation path's first byte,
+    // repeated.
+    async fn path_signer(
+        _key_name: String,
+        derivation_path: Vec<Vec<u8>>,
+        message_hash: Vec<u8>,
+    ) -> SecpSignature {
+        let secret_key = SecretKey::from_slice(&[derivation_path[0][0]; 32]).unwrap();
+        let message = Message::from_digest_slice(&message_hash).unwrap();
+        Secp256k1::new().sign_ecdsa(&message, &secret_key)
+    }
+
+    #[test]
+    fn test_sign_transaction_with_keys() {
+        let ctx = BitcoinContext {
+            network: Network::Regtest,
+            bitcoin_network: bitcoin::Network::Regtest,
+            key_name: "test_key",
+        };
+        let secp = Secp256k1::new();
+        let keys: Vec<InputKey> = [3u8, 4]
+            .iter()
+            .map(|seed| InputKey {
+                public_key: CompressedPublicKey(
+                    SecretKey::from_slice(&[*seed; 32])
+                        .unwrap()
+                        .public_key(&secp),
+                )
+                .into(),
+                derivation_path: vec![vec![*seed]],
+            })
+            .collect();
+        let addresses: Vec<Address> = keys
+            .iter()
+            .map(|key| {
+                Address::p2wpkh(
+                    &CompressedPublicKey::try_from(key.public_key).unwrap(),
+                    ctx.bitcoin_network,
+                )
+            })
+            .collect();
+
+        let utxos: Vec<Utxo> = (0..2u8)
+            .map(|i| Utxo {
+                outpoint: Outpoint {
+                    txid: vec![i + 1; 32],
+                    vout: 0,
+                },
+                value: 10_000,
+                height: 1,
+            })
+            .collect();
+        let (transaction, mut prevouts) = build_transaction_with_fee(
+            utxos.iter().collect(),
+            &addresses[0],
+            &PrimaryOutput::Address(addresses[0].clone(), 19_000),
+            1_000,
+        )
+        .unwrap();
+        prevouts[1].script_pubkey = addresses[1].script_pubkey();
+
+        let signed = block_on(sign_transaction_with_keys(
+            &ctx,
+            transaction.clone(),
+            &prevouts,
+            &keys,
+            path_signer,
+        ));
+
+        let mut sighash_cache = SighashCache::new(&transaction);
+        for (index, input) in signed.input.iter().enumerate() {
+            let sighash = sighash_cache
+                .p2wpkh_signature_hash(
+                    index,
+                    &prevouts[index].script_pubkey,
+                    prevouts[index].value,
+                    EcdsaSighashType::All,
+                )
+                .unwrap();
+            let signature = BitcoinSignature::from_slice(&input.witness[0]).unwrap();
+            assert!(secp
+                .verify_ecdsa(
+                    &Message::from(sighash),
+                    &signature.signature,
+                    &keys[index].public_key.inner
+                )
+                .is_ok());
+            assert_eq!(input.witness[1], keys[index].public_key.to_bytes());
+        }
+    }
+}
@@ -0,0 +1,492 @@
+use crate::{
+    common::{fee_for_weight, tx_weight, DUST_THRESHOLD},
+    ecdsa::mock_sign_with_ecdsa,
+    htlc_script::HtlcAddressFormat,
+    p2wpkh::MAX_CONCURRENT_SIGNATURES,
+    BitcoinContext,
+};
+use bitcoin::{
+    absolute::LockTime,
+    ecdsa::Signature as BitcoinSignature,
+    hashes::Hash,
+    secp256k1::{ecdsa::Signature as SecpSignature, Message},
+    sighash::{EcdsaSighashType, SighashCache},
+    transaction::Version,
+    Address, Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+};
+use futures::future::join_all;
+use ic_cdk::bitcoin_canister::{MillisatoshiPerByte, Utxo};
+
+/// The branch of the HTLC witness script a spend takes.
+pub enum HtlcBranch {
+    /// The OP_IF branch, unlocked by the responder with the preimage of the secret hash.
+    Claim(Vec<u8>),
+    /// The OP_ELSE branch, unlocked by the initiator once the timelock has passed. The
+    /// spending inputs must carry a relative timelock in their sequence, while an absolute
+    /// timelock goes into the transaction's lock time.
+    Refund,
+}
+
+impl HtlcBranch {
+    /// Returns the witness items pushed between the signature and the witness script.
+    fn witness_items(&self) -> Vec<Vec<u8>> {
+        match self {
+            // The preimage followed by a non-empty (true) branch selector.
+            HtlcBranch::Claim(preimage) => vec![preimage.clone(), vec![1]],
+            // An empty (false) branch selector.
+            HtlcBranch::Refund => vec![vec![]],
+        }
+    }
+}
+
+/// Assembles an unsigned transaction spending all `htlc_utxos` to `outputs`.
+///
+/// A remainder output to `remainder_address` is added if what is left after the
+/// outputs and the fee is above the dust threshold; smaller remainders go to the fee.
+fn build_transaction_with_fee(
+    htlc_address: &Address,
+    htlc_utxos: &[Utxo],
+    outputs: &[(Address, u64)],
+    remainder_address: &Address,
+    sequence: Sequence,
+    lock_time: LockTime,
+    fee: u64,
+) -> Result<(Transaction, Vec<TxOut>), String> {
+    let input = htlc_utxos
+        .iter()
+        .map(|utxo| TxIn {
+            previous_output: OutPoint {
+                txid: Txid::from_raw_hash(Hash::from_slice(&utxo.outpoint.txid).unwrap()),
+                vout: utxo.outpoint.vout,
+            },
+            sequence,
+            witness: Witness::new(),      // Will be filled in during signing
+            script_sig: ScriptBuf::new(), // Filled in during signing for nested P2WSH
+        })
+        .collect();
+
+    let prevouts = htlc_utxos
+        .iter()
+        .map(|utxo| TxOut {
+            value: Amount::from_sat(utxo.value),
+            script_pubkey: htlc_address.script_pubkey(),
+        })
+        .collect();
+
+    let mut output: Vec<TxOut> = outputs
+        .iter()
+        .map(|(address, amount)| TxOut {
+            script_pubkey: address.script_pubkey(),
+            value: Amount::from_sat(*amount),
+        })
+        .collect();
+
+    let total_in: u64 = htlc_utxos.iter().map(|utxo| utxo.value).sum();
+    let total_out: u64 = outputs.iter().map(|(_, amount)| amount).sum();
+    let remainder = total_in.checked_sub(total_out + fee).ok_or_else(|| {
+        format!(
+            "Insufficient HTLC balance {} to pay {} satoshi with fee {}",
+            total_in, total_out, fee
+        )
+    })?;
+
+    if remainder >= DUST_THRESHOLD {
+        output.push(TxOut {
+            script_pubkey: remainder_address.script_pubkey(),
+            value: Amount::from_sat(remainder),
+        });
+    }
+
+    Ok((
+        Transaction {
+            input,
+            output,
+            lock_time,
+            version: Version::TWO,
+        },
+        prevouts,
+    ))
+}
+
+// Builds a transaction spending all `htlc_utxos` through the given `branch` to
+// `outputs`. Returns the unsigned transaction, its prevouts, and the fee paid.
+//
+// The fee covers the transaction's size at `fee_per_byte` plus `extra_fee`, which
+// lets a child pay for its unconfirmed parent (CPFP).
+#[allow(clippy::too_many_arguments)]
+pub async fn build_transaction(
+    ctx: &BitcoinContext,
+    htlc_address: &Address,
+    htlc_utxos: &[Utxo],
+    witness_script: &ScriptBuf,
+    branch: &HtlcBranch,
+    outputs: &[(Address, u64)],
+    remainder_address: &Address,
+    sequence: Sequence,
+    lock_time: LockTime,
+    fee_per_byte: MillisatoshiPerByte,
+    extra_fee: u64,
+) -> Result<(Transaction, Vec<TxOut>, u64), String> {
+    // Same iterative approach as the P2WPKH builder: start with a fee of zero,
+    // mock-sign to learn the size, and rebuild until the fee covers the size.
+    //
+    // The loop stops as soon as the size no longer asks for a higher fee. Waiting for
+    // an exact match could cycle forever: with a remainder just above dust, the
+    // remainder output raises the fee, the higher fee drops the output, and so on. The
+    // transaction built at the higher fee pays at least the target rate.
+    let mut fee = 0;
+    loop {
+        let (transaction, prevouts) = build_transaction_with_fee(
+            htlc_address,
+            htlc_utxos,
+            outputs,
+            remainder_address,
+            sequence,
+            lock_time,
+            fee,
+        )?;
+
+        let signed_transaction = sign_transaction(
+            ctx,
+            transaction.clone(),
+            &prevouts,
+            witness_script,
+            branch,
+            vec![], // mock derivation path
+            mock_sign_with_ecdsa,
+        )
+        .await;
+
+        let weight = tx_weight(&signed_transaction);
+        let target_fee = fee_for_weight(weight, fee_per_byte) + extra_fee;
+        if target_fee <= fee {
+            return Ok((transaction, prevouts, fee));
+        } else {
+            fee = target_fee;
+        }
+    }
+}
+
+// Sign a P2WSH HTLC spend transaction.
+//
+// IMPORTANT: This method only supports signing transactions if:
+//
+// 1. All the inputs are referencing outpoints locked by `witness_script`.
+// 2. The key derived from `derivation_path` is the one required by `branch`.
+//
+// Inputs spending a P2SH-wrapped P2WSH output get the P2WSH program as scriptSig.
+pub async fn sign_transaction<SignFun, Fut>(
+    ctx: &BitcoinContext,
+    mut transaction: Transaction,
+    prevouts: &[TxOut],
+    witness_script: &ScriptBuf,
+    branch: &HtlcBranch,
+    derivation_path: Vec<Vec<u8>>,
+    signer: SignFun,
+) -> Transaction
+where
+    SignFun: Fn(String, Vec<Vec<u8>>, Vec<u8>) -> Fut,
+    Fut: std::future::Future<Output = SecpSignature>,
+{
+    let transaction_clone = transaction.clone();
+    let mut sighash_cache = SighashCache::new(&transaction_clone);
+
+    let messages: Vec<Vec<u8>> = (0..transaction.input.len())
+        .map(|index| {
+            let sighash = sighash_cache
+                .p2wsh_signature_hash(
+                    index,
+                    witness_script,
+                    prevouts[index].value,
+                    EcdsaSighashType::All,
+                )
+                .unwrap();
+
+            Message::from(sighash).as_ref().to_vec()
+        })
+        .collect();
+
+    let mut raw_signatures = Vec::with_capacity(messages.len());
+    for batch in messages.chunks(MAX_CONCURRENT_SIGNATURES) {
+        raw_signatures.extend(
+            join_all(batch.iter().map(|message| {
+                signer(
+                    ctx.key_name.to_string(),
+                    derivation_path.clone(),
+                    message.clone(),
+                )
+            }))
+            .await,
+        );
+    }
+
+    for ((input, prevout), raw_signature) in transaction
+        .input
+        .iter_mut()
+        .zip(prevouts)
+        .zip(raw_signatures)
+    {
+        let signature = BitcoinSignature {
+            signature: raw_signature,
+            sighash_type: EcdsaSighashType::All,
+        };
+
+        input.script_sig = HtlcAddressFormat::of(&prevout.script_pubkey).script_sig(witness_script);
+        input.witness = Witness::new();
+        input.witness.push(signature.to_vec());
+        for item in branch.witness_items() {
+            input.witness.push(item);
+        }
+        input.witness.push(witness_script.as_bytes());
+    }
+
+    transaction
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{
+        secp256k1::{PublicKey, Secp256k1, SecretKey},
+        CompressedPublicKey,
+    };
+    use futures::executor::block_on;
+    use ic_cdk::bitcoin_canister::{Network, Outpoint};
+
+    fn p2wpkh_address(seed: u8) -> Address {
+        let public_key = PublicKey::from_secret_key(
+            &Secp256k1::new(),
+            &SecretKey::from_slice(&[seed; 32]).unwrap(),
+        );
+        Address::p2wpkh(&CompressedPublicKey(public_key), bitcoin::Network::Regtest)
+    }
+
+    #[test]
+    fn test_multiple_outputs_and_remainder() {
+        let ctx = BitcoinContext {
+            network: Network::Regtest,
+            bitcoin_network: bitcoin::Network::Regtest,
+            key_name: "test_key",
+        };
+        let witness_script = ScriptBuf::from_bytes(vec![0x51]); // OP_TRUE
+        let htlc_address = Address::p2wsh(&witness_script, ctx.bitcoin_network);
+        let htlc_utxos = vec![Utxo {
+            outpoint: Outpoint {
+                txid: vec![9; 32],
+                vout: 0,
+            },
+            value: 100_000,
+            height: 1,
+        }];
+        let outputs = vec![(p2wpkh_address(1), 30_000), (p2wpkh_address(2), 20_000)];
+
+        let build = |extra_fee| {
+            block_on(build_transaction(
+                &ctx,
+                &htlc_address,
+                &htlc_utxos,
+                &witness_script,
+                &HtlcBranch::Claim(vec![0; 32]),
+                &outputs,
+                &htlc_address,
+                Sequence::MAX,
+                LockTime::ZERO,
+                2_000,
+                extra_fee,
+            ))
+            .unwrap()
+        };
+        let (transaction, prevouts, fee) = build(0);
+
+        assert_eq!(prevouts.len(), 1);
+        assert_eq!(transaction.output.len(), 3);
+        assert_eq!(transaction.output[0].value.to_sat(), 30_000);
+        assert_eq!(transaction.output[1].value.to_sat(), 20_000);
+        assert_eq!(
+            transaction.output[2].script_pubkey,
+            htlc_address.script_pubkey()
+        );
+        let total_out: u64 = transaction.output.iter().map(|o| o.value.to_sat()).sum();
+        assert!(fee > 0);
+        assert_eq!(total_out + fee, 100_000);
+
+        // An extra fee comes on top and is taken from the remainder.
+        let (bumped, _, bumped_fee) = build(500);
+        assert_eq!(bumped_fee, fee + 500);
+        assert_eq!(
+            bumped.output[2].value.to_sat(),
+            transaction.output[2].value.to_sat() - 500
+        );
+    }
+
+    #[test]
+    fn test_branch_witness_items() {
+        assert_eq!(
+            HtlcBranch::Claim(vec![7; 32]).witness_items(),
+            [vec![7; 32], vec![1]]
+        );
+        assert_eq!(HtlcBranch::Refund.witness_items(), [Vec::<u8>::new()]);
+    }
+
+    #[test]
+    fn test_claim_witness_layout() {
+        let ctx = BitcoinContext {
+            network: Network::Regtest,
+            bitcoin_network: bitcoin::Network::Regtest,
+            key_name: "test_key",
+        };
+        let witness_script = ScriptBuf::from_bytes(vec![0x51]);
+        let htlc_address = Address::p2wsh(&witness_script, ctx.bitcoin_network);
+        let htlc_utxos = vec![Utxo {
+            outpoint: Outpoint {
+                txid: vec![9; 32],
+                vout: 0,
+            },
+            value: 10_000,
+            height: 1,
+        }];
+        let (transaction, prevouts) = build_transaction_with_fee(
+            &htlc_address,
+            &htlc_utxos,
+            &[],
+            &p2wpkh_address(1),
+            Sequence::MAX,
+            LockTime::ZERO,
+            500,
+        )
+        .unwrap();
+
+        let signed = block_on(sign_transaction(
+            &ctx,
+            transaction,
+            &prevouts,
+            &witness_script,
+            &HtlcBranch::Claim(vec![7; 32]),
+            vec![],
+            mock_sign_with_ecdsa,
+        ));
+
+        let witness: Vec<&[u8]> = signed.input[0].witness.iter().collect();
+        assert_eq!(witness.len(), 4);
+        assert_eq!(witness[0].last(), Some(&(EcdsaSighashType::All as u8)));
+        assert_eq!(witness[1], [7; 32]);
+        assert_eq!(witness[2], [1]);
+        assert_eq!(witness[3], witness_script.as_bytes());
+        assert_eq!(signed.output[0].value.to_sat(), 9_500);
+    }
+
+    #[test]
+    fn test_outputs_exceeding_balance_are_rejected() {
+        let witness_script = ScriptBuf::from_bytes(vec![0x51]);
+        let htlc_address = Address::p2wsh(&witness_script, bitcoin::Network::Regtest);
+        let htlc_utxos = vec![Utxo {
+            outpoint: Outpoint {
+                txid: vec![9; 32],
+                vout: 0,
+            },
+            value: 10_000,
+            height: 1,
+        }];
+
+        let result = build_transaction_with_fee(
+            &htlc_address,
+            &htlc_utxos,
+            &[(p2wpkh_address(1), 10_001)],
+            &htlc_address,
+            Sequence::MAX,
+            LockTime::ZERO,
+            0,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_nested_spend_script_sig() {
+        let ctx = BitcoinContext {
+            network: Network::Regtest,
+            bitcoin_network: bitcoin::Network::Regtest,
+            key_name: "test_key",
+        };
+        let witness_script = ScriptBuf::from_bytes(vec![0x51]);
+        let htlc_address = Address::p2shwsh(&witness_script, ctx.bitcoin_network);
+        let htlc_utxos = vec![Utxo {
+            outpoint: Outpoint {
+                txid: vec![9; 32],
+                vout: 0,
+            },
+            value: 10_000,
+            height: 1,
+        }];
+        let (transaction, prevouts) = build_transaction_with_fee(
+            &htlc_address,
+            &htlc_utxos,
+            &[],
+            &p2wpkh_address(1),
+            Sequence::MAX,
+            LockTime::ZERO,
+            500,
+        )
+        .unwrap();
+
+        let signed = block_on(sign_transaction(
+            &ctx,
+            transaction,
+            &prevouts,
+            &witness_script,
+            &HtlcBranch::Refund,
+            vec![],
+            mock_sign_with_ecdsa,
+        ));
+
+        assert_eq!(
+            signed.input[0].script_sig,
+            HtlcAddressFormat::NestedP2shP2wsh.script_sig(&witness_script)
+        );
+        assert_eq!(signed.input[0].witness.len(), 3);
+    }
+
+    #[test]
+    fn test_fee_converges_with_remainder_near_dust() {
+        let ctx = BitcoinContext {
+            network: Network::Regtest,
+            bitcoin_network: bitcoin::Network::Regtest,
+            key_name: "test_key",
+        };
+        let witness_script = ScriptBuf::from_bytes(vec![0x51]);
+        let htlc_address = Address::p2wsh(&witness_script, ctx.bitcoin_network);
+        let outputs = vec![(p2wpkh_address(1), 30_000)];
+        let build = |value| {
+            let htlc_utxos = vec![Utxo {
+                outpoint: Outpoint {
+                    txid: vec![9; 32],
+                    vout: 0,
+                },
+                value,
+                height: 1,
+            }];
+            block_on(build_transaction(
+                &ctx,
+                &htlc_address,
+                &htlc_utxos,
+                &witness_script,
+                &HtlcBranch::Refund,
+                &outputs,
+                &p2wpkh_address(2),
+                Sequence::MAX,
+                LockTime::ZERO,
+                5_000,
+                0,
+            ))
+            .unwrap()
+        };
+        // The fees with and without a remainder output.
+        let (_, _, fee_with_remainder) = build(100_000);
+        let (_, _, fee_without_remainder) = build(30_800);
+        assert!(fee_with_remainder > fee_without_remainder);
+
+        // The remainder is above dust at the lower fee but below it at the higher one.
+        let (transaction, _, fee) = build(30_000 + fee_with_remainder + DUST_THRESHOLD - 1);
+        assert_eq!(fee, fee_with_remainder);
+        assert_eq!(transaction.output.len(), 1);
+    }
+}
@@ -0,0 +1,90 @@
+// This module exposes canister-wide service endpoints that are not tied to a
+// particular order, such as reporting which key and network the canister uses.
+
+use crate::{common::DerivationPath, ecdsa::get_ecdsa_public_key, BitcoinContext, BTC_CONTEXT};
+use bitcoin::{Address, CompressedPublicKey};
+use candid::{CandidType, Deserialize};
+use ic_cdk::{bitcoin_canister::Network, query, update};
+use std::cell::RefCell;
+
+/// Describes the Bitcoin identity the canister is operating with.
+///
+/// `example_address` is the P2WPKH address derived at account 0, index 0. It lets
+/// users confirm that the canister is using the expected key on the expected network.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct BitcoinIdentity {
+    pub key_name: String,
+    pub network: String,
+    pub example_address: String,
+}
+
+/// Identifies the deployed build and the optional features it supports.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct VersionInfo {
+    pub version: String,
+    pub features: Vec<String>,
+}
+
+/// Optional capabilities of this build, for clients to check before using them.
+const FEATURES: &[&str] = &[
+    "cltv",
+    "hash160",
+    "cooperative-close",
+    "redeem-templates",
+    "prepared-withdrawals",
+    "sub-accounts",
+];
+
+// In-memory cache for the canister identity. Note: this cache is not persistent across smart contract upgrades.
+thread_local! {
+    static IDENTITY_CACHE: RefCell<Option<BitcoinIdentity>> = const { RefCell::new(None) };
+}
+
+/// Returns the canonical lowercase name of an ICP Bitcoin network, matching its Candid variant.
+pub fn network_name(network: Network) -> &'static str {
+    match network {
+        Network::Mainnet => "mainnet",
+        Network::Testnet => "testnet",
+        Network::Regtest => "regtest",
+    }
+}
+
+/// Returns the crate version the canister was built from and its supported features.
+#[query]
+pub fn get_version() -> VersionInfo {
+    VersionInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+    }
+}
+
+/// Returns the canister's Bitcoin identity: key name, network and a representative address.
+///
+/// The first call derives the ECDSA public key for the fixed path `m/84/0/0/0/0`;
+/// the result is cached for subsequent calls.
+#[update]
+pub async fn get_canister_bitcoin_identity() -> Result<BitcoinIdentity, String> {
+    if let Some(identity) = IDENTITY_CACHE.with_borrow(|cache| cache.clone()) {
+        return Ok(identity);
+    }
+
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let identity = derive_identity(&ctx).await?;
+
+    IDENTITY_CACHE.with_borrow_mut(|cache| *cache = Some(identity.clone()));
+
+    Ok(identity)
+}
+
+async fn derive_identity(ctx: &BitcoinContext) -> Result<BitcoinIdentity, String> {
+    let derivation_path = DerivationPath::p2wpkh(0, 0);
+    let public_key = get_ecdsa_public_key(ctx, derivation_path.to_vec_u8_path()).await;
+    let public_key = CompressedPublicKey::from_slice(&public_key)
+        .map_err(|e| format!("Invalid canister public key: {}", e))?;
+
+    Ok(BitcoinIdentity {
+        key_name: ctx.key_name.to_string(),
+        network: network_name(ctx.network).to_string(),
+        example_address: Address::p2wpkh(&public_key, ctx.bitcoin_network).to_string(),
+    })
+}
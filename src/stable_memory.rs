@@ -0,0 +1,112 @@
+// This module lays out the canister's stable memory.
+//
+// Stable memory is split into virtual memories by a `MemoryManager`. One holds the order
+// book, which `htlc_orders` updates in place as orders change, so it survives upgrades
+// without being serialized. Two more hold the log of broadcast transactions, and another
+// holds the message the pre_upgrade hook saves the remaining heap state in.
+
+use candid::{de::IDLDeserialize, utils::ArgumentDecoder};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    DefaultMemoryImpl, StableCell,
+};
+use std::cell::RefCell;
+
+/// A virtual memory handed out by the memory manager.
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Memory holding the order book, see `htlc_orders::OrderStorage`.
+pub const ORDERS_MEMORY_ID: MemoryId = MemoryId::new(0);
+
+/// Memory holding the message saved by the pre_upgrade hook.
+const UPGRADE_MESSAGE_MEMORY_ID: MemoryId = MemoryId::new(1);
+
+/// Memories holding the index and the entries of the broadcast history, see
+/// `htlc_orders::get_broadcast_history`.
+pub const BROADCAST_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(2);
+pub const BROADCAST_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(3);
+
+/// Magic bytes every Candid message starts with.
+const CANDID_MAGIC: &[u8; 4] = b"DIDL";
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static UPGRADE_MESSAGE: RefCell<StableCell<Vec<u8>, Memory>> =
+        RefCell::new(StableCell::init(memory(UPGRADE_MESSAGE_MEMORY_ID), Vec::new()));
+}
+
+/// Returns the virtual memory with the given id.
+pub fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with_borrow(|manager| manager.get(id))
+}
+
+/// Saves `message` for the post_upgrade hook.
+/// Call this from the pre_upgrade hook in lib.rs.
+pub fn save_upgrade_message(message: Vec<u8>) {
+    UPGRADE_MESSAGE.with_borrow_mut(|cell| cell.set(message));
+}
+
+/// Returns the message the pre_upgrade hook saved, or an empty one if there is none.
+/// Call this from the post_upgrade hook in lib.rs, before anything else uses stable memory.
+///
+/// Versions before the memory manager wrote the message straight to stable memory with
+/// `stable_save`. Such a message is returned as is; it is overwritten as soon as the
+/// memory manager initializes, which is why it has to be read first.
+pub fn load_upgrade_message() -> Vec<u8> {
+    if let Some(message) = legacy_upgrade_message() {
+        return message;
+    }
+    UPGRADE_MESSAGE.with_borrow(|cell| cell.get().clone())
+}
+
+/// Returns the contents of stable memory if they hold a message written by `stable_save`.
+fn legacy_upgrade_message() -> Option<Vec<u8>> {
+    if ic_cdk::stable::stable_size() == 0 {
+        return None;
+    }
+    let mut magic = [0; 4];
+    ic_cdk::stable::stable_read(0, &mut magic);
+    (&magic == CANDID_MAGIC).then(ic_cdk::stable::stable_bytes)
+}
+
+/// Decodes the leading values of a saved message.
+///
+/// Values beyond those requested are skipped, and so are trailing bytes: a message read
+/// from raw stable memory is padded with zeros up to the page size.
+pub fn decode_message<T>(bytes: &[u8]) -> Option<T>
+where
+    T: for<'a> ArgumentDecoder<'a>,
+{
+    let mut deserializer = IDLDeserialize::new(bytes).ok()?;
+    ArgumentDecoder::decode(&mut deserializer).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_message() {
+        let mut bytes = candid::encode_args((7u64, "alice".to_string())).unwrap();
+        assert_eq!(decode_message::<(u64,)>(&bytes), Some((7,)));
+        bytes.extend_from_slice(&[0; 64]);
+        assert_eq!(
+            decode_message::<(u64, String)>(&bytes),
+            Some((7, "alice".to_string()))
+        );
+        assert_eq!(decode_message::<(String,)>(&bytes), None);
+        assert_eq!(decode_message::<(u64,)>(&[0; 8]), None);
+    }
+
+    #[test]
+    fn test_upgrade_message() {
+        assert!(UPGRADE_MESSAGE.with_borrow(|cell| cell.get().is_empty()));
+        save_upgrade_message(vec![1, 2, 3]);
+        assert_eq!(
+            UPGRADE_MESSAGE.with_borrow(|cell| cell.get().clone()),
+            [1, 2, 3]
+        );
+    }
+}
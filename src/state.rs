@@ -1,6 +1,7 @@
 // This module demonstrates how to store and manage state in an ICP canister.
 // It provides a simple example of storing person records (name + age) and querying them.
 
+use crate::validate;
 use candid::{CandidType, Deserialize};
 use ic_cdk::{query, update};
 use std::cell::RefCell;
@@ -8,7 +9,7 @@ use std::collections::HashMap;
 
 /// Represents a person with their basic information.
 /// This struct will be stored in the canister's state.
-#[derive(CandidType, Deserialize, Clone, Debug)]
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
 pub struct Person {
     pub name: String,
     pub age: u32,
@@ -43,13 +44,8 @@ thread_local! {
 #[update]
 pub fn store_person(name: String, age: u32) -> Result<String, String> {
     // Validate input
-    if name.trim().is_empty() {
-        return Err("Name cannot be empty".to_string());
-    }
-    
-    if age > 150 {
-        return Err("Age seems unrealistic (> 150)".to_string());
-    }
+    validate::non_empty(&name, "Name")?;
+    validate::in_range(age as u64, 0, 150, "Age")?;
 
     // Create the person struct
     let person = Person::new(name.clone(), age);
@@ -135,28 +131,18 @@ pub fn clear_all_people() -> String {
 // State persistence is handled in the main lib.rs file using the existing
 // pre_upgrade and post_upgrade hooks. The functions below are helper functions
 // that can be called from those hooks.
+//
+// The pre_upgrade hook saves a single message, so the people records are saved together
+// with the order book by `htlc_orders::save_orders_state`, as its first value.
 
-/// Helper function to save people state to stable memory.
-/// Call this from your main pre_upgrade hook in lib.rs.
-pub fn save_people_state() {
-    PEOPLE.with(|people| {
-        let people_map = people.borrow();
-        // Convert the HashMap to a stable format for storage
-        let stable_data: Vec<(String, Person)> = people_map.iter()
-            .map(|(k, v)| (k.clone(), v.clone()))
-            .collect();
-        
-        // Store in stable memory (this is a simplified example)
-        // In practice, you might want to use ic-stable-structures for more complex data
-        ic_cdk::storage::stable_save((stable_data,)).expect("Failed to save people state");
-    });
-}
-
-/// Helper function to restore people state from stable memory.
+/// Helper function to restore people state from the message saved before the upgrade,
+/// see `stable_memory::load_upgrade_message`.
 /// Call this from your main post_upgrade hook in lib.rs.
-pub fn restore_people_state() {
-    // Try to restore the state from stable memory
-    if let Ok((stable_data,)) = ic_cdk::storage::stable_restore::<(Vec<(String, Person)>,)>() {
+pub fn restore_people_state(message: &[u8]) {
+    // Try to restore the state from the saved message
+    if let Some((stable_data,)) =
+        crate::stable_memory::decode_message::<(Vec<(String, Person)>,)>(message)
+    {
         PEOPLE.with(|people| {
             let mut people_map = people.borrow_mut();
             for (name, person) in stable_data {
@@ -167,6 +153,28 @@ pub fn restore_people_state() {
     // If restoration fails, it just means there's no previous state to restore
 }
 
+/// Returns all stored people keyed by name, sorted by name.
+/// Used by the state backup in `backup.rs`.
+pub fn export_people() -> Vec<(String, Person)> {
+    PEOPLE.with(|people| {
+        let people_map = people.borrow();
+        let mut entries: Vec<(String, Person)> = people_map.iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    })
+}
+
+/// Replaces all stored people with `entries`.
+/// Used when restoring a state backup.
+pub fn restore_people(entries: Vec<(String, Person)>) {
+    PEOPLE.with(|people| {
+        let mut people_map = people.borrow_mut();
+        *people_map = entries.into_iter().collect();
+    });
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,144 @@
+// This module attributes the cycles spent on expensive operations to their callers.
+//
+// Costs are estimates computed from the published prices of the system APIs involved
+// (threshold ECDSA signing and the Bitcoin canister), not measured balances, so they are
+// meant for usage-based billing and quotas rather than exact accounting.
+
+use crate::BitcoinContext;
+use bitcoin::{consensus::serialize, Transaction};
+use candid::Principal;
+use ic_cdk::{
+    bitcoin_canister::{
+        cost_get_current_fee_percentiles, cost_get_utxos, cost_send_transaction,
+        GetCurrentFeePercentilesRequest, GetUtxosRequest, SendTransactionRequest,
+    },
+    management_canister::{cost_sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, SignWithEcdsaArgs},
+    query,
+};
+use std::{cell::RefCell, collections::HashMap};
+
+// Estimated cycles consumed per caller. Note: this is not persistent across smart contract upgrades.
+thread_local! {
+    static CYCLE_USAGE: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new());
+}
+
+/// Returns the cost of a single ECDSA signature with the canister's key.
+pub fn ecdsa_signature_cost(ctx: &BitcoinContext) -> u128 {
+    cost_sign_with_ecdsa(&SignWithEcdsaArgs {
+        message_hash: vec![0; 32],
+        derivation_path: vec![],
+        key_id: EcdsaKeyId {
+            curve: EcdsaCurve::Secp256k1,
+            name: ctx.key_name.to_string(),
+        },
+    })
+    .unwrap_or(0)
+}
+
+/// Estimates the cycles spent on an operation that looks up UTXOs and the fee rate,
+/// signs `signatures` inputs at `signature_cost` each, and optionally broadcasts
+/// `transaction`.
+pub fn estimate_spend_cycles(
+    ctx: &BitcoinContext,
+    transaction: &Transaction,
+    signatures: usize,
+    signature_cost: u128,
+    broadcast: bool,
+) -> u128 {
+    let lookups = cost_get_utxos(&GetUtxosRequest {
+        network: ctx.network,
+        ..Default::default()
+    }) + cost_get_current_fee_percentiles(&GetCurrentFeePercentilesRequest {
+        network: ctx.network,
+    });
+    let signing = signatures as u128 * signature_cost;
+    let sending = if broadcast {
+        estimate_broadcast_cycles(ctx, transaction)
+    } else {
+        0
+    };
+    lookups + signing + sending
+}
+
+/// Estimates the cycles spent on broadcasting `transaction`.
+pub fn estimate_broadcast_cycles(ctx: &BitcoinContext, transaction: &Transaction) -> u128 {
+    cost_send_transaction(&SendTransactionRequest {
+        network: ctx.network,
+        transaction: serialize(transaction),
+    })
+}
+
+/// Adds `cycles` to the usage of `caller`, saturating at `u64::MAX`.
+pub fn record_cycles(caller: Principal, cycles: u128) {
+    let cycles = u64::try_from(cycles).unwrap_or(u64::MAX);
+    CYCLE_USAGE.with_borrow_mut(|usage| {
+        let total = usage.entry(caller).or_default();
+        *total = total.saturating_add(cycles);
+    });
+}
+
+fn cycle_usage(principal: &Principal) -> u64 {
+    CYCLE_USAGE.with_borrow(|usage| usage.get(principal).copied().unwrap_or_default())
+}
+
+/// Returns the estimated cycles consumed by operations `principal` called.
+///
+/// Only controllers can call this method.
+#[query(guard = "crate::config::caller_is_controller")]
+pub fn get_caller_cycle_usage(principal: Principal) -> u64 {
+    cycle_usage(&principal)
+}
+
+/// Returns the estimated cycles consumed by operations the caller called.
+#[query]
+pub fn get_my_cycle_usage() -> u64 {
+    cycle_usage(&ic_cdk::api::msg_caller())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{absolute::LockTime, transaction::Version};
+    use ic_cdk::bitcoin_canister::Network;
+
+    #[test]
+    fn test_cycle_usage_accumulates_per_caller() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+
+        record_cycles(alice, 1_000);
+        record_cycles(alice, 500);
+        record_cycles(bob, u128::MAX);
+        record_cycles(bob, 1);
+
+        assert_eq!(cycle_usage(&alice), 1_500);
+        assert_eq!(cycle_usage(&bob), u64::MAX);
+        assert_eq!(cycle_usage(&Principal::anonymous()), 0);
+    }
+
+    #[test]
+    fn test_estimate_spend_cycles() {
+        let ctx = BitcoinContext {
+            network: Network::Testnet,
+            bitcoin_network: bitcoin::Network::Testnet,
+            key_name: "test_key_1",
+        };
+        let transaction = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![],
+        };
+
+        let without_broadcast = estimate_spend_cycles(&ctx, &transaction, 2, 1_000, false);
+        let with_broadcast = estimate_spend_cycles(&ctx, &transaction, 2, 1_000, true);
+        assert_eq!(
+            with_broadcast - without_broadcast,
+            estimate_broadcast_cycles(&ctx, &transaction)
+        );
+        assert_eq!(
+            estimate_spend_cycles(&ctx, &transaction, 3, 1_000, false) - without_broadcast,
+            1_000
+        );
+    }
+}
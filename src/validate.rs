@@ -0,0 +1,117 @@
+// This module holds the input checks shared by the canister's endpoints, so the same
+// kind of bad input is rejected with the same message everywhere.
+//
+// Every check names the offending field in its message and fails with
+// `HtlcError::Validation`. Endpoints that return `String` errors can use `?` on them
+// thanks to `From<HtlcError> for String`.
+
+use crate::error::HtlcError;
+
+/// Checks that `value` is not empty or whitespace only.
+pub fn non_empty(value: &str, field: &str) -> Result<(), HtlcError> {
+    if value.trim().is_empty() {
+        return Err(HtlcError::Validation(format!(
+            "{} must not be empty",
+            field
+        )));
+    }
+    Ok(())
+}
+
+/// Decodes the hex string `value`, checking that it encodes exactly `expected_len`
+/// bytes if given.
+pub fn hex_bytes(
+    value: &str,
+    field: &str,
+    expected_len: Option<usize>,
+) -> Result<Vec<u8>, HtlcError> {
+    let bytes = hex::decode(value)
+        .map_err(|e| HtlcError::Validation(format!("{} is not valid hex: {}", field, e)))?;
+    match expected_len {
+        Some(len) if bytes.len() != len => Err(HtlcError::Validation(format!(
+            "{} must be {} bytes, got {}",
+            field,
+            len,
+            bytes.len()
+        ))),
+        _ => Ok(bytes),
+    }
+}
+
+/// Checks that `min <= value <= max`.
+pub fn in_range(value: u64, min: u64, max: u64, field: &str) -> Result<(), HtlcError> {
+    if value < min || value > max {
+        return Err(HtlcError::Validation(format!(
+            "{} must be between {} and {}, got {}",
+            field, min, max, value
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that `value` is greater than zero.
+pub fn positive(value: u64, field: &str) -> Result<(), HtlcError> {
+    if value == 0 {
+        return Err(HtlcError::Validation(format!(
+            "{} must be greater than zero",
+            field
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_non_empty() {
+        assert!(non_empty("alice", "Name").is_ok());
+        assert_eq!(
+            non_empty(" \t", "Name"),
+            Err(HtlcError::Validation("Name must not be empty".to_string()))
+        );
+        assert!(non_empty("", "Name").is_err());
+    }
+
+    #[test]
+    fn test_hex_bytes() {
+        assert_eq!(hex_bytes("00ff", "Preimage", None), Ok(vec![0x00, 0xff]));
+        assert_eq!(hex_bytes("00FF", "Preimage", Some(2)), Ok(vec![0x00, 0xff]));
+        assert_eq!(
+            hex_bytes("00ff", "Secret hash", Some(32)),
+            Err(HtlcError::Validation(
+                "Secret hash must be 32 bytes, got 2".to_string()
+            ))
+        );
+        assert!(matches!(
+            hex_bytes("0g", "Preimage", None),
+            Err(HtlcError::Validation(message)) if message.starts_with("Preimage is not valid hex")
+        ));
+        assert!(hex_bytes("abc", "Preimage", None).is_err());
+    }
+
+    #[test]
+    fn test_in_range() {
+        assert!(in_range(0, 0, 150, "Age").is_ok());
+        assert!(in_range(150, 0, 150, "Age").is_ok());
+        assert_eq!(
+            in_range(151, 0, 150, "Age"),
+            Err(HtlcError::Validation(
+                "Age must be between 0 and 150, got 151".to_string()
+            ))
+        );
+        assert!(in_range(0, 1, 10, "TTL").is_err());
+    }
+
+    #[test]
+    fn test_positive() {
+        assert!(positive(1, "Amount").is_ok());
+        assert_eq!(
+            positive(0, "Amount"),
+            Err(HtlcError::Validation(
+                "Amount must be greater than zero".to_string()
+            ))
+        );
+    }
+}